@@ -0,0 +1,311 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::client::KvsClient;
+use kvs::engines::MemStore;
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+#[derive(Parser, Debug, Clone)]
+#[command(author = "Shubh")]
+#[command(version = env!("CARGO_PKG_VERSION"))]
+#[command(name = "kvs-bench")]
+#[command(about = "Measures the throughput and latency of GET and SET")]
+struct Cli {
+    #[command(subcommand)]
+    target: Target,
+
+    /// Clients sending requests at once, each on a thread of its own
+    #[arg(long, short = 'c', global = true, default_value_t = 50, value_parser = positive)]
+    clients: usize,
+
+    /// Requests each client sends
+    #[arg(long, short = 'n', global = true, default_value_t = 10_000, value_parser = positive)]
+    requests: usize,
+
+    /// Keys the requests pick from at random
+    #[arg(long, global = true, default_value_t = 10_000, value_parser = positive)]
+    keys: usize,
+
+    /// Bytes of each value set
+    #[arg(long = "value-size", short = 'd', global = true, default_value_t = 64)]
+    value_size: usize,
+
+    /// Share of requests that are GETs, the others are SETs
+    #[arg(long = "get-ratio", global = true, default_value_t = 0.5, value_parser = ratio)]
+    get_ratio: f64,
+
+    /// Leave the keys unset before the run, so GETs miss until SETs fill them
+    #[arg(long = "no-preload", global = true)]
+    no_preload: bool,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Target {
+    /// Send requests to a running server, network included. The server
+    /// serves each connection on a worker thread of its own, so it needs at
+    /// least as many --threads as there are clients.
+    Server {
+        #[arg(long = "addr", default_value = "127.0.0.1:6969")]
+        address: String,
+
+        /// Authenticate with this password, for servers started with
+        /// --requirepass
+        #[arg(long, env = "KVS_PASSWORD")]
+        password: Option<String>,
+    },
+    /// Call an engine opened in this process, without the network
+    Engine {
+        #[arg(long, value_enum, default_value = "kvs")]
+        engine: Engine,
+
+        /// Directory of the kvs engine, a temporary one removed after the run
+        /// if not given
+        #[arg(long = "data-dir")]
+        data_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+#[value(rename_all = "lowercase")]
+enum Engine {
+    Kvs,
+    Memory,
+}
+
+fn positive(arg: &str) -> std::result::Result<usize, String> {
+    match arg.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} is not a number greater than zero", arg)),
+    }
+}
+
+fn ratio(arg: &str) -> std::result::Result<f64, String> {
+    match arg.parse() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("{} is not a number from 0 to 1", arg)),
+    }
+}
+
+/// What a benchmark client sends its requests through
+trait Driver: Send {
+    fn get(&mut self, key: String) -> Result<()>;
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+}
+
+impl Driver for KvsClient {
+    fn get(&mut self, key: String) -> Result<()> {
+        KvsClient::get(self, key).map(drop)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+}
+
+/// Calls an engine directly, each client on a clone of it
+struct EngineDriver<E>(E);
+
+impl<E: KvsEngine> Driver for EngineDriver<E> {
+    fn get(&mut self, key: String) -> Result<()> {
+        self.0.get(key).map(drop)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+}
+
+/// Xorshift, random enough to pick keys and operations without a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift never leaves zero
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number below `n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// A number from 0 up to 1
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn key(n: usize) -> String {
+    format!("key:{:010}", n)
+}
+
+/// Latencies of the requests of one kind
+#[derive(Default)]
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    /// The latency `p` of the way through the sorted latencies
+    fn percentile(&self, p: f64) -> Duration {
+        let last = self.0.len() - 1;
+        self.0[(last as f64 * p).round() as usize]
+    }
+
+    fn print(&self, name: &str) {
+        if self.0.is_empty() {
+            return;
+        }
+        print!("{:<6}{:>10}", name, self.0.len());
+        for p in [0.5, 0.9, 0.99, 0.999, 1.0] {
+            print!("{:>12}", format!("{:.1?}", self.percentile(p)));
+        }
+        println!();
+    }
+}
+
+/// Sends `cli.requests` requests through each of `drivers` at once and
+/// prints the throughput and latency percentiles of GET and SET
+fn bench<D: Driver>(cli: &Cli, mut drivers: Vec<D>) -> Result<()> {
+    if !cli.no_preload {
+        let value = "x".repeat(cli.value_size);
+        let clients = drivers.len();
+        thread::scope(|scope| {
+            let preloads: Vec<_> = drivers
+                .iter_mut()
+                .enumerate()
+                .map(|(i, driver)| {
+                    let value = &value;
+                    scope.spawn(move || {
+                        (i..cli.keys)
+                            .step_by(clients)
+                            .try_for_each(|n| driver.set(key(n), value.clone()))
+                    })
+                })
+                .collect();
+            preloads
+                .into_iter()
+                .try_for_each(|preload| preload.join().unwrap())
+        })?;
+    }
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let start = Instant::now();
+    let runs = thread::scope(|scope| {
+        let runs: Vec<_> = drivers
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut driver)| {
+                scope.spawn(move || -> Result<(Latencies, Latencies)> {
+                    let mut rng = Rng::new(seed.wrapping_add(i as u64).wrapping_mul(0x9e37_79b9));
+                    let value = "x".repeat(cli.value_size);
+                    let mut gets = Latencies::default();
+                    let mut sets = Latencies::default();
+                    for _ in 0..cli.requests {
+                        let key = key(rng.below(cli.keys));
+                        if rng.unit() < cli.get_ratio {
+                            let started = Instant::now();
+                            driver.get(key)?;
+                            gets.0.push(started.elapsed());
+                        } else {
+                            let value = value.clone();
+                            let started = Instant::now();
+                            driver.set(key, value)?;
+                            sets.0.push(started.elapsed());
+                        }
+                    }
+                    Ok((gets, sets))
+                })
+            })
+            .collect();
+        runs.into_iter()
+            .map(|run| run.join().unwrap())
+            .collect::<Result<Vec<_>>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let mut gets = Latencies::default();
+    let mut sets = Latencies::default();
+    for (run_gets, run_sets) in runs {
+        gets.0.extend(run_gets.0);
+        sets.0.extend(run_sets.0);
+    }
+    gets.0.sort_unstable();
+    sets.0.sort_unstable();
+    let total = gets.0.len() + sets.0.len();
+    println!(
+        "{} clients, {} requests each, {} keys, {} byte values",
+        cli.clients, cli.requests, cli.keys, cli.value_size
+    );
+    println!(
+        "{} requests in {:.2}s, {:.0} requests/s",
+        total,
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64()
+    );
+    print!("{:<6}{:>10}", "", "requests");
+    for column in ["p50", "p90", "p99", "p99.9", "max"] {
+        print!("{:>12}", column);
+    }
+    println!();
+    gets.print("GET");
+    sets.print("SET");
+    Ok(())
+}
+
+fn bench_engine<E: KvsEngine>(cli: &Cli, engine: E) -> Result<()> {
+    let drivers = (0..cli.clients)
+        .map(|_| EngineDriver(engine.clone()))
+        .collect();
+    bench(cli, drivers)?;
+    engine.close()
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match &cli.target {
+        Target::Server { address, password } => {
+            let drivers = (0..cli.clients)
+                .map(|_| {
+                    let mut client = KvsClient::connect(address.as_str())?;
+                    if let Some(password) = password {
+                        client.auth(password.clone())?;
+                    }
+                    Ok(client)
+                })
+                .collect::<Result<_>>()
+                .map_err(|e| {
+                    KvsError::Message(format!("could not connect to {}: {:?}", address, e))
+                })?;
+            bench(&cli, drivers)
+        }
+        Target::Engine {
+            engine: Engine::Kvs,
+            data_dir,
+        } => {
+            let temp_dir;
+            let dir = match data_dir {
+                Some(dir) => dir.as_path(),
+                None => {
+                    temp_dir = TempDir::new()?;
+                    temp_dir.path()
+                }
+            };
+            bench_engine(&cli, KvStore::open(dir)?.into_handle())
+        }
+        Target::Engine {
+            engine: Engine::Memory,
+            ..
+        } => bench_engine(&cli, MemStore::new()),
+    }
+}
@@ -1,13 +1,16 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use env_logger;
 use env_logger::Builder;
-use kvs::client;
+use kvs::client::{self, KvsClient};
 use kvs::common;
-use kvs::common::RespData;
+use kvs::protocol::{self, RespValue};
 use kvs::Result;
 use log::{error, info, LevelFilter};
 use std::env;
 use std::net::TcpStream;
+use std::time::Instant;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author = "Shubh")]
@@ -16,19 +19,108 @@ use std::net::TcpStream;
 #[command(about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Cli {
     #[command(subcommand)]
-    cmd: client::Command,
+    cmd: Option<CliCommand>,
+
+    /// Print the man page and exit
+    #[arg(long, exclusive = true)]
+    man: bool,
 
     #[arg(long = "addr", global = true, default_value = "127.0.0.1:6969")]
     address: Option<String>,
+
+    /// Authenticate with this password, for servers started with
+    /// --requirepass
+    #[arg(long = "password", global = true, env = "KVS_PASSWORD")]
+    password: Option<String>,
+
+    /// Log in as this user with --password, the default user if not given
+    #[arg(long = "user", global = true, requires = "password")]
+    user: Option<String>,
+
+    /// Print the bytes of the command sent and of the reply received,
+    /// escaped to one line each
+    #[arg(long = "trace-wire", global = true)]
+    trace_wire: bool,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum CliCommand {
+    #[command(flatten)]
+    Kvs(client::Command),
+    /// Print shell completions
+    Completions { shell: Shell },
+    /// Load synthetic keys with pipelined SETs and print the throughput,
+    /// for datasets to try compaction and scans on
+    Fill {
+        /// Keys to set, such as 100000 or 1e6
+        #[arg(long, default_value = "100000", value_parser = count)]
+        keys: u64,
+        /// Bytes of each value
+        #[arg(long = "value-size", default_value_t = 256)]
+        value_size: usize,
+        /// Name of the keys, `{}` standing for the number of each
+        #[arg(long, default_value = "key:{}", value_parser = key_pattern)]
+        pattern: String,
+        /// SETs sent in each pipelined batch
+        #[arg(long, default_value = "1000", value_parser = count)]
+        batch: u64,
+    },
+}
+
+/// A count greater than zero, also taken in scientific notation such as 1e6
+fn count(arg: &str) -> std::result::Result<u64, String> {
+    let count = match arg.parse::<u64>() {
+        Ok(count) => Some(count),
+        Err(_) => arg
+            .parse::<f64>()
+            .ok()
+            .filter(|count| count.fract() == 0.0 && *count <= u64::MAX as f64)
+            .map(|count| count as u64),
+    };
+    match count {
+        Some(count) if count > 0 => Ok(count),
+        _ => Err(format!("{} is not a whole number greater than zero", arg)),
+    }
+}
+
+fn key_pattern(arg: &str) -> std::result::Result<String, String> {
+    match arg.contains("{}") {
+        true => Ok(arg.to_string()),
+        false => Err(format!("{} has no {{}} for the number of the key", arg)),
+    }
+}
+
+fn cli_command() -> clap::Command {
+    Cli::command().name("kvs-client")
 }
 
 fn handle_response(msg: &str) -> Result<()> {
-    let resp_data = common::parse_resp(msg).unwrap().1;
+    let resp_data = protocol::from_str(msg).unwrap();
     match resp_data {
-        RespData::BulkString(s) => {
-            info!("{}", s);
+        RespValue::BulkString(Some(_)) => {
+            if let Some(s) = resp_data.as_bulk_str() {
+                info!("{}", s);
+            }
+        }
+        RespValue::Integer(i) => {
+            info!("{}", i);
+        }
+        RespValue::Array(Some(elements)) => {
+            for element in elements {
+                match element {
+                    RespValue::BulkString(None) | RespValue::Null => info!("Key not found"),
+                    element => {
+                        if let Some(s) = element.as_bulk_str() {
+                            info!("{}", s)
+                        }
+                    }
+                }
+            }
+        }
+        RespValue::BulkString(None) | RespValue::Null => {
+            info!("Key not found");
         }
-        RespData::Error(e) => {
+        RespValue::Err(e) => {
             error!("{}", e);
         }
         _ => {}
@@ -36,6 +128,85 @@ fn handle_response(msg: &str) -> Result<()> {
     Ok(())
 }
 
+/// Prints INFO's `key:value` lines as aligned columns under their sections
+fn print_info(msg: &str) -> Result<()> {
+    let reply = protocol::from_str(msg).unwrap();
+    let info = match reply.as_bulk_str() {
+        Some(info) => info,
+        None => return handle_response(msg),
+    };
+    let width = info
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, _)| key.len())
+        .max()
+        .unwrap_or(0);
+    for line in info.lines() {
+        match line.split_once(':') {
+            Some((key, value)) => println!("  {:width$}  {}", key, value, width = width),
+            None => match line.strip_prefix("# ") {
+                Some(section) => println!("{}", section),
+                None => println!(),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Sets keys `0..keys` named after `pattern` in pipelined batches of `batch`
+fn fill(cli: &Cli, keys: u64, value_size: usize, pattern: &str, batch: u64) -> Result<()> {
+    let addr = common::parse_address(cli.address.clone().unwrap())?;
+    let mut client = KvsClient::connect(&addr)?.trace_wire(cli.trace_wire);
+    if let Some(password) = &cli.password {
+        match &cli.user {
+            Some(user) => client.auth_as(user.clone(), password.clone())?,
+            None => client.auth(password.clone())?,
+        }
+    }
+    let started = Instant::now();
+    let mut next = 0;
+    while next < keys {
+        let end = keys.min(next + batch);
+        let pairs: Vec<(String, String)> = (next..end)
+            .map(|n| {
+                (
+                    pattern.replace("{}", &n.to_string()),
+                    fill_value(n, value_size),
+                )
+            })
+            .collect();
+        client.set_pipelined(&pairs)?;
+        next = end;
+    }
+    let secs = started.elapsed().as_secs_f64();
+    let megabytes = (keys * value_size as u64) as f64 / 1e6;
+    println!(
+        "set {} keys of {} bytes in {:.2}s, {:.0} keys/s, {:.2} MB/s of values",
+        keys,
+        value_size,
+        secs,
+        keys as f64 / secs,
+        megabytes / secs
+    );
+    Ok(())
+}
+
+/// `size` letters and digits made up from `n`, different for every key so
+/// values compress about as well as text does
+fn fill_value(n: u64, size: usize) -> String {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+    // xorshift, seeded apart for neighbouring keys and never zero
+    let mut state = n.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            CHARS[(state % CHARS.len() as u64) as usize] as char
+        })
+        .collect()
+}
+
 fn main() -> Result<()> {
     dotenv::dotenv().ok();
     Builder::new()
@@ -43,8 +214,27 @@ fn main() -> Result<()> {
         .write_style(env_logger::WriteStyle::Always)
         .target(env_logger::Target::Stdout)
         .init();
-    let cli = Cli::parse();
-    if cli.cmd == client::Command::Version {
+    let mut cli = Cli::parse();
+    if cli.man {
+        return common::print_man_page(cli_command());
+    }
+    let cmd = match cli.cmd.take() {
+        Some(CliCommand::Kvs(cmd)) => cmd,
+        Some(CliCommand::Completions { shell }) => {
+            common::print_completions(shell, &mut cli_command());
+            return Ok(());
+        }
+        Some(CliCommand::Fill {
+            keys,
+            value_size,
+            pattern,
+            batch,
+        }) => return fill(&cli, keys, value_size, &pattern, batch),
+        None => cli_command()
+            .error(ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit(),
+    };
+    if cmd == client::Command::Version {
         info!("{}", env!("CARGO_PKG_VERSION"))
     }
 
@@ -53,9 +243,27 @@ fn main() -> Result<()> {
     match stream {
         Err(e) => error!("count not connect to server at: {}, err: {}", addr, e),
         Ok(mut stream) => {
-            client::handle_command(&cli.cmd, &mut stream).unwrap();
+            if let Some(password) = &cli.password {
+                let user = cli.user.as_deref();
+                if let Err(e) = client::authenticate(&mut stream, user, password) {
+                    error!("authentication failed: {:?}", e);
+                    return Ok(());
+                }
+            }
+            let message = client::command_message(&cmd)?;
+            if cli.trace_wire {
+                info!("-> {}", protocol::escape_wire(message.as_bytes()));
+            }
+            common::tcp_send_message(&stream, &message)?;
             let response = common::tcp_read_message(&mut stream);
-            handle_response(&response).unwrap();
+            if cli.trace_wire {
+                info!("<- {}", protocol::escape_wire(response.as_bytes()));
+            }
+            if cmd == client::Command::Info {
+                print_info(&response)?;
+            } else {
+                handle_response(&response).unwrap();
+            }
         }
     }
     Ok(())
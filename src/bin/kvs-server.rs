@@ -1,20 +1,53 @@
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 use env_logger::Builder;
-use kvs::engines::SledStore;
-use kvs::server::{self, KvsServer};
+use kvs::common;
+use kvs::engines::{MemStore, SledStore};
+use kvs::server::{KvsServer, Profile, ShutdownHandle};
 use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
-use kvs::Result;
-use kvs::{KvStore, KvsEngine};
+use kvs::{Compression, KvStore, KvStoreOptions, KvsEngine};
+use kvs::{KvsError, Result};
+use log::kv::{self, VisitSource, VisitValue};
 use log::{info, LevelFilter};
 use std::env;
 use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, ValueEnum)]
 #[value(rename_all = "lowercase")]
 enum Engine {
     Kvs,
     Sled,
+    /// Keys in memory only, lost when the server stops
+    Memory,
+}
+
+impl Engine {
+    /// Name as `--engine` takes it and the marker file holds it
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Sled => "sled",
+            Engine::Memory => "memory",
+        }
+    }
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+#[value(rename_all = "lowercase")]
+enum LogFormat {
+    /// Human readable lines on stderr
+    Text,
+    /// One JSON object a line on stdout, for log collectors
+    Json,
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -32,87 +65,536 @@ enum Pool {
 #[command(about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Opt {
     #[command(subcommand)]
-    cmd: Option<server::Command>,
-    #[arg(long = "addr", global = true, default_value = "127.0.0.1:6969")]
+    cmd: Option<ServerCommand>,
+    /// Print the man page and exit
+    #[arg(long, exclusive = true)]
+    man: bool,
+    /// Exercise the kvs engine and the filesystem of the current directory,
+    /// print a report and exit
+    #[arg(long = "self-test", exclusive = true)]
+    self_test: bool,
+    #[arg(
+        long = "addr",
+        global = true,
+        env = "KVS_ADDR",
+        default_value = "127.0.0.1:6969"
+    )]
     address: SocketAddr,
-    #[arg(long = "engine", global = true, value_enum ,default_value_t = Engine::Kvs)]
+    #[arg(long = "engine", global = true, env = "KVS_ENGINE", value_enum ,default_value_t = Engine::Kvs)]
     engine: Engine,
+    /// Format of the logs, set `RUST_LOG=kvs::request=debug` to also log
+    /// every request
+    #[arg(long = "log-format", global = true, env = "KVS_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Directory the engine keeps its files in, created if missing,
+    /// defaults to the current directory
+    #[arg(long = "data-dir", global = true, env = "KVS_DATA_DIR")]
+    data_dir: Option<PathBuf>,
     #[arg(long = "pool", global = true, value_enum, default_value_t = Pool::SharedQueue)]
     pool: Pool,
+    /// Compress the records the kvs engine writes: none, lz4, zstd or
+    /// zstd:<level>, for builds with the codec's feature
+    #[arg(long = "compression", global = true, value_parser = parse_compression, default_value = "none")]
+    compression: Compression,
+    /// Read back the logs compaction writes before removing the logs they
+    /// replace, trading a sync and a read of every copy for safety
+    #[arg(long = "verify-compaction", global = true)]
+    verify_compaction: bool,
+    /// Fail kvs engine reads and writes stuck on disk I/O for longer than
+    /// this many milliseconds with a timeout error, rather than holding a
+    /// worker thread on a hung disk
+    #[arg(long = "io-timeout", global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    io_timeout: Option<u64>,
+    /// Expire keys written without a TTL after this many seconds, for the
+    /// kvs and memory engines
+    #[arg(long = "default-ttl", global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    default_ttl: Option<u64>,
+    /// TTL in seconds of the keys starting with a prefix written without
+    /// one, as prefix=seconds, 0 to keep them from expiring. Repeat for more
+    /// prefixes, the longest one a key starts with applies.
+    #[arg(long = "namespace-ttl", global = true, value_parser = parse_namespace_ttl)]
+    namespace_ttls: Vec<(String, Option<Duration>)>,
+    /// Worker threads serving connections, defaults to the number of CPUs
+    #[arg(long = "threads", global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Run as a read-only replica of the server at this address
+    #[arg(long = "replicaof", global = true)]
+    replicaof: Option<SocketAddr>,
+    /// Reply to GET on a missing key with an error instead of a null bulk
+    /// string, as older servers did
+    #[arg(long = "missing-key-error", global = true)]
+    missing_key_error: bool,
+    /// Close connections buffering more than this many bytes of partial
+    /// requests and queued transactions
+    #[arg(long = "client-buffer-limit", global = true)]
+    client_buffer_limit: Option<usize>,
+    /// Refuse writes of keys longer than this many bytes
+    #[arg(long = "max-key-size", global = true)]
+    max_key_size: Option<usize>,
+    /// Refuse writes of values larger than this many bytes
+    #[arg(long = "max-value-size", global = true)]
+    max_value_size: Option<usize>,
+    /// Serve admin commands such as BACKUP only on this address, typically
+    /// a localhost one, and refuse them on --addr
+    #[arg(long = "admin-addr", global = true)]
+    admin_addr: Option<SocketAddr>,
+    /// Serve Prometheus metrics over HTTP at /metrics on this address
+    #[arg(long = "metrics-addr", global = true)]
+    metrics_addr: Option<SocketAddr>,
+    /// Refuse every command but AUTH until a client sent this password,
+    /// also sent to the primary of a replica
+    #[arg(long = "requirepass", global = true, env = "KVS_REQUIREPASS")]
+    requirepass: Option<String>,
+    /// Add a user as name:password:profile, where profile is readonly,
+    /// cache-client or admin. Repeat for more users, clients then have to
+    /// log in as one of them.
+    #[arg(long = "user", global = true, value_parser = parse_user)]
+    users: Vec<(String, String, Profile)>,
 }
 
-fn handle_command(cmd: &server::Command) {
-    match cmd {
-        server::Command::Version => {
-            println!("{}", env!("CARGO_PKG_VERSION"))
-        }
-    }
+fn parse_user(s: &str) -> std::result::Result<(String, String, Profile), String> {
+    let (name, rest) = s.split_once(':').ok_or("expected name:password:profile")?;
+    let (password, profile) = rest
+        .rsplit_once(':')
+        .ok_or("expected name:password:profile")?;
+    let profile = Profile::from_str(profile, true)?;
+    Ok((name.to_string(), password.to_string(), profile))
+}
+
+fn parse_namespace_ttl(s: &str) -> std::result::Result<(String, Option<Duration>), String> {
+    let (prefix, secs) = s.rsplit_once('=').ok_or("expected prefix=seconds")?;
+    let ttl = match secs.parse().map_err(|_| "expected prefix=seconds")? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    Ok((prefix.to_string(), ttl))
+}
+
+fn parse_compression(s: &str) -> std::result::Result<Compression, String> {
+    s.parse().map_err(|e| match e {
+        KvsError::Message(message) => message,
+        e => format!("{:?}", e),
+    })
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ServerCommand {
+    /// Print the version
+    Version,
+    /// Print shell completions
+    Completions { shell: Shell },
+}
+
+fn cli_command() -> clap::Command {
+    Opt::command().name("kvs-server")
 }
 
 fn main() -> Result<()> {
     dotenv::dotenv().ok();
-    Builder::new()
-        .filter(None, LevelFilter::Info)
-        .write_style(env_logger::WriteStyle::Always)
-        .target(env_logger::Target::Stderr)
-        .init();
     let opt = Opt::parse();
-    if let Some(cmd) = &opt.cmd {
-        handle_command(cmd);
+    init_logging(&opt.log_format);
+    if opt.man {
+        return common::print_man_page(cli_command());
+    }
+    if opt.self_test {
+        return self_test(&current_dir()?);
+    }
+    match &opt.cmd {
+        Some(ServerCommand::Version) => {
+            println!("{}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Some(ServerCommand::Completions { shell }) => {
+            common::print_completions(*shell, &mut cli_command());
+            return Ok(());
+        }
+        None => {}
     }
 
+    install_signal_handler()?;
     run(&opt)?;
 
     Ok(())
 }
 
+/// Logs at info level and above, or as `RUST_LOG` says, to stderr, or to
+/// stdout as one JSON object a line for `LogFormat::Json`
+fn init_logging(format: &LogFormat) {
+    let mut builder = Builder::new();
+    builder
+        .filter(None, LevelFilter::Info)
+        .parse_env("RUST_LOG");
+    match format {
+        LogFormat::Json => builder
+            .target(env_logger::Target::Stdout)
+            .format(|buf, record| {
+                let mut line = serde_json::Map::new();
+                line.insert("timestamp".into(), buf.timestamp().to_string().into());
+                line.insert("level".into(), record.level().as_str().into());
+                line.insert("target".into(), record.target().into());
+                line.insert("message".into(), record.args().to_string().into());
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                writeln!(buf, "{}", serde_json::Value::Object(line))
+            }),
+        LogFormat::Text => builder
+            .write_style(env_logger::WriteStyle::Always)
+            .target(env_logger::Target::Stderr),
+    };
+    builder.init();
+}
+
+/// Adds the key values of a log record to its JSON line
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: kv::Key<'kvs>,
+        value: kv::Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        let mut json = JsonValue(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+/// A log value as JSON, numbers and missing values kept as such
+struct JsonValue(serde_json::Value);
+
+impl<'v> VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: kv::Value) -> std::result::Result<(), kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> std::result::Result<(), kv::Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> std::result::Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> std::result::Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
+
+/// Shutdown handle of the running server, set once it is about to accept
+/// connections
+static SHUTDOWN: OnceLock<ShutdownHandle> = OnceLock::new();
+
+/// Shuts the server down on SIGINT, SIGTERM or SIGHUP. Installed before the
+/// engine opens, as PID 1 of a container the process would ignore those
+/// signals until it has a handler. A signal before the server runs or a
+/// second one exits right away.
+fn install_signal_handler() -> Result<()> {
+    let signalled = AtomicBool::new(false);
+    ctrlc::set_handler(move || match SHUTDOWN.get() {
+        Some(shutdown) if !signalled.swap(true, Ordering::SeqCst) => {
+            info!("received shutdown signal");
+            shutdown.shutdown();
+        }
+        _ => {
+            info!("received shutdown signal, exiting now");
+            process::exit(1);
+        }
+    })
+    .map_err(|e| KvsError::Message(format!("unable to install signal handler: {}", e)))
+}
+
 fn run(opt: &Opt) -> Result<()> {
-    let addr = opt.address;
     let engine = &opt.engine;
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-    info!("Listening on: {}", addr);
+    info!("Listening on: {}", opt.address);
     info!("Storage engine: {:?}", engine);
+    let threads = opt
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get() as u32));
+    info!("Worker threads: {}", threads);
+    let dir = match &opt.data_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.clone()
+        }
+        None => current_dir()?,
+    };
+    if !matches!(engine, Engine::Memory) {
+        info!("Data directory: {}", dir.display());
+        check_engine_marker(&dir, engine)?;
+    }
 
+    let mut options = KvStoreOptions::default()
+        .compression(opt.compression)
+        .verify_compaction(opt.verify_compaction);
+    if let Some(ms) = opt.io_timeout {
+        options = options.io_timeout(Duration::from_millis(ms));
+    }
+    let mut memory = MemStore::new();
+    if let Some(secs) = opt.default_ttl {
+        options = options.default_ttl(Duration::from_secs(secs));
+        memory = memory.default_ttl(Duration::from_secs(secs));
+    }
+    for (prefix, ttl) in &opt.namespace_ttls {
+        options = options.namespace_ttl(prefix, *ttl);
+        memory = memory.namespace_ttl(prefix, *ttl);
+    }
+    let open_kvs = || KvStore::open_with(&dir, options.clone()).map(KvStore::into_handle);
     match (&opt.engine, &opt.pool) {
-        (Engine::Kvs, Pool::Naive) => run_with_engine(
-            KvStore::open(&current_dir()?)?,
-            NaiveThreadPool::new(1)?,
-            addr,
-        ),
-        (Engine::Kvs, Pool::Rayon) => run_with_engine(
-            KvStore::open(&current_dir()?)?,
-            RayonThreadPool::new(1)?,
-            addr,
-        ),
-        (Engine::Kvs, Pool::SharedQueue) => run_with_engine(
-            KvStore::open(&current_dir()?)?,
-            SharedQueueThreadPool::new(1)?,
-            addr,
-        ),
-        (Engine::Sled, Pool::Naive) => run_with_engine(
-            SledStore::open(&current_dir()?)?,
-            NaiveThreadPool::new(1)?,
-            addr,
-        ),
-        (Engine::Sled, Pool::Rayon) => run_with_engine(
-            SledStore::open(&current_dir()?)?,
-            RayonThreadPool::new(1)?,
-            addr,
-        ),
+        (Engine::Kvs, Pool::Naive) => {
+            run_with_engine(open_kvs()?, NaiveThreadPool::new(threads)?, opt)
+        }
+        (Engine::Kvs, Pool::Rayon) => {
+            run_with_engine(open_kvs()?, RayonThreadPool::new(threads)?, opt)
+        }
+        (Engine::Kvs, Pool::SharedQueue) => {
+            run_with_engine(open_kvs()?, SharedQueueThreadPool::new(threads)?, opt)
+        }
+        (Engine::Sled, Pool::Naive) => {
+            run_with_engine(SledStore::open(&dir)?, NaiveThreadPool::new(threads)?, opt)
+        }
+        (Engine::Sled, Pool::Rayon) => {
+            run_with_engine(SledStore::open(&dir)?, RayonThreadPool::new(threads)?, opt)
+        }
         (Engine::Sled, Pool::SharedQueue) => run_with_engine(
-            SledStore::open(&current_dir()?)?,
-            SharedQueueThreadPool::new(1)?,
-            addr,
+            SledStore::open(&dir)?,
+            SharedQueueThreadPool::new(threads)?,
+            opt,
         ),
+        (Engine::Memory, Pool::Naive) => {
+            run_with_engine(memory, NaiveThreadPool::new(threads)?, opt)
+        }
+        (Engine::Memory, Pool::Rayon) => {
+            run_with_engine(memory, RayonThreadPool::new(threads)?, opt)
+        }
+        (Engine::Memory, Pool::SharedQueue) => {
+            run_with_engine(memory, SharedQueueThreadPool::new(threads)?, opt)
+        }
     }
 }
 
-fn run_with_engine<E: KvsEngine, P: ThreadPool>(
-    engine: E,
-    pool: P,
-    addr: SocketAddr,
-) -> Result<()> {
+fn run_with_engine<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, opt: &Opt) -> Result<()> {
     let mut server = KvsServer::new(engine, pool);
-    server.run(addr)?;
+    server.missing_key_error(opt.missing_key_error);
+    if let Some(bytes) = opt.client_buffer_limit {
+        server.client_buffer_limit(bytes);
+    }
+    if let Some(bytes) = opt.max_key_size {
+        server.max_key_size(bytes);
+    }
+    if let Some(bytes) = opt.max_value_size {
+        server.max_value_size(bytes);
+    }
+    if let Some(metrics_addr) = opt.metrics_addr {
+        info!("Metrics on: http://{}/metrics", metrics_addr);
+        server.metrics_listener(metrics_addr);
+    }
+    if let Some(admin_addr) = opt.admin_addr {
+        info!("Admin commands on: {}", admin_addr);
+        server.admin_listener(admin_addr);
+    }
+    if let Some(password) = &opt.requirepass {
+        info!("Authentication required");
+        server.require_pass(password.clone());
+    }
+    for (name, password, profile) in &opt.users {
+        info!("User {} with profile {:?}", name, profile);
+        server.add_user(name.clone(), password.clone(), *profile);
+    }
+    if let Some(primary) = opt.replicaof {
+        info!("Replica of: {}", primary);
+        server.replicate_from(primary);
+    }
+    let _ = SHUTDOWN.set(server.shutdown_handle());
+    server.run(opt.address)?;
+    Ok(())
+}
+
+/// File in the data directory naming the engine that writes it
+const ENGINE_MARKER: &str = "engine";
+
+/// Fails with `KvsError::WrongEngine` if `dir` was written by another engine
+/// than `engine`, and marks a directory no engine has written yet as
+/// `engine`'s. Directories written before there was a marker are told apart
+/// by their files.
+fn check_engine_marker(dir: &Path, engine: &Engine) -> Result<()> {
+    let marker = dir.join(ENGINE_MARKER);
+    let found = match fs::read_to_string(&marker) {
+        Ok(found) => Some(found.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut found = None;
+            for entry in fs::read_dir(dir)? {
+                let name = entry?.file_name();
+                let name = name.to_string_lossy();
+                if name.starts_with("wal_") && name.ends_with(".log") {
+                    found = Some("kvs".to_string());
+                } else if name == "conf" || name == "db" {
+                    found = Some("sled".to_string());
+                }
+            }
+            found
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match found {
+        Some(found) if found != engine.name() => Err(KvsError::WrongEngine {
+            requested: engine.name().to_string(),
+            found,
+        }),
+        Some(_) if marker.exists() => Ok(()),
+        _ => {
+            fs::write(&marker, engine.name())?;
+            Ok(())
+        }
+    }
+}
+
+/// Keys written by each step of the self-test
+const SELF_TEST_KEYS: usize = 1000;
+/// Synced writes timed by the self-test
+const SELF_TEST_SYNCS: u32 = 100;
+/// A synced write returning faster than this never reached a disk
+const NOOP_FSYNC: Duration = Duration::from_micros(20);
+
+/// Outcome of one step of the self-test
+enum Check {
+    Ok(String),
+    Warn(String),
+    Fail(String),
+}
+
+/// Runs the self-test in a temporary directory inside `dir`, so it measures
+/// the filesystem the server would store its logs on
+fn self_test(dir: &Path) -> Result<()> {
+    let temp_dir = tempfile::Builder::new()
+        .prefix(".kvs-self-test")
+        .tempdir_in(dir)?;
+    println!("kvs-server {} self-test", env!("CARGO_PKG_VERSION"));
+    println!("directory: {}", dir.display());
+
+    let mut failed = false;
+    let mut report = |name: &str, check: Result<Check>| {
+        let (status, detail) = match check {
+            Ok(Check::Ok(detail)) => ("ok", detail),
+            Ok(Check::Warn(detail)) => ("WARN", detail),
+            Ok(Check::Fail(detail)) => ("FAIL", detail),
+            Err(e) => ("FAIL", format!("{:?}", e)),
+        };
+        failed |= status == "FAIL";
+        println!("{:<12} {:<4} {}", name, status, detail);
+    };
+
+    let engine_dir = temp_dir.path().join("engine");
+    fs::create_dir(&engine_dir)?;
+    report("engine", check_engine(&engine_dir));
+    report("fsync", check_fsync(temp_dir.path()));
+    report("dir fsync", check_dir_fsync(temp_dir.path()));
+    report("rename", check_rename(temp_dir.path()));
+
+    if failed {
+        return Err(KvsError::Message("self-test failed".to_string()));
+    }
     Ok(())
 }
+
+/// Writes, reads, overwrites until compaction runs and reopens a store
+fn check_engine(dir: &Path) -> Result<Check> {
+    let options = KvStoreOptions::default()
+        .compaction_threshold(64 * 1024)
+        .compaction_interval(Duration::from_millis(10));
+    let store = KvStore::open_with(dir, options.clone())?;
+    let value = |round: usize, i: usize| format!("{}-{}-{}", round, i, "x".repeat(100));
+
+    let start = Instant::now();
+    for i in 0..SELF_TEST_KEYS {
+        store.set(format!("key{}", i), value(0, i))?;
+    }
+    let writes = start.elapsed();
+
+    let start = Instant::now();
+    for i in 0..SELF_TEST_KEYS {
+        if store.get(format!("key{}", i))? != Some(value(0, i)) {
+            return Ok(Check::Fail(format!("key{} read back wrong", i)));
+        }
+    }
+    let reads = start.elapsed();
+
+    for round in 1..=3 {
+        for i in 0..SELF_TEST_KEYS {
+            store.set(format!("key{}", i), value(round, i))?;
+        }
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.stats()?.compactions == 0 {
+        if Instant::now() > deadline {
+            return Ok(Check::Fail("compaction did not run within 5s".to_string()));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    store.close()?;
+
+    let store = KvStore::open_with(dir, options)?;
+    for i in 0..SELF_TEST_KEYS {
+        if store.get(format!("key{}", i))? != Some(value(3, i)) {
+            return Ok(Check::Fail(format!(
+                "key{} read back wrong after compaction and reopen",
+                i
+            )));
+        }
+    }
+    store.close()?;
+
+    let per_second = |elapsed: Duration| SELF_TEST_KEYS as f64 / elapsed.as_secs_f64();
+    Ok(Check::Ok(format!(
+        "{:.0} writes/s, {:.0} reads/s, compacted and reopened",
+        per_second(writes),
+        per_second(reads)
+    )))
+}
+
+/// Times synced writes. A disk takes tens of microseconds at the very least
+/// to persist a write, a filesystem answering faster ignores `fsync`, as
+/// tmpfs or a volume mounted with `nobarrier` do.
+fn check_fsync(dir: &Path) -> Result<Check> {
+    let mut file = File::create(dir.join("fsync"))?;
+    let block = [0u8; 4096];
+    let start = Instant::now();
+    for _ in 0..SELF_TEST_SYNCS {
+        file.write_all(&block)?;
+        file.sync_data()?;
+    }
+    let average = start.elapsed() / SELF_TEST_SYNCS;
+    if average < NOOP_FSYNC {
+        return Ok(Check::Warn(format!(
+            "{:?} per fsync, too fast for a disk, writes may not survive a power loss",
+            average
+        )));
+    }
+    Ok(Check::Ok(format!("{:?} per fsync", average)))
+}
+
+/// A crash can lose a newly created log file unless its directory is synced,
+/// some network filesystems refuse to sync directories
+fn check_dir_fsync(dir: &Path) -> Result<Check> {
+    match File::open(dir).and_then(|dir| dir.sync_all()) {
+        Ok(()) => Ok(Check::Ok("directories can be synced".to_string())),
+        Err(e) => Ok(Check::Warn(format!("directories cannot be synced: {}", e))),
+    }
+}
+
+/// The warm restart index is replaced by renaming a new one over it
+fn check_rename(dir: &Path) -> Result<Check> {
+    let from = dir.join("rename.new");
+    let to = dir.join("rename");
+    fs::write(&to, "old")?;
+    fs::write(&from, "new")?;
+    fs::rename(&from, &to)?;
+    if fs::read_to_string(&to)? != "new" || from.exists() {
+        return Ok(Check::Fail("rename did not replace the file".to_string()));
+    }
+    Ok(Check::Ok("renames replace files".to_string()))
+}
@@ -1,8 +1,9 @@
 use std::env;
+use std::path::{Path, PathBuf};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use kvs::KvsEngine;
-use kvs::{client, KvStore};
+use kvs::{client, dump, redis, ContentType, KvStore};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author = "Shubh")]
@@ -11,15 +12,82 @@ use kvs::{client, KvStore};
 #[command(about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Cli {
     #[command(subcommand)]
-    cmd: client::Command,
+    cmd: CliCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum CliCommand {
+    #[command(flatten)]
+    Store(client::Command),
+    /// Write every key to a dump file, resuming a dump that was cut short
+    Dump {
+        #[arg(long)]
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+        engine: Engine,
+    },
+    /// Set every key of a dump file, as written by dump or EXPORT
+    Restore {
+        #[arg(long)]
+        input: PathBuf,
+        #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+        engine: Engine,
+    },
+    /// Set the string keys of a redis AOF or RDB file and print how many
+    ImportRedis {
+        file: PathBuf,
+        #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+        engine: Engine,
+    },
+}
+
+/// Engine of the store in the current directory. The sled engine is not
+/// built yet, so `--engine sled` is refused; a dump restores into the other
+/// engines through `dump::import_file`.
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Engine {
+    Kvs,
 }
 
 fn main() -> kvs::Result<()> {
     let cli = Cli::parse();
+    let cmd = match &cli.cmd {
+        CliCommand::Store(cmd) => cmd,
+        CliCommand::Dump { output, engine } => {
+            let dir = env::current_dir()?;
+            let records = match engine {
+                Engine::Kvs => dump_store(KvStore::open(&dir)?.into_handle(), output)?,
+            };
+            println!("{}", records);
+            return Ok(());
+        }
+        CliCommand::Restore { input, engine } => {
+            let dir = env::current_dir()?;
+            let records = match engine {
+                Engine::Kvs => restore_store(KvStore::open(&dir)?.into_handle(), input)?,
+            };
+            println!("{}", records);
+            return Ok(());
+        }
+        CliCommand::ImportRedis { file, engine } => {
+            let dir = env::current_dir()?;
+            let import = match engine {
+                Engine::Kvs => import_redis(KvStore::open(&dir)?.into_handle(), file)?,
+            };
+            println!("{}", import.keys);
+            if import.skipped > 0 {
+                eprintln!(
+                    "skipped {} commands that are not string writes",
+                    import.skipped
+                );
+            }
+            return Ok(());
+        }
+    };
     let mut store = KvStore::open(std::env::current_dir().unwrap().as_path()).unwrap();
-    match &cli.cmd {
+    match cmd {
         client::Command::Get { key } => {
-            let val = store.get(key.into());
+            let val = store.get(key);
             if val.is_err() {
                 println!("Error: {:?}", val);
             }
@@ -30,17 +98,119 @@ fn main() -> kvs::Result<()> {
                 print!("Key not found");
             }
         }
-        client::Command::Set { key, value } => store.set(key.into(), value.into())?,
+        client::Command::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+            ..
+        } => store.set_with_ttl(key.into(), value.into(), *ttl)?,
+        client::Command::Set {
+            key,
+            value,
+            content_type,
+            ..
+        } => store.set_typed(key.into(), value.into(), *content_type)?,
         client::Command::Rm { key } => {
-            let val = store.remove(key.into());
+            let val = store.remove(key);
             if let Err(_) = val {
                 print!("Key not found");
                 std::process::exit(1)
             }
         }
+        client::Command::Mget { keys } => {
+            for value in store.get_many(keys)? {
+                match value {
+                    Some((value, _)) => println!("{}", String::from_utf8_lossy(&value)),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+        client::Command::Mset { pairs } => {
+            let batch = pairs
+                .chunks(2)
+                .map(|pair| match pair {
+                    [key, value] => Ok(client::Command::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                        expires_at: None,
+                        content_type: ContentType::Text,
+                        ttl: None,
+                    }),
+                    _ => Err(kvs::KvsError::InvalidCommand),
+                })
+                .collect::<kvs::Result<_>>()?;
+            store.write_batch(batch)?
+        }
+        client::Command::Backup { dest } => store.snapshot(Path::new(dest))?,
+        client::Command::Export { dest } => {
+            println!("{}", dump::export_file(&store.handle(), Path::new(dest))?)
+        }
+        client::Command::Append { key, value } => {
+            println!("{}", store.append(key.clone(), value.as_bytes())?)
+        }
+        client::Command::Exists { key } => println!("{}", store.exists(key)? as u8),
+        client::Command::Persist { key } => println!("{}", store.persist(key)? as u8),
+        client::Command::Keys { pattern } => {
+            for key in store.keys(pattern)? {
+                println!("{}", key);
+            }
+        }
+        client::Command::RmMatching { pattern } => {
+            println!("{}", store.remove_matching(pattern)?)
+        }
+        client::Command::Flushdb => {
+            store.remove_matching("*")?;
+        }
+        client::Command::Dbsize => println!("{}", store.key_count()?),
+        client::Command::Quarantine { drop: Some(key) } => {
+            println!("{}", store.drop_quarantined(key)? as u8)
+        }
+        client::Command::Quarantine { drop: None } => {
+            for quarantined in store.quarantined()? {
+                println!(
+                    "{} {}:{}",
+                    quarantined.key,
+                    quarantined.file.display(),
+                    quarantined.offset
+                );
+            }
+        }
+        client::Command::Bigkeys { count, sample } => {
+            println!("{}", store.big_keys(*count, *sample)?)
+        }
+        client::Command::TrainDictionary { sample } => {
+            println!("{}", store.train_dictionary(*sample)?)
+        }
+        client::Command::Info => {
+            let stats = store.stats()?;
+            println!("engine: {}", stats.engine);
+            println!("keys: {}", stats.keys);
+            println!("disk_bytes: {}", stats.disk_bytes);
+        }
+        client::Command::SetBytes { .. }
+        | client::Command::Merge { .. }
+        | client::Command::Shutdown { .. } => return Err(kvs::KvsError::InvalidCommand),
         client::Command::Version => {
             println!("{}", env!("CARGO_PKG_VERSION"))
         }
     }
-    Ok(())
+    store.close()
+}
+
+fn dump_store<E: KvsEngine>(store: E, output: &Path) -> kvs::Result<u64> {
+    let records = dump::export_file(&store, output)?;
+    store.close()?;
+    Ok(records)
+}
+
+fn restore_store<E: KvsEngine>(store: E, input: &Path) -> kvs::Result<u64> {
+    let records = dump::import_file(&store, input)?;
+    store.close()?;
+    Ok(records)
+}
+
+fn import_redis<E: KvsEngine>(store: E, file: &Path) -> kvs::Result<redis::RedisImport> {
+    let import = redis::import_file(&store, file)?;
+    store.close()?;
+    Ok(import)
 }
@@ -1,9 +1,14 @@
-use std::net::TcpStream;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::common::tcp_send_message;
-use crate::resp;
-use crate::KvsError;
+use crate::common::{tcp_read_message, tcp_send_message};
+use crate::protocol::{self, Decoded, FrameDecoder, KvsCommand, RespValue};
 use crate::Result;
+use crate::{ChangeEvent, ContentType, Cursor, KvsError};
 use clap::Subcommand;
 use serde::{Deserialize, Serialize};
 
@@ -19,15 +24,163 @@ pub enum Command {
         key: String,
         #[serde(rename = "v")]
         value: String,
+        /// Expiry as milliseconds since the unix epoch, only used in the log
+        #[arg(skip)]
+        #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// What the value holds, servers reject json values that do not parse
+        #[arg(long = "type", value_enum, default_value_t)]
+        #[serde(rename = "t", default, skip_serializing_if = "ContentType::is_text")]
+        content_type: ContentType,
+        /// Expire the key after this long, such as 30s or 5m, text values only
+        #[arg(long, value_parser = humantime::parse_duration, conflicts_with = "content_type")]
+        #[serde(skip)]
+        ttl: Option<Duration>,
     },
     Rm {
         #[serde(rename = "k")]
         key: String,
     },
+    /// Get the values of several keys in one request
+    Mget {
+        #[arg(required = true)]
+        #[serde(rename = "k")]
+        keys: Vec<String>,
+    },
+    /// Set several keys at once, given as key value pairs
+    Mset {
+        #[arg(required = true, num_args = 2.., value_names = ["KEY", "VALUE"])]
+        #[serde(rename = "p")]
+        pairs: Vec<String>,
+    },
+    /// A set of a value that need not be UTF-8, only used in the log and in
+    /// write batches
+    #[command(skip)]
+    SetBytes {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "v")]
+        value: Vec<u8>,
+        #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    /// Operand for the store's merge operator, only used in the log
+    #[command(skip)]
+    Merge {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "o")]
+        operand: String,
+    },
+    /// Snapshot the server's store into a directory on the server
+    Backup {
+        #[serde(rename = "d")]
+        dest: String,
+    },
+    /// Export the server's keys to a dump file on the server, resuming an
+    /// export that was cut short
+    Export {
+        #[serde(rename = "d")]
+        dest: String,
+    },
+    /// Append to the value of a key, setting it if missing, and print the
+    /// length of the new value
+    Append {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "v")]
+        value: String,
+    },
+    /// Print 1 if the key has a value, 0 otherwise
+    Exists {
+        #[serde(rename = "k")]
+        key: String,
+    },
+    /// Take the TTL off a key, print 1 if it had one, 0 otherwise
+    Persist {
+        #[serde(rename = "k")]
+        key: String,
+    },
+    /// List the keys matching a glob pattern
+    Keys {
+        #[serde(rename = "p")]
+        pattern: String,
+    },
+    /// Remove every key matching a glob pattern and print how many were
+    /// removed
+    RmMatching {
+        #[serde(rename = "p")]
+        pattern: String,
+    },
+    /// Remove every key
+    Flushdb,
+    /// Print the number of keys
+    Dbsize,
+    /// List the keys whose value failed its checksum, or drop one of them
+    Quarantine {
+        /// Remove this quarantined key
+        #[arg(long)]
+        #[serde(rename = "k", default, skip_serializing_if = "Option::is_none")]
+        drop: Option<String>,
+    },
+    /// Print the keys with the longest names, the largest values and the
+    /// most records
+    Bigkeys {
+        /// Keys of each kind to print
+        #[arg(long, default_value_t = 10)]
+        #[serde(rename = "c")]
+        count: usize,
+        /// Look at about this many keys instead of every key
+        #[arg(long)]
+        #[serde(rename = "s", default, skip_serializing_if = "Option::is_none")]
+        sample: Option<usize>,
+    },
+    /// Train a compression dictionary on a sample of the values, for
+    /// stores compressing with zstd, and print its id. Later writes are
+    /// compressed with it.
+    TrainDictionary {
+        /// Keys whose values to train on
+        #[arg(long, default_value_t = protocol::DICT_SAMPLE)]
+        #[serde(rename = "s")]
+        sample: usize,
+    },
+    /// Print the server's version, uptime, clients and storage figures
+    Info,
+    /// Stop the server, syncing its store to disk unless --nosave
+    Shutdown {
+        #[arg(long)]
+        #[serde(rename = "n", default)]
+        nosave: bool,
+    },
     #[command(name = "-V")]
     Version,
 }
 
+impl Command {
+    /// A set of `key` to `value`, a `SetBytes` unless the value is UTF-8
+    pub fn set_from_bytes(
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        content_type: ContentType,
+    ) -> Self {
+        match String::from_utf8(value) {
+            Ok(value) => Command::Set {
+                key,
+                value,
+                expires_at,
+                content_type,
+                ttl: None,
+            },
+            Err(e) => Command::SetBytes {
+                key,
+                value: e.into_bytes(),
+                expires_at,
+            },
+        }
+    }
+}
+
 pub fn parse_address(address: String) -> Result<String> {
     let parts: Vec<&str> = address.split(":").collect();
 
@@ -41,23 +194,654 @@ pub fn parse_address(address: String) -> Result<String> {
 }
 
 pub fn handle_command(cmd: &Command, stream: &mut TcpStream) -> Result<()> {
-    let resp_value = match &cmd {
-        Command::Set { key, value } => resp::RespValue::Array(Some(vec![
-            resp::RespValue::BulkString(Some(b"set".into())),
-            resp::RespValue::BulkString(Some(key.as_bytes().into())),
-            resp::RespValue::BulkString(Some(value.as_bytes().into())),
-        ])),
-        Command::Get { key } => resp::RespValue::Array(Some(vec![
-            resp::RespValue::BulkString(Some(b"get".into())),
-            resp::RespValue::BulkString(Some(key.as_bytes().into())),
-        ])),
-        Command::Rm { key } => resp::RespValue::Array(Some(vec![
-            resp::RespValue::BulkString(Some(b"rm".into())),
-            resp::RespValue::BulkString(Some(key.as_bytes().into())),
-        ])),
-        Command::Version => resp::RespValue::SimpleString("version".into()),
-    };
-    let message = resp::to_string(&resp_value).unwrap();
-    tcp_send_message(stream, &message)?;
-    Ok(())
+    tcp_send_message(stream, &command_message(cmd)?)
+}
+
+/// Encodes `cmd` as the frame `handle_command` sends
+pub fn command_message(cmd: &Command) -> Result<String> {
+    request_frame(&KvsCommand::try_from(cmd)?)
+}
+
+/// The request a command line or log command stands for. Commands only
+/// found in the log have none.
+impl TryFrom<&Command> for KvsCommand {
+    type Error = KvsError;
+
+    fn try_from(cmd: &Command) -> Result<Self> {
+        Ok(match cmd.clone() {
+            Command::Get { key } => KvsCommand::Get(key),
+            Command::Set {
+                key,
+                value,
+                content_type,
+                ttl,
+                ..
+            } => KvsCommand::Set(key, value.into_bytes(), content_type, ttl),
+            Command::Rm { key } => KvsCommand::Rm(key),
+            Command::Mget { keys } => KvsCommand::Mget(keys),
+            Command::Mset { pairs } => {
+                if !pairs.len().is_multiple_of(2) {
+                    return Err(KvsError::Message("MSET takes key value pairs".into()));
+                }
+                KvsCommand::Mset(
+                    pairs
+                        .chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone().into_bytes()))
+                        .collect(),
+                )
+            }
+            Command::Backup { dest } => KvsCommand::Backup(dest),
+            Command::Export { dest } => KvsCommand::Export(dest),
+            Command::Append { key, value } => KvsCommand::Append(key, value),
+            Command::Exists { key } => KvsCommand::Exists(key),
+            Command::Persist { key } => KvsCommand::Persist(key),
+            Command::Keys { pattern } => KvsCommand::Keys(pattern),
+            Command::RmMatching { pattern } => KvsCommand::RmMatching(pattern),
+            Command::Flushdb => KvsCommand::Flushdb,
+            Command::Dbsize => KvsCommand::Dbsize,
+            Command::Quarantine { drop: Some(key) } => KvsCommand::QuarantineDrop(key),
+            Command::Quarantine { drop: None } => KvsCommand::Quarantine,
+            Command::Bigkeys { count, sample } => KvsCommand::Bigkeys(count, sample),
+            Command::TrainDictionary { sample } => KvsCommand::DictTrain(sample),
+            Command::Info => KvsCommand::Info,
+            Command::Shutdown { nosave } => KvsCommand::Shutdown(!nosave),
+            Command::Version => KvsCommand::Version,
+            Command::SetBytes { .. } | Command::Merge { .. } => {
+                return Err(KvsError::InvalidCommand)
+            }
+        })
+    }
+}
+
+/// The frame of a request the client sends, UTF-8 as every argument the
+/// client takes is a `String`
+fn request_frame(command: &KvsCommand) -> Result<String> {
+    String::from_utf8(command.to_frame())
+        .map_err(|e| KvsError::Message(format!("unable to encode request: {}", e)))
+}
+
+/// Sends AUTH with `password`, and `username` if any, on `stream` and fails
+/// with the server's error reply if it refused them
+pub fn authenticate(stream: &mut TcpStream, username: Option<&str>, password: &str) -> Result<()> {
+    let auth = KvsCommand::Auth(username.map(str::to_owned), password.to_owned());
+    tcp_send_message(stream, &request_frame(&auth)?)?;
+    match tcp_read_message(stream).strip_prefix('-') {
+        Some(e) => Err(KvsError::Server(e.trim_end().to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Blocking client for a kvs server. A dropped connection is replaced on the
+/// next request. Reads interrupted by a dropped connection are retried once
+/// on a fresh one, writes fail with `KvsError::Interrupted` since they may
+/// have been applied.
+pub struct KvsClient {
+    addr: SocketAddr,
+    conn: Option<BufReader<TcpStream>>,
+    /// Database selected with `select`, selected again on a new connection
+    db: usize,
+    /// Username, if any, and password of the last successful AUTH, sent
+    /// again on a new connection
+    credentials: Option<(Option<String>, String)>,
+    /// Log the bytes sent and received, see `trace_wire`
+    trace_wire: bool,
+}
+
+/// Log target of the bytes a client traces, see `KvsClient::trace_wire`
+const WIRE_LOG: &str = "kvs::wire";
+
+impl KvsClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| KvsError::Message("no address to connect to".into()))?;
+        Ok(Self {
+            addr,
+            conn: Some(BufReader::new(TcpStream::connect(addr)?)),
+            db: 0,
+            credentials: None,
+            trace_wire: false,
+        })
+    }
+
+    /// Logs every frame sent and every reply received at info level on the
+    /// `kvs::wire` target, escaped to one line by `protocol::escape_wire`.
+    /// The credentials of AUTH are left out.
+    pub fn trace_wire(mut self, trace: bool) -> Self {
+        self.trace_wire = trace;
+        self
+    }
+
+    /// Authenticates with the server's password, for servers started with
+    /// `--requirepass`
+    pub fn auth(&mut self, password: String) -> Result<()> {
+        self.login(None, password)
+    }
+
+    /// Authenticates as one of the server's users, the commands that follow
+    /// are limited to what the user's profile allows
+    pub fn auth_as(&mut self, username: String, password: String) -> Result<()> {
+        self.login(Some(username), password)
+    }
+
+    fn login(&mut self, username: Option<String>, password: String) -> Result<()> {
+        let auth = KvsCommand::Auth(username.clone(), password.clone());
+        match self.request(&auth, true)? {
+            RespValue::SimpleString(_) => {
+                self.credentials = Some((username, password));
+                Ok(())
+            }
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Switches to numbered database `db` for the requests that follow
+    pub fn select(&mut self, db: usize) -> Result<()> {
+        match self.request(&KvsCommand::Select(db), true)? {
+            RespValue::SimpleString(_) => {
+                self.db = db;
+                Ok(())
+            }
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(&KvsCommand::Get(key), true) {
+            Ok(RespValue::BulkString(Some(value))) => String::from_utf8(value)
+                .map(Some)
+                .map_err(|e| KvsError::Message(format!("invalid utf-8 in value: {}", e))),
+            Ok(RespValue::BulkString(None) | RespValue::Null) => Ok(None),
+            // servers started with --missing-key-error
+            Err(KvsError::KeyNotFound) => Ok(None),
+            Ok(reply) => Err(unexpected_reply(reply)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the values of `keys` in one round trip, in the order of `keys`
+    pub fn mget(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        match self.request(&KvsCommand::Mget(keys.to_vec()), true)? {
+            RespValue::Array(Some(values)) if values.len() == keys.len() => values
+                .into_iter()
+                .map(|value| match value {
+                    RespValue::BulkString(Some(value)) => String::from_utf8(value)
+                        .map(Some)
+                        .map_err(|e| KvsError::Message(format!("invalid utf-8 in value: {}", e))),
+                    RespValue::BulkString(None) | RespValue::Null => Ok(None),
+                    reply => Err(unexpected_reply(reply)),
+                })
+                .collect(),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Sets every key of `pairs` to its value in one round trip, applied
+    /// all at once
+    pub fn mset(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        let pairs = pairs
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into_bytes()))
+            .collect();
+        match self.request(&KvsCommand::Mset(pairs), false)? {
+            RespValue::SimpleString(_) => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let set = KvsCommand::Set(key, value.into_bytes(), ContentType::Text, None);
+        match self.request(&set, false)? {
+            RespValue::SimpleString(_) => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Sets `value` that expires after `ttl`, sent in whole milliseconds
+    pub fn set_ex(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let set = KvsCommand::Set(key, value.into_bytes(), ContentType::Text, Some(ttl));
+        match self.request(&set, false)? {
+            RespValue::SimpleString(_) => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Sets `value` along with what it holds, the server rejects a json
+    /// value that does not parse
+    pub fn set_typed(
+        &mut self,
+        key: String,
+        value: String,
+        content_type: ContentType,
+    ) -> Result<()> {
+        let set = KvsCommand::Set(key, value.into_bytes(), content_type, None);
+        match self.request(&set, false)? {
+            RespValue::SimpleString(_) => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&KvsCommand::Rm(key), false)? {
+            RespValue::SimpleString(_) => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Removes every key matching the glob `pattern` in one request,
+    /// returns how many were removed
+    pub fn remove_matching(&mut self, pattern: String) -> Result<u64> {
+        match self.request(&KvsCommand::RmMatching(pattern), false)? {
+            RespValue::Integer(removed) if removed >= 0 => Ok(removed as u64),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Removes every key of the selected database
+    pub fn flushdb(&mut self) -> Result<()> {
+        match self.request(&KvsCommand::Flushdb, false)? {
+            RespValue::SimpleString(_) => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Appends `value` to the value of `key`, setting it if missing, and
+    /// returns the length of the new value
+    pub fn append(&mut self, key: String, value: String) -> Result<u64> {
+        match self.request(&KvsCommand::Append(key, value), false)? {
+            RespValue::Integer(len) if len >= 0 => Ok(len as u64),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        match self.request(&KvsCommand::Exists(key), true)? {
+            RespValue::Integer(n) => Ok(n == 1),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Takes the TTL off `key`, returns whether it had one
+    pub fn persist(&mut self, key: String) -> Result<bool> {
+        match self.request(&KvsCommand::Persist(key), false)? {
+            RespValue::Integer(n) => Ok(n == 1),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Gets up to `count` keys after `cursor`, or from the first key, and
+    /// the cursor of the next page, `None` once the last key was returned
+    pub fn scan(
+        &mut self,
+        cursor: Option<&Cursor>,
+        count: usize,
+    ) -> Result<(Vec<String>, Option<Cursor>)> {
+        let reply = self.request(&KvsCommand::Scan(cursor.cloned(), count), true)?;
+        let (next, keys) = match reply {
+            RespValue::Array(Some(parts)) => match <[RespValue; 2]>::try_from(parts) {
+                Ok([RespValue::BulkString(Some(next)), RespValue::Array(Some(keys))]) => {
+                    (next, keys)
+                }
+                Ok(parts) => return Err(unexpected_reply(RespValue::Array(Some(parts.into())))),
+                Err(parts) => return Err(unexpected_reply(RespValue::Array(Some(parts)))),
+            },
+            reply => return Err(unexpected_reply(reply)),
+        };
+        let keys = keys
+            .into_iter()
+            .map(|key| match key {
+                RespValue::BulkString(Some(key)) => String::from_utf8(key)
+                    .map_err(|e| KvsError::Message(format!("invalid utf-8 in key: {}", e))),
+                reply => Err(unexpected_reply(reply)),
+            })
+            .collect::<Result<_>>()?;
+        let next = match String::from_utf8_lossy(&next).as_ref() {
+            "0" => None,
+            next => Some(next.parse()?),
+        };
+        Ok((keys, next))
+    }
+
+    /// Turns the connection into a stream of the changes to keys starting
+    /// with `prefix`, in the database this client selected
+    pub fn subscribe(mut self, prefix: &str) -> Result<Subscription> {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => self.reconnect()?,
+        };
+        // every reply so far was read in full, nothing is left buffered
+        let mut conn = conn.into_inner();
+        conn.write_all(&KvsCommand::Subscribe(prefix.to_owned()).to_frame())?;
+        let mut subscription = Subscription {
+            conn,
+            decoder: FrameDecoder::new(),
+        };
+        match subscription.read_frame()? {
+            Some(RespValue::Array(Some(_))) => Ok(subscription),
+            Some(RespValue::Err(e)) => Err(server_error(e)),
+            reply => Err(KvsError::Message(format!("unexpected reply: {:?}", reply))),
+        }
+    }
+
+    /// Sets every pair with SETs sent in one write, their replies read after,
+    /// so the batch takes a single round trip. Every reply is read before
+    /// the first error reply, if any, is returned. A batch cut short by a
+    /// dropped connection is not sent again.
+    pub fn set_pipelined(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        let mut frames = String::new();
+        for (key, value) in pairs {
+            let set = KvsCommand::Set(
+                key.clone(),
+                value.clone().into_bytes(),
+                ContentType::Text,
+                None,
+            );
+            frames += &request_frame(&set)?;
+        }
+        let replies = self.round_trips(&frames, pairs.len())?;
+        match replies
+            .into_iter()
+            .find(|reply| !matches!(reply, RespValue::SimpleString(_)))
+        {
+            None => Ok(()),
+            Some(RespValue::Err(e)) => Err(server_error(e)),
+            Some(reply) => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        match self.request(&KvsCommand::Ping, true)? {
+            RespValue::SimpleString(s) if s == "PONG" => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Sends a command and returns its reply, error replies are returned as
+    /// errors, see `server_error`. An `idempotent` command is sent again when
+    /// its connection drops, as applying it twice does no harm.
+    fn request(&mut self, command: &KvsCommand, idempotent: bool) -> Result<RespValue> {
+        let frame = request_frame(command)?;
+        let reply = match self.round_trip(&frame) {
+            Err(KvsError::Interrupted) if idempotent => self.round_trip(&frame),
+            result => result,
+        }?;
+        match reply {
+            RespValue::Err(e) => Err(server_error(e)),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Sends `frame` on the open connection, or a new one, and reads the
+    /// reply. Fails with `Interrupted` when the connection drops once the
+    /// request may have reached the server, the server may also have closed
+    /// a connection that sat idle.
+    fn round_trip(&mut self, frame: &str) -> Result<RespValue> {
+        let mut replies = self.round_trips(frame, 1)?;
+        Ok(replies.remove(0))
+    }
+
+    /// Like `round_trip` for `frames` of `count` requests, whose replies
+    /// are read in order once they are all sent
+    fn round_trips(&mut self, frames: &str, count: usize) -> Result<Vec<RespValue>> {
+        let mut conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => self.reconnect()?,
+        };
+        self.trace_sent(frames);
+        let replies = conn
+            .get_mut()
+            .write_all(frames.as_bytes())
+            .map_err(KvsError::from)
+            .and_then(|_| self.read_replies(&mut conn, count));
+        match replies {
+            Ok(replies) => {
+                // only a connection that answered in full can be used again
+                self.conn = Some(conn);
+                Ok(replies)
+            }
+            Err(KvsError::Io(e)) => {
+                log::debug!("connection to {} dropped: {}", self.addr, e);
+                Err(KvsError::Interrupted)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Opens a new connection, authenticated and on the database this
+    /// client selected
+    fn reconnect(&self) -> Result<BufReader<TcpStream>> {
+        let mut conn = BufReader::new(TcpStream::connect(self.addr)?);
+        let mut setup = Vec::new();
+        if let Some((username, password)) = &self.credentials {
+            let auth = KvsCommand::Auth(username.clone(), password.clone());
+            setup.push(request_frame(&auth)?);
+        }
+        if self.db != 0 {
+            setup.push(request_frame(&KvsCommand::Select(self.db))?);
+        }
+        for frame in setup {
+            self.trace_sent(&frame);
+            conn.get_mut().write_all(frame.as_bytes())?;
+            if let RespValue::Err(e) = self.read_reply(&mut conn)? {
+                return Err(server_error(e));
+            }
+        }
+        Ok(conn)
+    }
+
+    /// Reads one complete RESP frame
+    fn read_reply<R: Read>(&self, reader: &mut R) -> Result<RespValue> {
+        let mut replies = self.read_replies(reader, 1)?;
+        Ok(replies.remove(0))
+    }
+
+    /// Reads `count` complete RESP frames sent back to back
+    fn read_replies<R: Read>(&self, reader: &mut R, count: usize) -> Result<Vec<RespValue>> {
+        let mut replies = Vec::with_capacity(count);
+        let mut decoder = FrameDecoder::new();
+        while replies.len() < count {
+            let frame = match decoder.next_frame() {
+                Ok(Decoded::Frame(frame)) => frame,
+                Ok(Decoded::NeedMoreData) => {
+                    if decoder.read_from(reader)? == 0 {
+                        return Err(KvsError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    continue;
+                }
+                Err(e) => return Err(KvsError::Message(format!("invalid reply: {}", e))),
+            };
+            if self.trace_wire {
+                log::info!(target: WIRE_LOG, "{} <- {}", self.addr, protocol::escape_wire(frame));
+            }
+            let reply = protocol::from_bytes(frame)
+                .map_err(|e| KvsError::Message(format!("invalid reply: {}", e)))?;
+            replies.push(reply);
+        }
+        Ok(replies)
+    }
+
+    /// Logs `frame` when tracing the wire, the credentials of an AUTH
+    /// frame replaced
+    fn trace_sent(&self, frame: &str) {
+        if !self.trace_wire {
+            return;
+        }
+        let frame = match frame.split_once(AUTH_FRAME) {
+            // the array header comes right before the command name
+            Some((header, _)) if !header.contains('\n') => {
+                format!("{}{}<redacted>", header, AUTH_FRAME)
+            }
+            _ => frame.to_string(),
+        };
+        log::info!(target: WIRE_LOG, "{} -> {}", self.addr, protocol::escape_wire(frame.as_bytes()));
+    }
+}
+
+/// What follows the array header of an AUTH frame, the credentials come next
+const AUTH_FRAME: &str = "\r\n$4\r\nauth\r\n";
+
+/// Changes to keys a server streams after SUBSCRIBE, see
+/// `KvsClient::subscribe`. Ends when the server closes the connection.
+pub struct Subscription {
+    conn: TcpStream,
+    decoder: FrameDecoder,
+}
+
+impl Subscription {
+    /// Reads the next frame, `None` once the server closed the connection
+    fn read_frame(&mut self) -> Result<Option<RespValue>> {
+        loop {
+            let frame = match self.decoder.next_frame() {
+                Ok(Decoded::Frame(frame)) => frame,
+                Ok(Decoded::NeedMoreData) => {
+                    if self.decoder.read_from(&mut self.conn)? == 0 {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(KvsError::Message(format!("invalid frame: {}", e))),
+            };
+            return protocol::from_bytes(frame)
+                .map(Some)
+                .map_err(|e| KvsError::Message(format!("invalid frame: {}", e)));
+        }
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = match self.read_frame() {
+                Ok(frame) => frame?,
+                Err(e) => return Some(Err(e)),
+            };
+            let RespValue::Array(Some(parts)) = &frame else {
+                return Some(Err(KvsError::Message(format!(
+                    "unexpected frame: {:?}",
+                    frame
+                ))));
+            };
+            let parts: Option<Vec<&str>> = parts.iter().map(RespValue::as_bulk_str).collect();
+            let change = match parts.as_deref() {
+                // sent while nothing changes
+                Some(["ping"]) => continue,
+                Some(["change", name, key]) => match *name {
+                    "set" => ChangeEvent::Set(key.to_string()),
+                    "rm" => ChangeEvent::Removed(key.to_string()),
+                    "expired" => ChangeEvent::Expired(key.to_string()),
+                    _ => return Some(Err(KvsError::Message(format!("unknown change: {}", name)))),
+                },
+                _ => {
+                    return Some(Err(KvsError::Message(format!(
+                        "unexpected frame: {:?}",
+                        frame
+                    ))))
+                }
+            };
+            return Some(Ok(change));
+        }
+    }
+}
+
+/// Maps an error reply onto the error the engine would have returned
+fn server_error(message: String) -> KvsError {
+    match message.as_str() {
+        "Key not found" => KvsError::KeyNotFound,
+        "ERR invalid command" => KvsError::InvalidCommand,
+        _ => KvsError::Server(message),
+    }
+}
+
+fn unexpected_reply(reply: RespValue) -> KvsError {
+    KvsError::Message(format!("unexpected reply: {:?}", reply))
+}
+
+/// Keeps up to `max_idle` connected clients around for reuse. Clones share
+/// the same pool.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    addr: SocketAddr,
+    max_idle: usize,
+    idle: Arc<Mutex<Vec<KvsClient>>>,
+    password: Option<String>,
+}
+
+impl KvsClientPool {
+    pub fn new<A: ToSocketAddrs>(addr: A, max_idle: usize) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| KvsError::Message("no address to connect to".into()))?;
+        Ok(Self {
+            addr,
+            max_idle,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            password: None,
+        })
+    }
+
+    /// Authenticate the clients the pool connects with `password`
+    pub fn with_password(mut self, password: String) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Takes an idle client or connects a new one, it goes back to the pool
+    /// when dropped
+    pub fn get(&self) -> Result<PooledClient> {
+        let client = self.idle.lock().unwrap().pop();
+        let client = match client {
+            Some(client) => client,
+            None => {
+                let mut client = KvsClient::connect(self.addr)?;
+                if let Some(password) = &self.password {
+                    client.auth(password.clone())?;
+                }
+                client
+            }
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self.clone(),
+        })
+    }
+}
+
+/// A client borrowed from a `KvsClientPool`
+pub struct PooledClient {
+    client: Option<KvsClient>,
+    pool: KvsClientPool,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let client = self.client.take().unwrap();
+        // a client whose connection failed mid request has none to give
+        // back, and one that selected another database would surprise the
+        // next borrower
+        if client.conn.is_none() || client.db != 0 {
+            return;
+        }
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < self.pool.max_idle {
+            idle.push(client);
+        }
+    }
 }
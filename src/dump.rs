@@ -0,0 +1,440 @@
+//! Streaming dump format of EXPORT and `kvs dump`, a portable copy of a
+//! store's keys that an interrupted export can resume and `kvs restore`
+//! loads into any engine
+//!
+//! A dump is `DUMP_MAGIC` and a version byte followed by tagged entries,
+//! integers little-endian:
+//!
+//! - `RECORD`: u32 key length, key, u8 content type, u32 value length, value
+//! - `CHECKPOINT`, after every `CHECKPOINT_EVERY` records: u64 records so
+//!   far, u32 key length, the last key, u32 checksum
+//! - `TRAILER`, once at the end: u64 records, u32 checksum
+//!
+//! A checksum is the CRC32 of every byte of the dump before it. Records are
+//! in key order, so an export cut short is resumed by truncating the dump
+//! after its last checkpoint and going on with the keys after the
+//! checkpoint's key.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::engines::{ContentType, Cursor, KvsEngine};
+use crate::{KvsError, Result};
+
+const DUMP_MAGIC: &[u8; 8] = b"KVSDUMP\n";
+const DUMP_VERSION: u8 = 1;
+
+const RECORD: u8 = b'r';
+const CHECKPOINT: u8 = b'c';
+const TRAILER: u8 = b'e';
+
+const CONTENT_TEXT: u8 = 0;
+const CONTENT_JSON: u8 = 1;
+
+/// Records between checkpoints unless `DumpWriter::checkpoint_every` says
+/// otherwise
+pub const CHECKPOINT_EVERY: u64 = 1024;
+
+/// Keys read from the engine at a time by `export`
+const EXPORT_PAGE: usize = 256;
+
+/// A key and its value as a dump holds them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpRecord {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub content_type: ContentType,
+}
+
+/// A point a dump can be resumed from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Records before the checkpoint
+    pub records: u64,
+    /// Key of the last record before the checkpoint
+    pub last_key: String,
+    /// Offset of the first byte after the checkpoint
+    offset: u64,
+    /// Checksum of the dump up to `offset`
+    crc: u32,
+}
+
+/// Writes a dump to `W`, see the module docs for the format
+pub struct DumpWriter<W: Write> {
+    writer: W,
+    hasher: crc32fast::Hasher,
+    records: u64,
+    last_key: Option<String>,
+    checkpoint_every: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Starts a dump by writing its header
+    pub fn new(writer: W) -> Result<Self> {
+        let mut dump = DumpWriter {
+            writer,
+            hasher: crc32fast::Hasher::new(),
+            records: 0,
+            last_key: None,
+            checkpoint_every: CHECKPOINT_EVERY,
+        };
+        dump.put(DUMP_MAGIC)?;
+        dump.put(&[DUMP_VERSION])?;
+        Ok(dump)
+    }
+
+    /// Write a checkpoint after every `records` records, at least one
+    pub fn checkpoint_every(mut self, records: u64) -> Self {
+        self.checkpoint_every = records.max(1);
+        self
+    }
+
+    /// Records written so far, including those of a resumed dump
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Key of the last record, the dump goes on with the keys after it
+    pub fn last_key(&self) -> Option<&str> {
+        self.last_key.as_deref()
+    }
+
+    /// Appends a record
+    /// # Errors
+    /// When `key` does not come after the last key, records must be in key
+    /// order for a resumed dump to know which keys it already holds
+    pub fn write(&mut self, key: &str, value: &[u8], content_type: ContentType) -> Result<()> {
+        if self.last_key().is_some_and(|last| key <= last) {
+            return Err(KvsError::Message(format!("dump key out of order: {}", key)));
+        }
+        self.put(&[RECORD])?;
+        self.put_len_prefixed(key.as_bytes())?;
+        self.put(&[match content_type {
+            ContentType::Text => CONTENT_TEXT,
+            ContentType::Json => CONTENT_JSON,
+        }])?;
+        self.put_len_prefixed(value)?;
+        self.records += 1;
+        self.last_key = Some(key.to_owned());
+        if self.records.is_multiple_of(self.checkpoint_every) {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the dump with its trailer and returns the writer, flushed
+    pub fn finish(mut self) -> Result<W> {
+        self.put(&[TRAILER])?;
+        self.put(&self.records.to_le_bytes())?;
+        self.put_crc()?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Flushed along with the records before it, so a dump cut short can be
+    /// resumed from the last checkpoint that reached the writer
+    fn checkpoint(&mut self) -> Result<()> {
+        let last_key = self.last_key.clone().unwrap_or_default();
+        self.put(&[CHECKPOINT])?;
+        self.put(&self.records.to_le_bytes())?;
+        self.put_len_prefixed(last_key.as_bytes())?;
+        self.put_crc()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn put_crc(&mut self) -> Result<()> {
+        let crc = self.hasher.clone().finalize();
+        self.put(&crc.to_le_bytes())
+    }
+
+    fn put_len_prefixed(&mut self, bytes: &[u8]) -> Result<()> {
+        let len = u32::try_from(bytes.len())
+            .map_err(|_| KvsError::Message("dump record too large".into()))?;
+        self.put(&len.to_le_bytes())?;
+        self.put(bytes)
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+impl DumpWriter<BufWriter<File>> {
+    /// Opens the dump at `path` to write to, a new one if there is no file.
+    /// A dump cut short is truncated after its last checkpoint and goes on
+    /// from there, or starts over when it has none.
+    /// # Errors
+    /// When the file is not a dump or holds a finished one
+    pub fn resume(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let checkpoint = match file.metadata()?.len() {
+            0 => None,
+            _ => {
+                let mut reader = DumpReader::new(BufReader::new(&file))?;
+                // anything after the last checkpoint, torn or not, is written again
+                while let Some(Ok(_)) = reader.next() {}
+                if reader.is_finished() {
+                    return Err(KvsError::Message(format!(
+                        "dump at {} is already finished",
+                        path.display()
+                    )));
+                }
+                reader.checkpoint().cloned()
+            }
+        };
+        let Some(checkpoint) = checkpoint else {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            return DumpWriter::new(BufWriter::new(file));
+        };
+        file.set_len(checkpoint.offset)?;
+        file.seek(SeekFrom::Start(checkpoint.offset))?;
+        Ok(DumpWriter {
+            writer: BufWriter::new(file),
+            hasher: crc32fast::Hasher::new_with_initial_len(checkpoint.crc, checkpoint.offset),
+            records: checkpoint.records,
+            last_key: Some(checkpoint.last_key),
+            checkpoint_every: CHECKPOINT_EVERY,
+        })
+    }
+}
+
+/// Reads the records of a dump from `R`, checking every checkpoint and the
+/// trailer on the way. A dump that ends before its trailer fails with the
+/// error of the read that hit the end.
+pub struct DumpReader<R: Read> {
+    reader: R,
+    hasher: crc32fast::Hasher,
+    offset: u64,
+    records: u64,
+    last_key: Option<String>,
+    checkpoint: Option<Checkpoint>,
+    finished: bool,
+    failed: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Reads and checks the header of the dump
+    pub fn new(reader: R) -> Result<Self> {
+        let mut dump = DumpReader {
+            reader,
+            hasher: crc32fast::Hasher::new(),
+            offset: 0,
+            records: 0,
+            last_key: None,
+            checkpoint: None,
+            finished: false,
+            failed: false,
+        };
+        if dump.read_bytes(DUMP_MAGIC.len())? != DUMP_MAGIC {
+            return Err(KvsError::Message("not a kvs dump".into()));
+        }
+        match dump.read_bytes(1)?[0] {
+            DUMP_VERSION => Ok(dump),
+            version => Err(KvsError::Message(format!(
+                "unsupported dump version {}",
+                version
+            ))),
+        }
+    }
+
+    /// The last checkpoint read, where a dump that breaks off after it can
+    /// be resumed from
+    pub fn checkpoint(&self) -> Option<&Checkpoint> {
+        self.checkpoint.as_ref()
+    }
+
+    /// Whether the trailer was read and the dump is complete
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The next record, `None` after the trailer
+    fn record(&mut self) -> Result<Option<DumpRecord>> {
+        loop {
+            match self.read_bytes(1)?[0] {
+                RECORD => {
+                    let key =
+                        String::from_utf8(self.read_len_prefixed()?).map_err(|_| self.corrupt())?;
+                    let content_type = match self.read_bytes(1)?[0] {
+                        CONTENT_TEXT => ContentType::Text,
+                        CONTENT_JSON => ContentType::Json,
+                        _ => return Err(self.corrupt()),
+                    };
+                    let value = self.read_len_prefixed()?;
+                    if self.last_key.as_ref().is_some_and(|last| key <= *last) {
+                        return Err(self.corrupt());
+                    }
+                    self.records += 1;
+                    self.last_key = Some(key.clone());
+                    return Ok(Some(DumpRecord {
+                        key,
+                        value,
+                        content_type,
+                    }));
+                }
+                CHECKPOINT => {
+                    let records = self.read_u64()?;
+                    let last_key = self.read_len_prefixed()?;
+                    if records != self.records
+                        || Some(last_key.as_slice()) != self.last_key.as_ref().map(|k| k.as_bytes())
+                    {
+                        return Err(self.corrupt());
+                    }
+                    self.read_crc()?;
+                    self.checkpoint = Some(Checkpoint {
+                        records,
+                        last_key: self.last_key.clone().unwrap_or_default(),
+                        offset: self.offset,
+                        crc: self.hasher.clone().finalize(),
+                    });
+                }
+                TRAILER => {
+                    if self.read_u64()? != self.records {
+                        return Err(self.corrupt());
+                    }
+                    self.read_crc()?;
+                    self.finished = true;
+                    return Ok(None);
+                }
+                _ => return Err(self.corrupt()),
+            }
+        }
+    }
+
+    /// Reads a checksum and checks it against the bytes before it
+    fn read_crc(&mut self) -> Result<()> {
+        let expected = self.hasher.clone().finalize();
+        let crc = u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap());
+        match crc == expected {
+            true => Ok(()),
+            false => Err(self.corrupt()),
+        }
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_len_prefixed(&mut self) -> Result<Vec<u8>> {
+        let len = u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap());
+        self.read_bytes(len as usize)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            return Err(KvsError::Message(format!(
+                "dump ends early at offset {}",
+                self.offset + buf.len() as u64
+            )));
+        }
+        self.hasher.update(&buf);
+        self.offset += len as u64;
+        Ok(buf)
+    }
+
+    fn corrupt(&self) -> KvsError {
+        KvsError::Message(format!("corrupt dump at offset {}", self.offset))
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = Result<DumpRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished || self.failed {
+            return None;
+        }
+        match self.record() {
+            Ok(record) => record.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Writes the keys of `engine` after the last key of `dump` to it, a page
+/// of keys at a time. Every page is read at one point in time, but writes
+/// made during the export may or may not make it into the dump. Expiry
+/// times are not exported.
+pub fn export<E: KvsEngine, W: Write>(engine: &E, dump: &mut DumpWriter<W>) -> Result<()> {
+    let mut cursor = dump.last_key().map(|key| Cursor::new(key.to_owned()));
+    loop {
+        let (pairs, next) = engine.scan_page(cursor, EXPORT_PAGE)?;
+        let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+        for (key, value) in keys.iter().zip(engine.get_many(&keys)?) {
+            // removed since the page was scanned
+            if let Some((value, content_type)) = value {
+                dump.write(key, &value, content_type)?;
+            }
+        }
+        match next {
+            Some(next) => cursor = Some(next),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Exports `engine` to the dump file at `path` and syncs it, resuming the
+/// dump there if an earlier export was cut short, see
+/// `DumpWriter::resume`. Returns the records in the dump.
+pub fn export_file<E: KvsEngine>(engine: &E, path: &Path) -> Result<u64> {
+    let mut dump = DumpWriter::resume(path)?;
+    export(engine, &mut dump)?;
+    let records = dump.records();
+    let file = dump
+        .finish()?
+        .into_inner()
+        .map_err(|e| KvsError::Io(e.into_error()))?;
+    file.sync_all()?;
+    Ok(records)
+}
+
+/// Sets every record of `dump` in `engine` as it is read, returns how many
+/// were set. A dump that turns out to be corrupt or cut short leaves the
+/// records before the damage set.
+pub fn import<E: KvsEngine, R: Read>(engine: &E, dump: DumpReader<R>) -> Result<u64> {
+    let mut records = 0;
+    for record in dump {
+        let DumpRecord {
+            key,
+            value,
+            content_type,
+        } = record?;
+        match content_type {
+            ContentType::Text => engine.set_bytes(key, value)?,
+            _ => {
+                let value = String::from_utf8(value).map_err(|_| {
+                    KvsError::Message(format!(
+                        "{} value of {} is not UTF-8",
+                        content_type.name(),
+                        key
+                    ))
+                })?;
+                engine.set_typed(key, value, content_type)?
+            }
+        }
+        records += 1;
+    }
+    Ok(records)
+}
+
+/// Imports the dump file at `path` into `engine`, see `import`. Returns the
+/// records set.
+pub fn import_file<E: KvsEngine>(engine: &E, path: &Path) -> Result<u64> {
+    let dump = DumpReader::new(BufReader::new(File::open(path)?))?;
+    import(engine, dump)
+}
@@ -1,166 +1,2344 @@
 use crate::client::Command;
 use crate::error::{KvsError, Result};
+use crate::metrics::Histogram;
 use dashmap::DashMap;
+use log::{error, info, warn};
+use lru::LruCache;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::mem;
+use std::num::NonZeroUsize;
+use std::ops::{Bound, Deref, RangeBounds, RangeFull};
 use std::path::PathBuf;
-use std::sync::atomic::AtomicBool;
-use std::sync::{atomic, Arc, Mutex};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fs::OpenOptions, path::Path};
 
-use super::KvsEngine;
+use super::{
+    BigKeys, ChangeEvent, ContentType, Cursor, DefaultTtls, EngineStats, ExpiryForecast, KeySize,
+    KvsEngine, QuarantinedKey, ScanPage, TypedValue, ValueReader,
+};
 
+/// Where the value of a key lives: a `Set` record, or the first `Merge`
+/// record of a key that was never set, followed by the merge operands
+/// written since
+#[derive(Clone)]
 struct CommandPos {
     walfile_num: u64,
     pos: u64,
     len: u64,
+    expires_at: Option<u64>,
+    operands: Vec<RecordPos>,
+}
+
+#[derive(Clone, Copy)]
+struct RecordPos {
+    walfile_num: u64,
+    pos: u64,
+    len: u64,
+}
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Bytes of log taken by every record making up the value
+    fn total_len(&self) -> u64 {
+        self.len + self.operands.iter().map(|op| op.len).sum::<u64>()
+    }
+
+    fn record(&self) -> RecordPos {
+        RecordPos {
+            walfile_num: self.walfile_num,
+            pos: self.pos,
+            len: self.len,
+        }
+    }
+}
+
+/// Positions of the latest record of every key, plus an ordered copy of the
+/// keys for range scans and the cached values of recently read keys.
+/// Mutations must go through the methods below so the three stay in sync,
+/// reads can use the `DashMap` directly.
+struct Index {
+    positions: DashMap<String, CommandPos>,
+    keys: RwLock<BTreeSet<String>>,
+    values: Option<Mutex<LruCache<String, TypedValue>>>,
+    /// Keys whose records failed their checksum, and the file and offset of
+    /// the corrupt record, until the key is written again or dropped
+    quarantined: DashMap<String, (PathBuf, u64)>,
+}
+
+impl Index {
+    fn new(value_cache: usize) -> Self {
+        Self {
+            positions: DashMap::new(),
+            keys: RwLock::new(BTreeSet::new()),
+            values: NonZeroUsize::new(value_cache).map(|cap| Mutex::new(LruCache::new(cap))),
+            quarantined: DashMap::new(),
+        }
+    }
+
+    fn insert(&self, key: String, cmd_pos: CommandPos) -> Option<CommandPos> {
+        self.keys.write().unwrap().insert(key.clone());
+        let old_cmd = self.positions.insert(key.clone(), cmd_pos);
+        self.forget_value(&key);
+        self.quarantined.remove(&key);
+        old_cmd
+    }
+
+    fn remove(&self, key: &str) -> Option<(String, CommandPos)> {
+        self.keys.write().unwrap().remove(key);
+        let removed = self.positions.remove(key);
+        self.forget_value(key);
+        self.quarantined.remove(key);
+        removed
+    }
+
+    /// Sets `key` aside after the record at `offset` of `file` failed its
+    /// checksum, reads of it fail from then on. The caller must hold the
+    /// key's entry of `positions` and have checked it still points at the
+    /// record, so a value written meanwhile is not quarantined.
+    fn quarantine(&self, key: &str, file: PathBuf, offset: u64) {
+        if !self.quarantined.contains_key(key) {
+            error!(
+                "quarantined key {}: the record at offset {} of {} is corrupt",
+                key,
+                offset,
+                file.display()
+            );
+            self.quarantined.insert(key.to_owned(), (file, offset));
+        }
+    }
+
+    /// The error reads of `key` fail with while it is quarantined
+    fn quarantined(&self, key: &str) -> Option<KvsError> {
+        let entry = self.quarantined.get(key)?;
+        let (file, offset) = entry.value();
+        Some(KvsError::Corruption {
+            file: file.clone(),
+            offset: *offset,
+        })
+    }
+
+    /// Removes the entries of quarantined keys that still point into the
+    /// logs before `first_output`, the records compaction could not copy
+    fn drop_uncopied(&self, first_output: u64) {
+        let mut keys = self.keys.write().unwrap();
+        for entry in self.quarantined.iter() {
+            let removed = self
+                .positions
+                .remove_if(entry.key(), |_, cmd_pos| cmd_pos.walfile_num < first_output);
+            if let Some((key, _)) = removed {
+                keys.remove(&key);
+            }
+        }
+    }
+
+    /// The cached value of `key`. Only valid while the caller holds the
+    /// key's entry of `positions`, which keeps writers from replacing it.
+    fn cached_value(&self, key: &str) -> Option<TypedValue> {
+        self.values.as_ref()?.lock().unwrap().get(key).cloned()
+    }
+
+    /// Caches the value read for `key`, the caller must hold the key's entry
+    /// of `positions` since it was read. Writers forget a value after they
+    /// replaced the entry, so a value cached this way is never stale.
+    fn cache_value(&self, key: &str, value: &TypedValue) {
+        if let Some(values) = &self.values {
+            values.lock().unwrap().put(key.to_owned(), value.clone());
+        }
+    }
+
+    fn forget_value(&self, key: &str) {
+        if let Some(values) = &self.values {
+            values.lock().unwrap().pop(key);
+        }
+    }
+
+    /// Removes `key` if its value expired by `now`, it may have been written
+    /// again since it was seen expired
+    fn remove_expired(&self, key: &str, now: u64) -> Option<CommandPos> {
+        let mut keys = self.keys.write().unwrap();
+        let (key, cmd_pos) = self
+            .positions
+            .remove_if(key, |_, cmd_pos| cmd_pos.is_expired(now))?;
+        keys.remove(&key);
+        self.forget_value(&key);
+        Some(cmd_pos)
+    }
+
+    /// Up to `limit` keys whose values expired by `now`
+    fn expired_keys(&self, now: u64, limit: usize) -> Vec<String> {
+        self.positions
+            .iter()
+            .filter(|entry| entry.is_expired(now))
+            .take(limit)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Appends a merge operand to the value of `key`, starting a new value if
+    /// there is none. Returns the number of bytes it made reclaimable.
+    fn merge(&self, key: String, operand: RecordPos, now: u64) -> u64 {
+        if let Some(mut cmd_pos) = self.positions.get_mut(&key) {
+            if !cmd_pos.is_expired(now) {
+                // compaction folds the operand into a single `Set`
+                cmd_pos.operands.push(operand);
+                drop(cmd_pos);
+                self.forget_value(&key);
+                return operand.len;
+            }
+        }
+        let cmd_pos = CommandPos {
+            walfile_num: operand.walfile_num,
+            pos: operand.pos,
+            len: operand.len,
+            expires_at: None,
+            operands: Vec::new(),
+        };
+        self.insert(key, cmd_pos)
+            .map_or(0, |old_cmd| old_cmd.total_len())
+    }
+
+    fn retain(&self, mut f: impl FnMut(&String, &mut CommandPos) -> bool) {
+        let mut keys = self.keys.write().unwrap();
+        self.positions.retain(|key, cmd_pos| {
+            let keep = f(key, cmd_pos);
+            if !keep {
+                keys.remove(key);
+                self.forget_value(key);
+            }
+            keep
+        });
+    }
+
+    /// Keys in `range` in ascending order
+    fn range_keys<R: RangeBounds<String>>(&self, range: R) -> Vec<String> {
+        self.keys.read().unwrap().range(range).cloned().collect()
+    }
+
+    /// Up to `limit` keys in ascending order, starting after `after`
+    fn keys_after(&self, after: Option<&str>, limit: usize) -> Vec<String> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_owned()),
+            None => Bound::Unbounded,
+        };
+        let keys = self.keys.read().unwrap();
+        keys.range((start, Bound::Unbounded))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Deref for Index {
+    type Target = DashMap<String, CommandPos>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.positions
+    }
+}
+
+/// Current time as milliseconds since the unix epoch
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// When a value written now with `ttl` expires, the end of time for a TTL
+/// too long to count in milliseconds
+pub(super) fn expiry_after(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_millis().saturating_add(ttl)
+}
+
+/// A random duration between zero and `max`, it only needs to spread
+/// expirations, not be unpredictable
+fn random_up_to(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    max.mul_f64(hasher.finish() as f64 / u64::MAX as f64)
 }
 
 const MAX_WAL_SIZE_THRESHOLD: u64 = 1024 * 1024;
+const MAX_LOG_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_KEY_SIZE: usize = 64 * 1024;
+const MAX_VALUE_SIZE: usize = 512 * 1024 * 1024;
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(2);
+const GROUP_SYNC_INTERVAL: Duration = Duration::from_millis(2);
+const READ_BUFFER_SIZE: usize = 8 * 1024;
 
-/// A key-value store for storing string pairs
-#[derive(Clone)]
+/// Log files start with `LOG_MAGIC` followed by a format version byte, see
+/// `LogFormat`. Files without it are logs of bare JSON commands from before
+/// records were checksummed. Older formats are still read but never written.
+const LOG_MAGIC: &[u8; 7] = b"KVSLOG\n";
+const LOG_VERSION_JSON: u8 = 1;
+const LOG_VERSION_BINARY: u8 = 2;
+const LOG_VERSION_COMPRESSED: u8 = 3;
+const LOG_HEADER_LEN: u64 = 8;
+/// Each record is the payload length and its CRC32, both little endian u32,
+/// followed by the encoded command
+const RECORD_HEADER_LEN: u64 = 8;
+const RECORD_SET: u8 = 0;
+const RECORD_RM: u8 = 1;
+const RECORD_MERGE: u8 = 2;
+/// A record payload compressed by `compress`, see `encode_command`
+const RECORD_COMPRESSED: u8 = 3;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+/// Zstd with a trained dictionary, the compressed bytes start with the u32
+/// id of the dictionary, see `Dictionaries`
+const CODEC_ZSTD_DICT: u8 = 3;
+/// Payloads shorter than this are written as they are, compressing them
+/// saves too little to pay for the codec
+const COMPRESS_MIN_LEN: usize = 128;
+/// Like `COMPRESS_MIN_LEN` once the store has a dictionary, which makes
+/// short payloads worth compressing
+const DICT_COMPRESS_MIN_LEN: usize = 32;
+/// Largest dictionary `KvsEngine::train_dictionary` trains
+#[cfg(feature = "zstd")]
+const DICT_MAX_SIZE: usize = 16 * 1024;
+/// Content type byte of a set record, text values have none
+const CONTENT_JSON: u8 = 1;
+/// Records at least this large are streamed by `get_reader`, smaller ones
+/// are read whole and cached like any other read
+const STREAM_MIN_LEN: u64 = 64 * 1024;
+const SNAPSHOT_MANIFEST: &str = "MANIFEST";
+/// Index saved by `close` for a warm restart, see `KvStore::open_warm`
+const WARM_INDEX: &str = "INDEX";
+const WARM_INDEX_MAGIC: &[u8; 8] = b"KVSIDX2\n";
+/// Lifetime counters, see `Counters`
+const COUNTERS_FILE: &str = "STATS";
+
+/// What the store did over its lifetime, or since `KvsEngine::reset_stats`.
+/// Saved to `COUNTERS_FILE` as compaction checks in and on `close`, so a
+/// crash loses at most the counts since the last check.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct Counters {
+    sets: u64,
+    removes: u64,
+    compactions: u64,
+    /// Bytes of the records written by writes, compaction copies excluded
+    bytes_written: u64,
+}
+
+/// Reads the counters saved in `dir`, starting over from zero when there
+/// are none or they can not be read
+fn load_counters(dir: &Path) -> Counters {
+    let path = dir.join(COUNTERS_FILE);
+    if !path.exists() {
+        return Counters::default();
+    }
+    match File::open(&path)
+        .map_err(KvsError::from)
+        .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?))
+    {
+        Ok(counters) => counters,
+        Err(e) => {
+            warn!("unable to read saved stats, starting from zero: {:?}", e);
+            Counters::default()
+        }
+    }
+}
+
+fn save_counters(dir: &Path, counters: &Counters) -> Result<()> {
+    // write aside and rename so a crash never leaves half written counters
+    let tmp_path = dir.join(format!("{}.tmp", COUNTERS_FILE));
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, counters)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(COUNTERS_FILE))?;
+    Ok(())
+}
+
+/// Describes the log files written by `KvStore::snapshot`
+#[derive(Serialize)]
+struct SnapshotManifest {
+    version: String,
+    files: Vec<SnapshotFile>,
+}
+
+#[derive(Serialize)]
+struct SnapshotFile {
+    name: String,
+    len: u64,
+}
+
+/// A key-value store for storing string pairs, as opened from its
+/// directory.
+///
+/// The value `open` returns owns the store: it is what closes it, compacts
+/// it on demand and snapshots it. Reads and writes go through
+/// `KvStoreHandle`s, cheap clones of the same store for other threads that
+/// the owner derefs to and hands out with `handle`. A directory is open at
+/// most once per process: opening it again fails with
+/// `KvsError::AlreadyOpen` until its owner and every handle are dropped,
+/// closed or not.
 pub struct KvStore {
-    index: Arc<DashMap<String, CommandPos>>,
-    reader: Arc<KvStoreReader>,
+    handle: KvStoreHandle,
+}
+
+/// A clone of an open `KvStore` that reads and writes it, see `KvStore`.
+///
+/// Clones share the index, the writer and background compaction. The
+/// engine's `close` and `snapshot` are there for servers that only hold
+/// handles, they act on the whole store like the owner's.
+#[derive(Clone)]
+pub struct KvStoreHandle {
+    index: Arc<Index>,
+    /// Each clone opens log files of its own, so clones used by different
+    /// threads never wait on each other to read
+    reader: KvStoreReader,
     writer: Arc<Mutex<KvStoreWriter>>,
-    running: Arc<AtomicBool>,
-    compaction_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    compactor: Arc<Compactor>,
+    warm_restart: bool,
+    expiry_jitter: Duration,
+    default_ttls: Arc<DefaultTtls>,
+    /// The other numbered databases, only a store of database 0 has them
+    databases: Option<Arc<Databases>>,
+    writer_waits: Arc<WriterWaits>,
+    /// Held for its claim on the directory, given up when the last clone
+    /// is dropped
+    _open_dir: Arc<OpenDir>,
+    io_timeout: Option<Duration>,
+}
+
+/// Directories of the stores open in this process, see `OpenDir`
+static OPEN_DIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Claims a store directory in `OPEN_DIRS` until the owner and every handle
+/// of the store are dropped, so the same process can't run two writers on
+/// one directory
+struct OpenDir {
+    path: PathBuf,
+}
+
+impl OpenDir {
+    fn claim(path: &Path) -> Result<Self> {
+        let path = fs::canonicalize(path)?;
+        if !OPEN_DIRS.lock().unwrap().insert(path.clone()) {
+            return Err(KvsError::AlreadyOpen(path));
+        }
+        Ok(OpenDir { path })
+    }
+}
+
+impl Drop for OpenDir {
+    fn drop(&mut self) {
+        OPEN_DIRS.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// How long writes wait for the writer, see `KvStoreHandle::lock_writer`
+#[derive(Default)]
+struct WriterWaits {
+    waiting: AtomicU64,
+    histogram: Mutex<Histogram>,
+}
+
+/// Numbered databases selected from a store of database 0, kept open until
+/// it is closed
+struct Databases {
+    path: PathBuf,
+    options: KvStoreOptions,
+    open: Mutex<BTreeMap<usize, KvStoreHandle>>,
+}
+
+/// Directory of numbered database `db` of the store at `path`
+fn database_path(path: &Path, db: usize) -> PathBuf {
+    match db {
+        0 => path.to_path_buf(),
+        db => path.join(format!("db{}", db)),
+    }
+}
+
+/// How hard a write tries to reach the disk before it returns
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Hand every write to the OS, it survives a crash of the process but
+    /// not of the machine
+    #[default]
+    Flush,
+    /// `fsync` every write, it survives a crash of the machine
+    Sync,
+    /// Like `Sync`, but a background thread does the `fsync` and every
+    /// write waiting meanwhile shares it, see
+    /// `KvStoreOptions::group_sync_interval`. Writes return once synced,
+    /// without holding up the writes after them.
+    Group,
+}
+
+/// How the records written to the log are compressed. Compressed logs are
+/// read whatever compression a store is opened with, and compaction rewrites
+/// the records it copies with the store's compression, so a store can change
+/// it across restarts. Zstd uses the newest dictionary trained by
+/// `KvsEngine::train_dictionary`, if any.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Fast, with a modest ratio. Needs the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard at a level from 1 to 22, slower with a better ratio the
+    /// higher it is. Needs the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// `none`, `lz4`, `zstd` at level 3, or `zstd:<level>`
+impl FromStr for Compression {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            #[cfg(feature = "lz4")]
+            "lz4" => Ok(Compression::Lz4),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd(3)),
+            #[cfg(feature = "zstd")]
+            s if s.starts_with("zstd:") => match s["zstd:".len()..].parse() {
+                Ok(level) if (1..=22).contains(&level) => Ok(Compression::Zstd(level)),
+                _ => Err(KvsError::Message(format!("invalid zstd level: {}", s))),
+            },
+            _ => Err(KvsError::Message(format!(
+                "unsupported compression: {}, this build supports none{}{}",
+                s,
+                if cfg!(feature = "lz4") { ", lz4" } else { "" },
+                if cfg!(feature = "zstd") { ", zstd" } else { "" },
+            ))),
+        }
+    }
+}
+
+/// Combines the value of a key with the operands merged into it since it was
+/// last set, oldest first: `(key, value, operands) -> new value`. `value` is
+/// `None` if the key was not set before its first merge.
+pub type MergeFn = Arc<dyn Fn(&str, Option<&str>, &[String]) -> String + Send + Sync>;
+
+/// Tuning knobs for `KvStore::open_with`, the defaults are what `open` uses
+///
+/// ```no_run
+/// # use kvs::{Durability, KvStore, KvStoreOptions};
+/// # use std::path::Path;
+/// let options = KvStoreOptions::default()
+///     .compaction_threshold(16 * 1024 * 1024)
+///     .durability(Durability::Sync);
+/// let store = KvStore::open_with(Path::new("data"), options)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone)]
+pub struct KvStoreOptions {
+    compaction_threshold: u64,
+    compaction_interval: Duration,
+    read_buffer_size: usize,
+    durability: Durability,
+    warm_restart: bool,
+    compaction_threads: usize,
+    merge_operator: Option<MergeFn>,
+    expiry_jitter: Duration,
+    sweep_limit: usize,
+    value_cache: usize,
+    mmap_reads: bool,
+    compression: Compression,
+    max_log_size: u64,
+    max_log_records: u64,
+    max_key_size: usize,
+    max_value_size: usize,
+    database: usize,
+    group_sync_interval: Duration,
+    group_sync_batch: u64,
+    verify_compaction: bool,
+    default_ttls: DefaultTtls,
+    io_timeout: Option<Duration>,
+}
+
+impl fmt::Debug for KvStoreOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStoreOptions")
+            .field("compaction_threshold", &self.compaction_threshold)
+            .field("compaction_interval", &self.compaction_interval)
+            .field("read_buffer_size", &self.read_buffer_size)
+            .field("durability", &self.durability)
+            .field("warm_restart", &self.warm_restart)
+            .field("compaction_threads", &self.compaction_threads)
+            .field("merge_operator", &self.merge_operator.is_some())
+            .field("expiry_jitter", &self.expiry_jitter)
+            .field("sweep_limit", &self.sweep_limit)
+            .field("value_cache", &self.value_cache)
+            .field("mmap_reads", &self.mmap_reads)
+            .field("compression", &self.compression)
+            .field("max_log_size", &self.max_log_size)
+            .field("max_log_records", &self.max_log_records)
+            .field("max_key_size", &self.max_key_size)
+            .field("max_value_size", &self.max_value_size)
+            .field("database", &self.database)
+            .field("group_sync_interval", &self.group_sync_interval)
+            .field("group_sync_batch", &self.group_sync_batch)
+            .field("verify_compaction", &self.verify_compaction)
+            .field("default_ttls", &self.default_ttls)
+            .field("io_timeout", &self.io_timeout)
+            .finish()
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: MAX_WAL_SIZE_THRESHOLD,
+            compaction_interval: COMPACTION_INTERVAL,
+            read_buffer_size: READ_BUFFER_SIZE,
+            durability: Durability::default(),
+            warm_restart: false,
+            compaction_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            merge_operator: None,
+            expiry_jitter: Duration::ZERO,
+            sweep_limit: usize::MAX,
+            value_cache: 0,
+            mmap_reads: false,
+            compression: Compression::None,
+            max_log_size: MAX_LOG_SIZE,
+            max_log_records: u64::MAX,
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: MAX_VALUE_SIZE,
+            database: 0,
+            group_sync_interval: GROUP_SYNC_INTERVAL,
+            group_sync_batch: u64::MAX,
+            verify_compaction: false,
+            default_ttls: DefaultTtls::default(),
+            io_timeout: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Bytes of stale records to accumulate before the logs are compacted
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// How often the background thread checks whether to compact
+    pub fn compaction_interval(mut self, interval: Duration) -> Self {
+        self.compaction_interval = interval;
+        self
+    }
+
+    /// Size of the buffer of each open log file reader
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Number of log files compaction writes in parallel, defaults to the
+    /// number of CPUs
+    pub fn compaction_threads(mut self, threads: usize) -> Self {
+        self.compaction_threads = threads;
+        self
+    }
+
+    /// Operator resolving the operands written by `KvsEngine::merge`. Reopen
+    /// a store with the same operator, its logs may hold unresolved operands.
+    pub fn merge_operator(
+        mut self,
+        merge: impl Fn(&str, Option<&str>, &[String]) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_operator = Some(Arc::new(merge));
+        self
+    }
+
+    /// Experimental: reuse the index saved by the last `close` instead of
+    /// reading every log file, provided the logs have not changed since.
+    /// `close` then saves the index for the next open.
+    pub fn warm_restart(mut self, enabled: bool) -> Self {
+        self.warm_restart = enabled;
+        self
+    }
+
+    /// Extend every TTL by a random amount up to `jitter`, so keys written
+    /// together with the same TTL do not all expire at once
+    pub fn expiry_jitter(mut self, jitter: Duration) -> Self {
+        self.expiry_jitter = jitter;
+        self
+    }
+
+    /// Expire the keys written without a TTL after `ttl`, as if it was
+    /// given, unless a `namespace_ttl` covers them. `KvsEngine::persist`
+    /// takes it off a key. None by default.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttls.set_default(ttl);
+        self
+    }
+
+    /// TTL of the keys starting with `prefix` written without one, in place
+    /// of `default_ttl`, `None` to keep them from expiring. The longest
+    /// prefix a key starts with applies.
+    pub fn namespace_ttl(mut self, prefix: impl Into<String>, ttl: Option<Duration>) -> Self {
+        self.default_ttls.set_namespace(prefix.into(), ttl);
+        self
+    }
+
+    /// Most expired keys the background thread drops from the index per
+    /// compaction interval, the rest wait for the next ones. Reads treat
+    /// expired keys as missing either way.
+    pub fn sweep_limit(mut self, keys: usize) -> Self {
+        self.sweep_limit = keys;
+        self
+    }
+
+    /// Keep the values of up to `keys` recently read keys in memory, so
+    /// `get` on a hot key does not read the log. Scans bypass the cache.
+    /// Disabled by default.
+    pub fn value_cache(mut self, keys: usize) -> Self {
+        self.value_cache = keys;
+        self
+    }
+
+    /// Read the log files that are no longer written to through memory
+    /// maps, so concurrent reads of them neither seek nor wait on each other.
+    /// The files must not be truncated by anything else while the store is
+    /// open.
+    pub fn mmap_reads(mut self, enabled: bool) -> Self {
+        self.mmap_reads = enabled;
+        self
+    }
+
+    /// Compress the records written from now on, those of writes and those
+    /// compaction copies. Records that compress poorly and small ones are
+    /// written as they are. Values in compressed logs are read whole, even
+    /// the large ones `get_reader` would stream. Defaults to none.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Start a new log once the one being written reaches `bytes`, so
+    /// compaction works on logs that are no longer written to and no single
+    /// file grows past about this size. Defaults to 64 MiB.
+    pub fn max_log_size(mut self, bytes: u64) -> Self {
+        self.max_log_size = bytes;
+        self
+    }
+
+    /// Start a new log once the one being written holds `records` records,
+    /// on top of `max_log_size`. Unlimited by default.
+    pub fn max_log_records(mut self, records: u64) -> Self {
+        self.max_log_records = records;
+        self
+    }
+
+    /// Refuse writes of keys longer than `bytes` with
+    /// `KvsError::KeyTooLarge`, before anything reaches the log. Defaults to
+    /// 64 KiB.
+    pub fn max_key_size(mut self, bytes: usize) -> Self {
+        self.max_key_size = bytes;
+        self
+    }
+
+    /// Refuse writes of values, and merge operands, larger than `bytes` with
+    /// `KvsError::ValueTooLarge`, before anything reaches the log. Defaults
+    /// to 512 MiB.
+    pub fn max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    /// Open numbered database `db`, kept in the `db<n>` directory under the
+    /// store's path, instead of database 0 in the path itself. Every
+    /// database has logs, an index and a compaction thread of its own.
+    pub fn database(mut self, db: usize) -> Self {
+        self.database = db;
+        self
+    }
+
+    /// With `Durability::Group`, how long the sync thread waits for more
+    /// writes to join a sync once the first one is waiting. Bounds the
+    /// latency a write pays for the `fsync` it shares. Defaults to 2ms.
+    pub fn group_sync_interval(mut self, interval: Duration) -> Self {
+        self.group_sync_interval = interval;
+        self
+    }
+
+    /// With `Durability::Group`, sync without waiting out the interval once
+    /// `writes` writes are waiting. Unlimited by default.
+    pub fn group_sync_batch(mut self, writes: u64) -> Self {
+        self.group_sync_batch = writes;
+        self
+    }
+
+    /// Sync every log compaction writes and read its records back before
+    /// the compacted logs are removed, failing the compaction and keeping
+    /// the old logs if a copy does not match the record it was copied from.
+    /// Costs a sync and a read of every copied record. Disabled by default.
+    pub fn verify_compaction(mut self, enabled: bool) -> Self {
+        self.verify_compaction = enabled;
+        self
+    }
+
+    /// Fail a read or write with `KvsError::Timeout` once it has spent
+    /// `timeout` on disk I/O, so a hung disk or NFS mount turns into errors
+    /// rather than threads blocked for good. The deadline is checked before
+    /// each read from a log and before a write takes the log, never once a
+    /// write has started, so a write that timed out wrote nothing. A single
+    /// system call that never returns can't be interrupted. No timeout by
+    /// default.
+    pub fn io_timeout(mut self, timeout: Duration) -> Self {
+        self.io_timeout = Some(timeout);
+        self
+    }
+}
+
+/// Handle on the background compaction thread shared by every clone of a
+/// store. The thread stops once `shutdown` is dropped, either by `close` or
+/// when the last clone goes away. The sync thread of `Durability::Group`
+/// stops with the last clone, writes may still come in after `close`.
+struct Compactor {
+    /// Held for the length of a compaction, background or on demand
+    running: Arc<Mutex<()>>,
+    shutdown: Mutex<Option<Sender<()>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+    group_sync: Option<Arc<GroupSync>>,
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if let Some(group_sync) = &self.group_sync {
+            group_sync.stop();
+        }
+    }
+}
+
+/// The sync thread of `Durability::Group` and the writes waiting on it.
+/// Writes are numbered in the order they were committed, the thread syncs
+/// the active log and wakes every write up to the last one it covered.
+struct GroupSync {
+    state: Mutex<GroupSyncState>,
+    changed: Condvar,
+}
+
+struct GroupSyncState {
+    /// The log being written, replaced when the writer moves to a new one
+    file: Arc<File>,
+    /// Last write committed and last write synced
+    committed: u64,
+    synced: u64,
+    /// Set when a sync failed, writes can no longer be acknowledged
+    failed: Option<String>,
+    stopped: bool,
+}
+
+impl GroupSync {
+    fn start(file: File, options: &KvStoreOptions) -> Arc<Self> {
+        let group_sync = Arc::new(GroupSync {
+            state: Mutex::new(GroupSyncState {
+                file: Arc::new(file),
+                committed: 0,
+                synced: 0,
+                failed: None,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let (interval, batch) = (options.group_sync_interval, options.group_sync_batch);
+        let thread_sync = group_sync.clone();
+        thread::spawn(move || thread_sync.run(interval, batch));
+        group_sync
+    }
+
+    fn run(&self, interval: Duration, batch: u64) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            state = self
+                .changed
+                .wait_while(state, |s| s.committed == s.synced && !s.stopped)
+                .unwrap();
+            if state.stopped {
+                return;
+            }
+            // give the writes right behind the first one a chance to join
+            state = self
+                .changed
+                .wait_timeout_while(state, interval, |s| {
+                    s.committed - s.synced < batch && !s.stopped
+                })
+                .unwrap()
+                .0;
+            let (file, target) = (state.file.clone(), state.committed);
+            drop(state);
+            let synced = file.sync_data();
+            state = self.state.lock().unwrap();
+            match synced {
+                // a switch may have covered more meanwhile
+                Ok(()) => state.synced = state.synced.max(target),
+                Err(e) => state.failed = Some(e.to_string()),
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Write `committed` reached the OS
+    fn committed(&self, committed: u64) {
+        self.state.lock().unwrap().committed = committed;
+        self.changed.notify_all();
+    }
+
+    /// The writer synced the log it wrote so far and moved on to `file`
+    fn switch(&self, file: File) {
+        let mut state = self.state.lock().unwrap();
+        state.file = Arc::new(file);
+        state.synced = state.committed;
+        self.changed.notify_all();
+    }
+
+    /// Waits until write `committed` was synced
+    fn wait(&self, committed: u64) -> Result<()> {
+        let state = self
+            .changed
+            .wait_while(self.state.lock().unwrap(), |s| {
+                s.synced < committed && s.failed.is_none()
+            })
+            .unwrap();
+        match &state.failed {
+            Some(e) => Err(KvsError::Message(format!("background sync failed: {}", e))),
+            None => Ok(()),
+        }
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+    }
 }
 
 impl KvStore {
     pub fn open(path: &Path) -> Result<Self> {
-        let mut index = DashMap::new();
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Experimental: opens the store with `KvStoreOptions::warm_restart`
+    pub fn open_warm(path: &Path) -> Result<Self> {
+        Self::open_with(path, KvStoreOptions::default().warm_restart(true))
+    }
+
+    pub fn open_with(path: &Path, options: KvStoreOptions) -> Result<Self> {
+        let databases = match options.database {
+            0 => Some(Arc::new(Databases {
+                path: path.to_path_buf(),
+                options: options.clone(),
+                open: Mutex::new(BTreeMap::new()),
+            })),
+            _ => None,
+        };
+        let path = &database_path(path, options.database);
+        if options.database != 0 {
+            fs::create_dir_all(path)?;
+        }
+        let open_dir = OpenDir::claim(path)?;
+        let mut index = Index::new(options.value_cache);
+        let warm_restart = options.warm_restart;
 
         let walfile_nums = sorted_walfile_nums(path)?;
-        let reader = Arc::new(KvStoreReader::from_walfiles(
+        let warm_index_path = path.join(WARM_INDEX);
+        let mut attached = false;
+        if warm_index_path.exists() {
+            if warm_restart {
+                match attach_index(&warm_index_path, path, &walfile_nums, &index) {
+                    Ok(true) => attached = true,
+                    Ok(false) => info!("saved index is out of date, reading logs"),
+                    Err(e) => warn!("unable to read saved index, reading logs: {:?}", e),
+                }
+            }
+            // the logs move on from here, never attach to the same file twice
+            fs::remove_file(&warm_index_path)?;
+        }
+        let reader = KvStoreReader::from_walfiles(
             path,
             walfile_nums.clone(),
             &mut index,
-        )?);
+            !attached,
+            &options,
+        )?;
         let current_walfile_num = walfile_nums.last().unwrap_or(&0) + 1;
         let index = Arc::new(index);
 
-        let writer = KvStoreWriter::new(
+        let mut writer = KvStoreWriter::new(
             path,
             current_walfile_num,
-            Arc::clone(&reader),
+            reader.clone(),
             index.clone(),
+            &options,
         )?;
+        let group_sync = match options.durability {
+            Durability::Group => {
+                let file = writer.writer.writer.get_ref().try_clone()?;
+                Some(GroupSync::start(file, &options))
+            }
+            _ => None,
+        };
+        writer.group_sync = group_sync.clone();
         let writer = Arc::new(Mutex::new(writer));
-        reader.add_reader(current_walfile_num)?;
+        reader.add_reader(current_walfile_num, false)?;
 
-        let running = Arc::new(AtomicBool::new(true));
-        let running_clone = running.clone();
+        let (shutdown, shutdown_rx) = mpsc::channel::<()>();
         let writer_clone = writer.clone();
+        let index_clone = index.clone();
+        let running = Arc::new(Mutex::new(()));
+        let running_clone = running.clone();
 
-        let compaction_thread = thread::spawn(move || {
-            while running_clone.load(atomic::Ordering::Relaxed) {
-                if let Ok(mut writer_guard) = writer_clone.lock() {
-                    if writer_guard.uncompacted > MAX_WAL_SIZE_THRESHOLD {
-                        if let Err(e) = writer_guard.run_compaction() {
-                            println!("Error compacting: {:?}", e);
-                        }
+        let compaction_thread = thread::spawn(move || loop {
+            match shutdown_rx.recv_timeout(options.compaction_interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            // look for expired keys before taking the writer, so a mass
+            // expiration does not hold up writes while the index is scanned
+            let expired = index_clone.expired_keys(now_millis(), options.sweep_limit);
+            let due = match writer_clone.lock() {
+                Ok(mut writer_guard) => {
+                    writer_guard.drop_expired(expired);
+                    if let Err(e) = writer_guard.save_counters() {
+                        warn!("unable to save stats: {:?}", e);
                     }
+                    writer_guard.uncompacted > options.compaction_threshold
+                }
+                Err(_) => false,
+            };
+            if due {
+                let _running = running_clone.lock().unwrap();
+                if let Err(e) = compact(&writer_clone) {
+                    println!("Error compacting: {:?}", e);
                 }
-                thread::sleep(Duration::from_secs(2));
             }
         });
 
-        Ok(Self {
+        let handle = KvStoreHandle {
             index,
             reader,
             writer,
-            running,
-            compaction_thread: Arc::new(Mutex::new(Some(compaction_thread))),
-        })
+            compactor: Arc::new(Compactor {
+                running,
+                shutdown: Mutex::new(Some(shutdown)),
+                handle: Mutex::new(Some(compaction_thread)),
+                group_sync,
+            }),
+            warm_restart,
+            expiry_jitter: options.expiry_jitter,
+            default_ttls: Arc::new(options.default_ttls.clone()),
+            databases,
+            writer_waits: Arc::default(),
+            _open_dir: Arc::new(open_dir),
+            io_timeout: options.io_timeout,
+        };
+        Ok(KvStore { handle })
+    }
+
+    /// A handle to the store for another thread
+    pub fn handle(&self) -> KvStoreHandle {
+        self.handle.clone()
+    }
+
+    /// Gives up ownership for a handle, for callers such as a server that
+    /// only need what handles do. The store is then closed through the
+    /// engine's `close`.
+    pub fn into_handle(self) -> KvStoreHandle {
+        self.handle
+    }
+
+    /// Closes the store, see `KvsEngine::close`. Handles left can still read
+    /// and write, and keep the directory from being opened again until the
+    /// last of them is dropped.
+    pub fn close(self) -> Result<()> {
+        self.handle.close()
+    }
+
+    /// Compacts the logs now rather than waiting for the background
+    /// compaction to find enough to reclaim, waiting for one in progress
+    /// to finish first
+    pub fn compact_now(&self) -> Result<()> {
+        let _running = self.handle.compactor.running.lock().unwrap();
+        compact(&self.handle.writer)
+    }
+
+    /// Writes a consistent copy of the store into `dest`, see
+    /// `KvsEngine::snapshot`
+    pub fn snapshot(&self, dest: &Path) -> Result<()> {
+        self.handle.snapshot(dest)
     }
 }
 
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        if Arc::strong_count(&self.compaction_thread) == 1 {
-            self.running.store(false, atomic::Ordering::Relaxed);
-            if let Ok(mut guard) = self.compaction_thread.lock() {
-                if let Some(handle) = guard.take() {
-                    handle.join().unwrap();
-                }
+impl Deref for KvStore {
+    type Target = KvStoreHandle;
+
+    fn deref(&self) -> &KvStoreHandle {
+        &self.handle
+    }
+}
+
+impl KvStoreHandle {
+    /// Takes the writer, counting the callers waiting for it and how long
+    /// they waited, so stalls behind compaction or a slow disk show in
+    /// `stats`
+    fn lock_writer(&self) -> MutexGuard<'_, KvStoreWriter> {
+        self.writer_waits.waiting.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let writer = self.writer.lock().unwrap();
+        let waited = started.elapsed();
+        self.writer_waits.waiting.fetch_sub(1, Ordering::Relaxed);
+        self.writer_waits.histogram.lock().unwrap().observe(waited);
+        writer
+    }
+
+    /// Runs a write with the writer and, with `Durability::Group`, waits for
+    /// the sync that covers it once the writer is free for the next one
+    fn write<T>(&self, f: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        let _deadline = IoDeadline::start(self.io_timeout);
+        let mut writer = self.lock_writer();
+        // a write that waited out its deadline for the writer writes nothing.
+        // Reads in `f` come before its records, once a record is written
+        // nothing checks the deadline and the write runs to the end.
+        let result = check_deadline()
+            .map_err(KvsError::from)
+            .and_then(|()| f(&mut writer))
+            .map_err(timed_out);
+        let (group_sync, committed) = (writer.group_sync.clone(), writer.commits);
+        drop(writer);
+        if let Some(group_sync) = group_sync {
+            group_sync.wait(committed)?;
+        }
+        result
+    }
+
+    fn databases_of(&self) -> Result<&Databases> {
+        self.databases
+            .as_deref()
+            .ok_or_else(|| KvsError::Message("databases are only reachable from database 0".into()))
+    }
+
+    /// Iterates over every key and its value, ordered by key.
+    ///
+    /// The keys are those in the index when `iter` is called: keys set
+    /// later are not visited, and keys removed or expired by the time the
+    /// iterator reaches them are skipped. Each value is read when its key is
+    /// reached, so it is the value at that point rather than at the start.
+    /// Writes and compaction go on while iterating.
+    pub fn iter(&self) -> KvStoreIter {
+        KvStoreIter {
+            store: self.clone(),
+            keys: self.index.range_keys(..).into_iter(),
+        }
+    }
+}
+
+/// Iterator over the keys and values of a store, see `KvStoreHandle::iter`
+pub struct KvStoreIter {
+    store: KvStoreHandle,
+    keys: std::vec::IntoIter<String>,
+}
+
+impl Iterator for KvStoreIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = now_millis();
+        for key in self.keys.by_ref() {
+            match self.store.read_key(&key, now) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
             }
         }
+        None
     }
 }
 
-impl KvsEngine for KvStore {
+impl KvsEngine for KvStoreHandle {
     /// Retrieves the value associated with the given key
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(val) = self.index.get(&key) {
-            return Ok(self.reader.get(&*val)?);
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(into_string))
+    }
+
+    /// Retrieves the bytes of the value associated with the given key
+    fn get_bytes(&self, key: impl AsRef<str>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_typed(key)?.map(|(value, _)| value))
+    }
+
+    /// Retrieves the value associated with the given key and its content type
+    fn get_typed(&self, key: impl AsRef<str>) -> Result<Option<(Vec<u8>, ContentType)>> {
+        let key = key.as_ref();
+        if let Some(e) = self.index.quarantined(key) {
+            return Err(e);
+        }
+        if let Some(val) = self.index.get(key) {
+            // expired entries stay in the index until the background sweep
+            // removes them, reads just treat them as missing
+            if !val.is_expired(now_millis()) {
+                // holding `val` keeps writers from replacing the value, and
+                // with it the cached one, until the read is done
+                if let Some(value) = self.index.cached_value(key) {
+                    return Ok(Some(value));
+                }
+                let value = self.read_value(key, &val)?;
+                if let Some(value) = &value {
+                    self.index.cache_value(key, value);
+                }
+                return Ok(value);
+            }
         }
         Ok(None)
     }
 
+    /// Streams the value of a plain set record from its log through a handle
+    /// of its own, checking the record's checksum once the value is read.
+    /// Small records, merged values and values in older formats are read
+    /// whole.
+    fn get_reader(&self, key: impl AsRef<str>) -> Result<Option<ValueReader>> {
+        let key = key.as_ref();
+        if let Some(e) = self.index.quarantined(key) {
+            return Err(e);
+        }
+        let Some(cmd_pos) = self.index.get(key) else {
+            return Ok(None);
+        };
+        if cmd_pos.is_expired(now_millis()) {
+            return Ok(None);
+        }
+        let binary = self
+            .reader
+            .logs
+            .files
+            .get(&cmd_pos.walfile_num)
+            .is_some_and(|log| log.format == LogFormat::Binary);
+        if cmd_pos.len < STREAM_MIN_LEN || !cmd_pos.operands.is_empty() || !binary {
+            drop(cmd_pos);
+            return Ok(self.get_typed(key)?.map(ValueReader::from));
+        }
+        // holding `cmd_pos` keeps compaction from removing the log until it
+        // is open
+        let file = log_path(&self.reader.logs.path, cmd_pos.walfile_num);
+        let reader = RecordValueReader::open(file, cmd_pos.record(), key, &self.index)
+            .inspect_err(|e| {
+                if let KvsError::Corruption { file, offset } = e {
+                    self.index.quarantine(key, file.clone(), *offset);
+                }
+            })?;
+        let content_type = reader.content_type;
+        Ok(Some(ValueReader::new(reader.len, content_type, reader)))
+    }
+
+    /// Retrieves the values of the given keys while holding the writer, so
+    /// no write lands between two of them
+    fn get_many<K: AsRef<str>>(&self, keys: &[K]) -> Result<Vec<Option<TypedValue>>> {
+        let _writer = self.lock_writer();
+        keys.iter().map(|key| self.get_typed(key)).collect()
+    }
+
+    /// Reads the expiry of a live entry off the index
+    fn expires_at(&self, key: impl AsRef<str>) -> Result<Option<u64>> {
+        let now = now_millis();
+        Ok(self
+            .index
+            .get(key.as_ref())
+            .filter(|cmd_pos| !cmd_pos.is_expired(now))
+            .and_then(|cmd_pos| cmd_pos.expires_at))
+    }
+
+    /// Checks the index for a live entry of the given key
+    fn exists(&self, key: impl AsRef<str>) -> Result<bool> {
+        let now = now_millis();
+        Ok(self
+            .index
+            .get(key.as_ref())
+            .is_some_and(|cmd_pos| !cmd_pos.is_expired(now)))
+    }
+
+    /// Returns the live keys in the index matching the glob `pattern`
+    fn keys(&self, pattern: &str) -> Result<Vec<String>> {
+        let pattern = glob::Pattern::new(pattern)
+            .map_err(|e| KvsError::Message(format!("invalid pattern {}: {}", pattern, e)))?;
+        let now = now_millis();
+        Ok(self
+            .index
+            .range_keys::<RangeFull>(..)
+            .into_iter()
+            .filter(|key| pattern.matches(key))
+            .filter(|key| {
+                self.index
+                    .get(key)
+                    .is_some_and(|cmd_pos| !cmd_pos.is_expired(now))
+            })
+            .collect())
+    }
+
+    /// Counts the live entries of the index
+    fn key_count(&self) -> Result<usize> {
+        let now = now_millis();
+        Ok(self
+            .index
+            .iter()
+            .filter(|entry| !entry.value().is_expired(now))
+            .count())
+    }
+
     /// Sets a value for the given key
     fn set(&self, key: String, value: String) -> Result<()> {
-        let mut writer = self.writer.lock().unwrap();
-        writer.set(key, value)?;
-        Ok(())
+        self.set_typed(key, value, ContentType::Text)
+    }
+
+    /// Sets a value for the given key along with its content type
+    fn set_typed(&self, key: String, value: String, content_type: ContentType) -> Result<()> {
+        let expires_at = self.default_expiry(&key);
+        self.write(|writer| writer.set(key, value.into_bytes(), expires_at, content_type))
+    }
+
+    /// Sets the given key to a value of any bytes
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let expires_at = self.default_expiry(&key);
+        self.write(|writer| writer.set(key, value, expires_at, ContentType::Text))
+    }
+
+    /// Sets a value for the given key that expires after `ttl`
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = self.expiry(ttl);
+        self.write(|writer| {
+            writer.set(key, value.into_bytes(), Some(expires_at), ContentType::Text)
+        })
     }
 
     /// Removes a key and its associated value from the store
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)?;
+    fn remove(&self, key: impl AsRef<str>) -> Result<()> {
+        self.write(|writer| writer.remove(key.as_ref()))
+    }
+
+    /// Writes the value of `key` again without an expiry
+    fn persist(&self, key: impl AsRef<str>) -> Result<bool> {
+        let key = key.as_ref();
+        self.write(|writer| {
+            let now = now_millis();
+            let expiring = self
+                .index
+                .get(key)
+                .is_some_and(|cmd_pos| cmd_pos.expires_at.is_some() && !cmd_pos.is_expired(now));
+            if !expiring {
+                return Ok(false);
+            }
+            let Some((value, content_type)) = self.get_typed(key)? else {
+                return Ok(false);
+            };
+            writer.set(key.to_owned(), value, None, content_type)?;
+            Ok(true)
+        })
+    }
+
+    /// Removes the matching keys as one batch, listed under the writer lock
+    fn remove_matching(&self, pattern: &str) -> Result<usize> {
+        self.write(|writer| {
+            let cmds: Vec<Command> = self
+                .keys(pattern)?
+                .into_iter()
+                .map(|key| Command::Rm { key })
+                .collect();
+            let removed = cmds.len();
+            if removed > 0 {
+                writer.write_batch(cmds)?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Appends a batch of commands under one writer lock and flush
+    fn write_batch(&self, mut cmds: Vec<Command>) -> Result<()> {
+        for cmd in &mut cmds {
+            if let Command::Set {
+                key, expires_at, ..
+            }
+            | Command::SetBytes {
+                key, expires_at, ..
+            } = cmd
+            {
+                *expires_at = expires_at.or_else(|| self.default_expiry(key));
+            }
+        }
+        self.write(|writer| writer.write_batch(cmds))
+    }
+
+    /// Returns the live pairs with keys in `range`, ordered by key
+    fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.read_keys(self.index.range_keys(range))
+    }
+
+    /// Returns the live pairs with keys starting with `prefix`, ordered by key
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let keys = self
+            .index
+            .range_keys((Bound::Included(prefix.to_owned()), Bound::Unbounded))
+            .into_iter()
+            .take_while(|key| key.starts_with(prefix))
+            .collect();
+        self.read_keys(keys)
+    }
+
+    /// Returns a page of live pairs after `cursor`, keys removed or expired
+    /// since they were listed are skipped and the next keys fill their place
+    fn scan_page(&self, cursor: Option<Cursor>, limit: usize) -> Result<ScanPage> {
+        let mut after = cursor.map(|cursor| cursor.after().to_owned());
+        let mut pairs = Vec::with_capacity(limit);
+        while pairs.len() < limit {
+            let keys = self.index.keys_after(after.as_deref(), limit - pairs.len());
+            let exhausted = keys.len() < limit - pairs.len();
+            after = keys.last().cloned().or(after);
+            pairs.extend(self.read_keys(keys)?);
+            if exhausted {
+                return Ok((pairs, None));
+            }
+        }
+        Ok((pairs, after.map(Cursor::new)))
+    }
+
+    /// Runs `f` under the writer lock, so no other write lands between the
+    /// read and the write
+    fn update<F>(&self, key: String, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&str>) -> Option<String>,
+    {
+        self.write(|writer| {
+            let current = self.get_typed(&key)?;
+            let text = current
+                .as_ref()
+                .map(|(value, _)| String::from_utf8_lossy(value));
+            match f(text.as_deref()) {
+                Some(value) => {
+                    let (expires_at, content_type) = self.kept(&key, current.as_ref())?;
+                    writer.set(key, value.into_bytes(), expires_at, content_type)
+                }
+                None if current.is_some() => writer.remove(&key),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Appends to the bytes of the value under the writer lock
+    fn append(&self, key: String, value: &[u8]) -> Result<usize> {
+        self.write(|writer| {
+            let current = self.get_typed(&key)?;
+            let (expires_at, content_type) = self.kept(&key, current.as_ref())?;
+            let mut appended = current.map(|(current, _)| current).unwrap_or_default();
+            appended.extend_from_slice(value);
+            let len = appended.len();
+            writer.set(key, appended, expires_at, content_type)?;
+            Ok(len)
+        })
+    }
+
+    /// Replaces the value of `key` with `new` if it currently is `expected`
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        // every write goes through the writer, holding it keeps the value
+        // from changing between the comparison and the swap
+        self.write(|writer| {
+            let current = self.get_bytes(&key)?;
+            if current != expected.map(String::into_bytes) {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => {
+                    let expires_at = self.default_expiry(&key);
+                    writer.set(key, value.into_bytes(), expires_at, ContentType::Text)?
+                }
+                None if current.is_some() => writer.remove(&key)?,
+                None => {}
+            }
+            Ok(true)
+        })
+    }
+
+    /// Merges `operand` into the value of `key` with the store's merge
+    /// operator, applied when the key is next read or compacted
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.write(|writer| writer.merge(key, operand))
+    }
+
+    /// Flushes the active log and syncs it to disk
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.lock_writer();
+        writer.writer.flush()?;
+        writer.writer.writer.get_ref().sync_data()?;
         Ok(())
     }
+
+    /// Counts live keys, the keys due to expire and the bytes of the log
+    /// files, along with the lifetime counters
+    fn stats(&self) -> Result<EngineStats> {
+        // the waits before this call, taking the writer below counts one more
+        let writer_waiting = self.writer_waits.waiting.load(Ordering::Relaxed);
+        let writer_wait = self.writer_waits.histogram.lock().unwrap().clone();
+        let counters = self.lock_writer().counters;
+        let disk_bytes = sorted_walfile_nums(&self.reader.logs.path)?
+            .into_iter()
+            // compaction may remove a file between listing and reading it
+            .filter_map(|num| fs::metadata(log_path(&self.reader.logs.path, num)).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        let now = now_millis();
+        let mut expiring = ExpiryForecast::default();
+        for entry in self.index.iter() {
+            if let Some(expires_at) = entry.value().expires_at {
+                expiring.add(expires_at, now);
+            }
+        }
+        Ok(EngineStats {
+            engine: "kvs",
+            keys: self.key_count()?,
+            disk_bytes,
+            sets: counters.sets,
+            removes: counters.removes,
+            compactions: counters.compactions,
+            bytes_written: counters.bytes_written,
+            writer_waiting,
+            writer_wait,
+            expiring,
+        })
+    }
+
+    /// Opens database `db` in the `db<n>` directory with the options this
+    /// store was opened with, or returns the store that is already open
+    fn select(&self, db: usize) -> Result<Self> {
+        if db == 0 {
+            return Ok(self.clone());
+        }
+        let databases = self.databases_of()?;
+        let mut open = databases.open.lock().unwrap();
+        if let Some(store) = open.get(&db) {
+            return Ok(store.clone());
+        }
+        let options = databases.options.clone().database(db);
+        let store = KvStore::open_with(&databases.path, options)?.into_handle();
+        open.insert(db, store.clone());
+        Ok(store)
+    }
+
+    /// Database 0 and those with a `db<n>` directory, whether or not they
+    /// were selected since the store was opened
+    fn databases(&self) -> Result<Vec<usize>> {
+        let databases = self.databases_of()?;
+        let mut dbs = vec![0];
+        for entry in fs::read_dir(&databases.path)? {
+            let name = entry?.file_name();
+            let db = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("db"))
+                .and_then(|db| db.parse::<usize>().ok());
+            if let Some(db) = db.filter(|db| *db > 0) {
+                dbs.push(db);
+            }
+        }
+        dbs.sort_unstable();
+        Ok(dbs)
+    }
+
+    /// Sizes keys by the records the index points at, a sample taking the
+    /// first keys in the index's hash order
+    fn big_keys(&self, top: usize, sample: Option<usize>) -> Result<BigKeys> {
+        let now = now_millis();
+        let sizes = self
+            .index
+            .iter()
+            .filter(|entry| !entry.is_expired(now))
+            .take(sample.unwrap_or(usize::MAX))
+            .map(|entry| KeySize {
+                key: entry.key().clone(),
+                value_bytes: entry.total_len(),
+                records: 1 + entry.operands.len(),
+            })
+            .collect();
+        Ok(BigKeys::rank(sizes, top))
+    }
+
+    /// Trains on the values of up to `sample` keys, read outside the writer
+    /// lock so writes go on meanwhile
+    fn train_dictionary(&self, sample: usize) -> Result<u32> {
+        let now = now_millis();
+        let keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|entry| !entry.is_expired(now))
+            .take(sample)
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(cmd_pos) = self.index.get(&key) {
+                if let Some((value, _)) = self.read_value(&key, &cmd_pos)? {
+                    values.push(value);
+                }
+            }
+        }
+        let logs = &self.reader.logs;
+        logs.dictionaries.train(&logs.path, &values)
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedKey>> {
+        let mut quarantined: Vec<QuarantinedKey> = self
+            .index
+            .quarantined
+            .iter()
+            .map(|entry| QuarantinedKey {
+                key: entry.key().clone(),
+                file: entry.value().0.clone(),
+                offset: entry.value().1,
+            })
+            .collect();
+        quarantined.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(quarantined)
+    }
+
+    /// Writes a removal of the key if it is still in the index, compaction
+    /// may have dropped it already
+    fn drop_quarantined(&self, key: impl AsRef<str>) -> Result<bool> {
+        let key = key.as_ref();
+        self.write(|writer| {
+            if self.index.quarantined(key).is_none() {
+                return Ok(false);
+            }
+            if self.index.contains_key(key) {
+                match writer.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.index.quarantined.remove(key);
+            Ok(true)
+        })
+    }
+
+    /// Zeroes the lifetime counters and saves them right away
+    fn reset_stats(&self) -> Result<()> {
+        let mut writer = self.lock_writer();
+        writer.counters = Counters::default();
+        writer.save_counters()
+    }
+
+    /// Registers a receiver with the writer, which sends it the changes it
+    /// applies from then on
+    fn subscribe(&self, prefix: &str) -> Result<Receiver<ChangeEvent>> {
+        let (sender, receiver) = mpsc::channel();
+        let mut writer = self.lock_writer();
+        writer.subscribers.push((prefix.to_owned(), sender));
+        Ok(receiver)
+    }
+
+    /// Writes a consistent copy of the store into `dest`, which can be
+    /// opened as a store of its own. Other numbered databases are not
+    /// copied.
+    fn snapshot(&self, dest: &Path) -> Result<()> {
+        self.lock_writer().snapshot(dest)
+    }
+
+    /// Stops background compaction, waiting for a compaction in progress to
+    /// finish, and flushes the log. Other clones can still read and write
+    /// but the store is no longer compacted.
+    ///
+    /// Dropping the last clone also stops compaction but does not wait for
+    /// it, call this first when the directory is about to be reopened. A
+    /// store opened with `open_warm` also saves its index here. Closing
+    /// database 0 closes the databases selected from it. The directory can
+    /// be opened again once every clone is dropped.
+    fn close(&self) -> Result<()> {
+        if let Some(databases) = &self.databases {
+            for store in databases.open.lock().unwrap().values() {
+                store.close()?;
+            }
+        }
+        // dropping the sender wakes the compaction thread and tells it to stop
+        self.compactor.shutdown.lock().unwrap().take();
+        let handle = self.compactor.handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            handle
+                .join()
+                .map_err(|_| KvsError::Message("compaction thread panicked".into()))?;
+        }
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        writer.save_counters()?;
+        if self.warm_restart {
+            save_index(&writer.path, &self.reader, &self.index)?;
+        }
+        Ok(())
+    }
+}
+
+impl KvStoreHandle {
+    /// Reads the value of `key` at `cmd_pos`, quarantining the key if one of
+    /// its records is corrupt. The caller must hold the key's entry.
+    fn read_value(&self, key: &str, cmd_pos: &CommandPos) -> Result<Option<TypedValue>> {
+        let _deadline = IoDeadline::start(self.io_timeout);
+        self.reader
+            .get(key, cmd_pos)
+            .map_err(timed_out)
+            .inspect_err(|e| {
+                if let KvsError::Corruption { file, offset } = e {
+                    self.index.quarantine(key, file.clone(), *offset);
+                }
+            })
+    }
+
+    /// When a key written now with `ttl` expires, jitter added
+    fn expiry(&self, ttl: Duration) -> u64 {
+        expiry_after(ttl.saturating_add(random_up_to(self.expiry_jitter)))
+    }
+
+    /// When `key` written now without a TTL expires, see
+    /// `KvStoreOptions::default_ttl`
+    fn default_expiry(&self, key: &str) -> Option<u64> {
+        self.default_ttls.ttl(key).map(|ttl| self.expiry(ttl))
+    }
+
+    /// Expiry and content type of a value replacing `current`, the value of
+    /// `key`: those of `current`, or the defaults of a new key
+    fn kept(&self, key: &str, current: Option<&TypedValue>) -> Result<(Option<u64>, ContentType)> {
+        match current {
+            Some((_, content_type)) => Ok((self.expires_at(key)?, *content_type)),
+            None => Ok((self.default_expiry(key), ContentType::Text)),
+        }
+    }
+
+    /// Resolves `keys` to their values, skipping keys that were removed or
+    /// expired since they were listed
+    fn read_keys(&self, keys: Vec<String>) -> Result<Vec<(String, String)>> {
+        let now = now_millis();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.read_key(&key, now)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// The value of a listed key, `None` if it was removed or expired by
+    /// `now` since
+    fn read_key(&self, key: &str, now: u64) -> Result<Option<String>> {
+        let Some(cmd_pos) = self.index.get(key) else {
+            return Ok(None);
+        };
+        if cmd_pos.is_expired(now) {
+            return Ok(None);
+        }
+        Ok(self
+            .read_value(key, &cmd_pos)?
+            .map(|(value, _)| into_string(value)))
+    }
 }
 
-fn new_log_file(dir: &Path, walfile_num: u64) -> Result<BufWriterWithPos<File>> {
-    let writer = BufWriterWithPos::new(
+/// A value as text, bytes that are not UTF-8 replaced
+fn into_string(value: Vec<u8>) -> String {
+    String::from_utf8(value).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+/// Opens log `walfile_num` for appending, writing the header of a new one.
+/// Logs of a store with `compression` are marked so builds that can't read
+/// compressed records refuse them rather than fail on the first one.
+fn new_log_file(
+    dir: &Path,
+    walfile_num: u64,
+    compression: Compression,
+) -> Result<BufWriterWithPos<File>> {
+    let mut writer = BufWriterWithPos::new(
         OpenOptions::new()
             .create(true)
             .append(true)
             .open(log_path(dir, walfile_num))?,
     )?;
+    if writer.pos == 0 {
+        writer.write_all(LOG_MAGIC)?;
+        writer.write_all(&[match compression {
+            Compression::None => LOG_VERSION_BINARY,
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            _ => LOG_VERSION_COMPRESSED,
+        }])?;
+        writer.flush()?;
+    }
     Ok(writer)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    /// Bare JSON commands back to back, no header and no checksums
+    LegacyJson,
+    /// Framed records holding JSON commands
+    FramedJson,
+    /// Framed records holding commands encoded by `encode_command`
+    Binary,
+    /// Like `Binary`, with records that may be compressed
+    Compressed,
+}
+
+impl LogFormat {
+    /// Whether records hold commands encoded by `encode_command`
+    fn is_binary(self) -> bool {
+        matches!(self, LogFormat::Binary | LogFormat::Compressed)
+    }
+}
+
+fn read_log_format<R: Read + Seek>(reader: &mut R) -> Result<LogFormat> {
+    reader.seek(io::SeekFrom::Start(0))?;
+    let mut header = [0; LOG_HEADER_LEN as usize];
+    let read = read_full(reader, &mut header)?;
+    let magic_len = read.min(LOG_MAGIC.len());
+    if header[..magic_len] != LOG_MAGIC[..magic_len] {
+        return Ok(LogFormat::LegacyJson);
+    }
+    // a file cut short inside its header never had a record written to it
+    if read < header.len() {
+        return Ok(LogFormat::Binary);
+    }
+    match header[LOG_MAGIC.len()] {
+        LOG_VERSION_JSON => Ok(LogFormat::FramedJson),
+        LOG_VERSION_BINARY => Ok(LogFormat::Binary),
+        LOG_VERSION_COMPRESSED => Ok(LogFormat::Compressed),
+        version => Err(KvsError::Message(format!(
+            "unsupported log format version {}",
+            version
+        ))),
+    }
+}
+
+/// Reads until `buf` is full or the reader is exhausted, returning the number
+/// of bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Appends `payload` as a framed record, returning the length of the record
+fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> Result<u64> {
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(RECORD_HEADER_LEN + payload.len() as u64)
+}
+
+/// Encodes a `Set`, `Rm` or `Merge` as a record payload. Integers are little endian:
+///
+/// - set: `RECORD_SET`, u32 key length, key, u32 value length, value, then
+///   u8 1 and u64 `expires_at` or just u8 0, then `CONTENT_JSON` for a json
+///   value or nothing for text. `SetBytes` is a text set of any bytes.
+/// - rm: `RECORD_RM`, u32 key length, key
+/// - merge: `RECORD_MERGE`, u32 key length, key, u32 operand length, operand
+///
+/// `compress` may wrap any of them in a `RECORD_COMPRESSED` payload.
+fn encode_command(cmd: &Command) -> Result<Vec<u8>> {
+    fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+        buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        buf.extend_from_slice(bytes);
+    }
+    fn put_set(
+        buf: &mut Vec<u8>,
+        key: &str,
+        value: &[u8],
+        expires_at: Option<u64>,
+        content_type: ContentType,
+    ) {
+        buf.reserve(19 + key.len() + value.len());
+        buf.push(RECORD_SET);
+        put_bytes(buf, key.as_bytes());
+        put_bytes(buf, value);
+        match expires_at {
+            Some(expires_at) => {
+                buf.push(1);
+                buf.extend_from_slice(&expires_at.to_le_bytes());
+            }
+            None => buf.push(0),
+        }
+        match content_type {
+            ContentType::Text => {}
+            ContentType::Json => buf.push(CONTENT_JSON),
+        }
+    }
+    let mut buf = Vec::new();
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            content_type,
+            ..
+        } => put_set(&mut buf, key, value.as_bytes(), *expires_at, *content_type),
+        Command::SetBytes {
+            key,
+            value,
+            expires_at,
+        } => put_set(&mut buf, key, value, *expires_at, ContentType::Text),
+        Command::Rm { key } => {
+            buf.push(RECORD_RM);
+            put_bytes(&mut buf, key.as_bytes());
+        }
+        Command::Merge { key, operand } => {
+            buf.push(RECORD_MERGE);
+            put_bytes(&mut buf, key.as_bytes());
+            put_bytes(&mut buf, operand.as_bytes());
+        }
+        _ => return Err(KvsError::InvalidCommand),
+    }
+    Ok(buf)
+}
+
+/// Compresses an encoded command with `compression` into a
+/// `RECORD_COMPRESSED` payload: the codec, u32 length of `payload`, then the
+/// compressed bytes. `payload` is returned as it is when it is short or
+/// would not get shorter.
+fn compress(
+    payload: Vec<u8>,
+    compression: Compression,
+    dictionaries: &Dictionaries,
+) -> Result<Vec<u8>> {
+    let min_len = match dictionaries.is_empty() {
+        true => COMPRESS_MIN_LEN,
+        false => DICT_COMPRESS_MIN_LEN,
+    };
+    if payload.len() < min_len {
+        return Ok(payload);
+    }
+    let compressed: Option<(u8, Vec<u8>)> = match compression {
+        Compression::None => None,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Some((CODEC_LZ4, lz4_flex::compress(&payload))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => match dictionaries.newest() {
+            Some((id, dictionary)) => Some((CODEC_ZSTD_DICT, dictionary.compress(id, &payload)?)),
+            None => Some((CODEC_ZSTD, zstd::bulk::compress(&payload, level)?)),
+        },
+    };
+    let Some((codec, compressed)) = compressed else {
+        return Ok(payload);
+    };
+    if compressed.len() + 6 >= payload.len() {
+        return Ok(payload);
+    }
+    let mut buf = Vec::with_capacity(compressed.len() + 6);
+    buf.push(RECORD_COMPRESSED);
+    buf.push(codec);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&compressed);
+    Ok(buf)
+}
+
+/// The encoded command in a payload `compress` wrote, or `payload` itself
+/// if it is not compressed
+fn decompress(payload: Vec<u8>, dictionaries: &Dictionaries) -> Result<Vec<u8>> {
+    if payload.first() != Some(&RECORD_COMPRESSED) {
+        return Ok(payload);
+    }
+    let (codec, len, compressed) = match payload.get(1..6) {
+        Some(header) => (
+            header[0],
+            u32::from_le_bytes(header[1..].try_into().unwrap()) as usize,
+            &payload[6..],
+        ),
+        None => return Err(KvsError::InvalidCommand),
+    };
+    let decompressed = decompress_with(codec, compressed, len, dictionaries)?;
+    if decompressed.len() != len || decompressed.first() == Some(&RECORD_COMPRESSED) {
+        return Err(KvsError::InvalidCommand);
+    }
+    Ok(decompressed)
+}
+
+/// Decompresses `compressed` with `codec` into `len` bytes. A codec this
+/// build lacks fails with a message rather than as corruption, so a store
+/// written by a build with more codecs is left alone.
+// which arms are reachable and which arguments are used depends on the
+// codec features
+#[allow(unreachable_patterns, unused_variables)]
+fn decompress_with(
+    codec: u8,
+    compressed: &[u8],
+    len: usize,
+    dictionaries: &Dictionaries,
+) -> Result<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "lz4")]
+        CODEC_LZ4 => lz4_flex::decompress(compressed, len).map_err(|_| KvsError::InvalidCommand),
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => zstd::bulk::decompress(compressed, len).map_err(|_| KvsError::InvalidCommand),
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD_DICT => dictionaries.decompress(compressed, len),
+        CODEC_LZ4 | CODEC_ZSTD | CODEC_ZSTD_DICT => Err(KvsError::Message(format!(
+            "record compressed with {}, which this build does not support",
+            if codec == CODEC_LZ4 { "lz4" } else { "zstd" }
+        ))),
+        _ => Err(KvsError::InvalidCommand),
+    }
+}
+
+/// Zstd dictionaries trained by `KvsEngine::train_dictionary`, by id. Each
+/// is saved to a `dict_<id>.zdict` file next to the logs and kept for good:
+/// records name the dictionary they were compressed with, so retraining
+/// leaves the older records readable, and compaction moves them over to the
+/// newest one. Builds without the `zstd` feature never load any.
+#[derive(Default)]
+struct Dictionaries {
+    /// Compression level of the store, `None` unless it compresses with zstd
+    #[cfg(feature = "zstd")]
+    level: Option<i32>,
+    #[cfg(feature = "zstd")]
+    by_id: RwLock<BTreeMap<u32, Arc<Dictionary>>>,
+}
+
+/// A dictionary prepared for both directions
+#[cfg(feature = "zstd")]
+struct Dictionary {
+    encoder: zstd::dict::EncoderDictionary<'static>,
+    decoder: zstd::dict::DecoderDictionary<'static>,
+}
+
+#[cfg(feature = "zstd")]
+impl Dictionary {
+    fn new(dictionary: &[u8], level: i32) -> Self {
+        Dictionary {
+            encoder: zstd::dict::EncoderDictionary::copy(dictionary, level),
+            decoder: zstd::dict::DecoderDictionary::copy(dictionary),
+        }
+    }
+
+    /// `payload` compressed with this dictionary, after `id`. The frame
+    /// leaves out the dictionary id and content size, the record has both.
+    fn compress(&self, id: u32, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut compressor = zstd::bulk::Compressor::with_prepared_dictionary(&self.encoder)?;
+        compressor.set_parameter(zstd::stream::raw::CParameter::DictIdFlag(false))?;
+        compressor.set_parameter(zstd::stream::raw::CParameter::ContentSizeFlag(false))?;
+        let mut buf = id.to_le_bytes().to_vec();
+        buf.extend(compressor.compress(payload)?);
+        Ok(buf)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Dictionaries {
+    /// Loads the dictionaries saved in `dir`
+    fn open(dir: &Path, compression: Compression) -> Result<Self> {
+        let level = match compression {
+            Compression::Zstd(level) => Some(level),
+            _ => None,
+        };
+        let mut by_id = BTreeMap::new();
+        for id in sorted_dictionary_ids(dir)? {
+            let dictionary = fs::read(dictionary_path(dir, id))?;
+            by_id.insert(
+                id,
+                Arc::new(Dictionary::new(&dictionary, level.unwrap_or(0))),
+            );
+        }
+        Ok(Dictionaries {
+            level,
+            by_id: RwLock::new(by_id),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_id.read().unwrap().is_empty()
+    }
+
+    /// The dictionary new records are compressed with
+    fn newest(&self) -> Option<(u32, Arc<Dictionary>)> {
+        let by_id = self.by_id.read().unwrap();
+        by_id
+            .last_key_value()
+            .map(|(id, dictionary)| (*id, dictionary.clone()))
+    }
+
+    /// Decompresses a `CODEC_ZSTD_DICT` payload into `len` bytes
+    fn decompress(&self, compressed: &[u8], len: usize) -> Result<Vec<u8>> {
+        let Some((id, compressed)) = compressed.split_first_chunk::<4>() else {
+            return Err(KvsError::InvalidCommand);
+        };
+        let id = u32::from_le_bytes(*id);
+        let dictionary = self.by_id.read().unwrap().get(&id).cloned();
+        let Some(dictionary) = dictionary else {
+            return Err(KvsError::Message(format!(
+                "record compressed with dictionary {}, which is missing",
+                id
+            )));
+        };
+        let mut decompressor =
+            zstd::bulk::Decompressor::with_prepared_dictionary(&dictionary.decoder)?;
+        decompressor
+            .decompress(compressed, len)
+            .map_err(|_| KvsError::InvalidCommand)
+    }
+
+    /// Trains a dictionary on `samples` and saves it to `dir` under the
+    /// next id, which it returns. Records are compressed with it from then
+    /// on.
+    fn train(&self, dir: &Path, samples: &[Vec<u8>]) -> Result<u32> {
+        let Some(level) = self.level else {
+            return Err(KvsError::Message(
+                "dictionaries are only used by stores compressing with zstd".into(),
+            ));
+        };
+        let dictionary = zstd::dict::from_samples(samples, DICT_MAX_SIZE).map_err(|e| {
+            KvsError::Message(format!(
+                "unable to train a dictionary on {} values: {}",
+                samples.len(),
+                e
+            ))
+        })?;
+        let mut by_id = self.by_id.write().unwrap();
+        let id = by_id.last_key_value().map_or(1, |(id, _)| id + 1);
+        // write aside and rename so a crash never leaves half a dictionary
+        let path = dictionary_path(dir, id);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&dictionary)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        by_id.insert(id, Arc::new(Dictionary::new(&dictionary, level)));
+        Ok(id)
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+impl Dictionaries {
+    fn open(_dir: &Path, _compression: Compression) -> Result<Self> {
+        Ok(Dictionaries {})
+    }
+
+    fn is_empty(&self) -> bool {
+        true
+    }
+
+    fn train(&self, _dir: &Path, _samples: &[Vec<u8>]) -> Result<u32> {
+        Err(KvsError::Message(
+            "dictionaries need zstd, which this build does not support".into(),
+        ))
+    }
+}
+
+/// Decodes a record payload written in `format`, compressed ones with
+/// `dictionaries` at hand
+fn decode_command(
+    format: LogFormat,
+    payload: &[u8],
+    dictionaries: &Dictionaries,
+) -> Result<Command> {
+    if !format.is_binary() {
+        return Ok(serde_json::from_slice(payload)?);
+    }
+    if payload.first() == Some(&RECORD_COMPRESSED) {
+        let payload = decompress(payload.to_vec(), dictionaries)?;
+        return decode_command(format, &payload, dictionaries);
+    }
+
+    fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if rest.len() < n {
+            return Err(KvsError::InvalidCommand);
+        }
+        let (head, tail) = rest.split_at(n);
+        *rest = tail;
+        Ok(head)
+    }
+    fn take_bytes(rest: &mut &[u8]) -> Result<Vec<u8>> {
+        let len = u32::from_le_bytes(take(rest, 4)?.try_into().unwrap()) as usize;
+        Ok(take(rest, len)?.to_vec())
+    }
+    fn take_str(rest: &mut &[u8]) -> Result<String> {
+        String::from_utf8(take_bytes(rest)?).map_err(|_| KvsError::InvalidCommand)
+    }
+    let mut rest = payload;
+    let cmd = match take(&mut rest, 1)?[0] {
+        RECORD_SET => {
+            let key = take_str(&mut rest)?;
+            let value = take_bytes(&mut rest)?;
+            let expires_at = match take(&mut rest, 1)?[0] {
+                0 => None,
+                _ => Some(u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap())),
+            };
+            let content_type = match rest.first() {
+                None => ContentType::Text,
+                Some(&CONTENT_JSON) => {
+                    rest = &rest[1..];
+                    ContentType::Json
+                }
+                Some(_) => return Err(KvsError::InvalidCommand),
+            };
+            match Command::set_from_bytes(key, value, expires_at, content_type) {
+                Command::SetBytes { .. } if !content_type.is_text() => {
+                    return Err(KvsError::InvalidCommand)
+                }
+                cmd => cmd,
+            }
+        }
+        RECORD_RM => Command::Rm {
+            key: take_str(&mut rest)?,
+        },
+        RECORD_MERGE => Command::Merge {
+            key: take_str(&mut rest)?,
+            operand: take_str(&mut rest)?,
+        },
+        _ => return Err(KvsError::InvalidCommand),
+    };
+    if !rest.is_empty() {
+        return Err(KvsError::InvalidCommand);
+    }
+    Ok(cmd)
+}
+
+/// Indexes every record of a log file, returning the number of bytes
+/// compaction could reclaim from it. A framed log that ends in a record that
+/// is torn or fails its checksum returns `KvsError::Corruption` pointing at
+/// that record, with every record before it already indexed.
 fn load(
+    dir: &Path,
     walfile_num: u64,
     reader: &mut BufReaderWithPos<File>,
-    index: &DashMap<String, CommandPos>,
+    index: &Index,
+    dictionaries: &Dictionaries,
 ) -> Result<u64> {
-    let mut pos = reader.seek(io::SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let format = read_log_format(reader)?;
     let mut uncompacted_size = 0;
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(
-                    key,
-                    CommandPos {
-                        walfile_num,
-                        pos,
-                        len: new_pos - pos,
-                    },
-                ) {
-                    uncompacted_size += old_cmd.len
+    let now = now_millis();
+    match format {
+        LogFormat::LegacyJson => {
+            let mut pos = reader.seek(io::SeekFrom::Start(0))?;
+            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                let new_pos = stream.byte_offset() as u64;
+                uncompacted_size +=
+                    index_command(cmd?, walfile_num, pos, new_pos - pos, index, now);
+                pos = new_pos;
+            }
+        }
+        LogFormat::FramedJson | LogFormat::Binary | LogFormat::Compressed => {
+            let file_len = reader.seek(io::SeekFrom::End(0))?;
+            let mut pos = reader.seek(io::SeekFrom::Start(LOG_HEADER_LEN.min(file_len)))?;
+            let corruption = |offset| KvsError::Corruption {
+                file: log_path(dir, walfile_num),
+                offset,
+            };
+            loop {
+                let mut header = [0; RECORD_HEADER_LEN as usize];
+                match read_full(reader, &mut header)? {
+                    0 => break,
+                    n if n < header.len() => return Err(corruption(pos)),
+                    _ => {}
+                }
+                let payload_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+                let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+                let len = RECORD_HEADER_LEN + payload_len;
+                if pos + len > file_len {
+                    return Err(corruption(pos));
                 }
+                let mut payload = vec![0; payload_len as usize];
+                reader.read_exact(&mut payload)?;
+                if crc32fast::hash(&payload) != crc {
+                    return Err(corruption(pos));
+                }
+                let cmd = match decode_command(format, &payload, dictionaries) {
+                    Ok(cmd) => cmd,
+                    Err(e @ KvsError::Message(_)) => return Err(e),
+                    Err(_) => return Err(corruption(pos)),
+                };
+                uncompacted_size += index_command(cmd, walfile_num, pos, len, index, now);
+                pos += len;
             }
-            Command::Rm { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted_size += old_cmd.1.len;
-                } else {
-                    uncompacted_size += new_pos - pos;
+        }
+    }
+    Ok(uncompacted_size)
+}
+
+/// Applies a command read back from the log to the index, returning the
+/// number of bytes it made reclaimable
+fn index_command(
+    cmd: Command,
+    walfile_num: u64,
+    pos: u64,
+    len: u64,
+    index: &Index,
+    now: u64,
+) -> u64 {
+    match cmd {
+        Command::Set {
+            key, expires_at, ..
+        }
+        | Command::SetBytes {
+            key, expires_at, ..
+        } => {
+            let cmd_pos = CommandPos {
+                walfile_num,
+                pos,
+                len,
+                expires_at,
+                operands: Vec::new(),
+            };
+            if cmd_pos.is_expired(now) {
+                let mut reclaimable = cmd_pos.len;
+                if let Some((_, old_cmd)) = index.remove(&key) {
+                    reclaimable += old_cmd.total_len();
                 }
+                reclaimable
+            } else {
+                index
+                    .insert(key, cmd_pos)
+                    .map_or(0, |old_cmd| old_cmd.total_len())
             }
-            _ => {}
         }
-        pos = new_pos;
+        Command::Rm { key } => match index.remove(&key) {
+            Some((_, old_cmd)) => old_cmd.total_len(),
+            None => len,
+        },
+        Command::Merge { key, .. } => index.merge(
+            key,
+            RecordPos {
+                walfile_num,
+                pos,
+                len,
+            },
+            now,
+        ),
+        _ => 0,
     }
-    Ok(uncompacted_size)
 }
 
 fn sorted_walfile_nums(path: &Path) -> Result<Vec<u64>> {
@@ -184,6 +2362,197 @@ fn log_path(dir: &Path, walfile_num: u64) -> PathBuf {
     dir.join(format!("wal_{}.log", walfile_num))
 }
 
+fn dictionary_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("dict_{}.zdict", id))
+}
+
+/// Ids of the dictionaries saved in `dir`, see `Dictionaries`
+fn sorted_dictionary_ids(dir: &Path) -> Result<Vec<u32>> {
+    let mut ids: Vec<u32> = fs::read_dir(dir)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("zdict".as_ref()))
+        .filter_map(|path| {
+            path.file_stem()?
+                .to_str()?
+                .strip_prefix("dict_")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Writes every index entry to `WARM_INDEX` along with the length of each
+/// log file, so a later open can tell whether the logs changed since. Must
+/// be called with the writer locked.
+///
+/// Layout, integers little endian: magic, u64 file count, (u64 walfile_num,
+/// u64 len) per file, u64 entry count, (u32 key len, key, u64 walfile_num,
+/// u64 pos, u64 len, u64 expires_at or u64::MAX, u32 operand count,
+/// (u64 walfile_num, u64 pos, u64 len) per operand) per entry, u32 CRC32 of
+/// everything before it.
+fn save_index(dir: &Path, reader: &KvStoreReader, index: &Index) -> Result<()> {
+    let mut walfile_nums: Vec<u64> = reader.logs.files.iter().map(|pair| *pair.key()).collect();
+    walfile_nums.sort_unstable();
+
+    let mut buf = WARM_INDEX_MAGIC.to_vec();
+    buf.extend_from_slice(&(walfile_nums.len() as u64).to_le_bytes());
+    for walfile_num in walfile_nums {
+        let len = fs::metadata(log_path(dir, walfile_num))?.len();
+        buf.extend_from_slice(&walfile_num.to_le_bytes());
+        buf.extend_from_slice(&len.to_le_bytes());
+    }
+    buf.extend_from_slice(&(index.len() as u64).to_le_bytes());
+    for entry in index.iter() {
+        buf.extend_from_slice(&(entry.key().len() as u32).to_le_bytes());
+        buf.extend_from_slice(entry.key().as_bytes());
+        buf.extend_from_slice(&entry.walfile_num.to_le_bytes());
+        buf.extend_from_slice(&entry.pos.to_le_bytes());
+        buf.extend_from_slice(&entry.len.to_le_bytes());
+        buf.extend_from_slice(&entry.expires_at.unwrap_or(u64::MAX).to_le_bytes());
+        buf.extend_from_slice(&(entry.operands.len() as u32).to_le_bytes());
+        for operand in &entry.operands {
+            buf.extend_from_slice(&operand.walfile_num.to_le_bytes());
+            buf.extend_from_slice(&operand.pos.to_le_bytes());
+            buf.extend_from_slice(&operand.len.to_le_bytes());
+        }
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    // write aside and rename so a crash never leaves a half written index
+    let tmp_path = dir.join(format!("{}.tmp", WARM_INDEX));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(WARM_INDEX))?;
+    Ok(())
+}
+
+/// Fills `index` from a file written by `save_index`. Returns `false`
+/// without touching `index` if the log files are not exactly the ones the
+/// index was saved against.
+fn attach_index(file: &Path, dir: &Path, walfile_nums: &[u64], index: &Index) -> Result<bool> {
+    let file = File::open(file)?;
+    // the mapping is private to this process and dropped before we return
+    let map = unsafe { Mmap::map(&file)? };
+    let invalid = || KvsError::Message("invalid saved index".into());
+    if map.len() < WARM_INDEX_MAGIC.len() + 4 || !map.starts_with(WARM_INDEX_MAGIC) {
+        return Err(invalid());
+    }
+    let (body, crc) = map.split_at(map.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(invalid());
+    }
+
+    fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if rest.len() < n {
+            return Err(KvsError::Message("invalid saved index".into()));
+        }
+        let (head, tail) = rest.split_at(n);
+        *rest = tail;
+        Ok(head)
+    }
+    fn take_u64(rest: &mut &[u8]) -> Result<u64> {
+        Ok(u64::from_le_bytes(take(rest, 8)?.try_into().unwrap()))
+    }
+    let mut rest = &body[WARM_INDEX_MAGIC.len()..];
+
+    let file_count = take_u64(&mut rest)? as usize;
+    if file_count != walfile_nums.len() {
+        return Ok(false);
+    }
+    for &walfile_num in walfile_nums {
+        let (saved_num, saved_len) = (take_u64(&mut rest)?, take_u64(&mut rest)?);
+        let len = fs::metadata(log_path(dir, walfile_num))?.len();
+        if saved_num != walfile_num || saved_len != len {
+            return Ok(false);
+        }
+    }
+
+    let entry_count = take_u64(&mut rest)?;
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        let key_len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+        let key = String::from_utf8(take(&mut rest, key_len)?.to_vec()).map_err(|_| invalid())?;
+        let walfile_num = take_u64(&mut rest)?;
+        let pos = take_u64(&mut rest)?;
+        let len = take_u64(&mut rest)?;
+        let expires_at = Some(take_u64(&mut rest)?).filter(|&at| at != u64::MAX);
+        let operand_count = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap());
+        let mut operands = Vec::new();
+        for _ in 0..operand_count {
+            operands.push(RecordPos {
+                walfile_num: take_u64(&mut rest)?,
+                pos: take_u64(&mut rest)?,
+                len: take_u64(&mut rest)?,
+            });
+        }
+        entries.push((
+            key,
+            CommandPos {
+                walfile_num,
+                pos,
+                len,
+                expires_at,
+                operands,
+            },
+        ));
+    }
+    for (key, cmd_pos) in entries {
+        index.insert(key, cmd_pos);
+    }
+    Ok(true)
+}
+
+thread_local! {
+    /// When the engine operation running on this thread runs out of time,
+    /// see `KvStoreOptions::io_timeout`
+    static IO_DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Sets the I/O deadline of this thread for as long as it lives. An
+/// operation started inside another keeps the outer, earlier deadline.
+struct IoDeadline {
+    previous: Option<Instant>,
+}
+
+impl IoDeadline {
+    fn start(timeout: Option<Duration>) -> Self {
+        let previous = IO_DEADLINE.with(Cell::get);
+        if previous.is_none() {
+            IO_DEADLINE.with(|deadline| deadline.set(timeout.map(|t| Instant::now() + t)));
+        }
+        IoDeadline { previous }
+    }
+}
+
+impl Drop for IoDeadline {
+    fn drop(&mut self) {
+        IO_DEADLINE.with(|deadline| deadline.set(self.previous));
+    }
+}
+
+/// Fails with `ErrorKind::TimedOut` once this thread's deadline has passed
+fn check_deadline() -> io::Result<()> {
+    match IO_DEADLINE.with(Cell::get) {
+        Some(deadline) if Instant::now() >= deadline => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "engine I/O deadline passed",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Turns the error of a missed deadline into `KvsError::Timeout`
+fn timed_out(e: KvsError) -> KvsError {
+    match e {
+        KvsError::Io(e) if e.kind() == io::ErrorKind::TimedOut => KvsError::Timeout,
+        e => e,
+    }
+}
+
 #[derive(Debug)]
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
@@ -191,10 +2560,10 @@ struct BufReaderWithPos<R: Read + Seek> {
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
+    fn with_capacity(capacity: usize, mut inner: R) -> Result<Self> {
         let pos = inner.seek(io::SeekFrom::Current(0))?;
         Ok(BufReaderWithPos {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos,
         })
     }
@@ -202,6 +2571,7 @@ impl<R: Read + Seek> BufReaderWithPos<R> {
 
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check_deadline()?;
         let len = self.reader.read(buf)?;
         self.pos += len as u64;
         Ok(len)
@@ -251,173 +2621,1032 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     }
 }
 
-struct KvStoreReader {
+/// A log file the store reads from
+struct LogFile {
+    format: LogFormat,
+    /// Map of a log that is no longer written to, see
+    /// `KvStoreOptions::mmap_reads`
+    map: Option<Mmap>,
+}
+
+impl LogFile {
+    fn new(reader: &mut BufReaderWithPos<File>, sealed: bool, mmap_reads: bool) -> Result<Self> {
+        let format = read_log_format(reader)?;
+        // sealed logs never change again, and are only removed once
+        // compaction moved everything out of them
+        let map = if sealed && mmap_reads {
+            Some(unsafe { Mmap::map(reader.reader.get_ref())? })
+        } else {
+            None
+        };
+        Ok(LogFile { format, map })
+    }
+}
+
+/// The log files of a store, shared by every `KvStoreReader` on it
+struct LogFiles {
     path: PathBuf,
-    readers: DashMap<u64, BufReaderWithPos<File>>,
+    files: DashMap<u64, LogFile>,
+    /// Logs numbered below this were removed by compaction, readers close
+    /// their handles on them
+    safe_point: AtomicU64,
+    read_buffer_size: usize,
+    mmap_reads: bool,
+    merge_operator: Option<MergeFn>,
+    /// How records written to the logs are compressed
+    compression: Compression,
+    dictionaries: Dictionaries,
+}
+
+/// Reads the value of a set record straight from its log, hashing what it
+/// reads so the record's checksum is checked once the value is done. A
+/// record that fails it quarantines its key if the key still points at it.
+struct RecordValueReader {
+    value: io::Take<BufReader<File>>,
+    len: u64,
+    content_type: ContentType,
+    hasher: crc32fast::Hasher,
+    crc: u32,
+    /// The bytes after the value, hashed once it is read
+    trailer: Vec<u8>,
+    file: PathBuf,
+    record: RecordPos,
+    key: String,
+    index: Arc<Index>,
+}
+
+impl RecordValueReader {
+    /// Opens `file` at the value of the set record at `record`, reading the
+    /// bytes around the value to find it and its content type
+    fn open(file: PathBuf, record: RecordPos, key: &str, index: &Arc<Index>) -> Result<Self> {
+        let corruption = || KvsError::Corruption {
+            file: file.clone(),
+            offset: record.pos,
+        };
+        let mut reader = BufReader::new(File::open(&file)?);
+        reader.seek(io::SeekFrom::Start(record.pos))?;
+        let mut header = [0; RECORD_HEADER_LEN as usize + 5];
+        reader.read_exact(&mut header)?;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let key_len = u32::from_le_bytes(header[9..].try_into().unwrap()) as u64;
+        if header[8] != RECORD_SET || record.len < header.len() as u64 + key_len + 4 {
+            return Err(corruption());
+        }
+        let mut prefix = header[8..].to_vec();
+        prefix.resize(prefix.len() + key_len as usize + 4, 0);
+        reader.read_exact(&mut prefix[5..])?;
+        let len = u32::from_le_bytes(prefix[prefix.len() - 4..].try_into().unwrap()) as u64;
+        let value_pos = record.pos + RECORD_HEADER_LEN + prefix.len() as u64;
+        // the expiry flag, its time if set, and the content type if json
+        let trailer_len = (record.pos + record.len)
+            .checked_sub(value_pos + len)
+            .ok_or_else(corruption)?;
+        if !matches!(trailer_len, 1 | 2 | 9 | 10) {
+            return Err(corruption());
+        }
+        let mut trailer = vec![0; trailer_len as usize];
+        reader.seek(io::SeekFrom::Start(value_pos + len))?;
+        reader.read_exact(&mut trailer)?;
+        let content_type = match trailer_len {
+            2 | 10 => ContentType::Json,
+            _ => ContentType::Text,
+        };
+        reader.seek(io::SeekFrom::Start(value_pos))?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&prefix);
+        Ok(RecordValueReader {
+            value: reader.take(len),
+            len,
+            content_type,
+            hasher,
+            crc,
+            trailer,
+            file,
+            record,
+            key: key.to_owned(),
+            index: Arc::clone(index),
+        })
+    }
+}
+
+impl Read for RecordValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.value.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        // the last bytes are only handed out once the checksum matched, the
+        // trailer is taken so it is hashed once
+        if self.value.limit() == 0 && !self.trailer.is_empty() {
+            self.hasher.update(&mem::take(&mut self.trailer));
+            if self.hasher.clone().finalize() != self.crc {
+                if let Some(entry) = self.index.get(&self.key) {
+                    if entry.walfile_num == self.record.walfile_num && entry.pos == self.record.pos
+                    {
+                        self.index
+                            .quarantine(&self.key, self.file.clone(), self.record.pos);
+                    }
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt record at offset {}", self.record.pos),
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Reads values out of the log files through handles of its own. A clone
+/// shares the logs but opens new handles as it needs them, so clones don't
+/// contend on file positions.
+struct KvStoreReader {
+    logs: Arc<LogFiles>,
+    handles: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        Self::new(Arc::clone(&self.logs))
+    }
+}
+
+fn no_merge_operator() -> KvsError {
+    KvsError::Message("no merge operator registered".into())
 }
 
 impl KvStoreReader {
-    fn get(&self, cmd_pos: &CommandPos) -> Result<Option<String>> {
-        let reader = self.readers.get_mut(&cmd_pos.walfile_num);
-        if reader.is_none() {
-            return Err(KvsError::Message("KvStoreReader: Reader not found".into()));
+    /// A reader without any handles open yet
+    fn new(logs: Arc<LogFiles>) -> Self {
+        Self {
+            logs,
+            handles: RefCell::new(BTreeMap::new()),
         }
-        let mut reader = reader.unwrap();
-        reader.seek(io::SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.by_ref().take(cmd_pos.len);
-        if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-            return Ok(Some(value));
+    }
+
+    /// Reads the value of `key` stored at `cmd_pos` and its content type,
+    /// applying its merge operands if it has any. Merged values keep the
+    /// content type of the value they were merged into.
+    fn get(&self, key: &str, cmd_pos: &CommandPos) -> Result<Option<TypedValue>> {
+        let (format, payload) = self.read_payload(cmd_pos.record())?;
+        let mut operands = Vec::with_capacity(cmd_pos.operands.len() + 1);
+        let (base, content_type) = match decode_command(format, &payload, &self.logs.dictionaries)?
+        {
+            Command::Set {
+                value,
+                content_type,
+                ..
+            } => (Some(value.into_bytes()), content_type),
+            Command::SetBytes { value, .. } => (Some(value), ContentType::Text),
+            Command::Merge { operand, .. } => {
+                operands.push(operand);
+                (None, ContentType::Text)
+            }
+            _ => return Err(KvsError::InvalidCommand),
+        };
+        for record in &cmd_pos.operands {
+            let (format, payload) = self.read_payload(*record)?;
+            match decode_command(format, &payload, &self.logs.dictionaries)? {
+                Command::Merge { operand, .. } => operands.push(operand),
+                _ => return Err(KvsError::InvalidCommand),
+            }
         }
-        return Err(KvsError::InvalidCommand);
+        if operands.is_empty() {
+            return Ok(base.map(|value| (value, content_type)));
+        }
+        let merge = self
+            .logs
+            .merge_operator
+            .as_ref()
+            .ok_or_else(no_merge_operator)?;
+        // merge operators work on text, other bytes are replaced
+        let base = base.as_deref().map(String::from_utf8_lossy);
+        let merged = merge(key, base.as_deref(), &operands);
+        Ok(Some((merged.into_bytes(), content_type)))
+    }
+
+    /// Reads the encoded command of the record at `cmd_pos`, checking its
+    /// checksum if the log has one
+    fn read_payload(&self, cmd_pos: RecordPos) -> Result<(LogFormat, Vec<u8>)> {
+        let corruption = || KvsError::Corruption {
+            file: log_path(&self.logs.path, cmd_pos.walfile_num),
+            offset: cmd_pos.pos,
+        };
+        let format = self
+            .logs
+            .files
+            .get(&cmd_pos.walfile_num)
+            .ok_or_else(|| KvsError::Message("KvStoreReader: Reader not found".into()))?
+            .format;
+        let mut record = match self.read_mapped(cmd_pos) {
+            Some(mapped) => mapped.ok_or_else(corruption)?,
+            None => self.read_buffered(cmd_pos)?,
+        };
+        if format == LogFormat::LegacyJson {
+            return Ok((format, record));
+        }
+
+        let payload = record.split_off(RECORD_HEADER_LEN as usize);
+        let crc = u32::from_le_bytes(record[4..].try_into().unwrap());
+        if crc32fast::hash(&payload) != crc {
+            return Err(corruption());
+        }
+        Ok((format, payload))
+    }
+
+    /// Copies the record at `cmd_pos` out of the map of its log, `None` if
+    /// the log is not mapped, `Some(None)` if the record is past its end
+    fn read_mapped(&self, cmd_pos: RecordPos) -> Option<Option<Vec<u8>>> {
+        let log = self.logs.files.get(&cmd_pos.walfile_num)?;
+        let map = log.map.as_ref()?;
+        let record = map.get(cmd_pos.pos as usize..(cmd_pos.pos + cmd_pos.len) as usize);
+        Some(record.map(<[u8]>::to_vec))
+    }
+
+    /// Reads the record at `cmd_pos` through this reader's handle on its
+    /// log, opening one if it has none yet
+    fn read_buffered(&self, cmd_pos: RecordPos) -> Result<Vec<u8>> {
+        let mut handles = self.handles.borrow_mut();
+        // handles on logs compaction removed would keep their disk space
+        let safe_point = self.logs.safe_point.load(Ordering::Acquire);
+        while handles
+            .first_key_value()
+            .is_some_and(|(&walfile_num, _)| walfile_num < safe_point)
+        {
+            handles.pop_first();
+        }
+        let reader = match handles.entry(cmd_pos.walfile_num) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => entry.insert(BufReaderWithPos::with_capacity(
+                self.logs.read_buffer_size,
+                File::open(log_path(&self.logs.path, cmd_pos.walfile_num))?,
+            )?),
+        };
+        reader.seek(io::SeekFrom::Start(cmd_pos.pos))?;
+        let mut record = vec![0; cmd_pos.len as usize];
+        reader.read_exact(&mut record)?;
+        Ok(record)
     }
 
+    /// Opens every log file, indexing their records if `replay` is set
     fn from_walfiles(
         path: &Path,
         walfile_nums: Vec<u64>,
-        index: &DashMap<String, CommandPos>,
+        index: &Index,
+        replay: bool,
+        options: &KvStoreOptions,
     ) -> Result<Self> {
-        let readers = DashMap::new();
+        let files = DashMap::new();
+        let mut handles = BTreeMap::new();
+        let newest = walfile_nums.last().copied();
+        let dictionaries = Dictionaries::open(path, options.compression)?;
         for walfile_num in walfile_nums {
-            let mut reader =
-                BufReaderWithPos::new(File::open(log_path(path, walfile_num)).unwrap())?;
-            load(walfile_num, &mut reader, index)?;
-            readers.insert(walfile_num, reader);
+            let file_path = log_path(path, walfile_num);
+            let mut reader = BufReaderWithPos::with_capacity(
+                options.read_buffer_size,
+                File::open(&file_path).unwrap(),
+            )?;
+            if replay {
+                match load(path, walfile_num, &mut reader, index, &dictionaries) {
+                    Ok(_) => {}
+                    // a torn or garbled tail of the newest log is what a
+                    // crash in the middle of a write leaves behind,
+                    // everything before it was indexed so drop the tail and
+                    // carry on
+                    Err(KvsError::Corruption { offset, .. }) if Some(walfile_num) == newest => {
+                        warn!(
+                            "truncating {} at offset {} after an incomplete record",
+                            file_path.display(),
+                            offset
+                        );
+                        OpenOptions::new()
+                            .write(true)
+                            .open(&file_path)?
+                            .set_len(offset)?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            // a new log is started on open, these are never written again
+            files.insert(
+                walfile_num,
+                LogFile::new(&mut reader, true, options.mmap_reads)?,
+            );
+            handles.insert(walfile_num, reader);
         }
         Ok(Self {
-            path: path.into(),
-            readers,
+            logs: Arc::new(LogFiles {
+                path: path.into(),
+                files,
+                safe_point: AtomicU64::new(0),
+                read_buffer_size: options.read_buffer_size,
+                mmap_reads: options.mmap_reads,
+                merge_operator: options.merge_operator.clone(),
+                compression: options.compression,
+                dictionaries,
+            }),
+            handles: RefCell::new(handles),
         })
     }
 
-    fn add_reader(&self, walfile_num: u64) -> Result<()> {
-        if self.readers.contains_key(&walfile_num) {
+    /// Opens the log `walfile_num`, `sealed` if nothing will be appended to
+    /// it anymore
+    fn add_reader(&self, walfile_num: u64, sealed: bool) -> Result<()> {
+        if self.logs.files.contains_key(&walfile_num) {
             return Err(KvsError::Message(
                 "KvStoreReader: Reader already exists".into(),
             ));
         }
-        self.readers.insert(
+        let mut reader = BufReaderWithPos::with_capacity(
+            self.logs.read_buffer_size,
+            File::open(log_path(&self.logs.path, walfile_num))?,
+        )?;
+        self.logs.files.insert(
             walfile_num,
-            BufReaderWithPos::new(File::open(log_path(&self.path, walfile_num)).unwrap()).unwrap(),
+            LogFile::new(&mut reader, sealed, self.logs.mmap_reads)?,
         );
+        self.handles.borrow_mut().insert(walfile_num, reader);
+        Ok(())
+    }
+
+    /// Marks a log that is no longer written to as sealed, mapping it when
+    /// `KvStoreOptions::mmap_reads` is on
+    fn seal(&self, walfile_num: u64) -> Result<()> {
+        if !self.logs.mmap_reads {
+            return Ok(());
+        }
+        let file = File::open(log_path(&self.logs.path, walfile_num))?;
+        if let Some(mut log) = self.logs.files.get_mut(&walfile_num) {
+            log.map = Some(unsafe { Mmap::map(&file)? });
+        }
         Ok(())
     }
 
+    /// Removes the logs numbered below `compaction_walfile_num`, other
+    /// readers close their handles on them at their next read
     fn close_stale_handles(&self, compaction_walfile_num: u64) -> Result<()> {
-        let keys: Vec<u64> = self.readers.iter().map(|pair| *pair.key()).collect();
-        let stale_files: Vec<_> = keys
+        let stale_files: Vec<u64> = self
+            .logs
+            .files
             .iter()
-            .filter(|x| **x < compaction_walfile_num)
-            .cloned()
+            .map(|pair| *pair.key())
+            .filter(|walfile_num| *walfile_num < compaction_walfile_num)
             .collect();
+        self.logs
+            .safe_point
+            .store(compaction_walfile_num, Ordering::Release);
         for stale_walfile_num in &stale_files {
-            let path = log_path(&self.path, *stale_walfile_num);
-            fs::remove_file(&path)?;
-            self.readers.remove(&stale_walfile_num);
+            self.handles.borrow_mut().remove(stale_walfile_num);
+            self.logs.files.remove(stale_walfile_num);
+            fs::remove_file(log_path(&self.logs.path, *stale_walfile_num))?;
         }
         Ok(())
     }
 }
 
 struct KvStoreWriter {
-    reader: Arc<KvStoreReader>,
+    reader: KvStoreReader,
     writer: BufWriterWithPos<File>,
     active_wal: u64,
     // number of bytes that can be saved by compaction
     uncompacted: u64,
     path: Arc<PathBuf>,
-    index: Arc<DashMap<String, CommandPos>>,
+    index: Arc<Index>,
+    durability: Durability,
+    compaction_threads: usize,
+    verify_compaction: bool,
+    counters: Counters,
+    // what `COUNTERS_FILE` holds
+    saved_counters: Counters,
+    // records written to the active log
+    active_records: u64,
+    max_log_size: u64,
+    max_log_records: u64,
+    max_key_size: usize,
+    max_value_size: usize,
+    // writes committed, what `GroupSync` counts
+    commits: u64,
+    group_sync: Option<Arc<GroupSync>>,
+    // receivers of `KvsEngine::subscribe` and the prefix they watch
+    subscribers: Vec<(String, Sender<ChangeEvent>)>,
 }
 
 impl KvStoreWriter {
     fn new(
         path: &Path,
         active_wal: u64,
-        reader: Arc<KvStoreReader>,
-        index: Arc<DashMap<String, CommandPos>>,
+        reader: KvStoreReader,
+        index: Arc<Index>,
+        options: &KvStoreOptions,
     ) -> Result<Self> {
+        let counters = load_counters(path);
         Ok(Self {
             reader,
-            writer: new_log_file(path, active_wal)?,
+            writer: new_log_file(path, active_wal, options.compression)?,
             active_wal,
             uncompacted: 0,
             path: Arc::new(path.into()),
             index,
+            durability: options.durability,
+            compaction_threads: options.compaction_threads,
+            verify_compaction: options.verify_compaction,
+            counters,
+            saved_counters: counters,
+            active_records: 0,
+            max_log_size: options.max_log_size,
+            max_log_records: options.max_log_records,
+            max_key_size: options.max_key_size,
+            max_value_size: options.max_value_size,
+            commits: 0,
+            group_sync: None,
+            subscribers: Vec::new(),
         })
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set {
-            key: key.clone(),
-            value,
-        };
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+    /// Seals the active log and starts the next one once the active log
+    /// reached `KvStoreOptions::max_log_size` or `max_log_records`. Called
+    /// before writing, so the records of one write stay in one log.
+    fn rotate_if_full(&mut self) -> Result<()> {
+        if self.writer.pos < self.max_log_size && self.active_records < self.max_log_records {
+            return Ok(());
+        }
+        // `sync` only reaches the active log, the sealed one is synced here
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        let sealed = self.active_wal;
+        self.active_wal += 1;
+        self.writer = new_log_file(&self.path, self.active_wal, self.reader.logs.compression)?;
+        self.switched_log()?;
+        self.reader.add_reader(self.active_wal, false)?;
+        self.reader.seal(sealed)?;
+        self.active_records = 0;
+        Ok(())
+    }
+
+    /// Encodes `cmd` as the payload of its record, compressed with
+    /// `KvStoreOptions::compression`
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        let logs = &self.reader.logs;
+        compress(encode_command(cmd)?, logs.compression, &logs.dictionaries)
+    }
+
+    /// Fails a write of `key`, and of a value of `value_len` bytes if it
+    /// has one, larger than `KvStoreOptions::max_key_size` or
+    /// `max_value_size`
+    fn check_size(&self, key: &str, value_len: Option<usize>) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(KvsError::KeyTooLarge {
+                len: key.len(),
+                max: self.max_key_size,
+            });
+        }
+        match value_len {
+            Some(len) if len > self.max_value_size => Err(KvsError::ValueTooLarge {
+                len,
+                max: self.max_value_size,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Points the sync thread at the new active log, the old one was synced
+    fn switched_log(&mut self) -> Result<()> {
+        if let Some(group_sync) = &self.group_sync {
+            group_sync.switch(self.writer.writer.get_ref().try_clone()?);
+        }
+        Ok(())
+    }
+
+    /// Sends the change of `key` to the subscribers watching it, dropping
+    /// the ones whose receiver went away
+    fn publish(&mut self, key: &str, change: fn(String) -> ChangeEvent) {
+        self.subscribers.retain(|(prefix, sender)| {
+            !key.starts_with(prefix.as_str()) || sender.send(change(key.to_owned())).is_ok()
+        });
+    }
+
+    /// Saves the counters if they changed since they were last saved
+    fn save_counters(&mut self) -> Result<()> {
+        if self.counters != self.saved_counters {
+            save_counters(&self.path, &self.counters)?;
+            self.saved_counters = self.counters;
+        }
+        Ok(())
+    }
+
+    /// Pushes the records written so far as far as `durability` asks for
+    fn commit(&mut self) -> Result<()> {
         self.writer.flush()?;
+        match self.durability {
+            Durability::Flush => {}
+            Durability::Sync => self.writer.writer.get_ref().sync_data()?,
+            Durability::Group => {
+                self.commits += 1;
+                if let Some(group_sync) = &self.group_sync {
+                    group_sync.committed(self.commits);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        content_type: ContentType,
+    ) -> Result<()> {
+        self.check_size(&key, Some(value.len()))?;
+        let cmd = Command::set_from_bytes(key.clone(), value, expires_at, content_type);
+        self.rotate_if_full()?;
+        let pos = self.writer.pos;
+        let payload = self.encode(&cmd)?;
+        let len = write_record(&mut self.writer, &payload)?;
+        self.active_records += 1;
+        self.commit()?;
+        self.counters.sets += 1;
+        self.counters.bytes_written += len;
 
-        let new_pos = self.writer.pos;
         let cmd_pos = CommandPos {
             walfile_num: self.active_wal,
             pos,
-            len: new_pos - pos,
+            len,
+            expires_at,
+            operands: Vec::new(),
         };
-        if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
-            self.uncompacted += old_cmd.len;
+        if let Some(old_cmd) = self.index.insert(key.clone(), cmd_pos) {
+            self.uncompacted += old_cmd.total_len();
+        }
+        self.publish(&key, ChangeEvent::Set);
+        Ok(())
+    }
+
+    fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        if self.reader.logs.merge_operator.is_none() {
+            return Err(no_merge_operator());
         }
+        self.check_size(&key, Some(operand.len()))?;
+        let cmd = Command::Merge {
+            key: key.clone(),
+            operand,
+        };
+        self.rotate_if_full()?;
+        let pos = self.writer.pos;
+        let payload = self.encode(&cmd)?;
+        let len = write_record(&mut self.writer, &payload)?;
+        self.active_records += 1;
+        self.commit()?;
+        self.counters.bytes_written += len;
+
+        let operand = RecordPos {
+            walfile_num: self.active_wal,
+            pos,
+            len,
+        };
+        self.uncompacted += self.index.merge(key.clone(), operand, now_millis());
+        self.publish(&key, ChangeEvent::Set);
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let cmd = Command::Rm { key: key.clone() };
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        if let Some((_, cmd)) = self.index.remove(&key) {
-            self.uncompacted += cmd.len;
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.check_size(key, None)?;
+        let cmd = Command::Rm { key: key.into() };
+        self.rotate_if_full()?;
+        let payload = self.encode(&cmd)?;
+        let len = write_record(&mut self.writer, &payload)?;
+        self.active_records += 1;
+        self.commit()?;
+        self.counters.bytes_written += len;
+        if let Some((_, cmd)) = self.index.remove(key) {
+            self.uncompacted += cmd.total_len();
+            if cmd.is_expired(now_millis()) {
+                return Err(KvsError::KeyNotFound);
+            }
+            self.counters.removes += 1;
+            self.publish(key, ChangeEvent::Removed);
             return Ok(());
         } else {
             return Err(KvsError::KeyNotFound);
         }
     }
 
-    fn run_compaction(&mut self) -> Result<()> {
-        let active_wal = self.active_wal;
-        let compaction_walfile_num = active_wal + 1;
-        self.active_wal = active_wal + 2;
-        let mut compaction_writer = new_log_file(&self.path, compaction_walfile_num)?;
+    fn write_batch(&mut self, cmds: Vec<Command>) -> Result<()> {
+        // validate the whole batch before anything reaches the log
+        let now = now_millis();
+        let mut live: HashMap<&str, bool> = HashMap::new();
+        for cmd in &cmds {
+            match cmd {
+                Command::Set { key, value, .. } => {
+                    self.check_size(key, Some(value.len()))?;
+                    live.insert(key, true);
+                }
+                Command::SetBytes { key, value, .. } => {
+                    self.check_size(key, Some(value.len()))?;
+                    live.insert(key, true);
+                }
+                Command::Rm { key } => {
+                    self.check_size(key, None)?;
+                    let exists = live.get(key.as_str()).copied().unwrap_or_else(|| {
+                        self.index
+                            .get(key)
+                            .is_some_and(|cmd_pos| !cmd_pos.is_expired(now))
+                    });
+                    if !exists {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    live.insert(key, false);
+                }
+                _ => return Err(KvsError::InvalidCommand),
+            }
+        }
+
+        self.rotate_if_full()?;
+        let mut positions = Vec::with_capacity(cmds.len());
+        for cmd in &cmds {
+            let pos = self.writer.pos;
+            let payload = self.encode(cmd)?;
+            let len = write_record(&mut self.writer, &payload)?;
+            positions.push((pos, len));
+        }
+        self.active_records += positions.len() as u64;
+        self.commit()?;
+
+        for (cmd, (pos, len)) in cmds.into_iter().zip(positions) {
+            self.counters.bytes_written += len;
+            match cmd {
+                Command::Set {
+                    key, expires_at, ..
+                }
+                | Command::SetBytes {
+                    key, expires_at, ..
+                } => {
+                    let cmd_pos = CommandPos {
+                        walfile_num: self.active_wal,
+                        pos,
+                        len,
+                        expires_at,
+                        operands: Vec::new(),
+                    };
+                    if let Some(old_cmd) = self.index.insert(key.clone(), cmd_pos) {
+                        self.uncompacted += old_cmd.total_len();
+                    }
+                    self.counters.sets += 1;
+                    self.publish(&key, ChangeEvent::Set);
+                }
+                Command::Rm { key } => {
+                    if let Some((_, old_cmd)) = self.index.remove(&key) {
+                        self.uncompacted += old_cmd.total_len();
+                    }
+                    self.counters.removes += 1;
+                    self.publish(&key, ChangeEvent::Removed);
+                }
+                _ => unreachable!("batch was validated above"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies every live log file into `dest` along with a manifest. Holding
+    /// the writer also keeps compaction from running until the copy is done.
+    fn snapshot(&mut self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        if !sorted_walfile_nums(dest)?.is_empty() {
+            return Err(KvsError::Message(format!(
+                "snapshot destination {} already contains log files",
+                dest.display()
+            )));
+        }
+        self.writer.flush()?;
 
-        // new active wal file
-        self.writer = new_log_file(&self.path, self.active_wal)?;
-        self.reader.add_reader(self.active_wal)?;
+        let mut walfile_nums: Vec<u64> = self
+            .reader
+            .logs
+            .files
+            .iter()
+            .map(|pair| *pair.key())
+            .collect();
+        walfile_nums.sort_unstable();
+        let mut files = Vec::with_capacity(walfile_nums.len());
+        for walfile_num in walfile_nums {
+            let src = log_path(&self.path, walfile_num);
+            let dst = log_path(dest, walfile_num);
+            // sealed log files are never appended to again so a hard link is
+            // as good as a copy, the active one keeps growing after we return
+            if walfile_num == self.active_wal || fs::hard_link(&src, &dst).is_err() {
+                fs::copy(&src, &dst)?;
+            }
+            files.push(SnapshotFile {
+                name: format!("wal_{}.log", walfile_num),
+                len: fs::metadata(&dst)?.len(),
+            });
+        }
+        // the logs may hold records compressed with any of the dictionaries
+        for id in sorted_dictionary_ids(&self.path)? {
+            let dst = dictionary_path(dest, id);
+            fs::copy(dictionary_path(&self.path, id), &dst)?;
+            files.push(SnapshotFile {
+                name: format!("dict_{}.zdict", id),
+                len: fs::metadata(&dst)?.len(),
+            });
+        }
 
-        let mut pos: u64 = 0;
+        let manifest = SnapshotManifest {
+            version: env!("CARGO_PKG_VERSION").into(),
+            files,
+        };
+        let mut manifest_file = File::create(dest.join(SNAPSHOT_MANIFEST))?;
+        serde_json::to_writer_pretty(&mut manifest_file, &manifest)?;
+        manifest_file.sync_all()?;
+        Ok(())
+    }
 
-        for mut cmd_pos in self.index.iter_mut() {
-            if cmd_pos.walfile_num >= compaction_walfile_num {
-                continue;
+    /// Drops `keys` from the index if they are still expired and accounts
+    /// their records as reclaimable by compaction
+    fn drop_expired(&mut self, keys: Vec<String>) {
+        let now = now_millis();
+        for key in keys {
+            if let Some(cmd_pos) = self.index.remove_expired(&key, now) {
+                self.uncompacted += cmd_pos.total_len();
+                self.publish(&key, ChangeEvent::Expired);
             }
-            // println!("compacting walfile num: {}", cmd_pos.walfile_num);
-            let reader = self.reader.readers.get_mut(&cmd_pos.walfile_num);
-            if reader.is_none() {
-                panic!("reader not found for the command that was in the index?");
+        }
+    }
+
+    fn sweep_expired(&mut self) {
+        let now = now_millis();
+        let mut expired = 0;
+        let mut expired_keys = Vec::new();
+        self.index.retain(|key, cmd_pos| {
+            if cmd_pos.is_expired(now) {
+                expired += cmd_pos.total_len();
+                if !self.subscribers.is_empty() {
+                    expired_keys.push(key.clone());
+                }
+                return false;
             }
-            let mut reader = reader.unwrap();
-            reader
-                .seek(io::SeekFrom::Start(cmd_pos.pos))
-                .expect("unable to seek reader");
+            true
+        });
+        self.uncompacted += expired;
+        for key in expired_keys {
+            self.publish(&key, ChangeEvent::Expired);
+        }
+    }
 
-            let mut cmd_reader = reader.by_ref().take(cmd_pos.len);
-            let len = io::copy(&mut cmd_reader, &mut compaction_writer)?;
-            *cmd_pos.value_mut() = CommandPos {
-                walfile_num: compaction_walfile_num,
-                pos,
-                len,
-            };
-            pos += len;
+    /// Seals the active log and gathers the live records of every sealed
+    /// log for a compaction to copy, see `compact`
+    fn start_compaction(&mut self) -> Result<Compaction> {
+        self.sweep_expired();
+
+        // live records grouped by the log they are in, each group is copied
+        // by one worker so every log is read sequentially by one handle
+        let mut segments: BTreeMap<u64, Vec<(String, CommandPos)>> = BTreeMap::new();
+        for entry in self.index.iter() {
+            segments
+                .entry(entry.walfile_num)
+                .or_default()
+                .push((entry.key().clone(), entry.value().clone()));
         }
+        let outputs = partition_segments(segments, self.compaction_threads);
 
-        compaction_writer.flush().unwrap();
-        self.reader.add_reader(compaction_walfile_num)?;
-        self.reader.close_stale_handles(compaction_walfile_num)?;
+        // compaction outputs take the numbers between the current and the
+        // new active log so they sort before anything written from now on
+        let first_output = self.active_wal + 1;
+        if self.durability == Durability::Group {
+            // writes waiting on the sync thread are in the log left behind
+            self.writer.flush()?;
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        self.active_wal = first_output + outputs.len() as u64;
+        self.writer = new_log_file(&self.path, self.active_wal, self.reader.logs.compression)?;
+        self.switched_log()?;
+        self.reader.add_reader(self.active_wal, false)?;
+        self.active_records = 0;
+        // what is written from now on is counted against the next compaction
         self.uncompacted = 0;
 
+        Ok(Compaction {
+            first_output,
+            outputs,
+            verify: self.verify_compaction,
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+        })
+    }
+
+    /// Points the index at the copies of the records that were not written
+    /// again while they were copied, and removes the compacted logs
+    fn finish_compaction(&mut self, first_output: u64, compacted: Vec<Compacted>) -> Result<()> {
+        for (walfile_num, moved) in compacted {
+            self.reader.add_reader(walfile_num, true)?;
+            for (key, copied, cmd_pos) in moved {
+                match self.index.get_mut(&key) {
+                    Some(mut entry)
+                        if entry.walfile_num == copied.walfile_num && entry.pos == copied.pos =>
+                    {
+                        // operands merged since the copy apply on top of it
+                        let operands = entry.operands.split_off(copied.operands.len());
+                        *entry = CommandPos {
+                            operands,
+                            ..cmd_pos
+                        };
+                    }
+                    // set or removed meanwhile, the copy is already stale
+                    _ => self.uncompacted += cmd_pos.len,
+                }
+            }
+        }
+        self.index.drop_uncopied(first_output);
+        self.reader.close_stale_handles(first_output)?;
+        self.counters.compactions += 1;
         Ok(())
     }
 }
+
+/// Records of one compaction output: the output log and, per key, where
+/// its record was copied from and where it was copied to
+type Compacted = (u64, Vec<(String, CommandPos, CommandPos)>);
+
+/// The work of a compaction that runs without the writer, see `compact`
+struct Compaction {
+    first_output: u64,
+    outputs: Vec<Vec<(String, CommandPos)>>,
+    /// Read the copies back before they replace the records, see
+    /// `KvStoreOptions::verify_compaction`
+    verify: bool,
+    reader: KvStoreReader,
+    index: Arc<Index>,
+}
+
+impl Compaction {
+    /// Copies the records of each output into a new log in parallel
+    fn copy_records(self) -> Result<Vec<Compacted>> {
+        let logs = &self.reader.logs;
+        let first_output = self.first_output;
+        let index = &self.index;
+        let verify = self.verify;
+        self.outputs
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, records)| {
+                let reader = KvStoreReader::new(Arc::clone(logs));
+                let walfile_num = first_output + i as u64;
+                let mut writer = new_log_file(&logs.path, walfile_num, logs.compression)?;
+                let mut moved = Vec::with_capacity(records.len());
+                // checksums of the uncompressed payloads, for `verify_output`
+                let mut checksums = Vec::new();
+                for (key, cmd_pos) in records {
+                    let payload = match copy_payload(&reader, &key, &cmd_pos) {
+                        Ok(payload) => payload,
+                        // `finish_compaction` drops what is left of the key
+                        Err(KvsError::Corruption { file, offset }) => {
+                            if let Some(entry) = index.get(&key) {
+                                if entry.walfile_num == cmd_pos.walfile_num
+                                    && entry.pos == cmd_pos.pos
+                                {
+                                    index.quarantine(&key, file, offset);
+                                }
+                            }
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    let pos = writer.pos;
+                    if verify {
+                        checksums.push(crc32fast::hash(&payload));
+                    }
+                    let payload = compress(payload, logs.compression, &logs.dictionaries)?;
+                    let len = write_record(&mut writer, &payload)?;
+                    let expires_at = cmd_pos.expires_at;
+                    moved.push((
+                        key,
+                        cmd_pos,
+                        CommandPos {
+                            walfile_num,
+                            pos,
+                            len,
+                            expires_at,
+                            operands: Vec::new(),
+                        },
+                    ));
+                }
+                writer.flush()?;
+                if verify {
+                    writer.writer.get_ref().sync_data()?;
+                    verify_output(logs, walfile_num, &moved, &checksums)?;
+                }
+                Ok((walfile_num, moved))
+            })
+            .collect()
+    }
+}
+
+/// Reads back the records compaction wrote to log `walfile_num`, back to
+/// back in the order of `moved`, and checks each against its record
+/// checksum and against `checksums`, those of the payloads copied into it
+fn verify_output(
+    logs: &LogFiles,
+    walfile_num: u64,
+    moved: &[(String, CommandPos, CommandPos)],
+    checksums: &[u32],
+) -> Result<()> {
+    let file = log_path(&logs.path, walfile_num);
+    let mut reader = BufReader::new(File::open(&file)?);
+    reader.seek(io::SeekFrom::Start(LOG_HEADER_LEN))?;
+    for ((_, _, copy), checksum) in moved.iter().zip(checksums) {
+        let corruption = || KvsError::Corruption {
+            file: file.clone(),
+            offset: copy.pos,
+        };
+        let mut record = vec![0; copy.len as usize];
+        reader.read_exact(&mut record)?;
+        let payload = record.split_off(RECORD_HEADER_LEN as usize);
+        if crc32fast::hash(&payload) != u32::from_le_bytes(record[4..].try_into().unwrap()) {
+            return Err(corruption());
+        }
+        let payload = decompress(payload, &logs.dictionaries).map_err(|_| corruption())?;
+        if crc32fast::hash(&payload) != *checksum {
+            return Err(corruption());
+        }
+    }
+    Ok(())
+}
+
+/// The payload of the record compaction writes for `key`, uncompressed: the
+/// record at `cmd_pos` as it is if it is a plain set, otherwise the value
+/// with its merge operands resolved, in the current format
+fn copy_payload(reader: &KvStoreReader, key: &str, cmd_pos: &CommandPos) -> Result<Vec<u8>> {
+    let (format, payload) = reader.read_payload(cmd_pos.record())?;
+    let payload = match format.is_binary() {
+        true => decompress(payload, &reader.logs.dictionaries)?,
+        false => payload,
+    };
+    let plain_set =
+        format.is_binary() && payload.first() == Some(&RECORD_SET) && cmd_pos.operands.is_empty();
+    if plain_set {
+        return Ok(payload);
+    }
+    let (value, content_type) = reader.get(key, cmd_pos)?.ok_or(KvsError::InvalidCommand)?;
+    encode_command(&Command::set_from_bytes(
+        key.to_owned(),
+        value,
+        cmd_pos.expires_at,
+        content_type,
+    ))
+}
+
+/// Rewrites the live records of every sealed log into new logs and removes
+/// the old ones. The writer is only held to start and to finish, writes go
+/// on to a new active log while the records are copied. A compaction that
+/// fails removes the logs it wrote and leaves the old ones in place.
+fn compact(writer: &Mutex<KvStoreWriter>) -> Result<()> {
+    let compaction = writer.lock().unwrap().start_compaction()?;
+    let first_output = compaction.first_output;
+    let outputs = first_output..first_output + compaction.outputs.len() as u64;
+    let path = compaction.reader.logs.path.clone();
+    let compacted = match compaction.copy_records() {
+        Ok(compacted) => compacted,
+        Err(e) => {
+            // nothing points at the copies, a reopen must not replay them
+            for walfile_num in outputs {
+                let _ = fs::remove_file(log_path(&path, walfile_num));
+            }
+            return Err(e);
+        }
+    };
+    writer
+        .lock()
+        .unwrap()
+        .finish_compaction(first_output, compacted)
+}
+
+/// Spreads log segments over at most `outputs` groups of roughly equal size,
+/// biggest segments first
+fn partition_segments(
+    segments: BTreeMap<u64, Vec<(String, CommandPos)>>,
+    outputs: usize,
+) -> Vec<Vec<(String, CommandPos)>> {
+    let mut segments: Vec<_> = segments.into_values().collect();
+    segments.sort_by_key(|records| {
+        std::cmp::Reverse(
+            records
+                .iter()
+                .map(|(_, cmd_pos)| cmd_pos.total_len())
+                .sum::<u64>(),
+        )
+    });
+    let mut groups: Vec<(u64, Vec<(String, CommandPos)>)> = Vec::new();
+    for records in segments {
+        let size: u64 = records.iter().map(|(_, cmd_pos)| cmd_pos.total_len()).sum();
+        if groups.len() < outputs.max(1) {
+            groups.push((size, records));
+            continue;
+        }
+        let smallest = groups
+            .iter_mut()
+            .min_by_key(|(group_size, _)| *group_size)
+            .unwrap();
+        smallest.0 += size;
+        smallest.1.extend(records);
+    }
+    groups.into_iter().map(|(_, records)| records).collect()
+}
@@ -0,0 +1,630 @@
+use super::kvs::{expiry_after, now_millis};
+use super::{
+    BigKeys, ChangeEvent, ContentType, Cursor, DefaultTtls, EngineStats, ExpiryForecast, KeySize,
+    KvStore, KvsEngine, MergeFn, QuarantinedKey, ScanPage, TypedValue, ValueReader,
+};
+use crate::client::Command;
+use crate::{KvsError, Result};
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// A value as the map holds it
+struct Entry {
+    value: Vec<u8>,
+    content_type: ContentType,
+    /// Milliseconds since the unix epoch after which the entry reads as
+    /// missing
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Lifetime counters and subscribers, behind the lock every write takes
+#[derive(Default)]
+struct Writer {
+    sets: u64,
+    removes: u64,
+    subscribers: Vec<(String, Sender<ChangeEvent>)>,
+}
+
+impl Writer {
+    /// Sends the change of `key` to the subscribers watching it, dropping
+    /// the ones whose receiver went away
+    fn publish(&mut self, key: &str, change: fn(String) -> ChangeEvent) {
+        self.subscribers.retain(|(prefix, sender)| {
+            !key.starts_with(prefix.as_str()) || sender.send(change(key.to_owned())).is_ok()
+        });
+    }
+}
+
+/// One numbered database
+#[derive(Default)]
+struct Database {
+    entries: DashMap<String, Entry>,
+    /// Writes hold it while they change `entries`, which orders them for
+    /// subscribers and keeps batches and compare-and-swap atomic
+    writer: Mutex<Writer>,
+}
+
+/// A store that keeps its keys in memory only, for tests and for
+/// benchmarking the server apart from the disk. Keys are lost once the last
+/// clone is dropped. Expired keys read as missing and are dropped by the
+/// next write of the key, there is no background sweep.
+#[derive(Clone)]
+pub struct MemStore {
+    db: Arc<Database>,
+    /// Every database selected so far, shared by all of them
+    databases: Arc<Mutex<BTreeMap<usize, Arc<Database>>>>,
+    merge_operator: Option<MergeFn>,
+    default_ttls: Arc<DefaultTtls>,
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        let db = Arc::new(Database::default());
+        MemStore {
+            databases: Arc::new(Mutex::new(BTreeMap::from([(0, db.clone())]))),
+            db,
+            merge_operator: None,
+            default_ttls: Arc::default(),
+        }
+    }
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Operator resolving the operands of `KvsEngine::merge`, applied as
+    /// soon as an operand is merged
+    pub fn merge_operator(
+        mut self,
+        merge: impl Fn(&str, Option<&str>, &[String]) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_operator = Some(Arc::new(merge));
+        self
+    }
+
+    /// Expire the keys written without a TTL after `ttl`, see
+    /// `KvStoreOptions::default_ttl`
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        Arc::make_mut(&mut self.default_ttls).set_default(ttl);
+        self
+    }
+
+    /// TTL of the keys starting with `prefix` written without one, see
+    /// `KvStoreOptions::namespace_ttl`
+    pub fn namespace_ttl(mut self, prefix: impl Into<String>, ttl: Option<Duration>) -> Self {
+        Arc::make_mut(&mut self.default_ttls).set_namespace(prefix.into(), ttl);
+        self
+    }
+
+    /// When `key` written now without a TTL expires
+    fn default_expiry(&self, key: &str) -> Option<u64> {
+        self.default_ttls.ttl(key).map(expiry_after)
+    }
+
+    fn lock_writer(&self) -> MutexGuard<'_, Writer> {
+        self.db.writer.lock().unwrap()
+    }
+
+    /// Live keys in `range`, ordered by key
+    fn range_keys<R: RangeBounds<String>>(&self, range: R) -> Vec<String> {
+        let now = now_millis();
+        let mut keys: Vec<String> = self
+            .db
+            .entries
+            .iter()
+            .filter(|entry| range.contains(entry.key()) && !entry.is_expired(now))
+            .map(|entry| entry.key().clone())
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Values of `keys` as text, skipping keys removed since they were listed
+    fn read_keys(&self, keys: Vec<String>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn insert(
+        &self,
+        writer: &mut Writer,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        content_type: ContentType,
+    ) {
+        let entry = Entry {
+            value,
+            content_type,
+            expires_at,
+        };
+        self.db.entries.insert(key.clone(), entry);
+        writer.sets += 1;
+        writer.publish(&key, ChangeEvent::Set);
+    }
+
+    /// Expiry and content type of a value replacing the value of `key`:
+    /// those of the live value, or the defaults of a new key
+    fn kept(&self, key: &str) -> (Option<u64>, ContentType) {
+        match self
+            .db
+            .entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(now_millis()))
+        {
+            Some(entry) => (entry.expires_at, entry.content_type),
+            None => (self.default_expiry(key), ContentType::Text),
+        }
+    }
+
+    fn delete(&self, writer: &mut Writer, key: &str) -> Result<()> {
+        match self.db.entries.remove(key) {
+            Some((_, entry)) if !entry.is_expired(now_millis()) => {
+                writer.removes += 1;
+                writer.publish(key, ChangeEvent::Removed);
+                Ok(())
+            }
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+}
+
+impl KvsEngine for MemStore {
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key)?
+            .map(|value| String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    fn get_bytes(&self, key: impl AsRef<str>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_typed(key)?.map(|(value, _)| value))
+    }
+
+    fn get_typed(&self, key: impl AsRef<str>) -> Result<Option<(Vec<u8>, ContentType)>> {
+        Ok(self
+            .db
+            .entries
+            .get(key.as_ref())
+            .filter(|entry| !entry.is_expired(now_millis()))
+            .map(|entry| (entry.value.clone(), entry.content_type)))
+    }
+
+    /// Values are in memory already, the reader goes over a copy
+    fn get_reader(&self, key: impl AsRef<str>) -> Result<Option<ValueReader>> {
+        Ok(self.get_typed(key)?.map(ValueReader::from))
+    }
+
+    /// Reads while holding the writer, so no write lands between two keys
+    fn get_many<K: AsRef<str>>(&self, keys: &[K]) -> Result<Vec<Option<TypedValue>>> {
+        let _writer = self.lock_writer();
+        keys.iter().map(|key| self.get_typed(key)).collect()
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_typed(key, value, ContentType::Text)
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let expires_at = self.default_expiry(&key);
+        let mut writer = self.lock_writer();
+        self.insert(&mut writer, key, value, expires_at, ContentType::Text);
+        Ok(())
+    }
+
+    fn set_typed(&self, key: String, value: String, content_type: ContentType) -> Result<()> {
+        let expires_at = self.default_expiry(&key);
+        let mut writer = self.lock_writer();
+        self.insert(
+            &mut writer,
+            key,
+            value.into_bytes(),
+            expires_at,
+            content_type,
+        );
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_after(ttl);
+        let mut writer = self.lock_writer();
+        self.insert(
+            &mut writer,
+            key,
+            value.into_bytes(),
+            Some(expires_at),
+            ContentType::Text,
+        );
+        Ok(())
+    }
+
+    fn remove(&self, key: impl AsRef<str>) -> Result<()> {
+        let mut writer = self.lock_writer();
+        self.delete(&mut writer, key.as_ref())
+    }
+
+    fn persist(&self, key: impl AsRef<str>) -> Result<bool> {
+        let key = key.as_ref();
+        let mut writer = self.lock_writer();
+        let now = now_millis();
+        let value = match self.db.entries.get(key) {
+            Some(entry) if entry.expires_at.is_some() && !entry.is_expired(now) => {
+                (entry.value.clone(), entry.content_type)
+            }
+            _ => return Ok(false),
+        };
+        self.insert(&mut writer, key.to_owned(), value.0, None, value.1);
+        Ok(true)
+    }
+
+    fn remove_matching(&self, pattern: &str) -> Result<usize> {
+        let mut writer = self.lock_writer();
+        let mut removed = 0;
+        for key in self.keys(pattern)? {
+            match self.delete(&mut writer, &key) {
+                Ok(()) => removed += 1,
+                // expired since it was listed
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    fn write_batch(&self, cmds: Vec<Command>) -> Result<()> {
+        let mut writer = self.lock_writer();
+        // validate the whole batch before applying any of it
+        let mut live: HashMap<&str, bool> = HashMap::new();
+        for cmd in &cmds {
+            match cmd {
+                Command::Set { key, .. } | Command::SetBytes { key, .. } => {
+                    live.insert(key, true);
+                }
+                Command::Rm { key } => {
+                    let exists = match live.get(key.as_str()) {
+                        Some(exists) => *exists,
+                        None => self.exists(key)?,
+                    };
+                    if !exists {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    live.insert(key, false);
+                }
+                _ => return Err(KvsError::InvalidCommand),
+            }
+        }
+        for cmd in cmds {
+            match cmd {
+                Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                    content_type,
+                    ..
+                } => {
+                    let expires_at = expires_at.or_else(|| self.default_expiry(&key));
+                    self.insert(
+                        &mut writer,
+                        key,
+                        value.into_bytes(),
+                        expires_at,
+                        content_type,
+                    )
+                }
+                Command::SetBytes {
+                    key,
+                    value,
+                    expires_at,
+                } => {
+                    let expires_at = expires_at.or_else(|| self.default_expiry(&key));
+                    self.insert(&mut writer, key, value, expires_at, ContentType::Text)
+                }
+                Command::Rm { key } => self.delete(&mut writer, &key)?,
+                _ => unreachable!("batch was validated above"),
+            }
+        }
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut writer = self.lock_writer();
+        let current = self.get_bytes(&key)?;
+        if current != expected.map(String::into_bytes) {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                let expires_at = self.default_expiry(&key);
+                self.insert(
+                    &mut writer,
+                    key,
+                    value.into_bytes(),
+                    expires_at,
+                    ContentType::Text,
+                )
+            }
+            None if current.is_some() => self.delete(&mut writer, &key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&str>) -> Option<String>,
+    {
+        let mut writer = self.lock_writer();
+        let current = self.get(&key)?;
+        match f(current.as_deref()) {
+            Some(value) => {
+                let (expires_at, content_type) = self.kept(&key);
+                self.insert(
+                    &mut writer,
+                    key,
+                    value.into_bytes(),
+                    expires_at,
+                    content_type,
+                )
+            }
+            None if current.is_some() => self.delete(&mut writer, &key)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn append(&self, key: String, value: &[u8]) -> Result<usize> {
+        let mut writer = self.lock_writer();
+        let (expires_at, content_type) = self.kept(&key);
+        let mut appended = self.get_bytes(&key)?.unwrap_or_default();
+        appended.extend_from_slice(value);
+        let len = appended.len();
+        self.insert(&mut writer, key, appended, expires_at, content_type);
+        Ok(len)
+    }
+
+    /// Applies the merge operator right away, the merged value keeps the
+    /// expiry of the value it replaces
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let merge = self
+            .merge_operator
+            .as_ref()
+            .ok_or_else(|| KvsError::Message("no merge operator registered".into()))?;
+        let mut writer = self.lock_writer();
+        let now = now_millis();
+        let (existing, expires_at) = match self.db.entries.get(&key) {
+            Some(entry) if !entry.is_expired(now) => (
+                Some(String::from_utf8_lossy(&entry.value).into_owned()),
+                entry.expires_at,
+            ),
+            _ => (None, None),
+        };
+        let value = merge(&key, existing.as_deref(), &[operand]);
+        self.insert(
+            &mut writer,
+            key,
+            value.into_bytes(),
+            expires_at,
+            ContentType::Text,
+        );
+        Ok(())
+    }
+
+    fn exists(&self, key: impl AsRef<str>) -> Result<bool> {
+        let now = now_millis();
+        Ok(self
+            .db
+            .entries
+            .get(key.as_ref())
+            .is_some_and(|entry| !entry.is_expired(now)))
+    }
+
+    fn expires_at(&self, key: impl AsRef<str>) -> Result<Option<u64>> {
+        let now = now_millis();
+        Ok(self
+            .db
+            .entries
+            .get(key.as_ref())
+            .filter(|entry| !entry.is_expired(now))
+            .and_then(|entry| entry.expires_at))
+    }
+
+    fn keys(&self, pattern: &str) -> Result<Vec<String>> {
+        let pattern = glob::Pattern::new(pattern)
+            .map_err(|e| KvsError::Message(format!("invalid pattern {}: {}", pattern, e)))?;
+        Ok(self
+            .range_keys(..)
+            .into_iter()
+            .filter(|key| pattern.matches(key))
+            .collect())
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        let now = now_millis();
+        Ok(self
+            .db
+            .entries
+            .iter()
+            .filter(|entry| !entry.is_expired(now))
+            .count())
+    }
+
+    fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.read_keys(self.range_keys(range))
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let keys = self
+            .range_keys(prefix.to_owned()..)
+            .into_iter()
+            .take_while(|key| key.starts_with(prefix))
+            .collect();
+        self.read_keys(keys)
+    }
+
+    /// Sorts the live keys for every page, fine for the sizes the store is
+    /// meant for
+    fn scan_page(&self, cursor: Option<Cursor>, limit: usize) -> Result<ScanPage> {
+        let keys = match &cursor {
+            Some(cursor) => {
+                self.range_keys((Bound::Excluded(cursor.after().to_owned()), Bound::Unbounded))
+            }
+            None => self.range_keys(..),
+        };
+        let more = keys.len() > limit;
+        let keys: Vec<String> = keys.into_iter().take(limit).collect();
+        let next = match more {
+            true => keys.last().cloned().map(Cursor::new),
+            false => None,
+        };
+        Ok((self.read_keys(keys)?, next))
+    }
+
+    /// Writes the keys into a new kvs store in `dest`, so a snapshot
+    /// outlives the process
+    fn snapshot(&self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(KvsError::Message(format!(
+                "snapshot destination {} is not empty",
+                dest.display()
+            )));
+        }
+        let batch = {
+            let _writer = self.lock_writer();
+            let now = now_millis();
+            self.db
+                .entries
+                .iter()
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| {
+                    Command::set_from_bytes(
+                        entry.key().clone(),
+                        entry.value.clone(),
+                        entry.expires_at,
+                        entry.content_type,
+                    )
+                })
+                .collect()
+        };
+        let store = KvStore::open(dest)?;
+        store.write_batch(batch)?;
+        store.close()
+    }
+
+    /// Nothing to sync
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let writer = self.lock_writer();
+        let now = now_millis();
+        let mut expiring = ExpiryForecast::default();
+        for entry in self.db.entries.iter() {
+            if let Some(expires_at) = entry.expires_at {
+                expiring.add(expires_at, now);
+            }
+        }
+        Ok(EngineStats {
+            engine: "memory",
+            keys: self.key_count()?,
+            sets: writer.sets,
+            removes: writer.removes,
+            expiring,
+            ..EngineStats::default()
+        })
+    }
+
+    fn select(&self, db: usize) -> Result<Self> {
+        let mut databases = self.databases.lock().unwrap();
+        Ok(MemStore {
+            db: databases.entry(db).or_default().clone(),
+            databases: self.databases.clone(),
+            merge_operator: self.merge_operator.clone(),
+            default_ttls: self.default_ttls.clone(),
+        })
+    }
+
+    /// Databases selected since the store was created
+    fn databases(&self) -> Result<Vec<usize>> {
+        Ok(self.databases.lock().unwrap().keys().copied().collect())
+    }
+
+    /// Merges are applied as they come, so every value is a single record
+    fn big_keys(&self, top: usize, sample: Option<usize>) -> Result<BigKeys> {
+        let now = now_millis();
+        let sizes = self
+            .db
+            .entries
+            .iter()
+            .filter(|entry| !entry.is_expired(now))
+            .take(sample.unwrap_or(usize::MAX))
+            .map(|entry| KeySize {
+                key: entry.key().clone(),
+                value_bytes: entry.value.len() as u64,
+                records: 1,
+            })
+            .collect();
+        Ok(BigKeys::rank(sizes, top))
+    }
+
+    /// Values are kept as they are, there is nothing to compress them for
+    fn train_dictionary(&self, _sample: usize) -> Result<u32> {
+        Err(KvsError::Message(
+            "the memory engine does not compress values".into(),
+        ))
+    }
+
+    /// Values never leave memory, so none are ever corrupt
+    fn quarantined(&self) -> Result<Vec<QuarantinedKey>> {
+        Ok(Vec::new())
+    }
+
+    fn drop_quarantined(&self, _key: impl AsRef<str>) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn reset_stats(&self) -> Result<()> {
+        let mut writer = self.lock_writer();
+        writer.sets = 0;
+        writer.removes = 0;
+        Ok(())
+    }
+
+    fn subscribe(&self, prefix: &str) -> Result<Receiver<ChangeEvent>> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock_writer()
+            .subscribers
+            .push((prefix.to_owned(), sender));
+        Ok(receiver)
+    }
+
+    /// Nothing to flush, the keys stay readable until the last clone is
+    /// dropped
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,21 +1,524 @@
+use crate::client::Command;
+use crate::metrics::Histogram;
+use crate::KvsError;
 pub use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// Position of a paged scan, the key the previous page ended on. Pages
+/// continue by key order, so a cursor survives compaction and concurrent
+/// writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    after: String,
+}
+
+impl Cursor {
+    pub fn new(after: String) -> Self {
+        Cursor { after }
+    }
+
+    /// The last key of the previous page
+    pub fn after(&self) -> &str {
+        &self.after
+    }
+}
+
+/// Cursors are sent to clients as the hex of the key, which never collides
+/// with the `0` that starts and ends a SCAN
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.after.bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || KvsError::Message(format!("invalid cursor: {}", s));
+        if !s.is_ascii() || !s.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        Ok(Cursor::new(
+            String::from_utf8(bytes).map_err(|_| invalid())?,
+        ))
+    }
+}
+
+/// What a value holds, stored along with it so clients can tell how to
+/// display and validate it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    /// Plain text, what values are without a content type
+    #[default]
+    Text,
+    /// A JSON document
+    Json,
+}
+
+impl ContentType {
+    pub fn is_text(&self) -> bool {
+        *self == ContentType::Text
+    }
+
+    /// Lowercase name, as `SET`'s `TYPE` option takes it
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentType::Text => "text",
+            ContentType::Json => "json",
+        }
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(ContentType::Text),
+            "json" => Ok(ContentType::Json),
+            _ => Err(KvsError::Message(format!("invalid content type: {}", s))),
+        }
+    }
+}
+
+/// Figures an engine reports for monitoring
+#[derive(Debug, Clone, Default)]
+pub struct EngineStats {
+    /// Name of the engine, as `--engine` takes it
+    pub engine: &'static str,
+    /// Keys with a value
+    pub keys: usize,
+    /// Size of the engine's files on disk
+    pub disk_bytes: u64,
+    /// Values set over the lifetime of the engine's files, or since
+    /// `KvsEngine::reset_stats`, like the counters below
+    pub sets: u64,
+    /// Keys removed
+    pub removes: u64,
+    /// Compactions run
+    pub compactions: u64,
+    /// Bytes written to the engine's files by writes
+    pub bytes_written: u64,
+    /// Writes waiting for the writer lock right now, held by another write
+    /// or by compaction
+    pub writer_waiting: u64,
+    /// Time writes waited for the writer lock since the engine was opened
+    pub writer_wait: Histogram,
+    /// Keys with a value that are due to expire soon
+    pub expiring: ExpiryForecast,
+}
+
+/// Keys due to expire within the next minute, hour and day, each count
+/// including the keys of the shorter spans, to see mass expirations coming
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExpiryForecast {
+    pub minute: u64,
+    pub hour: u64,
+    pub day: u64,
+}
+
+impl ExpiryForecast {
+    /// Counts a key expiring at `expires_at` as of `now`, both milliseconds
+    /// since the unix epoch
+    pub fn add(&mut self, expires_at: u64, now: u64) {
+        if expires_at <= now {
+            return;
+        }
+        let left = Duration::from_millis(expires_at - now);
+        if left <= Duration::from_secs(60) {
+            self.minute += 1;
+        }
+        if left <= Duration::from_secs(60 * 60) {
+            self.hour += 1;
+        }
+        if left <= Duration::from_secs(24 * 60 * 60) {
+            self.day += 1;
+        }
+    }
+}
+
+/// How much room a key takes, see `KvsEngine::big_keys`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySize {
+    pub key: String,
+    /// Bytes of log taken by the records of the value, merge operands
+    /// included, or bytes of the value for engines without a log
+    pub value_bytes: u64,
+    /// Records the value is spread over, more than one while merge operands
+    /// wait for compaction to fold them
+    pub records: usize,
+}
+
+/// The keys taking the most room, see `KvsEngine::big_keys`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BigKeys {
+    /// Keys looked at
+    pub scanned: usize,
+    /// Keys with the longest names, longest first
+    pub longest_keys: Vec<KeySize>,
+    /// Keys with the largest values, largest first
+    pub largest_values: Vec<KeySize>,
+    /// Keys spread over the most records, most first, leaving out keys held
+    /// by a single record
+    pub most_fragmented: Vec<KeySize>,
+}
+
+impl BigKeys {
+    /// Ranks the `top` offenders of each kind among `sizes`, ties broken by
+    /// key so the report is the same across runs
+    pub(crate) fn rank(sizes: Vec<KeySize>, top: usize) -> Self {
+        let pick = |by: &dyn Fn(&KeySize) -> u64, min: u64| {
+            let mut picked: Vec<KeySize> = sizes
+                .iter()
+                .filter(|size| by(size) >= min)
+                .cloned()
+                .collect();
+            picked.sort_by(|a, b| by(b).cmp(&by(a)).then_with(|| a.key.cmp(&b.key)));
+            picked.truncate(top);
+            picked
+        };
+        BigKeys {
+            scanned: sizes.len(),
+            longest_keys: pick(&|size| size.key.len() as u64, 0),
+            largest_values: pick(&|size| size.value_bytes, 0),
+            most_fragmented: pick(&|size| size.records as u64, 2),
+        }
+    }
+}
+
+/// The report BIGKEYS replies with
+impl fmt::Display for BigKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "scanned {} keys", self.scanned)?;
+        writeln!(f, "longest keys:")?;
+        for (i, size) in self.longest_keys.iter().enumerate() {
+            writeln!(f, "  {}) {} ({} bytes)", i + 1, size.key, size.key.len())?;
+        }
+        writeln!(f, "largest values:")?;
+        for (i, size) in self.largest_values.iter().enumerate() {
+            writeln!(f, "  {}) {} ({} bytes)", i + 1, size.key, size.value_bytes)?;
+        }
+        write!(f, "most fragmented:")?;
+        for (i, size) in self.most_fragmented.iter().enumerate() {
+            write!(f, "\n  {}) {} ({} records)", i + 1, size.key, size.records)?;
+        }
+        Ok(())
+    }
+}
+
+/// A key whose value failed its checksum, see `KvsEngine::quarantined`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedKey {
+    pub key: String,
+    /// Log the corrupt record was found in, which compaction may have
+    /// removed since
+    pub file: PathBuf,
+    /// Offset of the corrupt record in `file`
+    pub offset: u64,
+}
+
+/// A change to a key, see `KvsEngine::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// The key was set, or an operand was merged into it
+    Set(String),
+    /// The key was removed
+    Removed(String),
+    /// The key expired and was dropped by the background sweep
+    Expired(String),
+}
+
+impl ChangeEvent {
+    pub fn key(&self) -> &str {
+        match self {
+            ChangeEvent::Set(key) | ChangeEvent::Removed(key) | ChangeEvent::Expired(key) => key,
+        }
+    }
+
+    /// Lowercase name of the change, as SUBSCRIBE sends it
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeEvent::Set(_) => "set",
+            ChangeEvent::Removed(_) => "rm",
+            ChangeEvent::Expired(_) => "expired",
+        }
+    }
+}
+
+/// The bytes of a value read as they are needed, see `KvsEngine::get_reader`
+pub struct ValueReader {
+    len: u64,
+    content_type: ContentType,
+    reader: Box<dyn Read + Send>,
+}
+
+impl ValueReader {
+    /// A value of `len` bytes read from `reader`
+    pub fn new(len: u64, content_type: ContentType, reader: impl Read + Send + 'static) -> Self {
+        ValueReader {
+            len,
+            content_type,
+            reader: Box::new(reader),
+        }
+    }
+
+    /// Bytes of the value, all of which the reader yields
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+}
+
+/// A value already read whole
+impl From<TypedValue> for ValueReader {
+    fn from((value, content_type): TypedValue) -> Self {
+        ValueReader::new(value.len() as u64, content_type, io::Cursor::new(value))
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// TTLs of keys written without one: a default and overrides for the keys
+/// starting with a prefix, see `KvStoreOptions::default_ttl`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DefaultTtls {
+    ttl: Option<Duration>,
+    /// `None` keeps the keys under a prefix from expiring
+    namespaces: BTreeMap<String, Option<Duration>>,
+}
+
+impl DefaultTtls {
+    pub(crate) fn set_default(&mut self, ttl: Duration) {
+        self.ttl = Some(ttl);
+    }
+
+    pub(crate) fn set_namespace(&mut self, prefix: String, ttl: Option<Duration>) {
+        self.namespaces.insert(prefix, ttl);
+    }
+
+    /// TTL of `key` written without one, that of the longest prefix it
+    /// starts with or else the default
+    pub(crate) fn ttl(&self, key: &str) -> Option<Duration> {
+        self.namespaces
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.ttl, |(_, ttl)| *ttl)
+    }
+}
+
+/// A page of pairs and the cursor of the next page, see `KvsEngine::scan_page`
+pub type ScanPage = (Vec<(String, String)>, Option<Cursor>);
+
+/// The bytes of a value and its content type, see `KvsEngine::get_typed`
+pub type TypedValue = (Vec<u8>, ContentType);
+
 pub trait KvsEngine: Clone + Send + 'static {
     /// Get the corresponding value for a key
     /// It returns an option that will be none
-    /// if key does not exists
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// if key does not exists. Bytes of a value that are not UTF-8 are
+    /// replaced, see `get_bytes`.
+    /// Methods that only look a key up take it borrowed or owned, so callers
+    /// holding a `&str` need not allocate.
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>>;
+
+    /// Get the bytes of the value for a key, as `set_bytes` stored them
+    fn get_bytes(&self, key: impl AsRef<str>) -> Result<Option<Vec<u8>>>;
 
     /// Set the value at key, like HashMap
     /// If previous value was there it will be overwritten
     fn set(&self, key: String, value: String) -> Result<()>;
 
+    /// Set the value at key to bytes that need not be UTF-8
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()>;
+
+    /// Set the value at key along with the content type of the value
+    fn set_typed(&self, key: String, value: String, content_type: ContentType) -> Result<()>;
+
+    /// Get the bytes of the value at key along with its content type, `Text`
+    /// for values set without one
+    fn get_typed(&self, key: impl AsRef<str>) -> Result<Option<(Vec<u8>, ContentType)>>;
+
+    /// Get a reader of the bytes of the value at key, which engines stream
+    /// large values through from their files instead of reading them whole.
+    /// Bytes found corrupt once reading started fail the read with
+    /// `io::ErrorKind::InvalidData`.
+    fn get_reader(&self, key: impl AsRef<str>) -> Result<Option<ValueReader>>;
+
+    /// Get the values of `keys` along with their content types, in the
+    /// order of `keys`, as they all were at one point in time
+    fn get_many<K: AsRef<str>>(&self, keys: &[K]) -> Result<Vec<Option<TypedValue>>>;
+
+    /// Set the value at key that expires after `ttl`
+    /// Expired keys behave as if they were removed
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
     /// Remove the key, value pair at key
     /// # Errors
     /// KeyNotFound if key is not there in the map
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: impl AsRef<str>) -> Result<()>;
+
+    /// Keep `key` from expiring, returning whether it had a TTL. It stays
+    /// without one, default TTLs aside, until it is written again.
+    fn persist(&self, key: impl AsRef<str>) -> Result<bool>;
+
+    /// Remove every key matching the glob `pattern` under one writer lock,
+    /// so no write lands between listing the keys and removing them.
+    /// Returns the number of keys removed.
+    /// # Errors
+    /// When `pattern` is not a valid glob
+    fn remove_matching(&self, pattern: &str) -> Result<usize>;
+
+    /// Apply a batch of `Set` and `Rm` commands in order with a single flush
+    /// # Errors
+    /// KeyNotFound if a removed key is not there, InvalidCommand for any
+    /// other command. Nothing is written if the batch is rejected.
+    fn write_batch(&self, cmds: Vec<Command>) -> Result<()>;
+
+    /// Atomically replace the value at key with `new` if it currently is
+    /// `expected`, where `None` stands for a missing key on either side.
+    /// Returns whether the swap happened.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+
+    /// Replace the value at key with what `f` returns for the current value,
+    /// removing the key when it returns `None`. No other write lands between
+    /// the read and the write; `f` should not call back into the engine. A
+    /// key that exists keeps its expiry and content type.
+    fn update<F>(&self, key: String, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&str>) -> Option<String>;
+
+    /// Append `value` to the bytes at key, setting it if missing. A key that
+    /// exists keeps its expiry and content type. Returns the length of the
+    /// new value.
+    fn append(&self, key: String, value: &[u8]) -> Result<usize>;
+
+    /// Merge `operand` into the value at key with the merge operator the
+    /// engine was opened with, without reading the current value first
+    /// # Errors
+    /// When the engine has no merge operator
+    fn merge(&self, key: String, operand: String) -> Result<()>;
+
+    /// Whether key has a value, answered without reading the log
+    fn exists(&self, key: impl AsRef<str>) -> Result<bool>;
+
+    /// When the value at key expires, in milliseconds since the unix epoch.
+    /// `None` for a key without an expiry or without a value.
+    fn expires_at(&self, key: impl AsRef<str>) -> Result<Option<u64>>;
+
+    /// Get the keys matching the glob `pattern`, ordered by key, answered
+    /// without reading the log
+    /// # Errors
+    /// When `pattern` is not a valid glob
+    fn keys(&self, pattern: &str) -> Result<Vec<String>>;
+
+    /// Number of keys with a value
+    fn key_count(&self) -> Result<usize>;
+
+    /// Get all key value pairs with keys in `range`, ordered by key
+    fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>>;
+
+    /// Get all key value pairs with keys starting with `prefix`, ordered by key
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+
+    /// Get up to `limit` key value pairs, ordered by key, starting after
+    /// `cursor` or from the first key. Also returns the cursor of the next
+    /// page, `None` once the scan reached the last key.
+    fn scan_page(&self, cursor: Option<Cursor>, limit: usize) -> Result<ScanPage>;
+
+    /// Write a consistent copy of the store into the `dest` directory
+    /// that can be opened as a store of its own
+    fn snapshot(&self, dest: &Path) -> Result<()>;
+
+    /// Wait for every write so far to reach the disk, whatever durability
+    /// the engine writes with
+    fn sync(&self) -> Result<()>;
+
+    /// Report the engine's size and activity
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// Get numbered database `db` of this engine, a keyspace of its own.
+    /// Database 0 is the engine itself.
+    fn select(&self, db: usize) -> Result<Self>;
+
+    /// Numbered databases that may hold keys, in order
+    fn databases(&self) -> Result<Vec<usize>>;
+
+    /// Find the `top` keys with the longest names, the largest values and
+    /// the most records, looking at every key or at about `sample` of them,
+    /// answered without reading the log
+    fn big_keys(&self, top: usize, sample: Option<usize>) -> Result<BigKeys>;
+
+    /// Train a zstd dictionary on the values of up to `sample` keys and
+    /// compress the records written from now on with it, returning its id.
+    /// Ids grow with each training, records keep the one they were written
+    /// with.
+    /// # Errors
+    /// When the engine does not compress with zstd, or there are too few
+    /// values to train on
+    fn train_dictionary(&self, sample: usize) -> Result<u32>;
+
+    /// Keys whose value failed its checksum when it was read, ordered by key.
+    /// Reads of a quarantined key fail with `KvsError::Corruption` until it
+    /// is written again or dropped, other keys are served as usual. The list
+    /// is kept until the engine is closed.
+    fn quarantined(&self) -> Result<Vec<QuarantinedKey>>;
+
+    /// Remove quarantined `key` along with what is left of its value, and
+    /// take it out of quarantine. Returns whether the key was quarantined.
+    fn drop_quarantined(&self, key: impl AsRef<str>) -> Result<bool>;
+
+    /// Zero the lifetime counters of `stats`
+    fn reset_stats(&self) -> Result<()>;
+
+    /// Get the changes to keys starting with `prefix` from now on, each sent
+    /// once the write is applied, in the order writes were applied. Changes
+    /// stop being sent once the receiver is dropped.
+    fn subscribe(&self, prefix: &str) -> Result<Receiver<ChangeEvent>>;
+
+    /// Flush pending writes and stop background work, for a clean shutdown
+    fn close(&self) -> Result<()>;
 }
 
 mod kvs;
+mod memory;
 mod sled;
-pub use self::kvs::KvStore;
+pub use self::kvs::{
+    Compression, Durability, KvStore, KvStoreHandle, KvStoreIter, KvStoreOptions, MergeFn,
+};
+pub use self::memory::MemStore;
 pub use self::sled::SledStore;
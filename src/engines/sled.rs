@@ -1,6 +1,13 @@
+use super::{
+    BigKeys, ChangeEvent, ContentType, Cursor, EngineStats, QuarantinedKey, ScanPage, TypedValue,
+    ValueReader,
+};
+use crate::client::Command;
 use std::{
+    ops::RangeBounds,
     path::Path,
-    sync::{Arc, Mutex},
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::Duration,
 };
 
 pub struct SledStore(Arc<Mutex<SharedSledStore>>);
@@ -18,11 +25,156 @@ impl super::KvsEngine for SledStore {
         unimplemented!()
     }
 
-    fn get(&self, _key: String) -> super::Result<Option<String>> {
+    fn set_with_ttl(&self, _key: String, _value: String, _ttl: Duration) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn get(&self, _key: impl AsRef<str>) -> super::Result<Option<String>> {
+        unimplemented!()
+    }
+
+    fn set_typed(
+        &self,
+        _key: String,
+        _value: String,
+        _content_type: ContentType,
+    ) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn get_typed(&self, _key: impl AsRef<str>) -> super::Result<Option<(Vec<u8>, ContentType)>> {
+        unimplemented!()
+    }
+
+    fn get_reader(&self, _key: impl AsRef<str>) -> super::Result<Option<ValueReader>> {
+        unimplemented!()
+    }
+
+    fn get_many<K: AsRef<str>>(&self, _keys: &[K]) -> super::Result<Vec<Option<TypedValue>>> {
+        unimplemented!()
+    }
+
+    fn get_bytes(&self, _key: impl AsRef<str>) -> super::Result<Option<Vec<u8>>> {
+        unimplemented!()
+    }
+
+    fn set_bytes(&self, _key: String, _value: Vec<u8>) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn remove(&self, _key: impl AsRef<str>) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn persist(&self, _key: impl AsRef<str>) -> super::Result<bool> {
+        unimplemented!()
+    }
+
+    fn remove_matching(&self, _pattern: &str) -> super::Result<usize> {
+        unimplemented!()
+    }
+
+    fn write_batch(&self, _cmds: Vec<Command>) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn compare_and_swap(
+        &self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> super::Result<bool> {
+        unimplemented!()
+    }
+
+    fn update<F>(&self, _key: String, _f: F) -> super::Result<()>
+    where
+        F: FnOnce(Option<&str>) -> Option<String>,
+    {
+        unimplemented!()
+    }
+
+    fn append(&self, _key: String, _value: &[u8]) -> super::Result<usize> {
+        unimplemented!()
+    }
+
+    fn merge(&self, _key: String, _operand: String) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn range<R: RangeBounds<String>>(&self, _range: R) -> super::Result<Vec<(String, String)>> {
+        unimplemented!()
+    }
+
+    fn scan_prefix(&self, _prefix: &str) -> super::Result<Vec<(String, String)>> {
+        unimplemented!()
+    }
+
+    fn exists(&self, _key: impl AsRef<str>) -> super::Result<bool> {
+        unimplemented!()
+    }
+
+    fn expires_at(&self, _key: impl AsRef<str>) -> super::Result<Option<u64>> {
+        unimplemented!()
+    }
+
+    fn keys(&self, _pattern: &str) -> super::Result<Vec<String>> {
+        unimplemented!()
+    }
+
+    fn key_count(&self) -> super::Result<usize> {
+        unimplemented!()
+    }
+
+    fn sync(&self) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn stats(&self) -> super::Result<EngineStats> {
+        unimplemented!()
+    }
+
+    fn select(&self, _db: usize) -> super::Result<Self> {
+        unimplemented!()
+    }
+
+    fn databases(&self) -> super::Result<Vec<usize>> {
+        unimplemented!()
+    }
+
+    fn big_keys(&self, _top: usize, _sample: Option<usize>) -> super::Result<BigKeys> {
+        unimplemented!()
+    }
+
+    fn train_dictionary(&self, _sample: usize) -> super::Result<u32> {
+        unimplemented!()
+    }
+
+    fn quarantined(&self) -> super::Result<Vec<QuarantinedKey>> {
+        unimplemented!()
+    }
+
+    fn drop_quarantined(&self, _key: impl AsRef<str>) -> super::Result<bool> {
+        unimplemented!()
+    }
+
+    fn reset_stats(&self) -> super::Result<()> {
+        unimplemented!()
+    }
+
+    fn subscribe(&self, _prefix: &str) -> super::Result<Receiver<ChangeEvent>> {
+        unimplemented!()
+    }
+
+    fn scan_page(&self, _cursor: Option<Cursor>, _limit: usize) -> super::Result<ScanPage> {
+        unimplemented!()
+    }
+
+    fn snapshot(&self, _dest: &Path) -> super::Result<()> {
         unimplemented!()
     }
 
-    fn remove(&self, _key: String) -> super::Result<()> {
+    fn close(&self) -> super::Result<()> {
         unimplemented!()
     }
 }
@@ -1,10 +1,45 @@
 use std::io;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum KvsError {
     Message(String),
     KeyNotFound,
     InvalidCommand,
+    /// A log record failed its checksum or could not be decoded
+    Corruption {
+        file: PathBuf,
+        offset: u64,
+    },
+    /// The data directory was written by another engine than the one asked
+    /// to open it
+    WrongEngine {
+        requested: String,
+        found: String,
+    },
+    /// The data directory is already open by a store of this process, see
+    /// `KvStore`
+    AlreadyOpen(PathBuf),
+    /// A key of `len` bytes where at most `max` are taken, see
+    /// `KvStoreOptions::max_key_size`
+    KeyTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A value of `len` bytes where at most `max` are taken, see
+    /// `KvStoreOptions::max_value_size`
+    ValueTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// An error reply from a kvs server
+    Server(String),
+    /// The connection to a server dropped before it answered a request that
+    /// is not safe to repeat, the request may or may not have been applied
+    Interrupted,
+    /// An engine read or write ran past `KvStoreOptions::io_timeout`. A
+    /// write that timed out wrote nothing.
+    Timeout,
     Io(io::Error),
     Serde(serde_json::Error),
 }
@@ -2,11 +2,19 @@
 
 pub mod client;
 pub mod common;
+pub mod dump;
 pub mod engines;
 pub mod error;
-pub mod resp;
+pub mod metrics;
+pub mod protocol;
+pub mod redis;
+pub mod replication;
 pub mod server;
 pub mod thread_pool;
 
-pub use engines::{KvStore, KvsEngine};
+pub use engines::{
+    BigKeys, ChangeEvent, Compression, ContentType, Cursor, Durability, EngineStats,
+    ExpiryForecast, KeySize, KvStore, KvStoreHandle, KvStoreIter, KvStoreOptions, KvsEngine,
+    MergeFn, QuarantinedKey, ScanPage, TypedValue, ValueReader,
+};
 pub use error::{KvsError, Result};
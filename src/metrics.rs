@@ -0,0 +1,324 @@
+//! Counters and latency histograms of a running server, rendered in the
+//! Prometheus text format by the STATS command and the `/metrics` listener
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::engines::EngineStats;
+
+/// Upper bounds of the latency buckets in microseconds, the last bucket is
+/// everything slower
+const LATENCY_BUCKETS_MICROS: [u64; 10] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000, 1_000_000,
+];
+
+/// Latencies counted in fixed buckets
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS_MICROS.len() + 1],
+    sum: Duration,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MICROS.len());
+        self.buckets[bucket] += 1;
+        self.sum += elapsed;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Mean of the observations, zero before the first one
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.sum / count as u32,
+        }
+    }
+
+    /// Writes the histogram as the `_bucket`, `_sum` and `_count` series of
+    /// `name`, with `labels`, empty or ending in a comma, on every line
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = match LATENCY_BUCKETS_MICROS.get(i) {
+                Some(micros) => (*micros as f64 / 1e6).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+/// Traffic of one client connection, or of every connection of a server
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WireStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Complete RESP frames read off the connection
+    pub frames: u64,
+    /// Frames that are not valid RESP or not a command the server knows
+    pub parse_errors: u64,
+    /// Connections closed for buffering more than the client buffer limit
+    /// in partial frames and queued commands
+    pub oversized_frames: u64,
+}
+
+/// Shared by every connection of a server
+pub struct Metrics {
+    started: Instant,
+    /// Latency of every command by name
+    commands: Mutex<BTreeMap<&'static str, Histogram>>,
+    connected_clients: AtomicU64,
+    connections: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    frames: AtomicU64,
+    parse_errors: AtomicU64,
+    oversized_frames: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            commands: Mutex::new(BTreeMap::new()),
+            connected_clients: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            oversized_frames: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_command(&self, name: &'static str, elapsed: Duration) {
+        self.commands
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` read from a client, in the server's totals and in
+    /// `conn`, the traffic of its connection. So do `frame_read`,
+    /// `parse_error` and `oversized_frame`.
+    pub fn bytes_read(&self, conn: &mut WireStats, bytes: u64) {
+        conn.bytes_read += bytes;
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` written to a client in the server's totals, as they
+    /// reach the socket
+    pub fn bytes_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn frame_read(&self, conn: &mut WireStats) {
+        conn.frames += 1;
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(&self, conn: &mut WireStats) {
+        conn.parse_errors += 1;
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn oversized_frame(&self, conn: &mut WireStats) {
+        conn.oversized_frames += 1;
+        self.oversized_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Traffic of every connection since the server started
+    pub fn wire(&self) -> WireStats {
+        WireStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            frames: self.frames.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            oversized_frames: self.oversized_frames.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn connected_clients(&self) -> u64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    /// Renders the server's metrics and `engine`'s in the Prometheus text
+    /// exposition format
+    pub fn render(&self, engine: &EngineStats) -> String {
+        let mut out = String::new();
+        let commands = self.commands.lock().unwrap();
+
+        out += "# HELP kvs_commands_total Commands processed, by command.\n";
+        out += "# TYPE kvs_commands_total counter\n";
+        for (name, histogram) in commands.iter() {
+            let _ = writeln!(
+                out,
+                "kvs_commands_total{{command=\"{}\"}} {}",
+                name, histogram.count
+            );
+        }
+
+        out += "# HELP kvs_command_duration_seconds Time to execute a command.\n";
+        out += "# TYPE kvs_command_duration_seconds histogram\n";
+        for (name, histogram) in commands.iter() {
+            let labels = format!("command=\"{}\",", name);
+            histogram.render(&mut out, "kvs_command_duration_seconds", &labels);
+        }
+
+        out += "# HELP kvs_writer_wait_seconds Time writes waited for the writer lock.\n";
+        out += "# TYPE kvs_writer_wait_seconds histogram\n";
+        engine
+            .writer_wait
+            .render(&mut out, "kvs_writer_wait_seconds", "");
+
+        out += "# HELP kvs_expiring_keys Keys due to expire within a span of time.\n";
+        out += "# TYPE kvs_expiring_keys gauge\n";
+        for (within, keys) in [
+            ("1m", engine.expiring.minute),
+            ("1h", engine.expiring.hour),
+            ("1d", engine.expiring.day),
+        ] {
+            let _ = writeln!(out, "kvs_expiring_keys{{within=\"{}\"}} {}", within, keys);
+        }
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "kvs_connected_clients",
+            "gauge",
+            "Open client connections.",
+            self.connected_clients().to_string(),
+        );
+        metric(
+            "kvs_connections_total",
+            "counter",
+            "Client connections accepted.",
+            self.connections.load(Ordering::Relaxed).to_string(),
+        );
+        let wire = self.wire();
+        metric(
+            "kvs_net_input_bytes_total",
+            "counter",
+            "Bytes read from clients.",
+            wire.bytes_read.to_string(),
+        );
+        metric(
+            "kvs_net_output_bytes_total",
+            "counter",
+            "Bytes written to clients.",
+            wire.bytes_written.to_string(),
+        );
+        metric(
+            "kvs_frames_total",
+            "counter",
+            "RESP frames read from clients.",
+            wire.frames.to_string(),
+        );
+        metric(
+            "kvs_parse_errors_total",
+            "counter",
+            "Frames from clients that are not valid RESP or not a known command.",
+            wire.parse_errors.to_string(),
+        );
+        metric(
+            "kvs_oversized_frames_total",
+            "counter",
+            "Connections closed for exceeding the client buffer limit.",
+            wire.oversized_frames.to_string(),
+        );
+        metric(
+            "kvs_writer_waiting",
+            "gauge",
+            "Writes waiting for the writer lock.",
+            engine.writer_waiting.to_string(),
+        );
+        metric(
+            "kvs_keys",
+            "gauge",
+            "Keys with a value.",
+            engine.keys.to_string(),
+        );
+        metric(
+            "kvs_wal_bytes",
+            "gauge",
+            "Size of the log files on disk.",
+            engine.disk_bytes.to_string(),
+        );
+        metric(
+            "kvs_sets_total",
+            "counter",
+            "Values set over the lifetime of the store.",
+            engine.sets.to_string(),
+        );
+        metric(
+            "kvs_removes_total",
+            "counter",
+            "Keys removed over the lifetime of the store.",
+            engine.removes.to_string(),
+        );
+        metric(
+            "kvs_written_bytes_total",
+            "counter",
+            "Bytes written to the logs over the lifetime of the store.",
+            engine.bytes_written.to_string(),
+        );
+        metric(
+            "kvs_compactions_total",
+            "counter",
+            "Compactions run over the lifetime of the store.",
+            engine.compactions.to_string(),
+        );
+        metric(
+            "kvs_uptime_seconds",
+            "gauge",
+            "Time since the server started.",
+            self.uptime().as_secs_f64().to_string(),
+        );
+        out
+    }
+}
@@ -0,0 +1,577 @@
+use crate::protocol::RespValue;
+use crate::{ContentType, Cursor};
+use log::error;
+use std::time::Duration;
+
+/// A request of the wire protocol. `parse_command` reads one off the wire
+/// and `to_frame` writes one, so servers, replicas and clients share it.
+/// `client::Command` is the command line and log record counterpart, which
+/// maps onto it.
+pub enum KvsCommand {
+    Ping,
+    /// `SET key value [TYPE text|json] [EX seconds|PX milliseconds]`, the
+    /// value may be any bytes
+    Set(String, Vec<u8>, ContentType, Option<Duration>),
+    Get(String),
+    Rm(String),
+    /// `RM MATCH pattern`, remove every key matching a glob pattern
+    RmMatching(String),
+    /// Remove every key of the selected database
+    Flushdb,
+    /// `MGET key [key ...]`
+    Mget(Vec<String>),
+    /// `MSET key value [key value ...]`, the values may be any bytes
+    Mset(Vec<(String, Vec<u8>)>),
+    Version,
+    Multi,
+    Exec,
+    Discard,
+    Backup(String),
+    /// Write the keys to a dump file on the server, resuming an export that
+    /// was cut short, see `dump`
+    Export(String),
+    Sync,
+    /// Append to the value of a key, setting it if missing
+    Append(String, String),
+    /// Take the TTL off a key
+    Persist(String),
+    /// Compare-and-swap, `None` stands for a missing key
+    Cas(String, Option<String>, Option<String>),
+    /// Protocol handshake with the requested version, if any
+    Hello(Option<String>),
+    /// Page of keys after the cursor, `None` starts from the first key
+    Scan(Option<Cursor>, usize),
+    Exists(String),
+    /// Keys matching a glob pattern
+    Keys(String),
+    Dbsize,
+    /// `BIGKEYS [COUNT n] [SAMPLE n]`, the keys taking the most room, looking
+    /// at every key unless SAMPLE caps how many
+    Bigkeys(usize, Option<usize>),
+    /// Keys whose value failed its checksum
+    Quarantine,
+    /// `QUARANTINE DROP key`, remove a quarantined key
+    QuarantineDrop(String),
+    /// Server metrics in the Prometheus text format
+    Stats,
+    /// `STATS RESET`, zero the engine's lifetime counters
+    StatsReset,
+    /// `DICT TRAIN [SAMPLE n]`, train a compression dictionary on the values
+    /// of up to n keys
+    DictTrain(usize),
+    /// Server state as `key:value` lines
+    Info,
+    /// Stop the server, syncing the engine to disk first unless NOSAVE
+    Shutdown(bool),
+    /// Switch the connection to a numbered database
+    Select(usize),
+    /// `AUTH [username] password`
+    Auth(Option<String>, String),
+    /// Stream the changes to keys starting with a prefix to the connection
+    Subscribe(String),
+}
+
+impl KvsCommand {
+    /// Lowercase command name, as metrics label commands
+    pub fn name(&self) -> &'static str {
+        match self {
+            KvsCommand::Ping => "ping",
+            KvsCommand::Set(..) => "set",
+            KvsCommand::Get(_) => "get",
+            KvsCommand::Rm(_) | KvsCommand::RmMatching(_) => "rm",
+            KvsCommand::Flushdb => "flushdb",
+            KvsCommand::Mget(_) => "mget",
+            KvsCommand::Mset(_) => "mset",
+            KvsCommand::Version => "version",
+            KvsCommand::Multi => "multi",
+            KvsCommand::Exec => "exec",
+            KvsCommand::Discard => "discard",
+            KvsCommand::Backup(_) => "backup",
+            KvsCommand::Export(_) => "export",
+            KvsCommand::Sync => "sync",
+            KvsCommand::Append(..) => "append",
+            KvsCommand::Persist(_) => "persist",
+            KvsCommand::Cas(..) => "cas",
+            KvsCommand::Hello(_) => "hello",
+            KvsCommand::Scan(..) => "scan",
+            KvsCommand::Exists(_) => "exists",
+            KvsCommand::Keys(_) => "keys",
+            KvsCommand::Dbsize => "dbsize",
+            KvsCommand::Bigkeys(..) => "bigkeys",
+            KvsCommand::Quarantine | KvsCommand::QuarantineDrop(_) => "quarantine",
+            KvsCommand::Stats | KvsCommand::StatsReset => "stats",
+            KvsCommand::DictTrain(_) => "dict",
+            KvsCommand::Info => "info",
+            KvsCommand::Shutdown(_) => "shutdown",
+            KvsCommand::Select(_) => "select",
+            KvsCommand::Auth(..) => "auth",
+            KvsCommand::Subscribe(_) => "subscribe",
+        }
+    }
+
+    /// Whether the command changes keys, as a read only replica refuses
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            KvsCommand::Set(..)
+                | KvsCommand::Mset(_)
+                | KvsCommand::Rm(_)
+                | KvsCommand::RmMatching(_)
+                | KvsCommand::Flushdb
+                | KvsCommand::Append(..)
+                | KvsCommand::Persist(_)
+                | KvsCommand::Cas(..)
+        )
+    }
+
+    /// The RESP frame of the command as a client sends it, `parse_command`
+    /// reads it back into the same command. Its name comes first, followed
+    /// by its arguments.
+    pub fn to_frame(&self) -> Vec<u8> {
+        fn bulk(part: impl Into<Vec<u8>>) -> Option<Vec<u8>> {
+            Some(part.into())
+        }
+        let mut parts = vec![bulk(self.name())];
+        match self {
+            KvsCommand::Ping
+            | KvsCommand::Version
+            | KvsCommand::Multi
+            | KvsCommand::Exec
+            | KvsCommand::Discard
+            | KvsCommand::Sync
+            | KvsCommand::Dbsize
+            | KvsCommand::Flushdb
+            | KvsCommand::Quarantine
+            | KvsCommand::Stats
+            | KvsCommand::Info
+            | KvsCommand::Shutdown(true) => {}
+            KvsCommand::Set(key, value, content_type, ttl) => {
+                parts.extend([bulk(key.as_str()), bulk(value.as_slice())]);
+                if !content_type.is_text() {
+                    parts.extend([bulk("type"), bulk(content_type.name())]);
+                }
+                if let Some(ttl) = ttl {
+                    // PX takes whole milliseconds greater than zero
+                    let millis = ttl.as_millis().max(1).to_string();
+                    parts.extend([bulk("px"), bulk(millis)]);
+                }
+            }
+            KvsCommand::Get(key)
+            | KvsCommand::Rm(key)
+            | KvsCommand::Exists(key)
+            | KvsCommand::Persist(key)
+            | KvsCommand::Backup(key)
+            | KvsCommand::Export(key)
+            | KvsCommand::Keys(key)
+            | KvsCommand::Subscribe(key) => parts.push(bulk(key.as_str())),
+            KvsCommand::Mget(keys) => parts.extend(keys.iter().map(|key| bulk(key.as_str()))),
+            KvsCommand::Mset(pairs) => {
+                for (key, value) in pairs {
+                    parts.extend([bulk(key.as_str()), bulk(value.as_slice())]);
+                }
+            }
+            KvsCommand::Append(key, value) => {
+                parts.extend([bulk(key.as_str()), bulk(value.as_str())])
+            }
+            KvsCommand::Cas(key, expected, new) => parts.extend([
+                bulk(key.as_str()),
+                expected.as_deref().and_then(bulk),
+                new.as_deref().and_then(bulk),
+            ]),
+            KvsCommand::Hello(version) => parts.extend(version.as_deref().map(bulk)),
+            KvsCommand::Scan(cursor, count) => {
+                let cursor = cursor
+                    .as_ref()
+                    .map_or_else(|| "0".to_string(), Cursor::to_string);
+                parts.extend([bulk(cursor), bulk("count"), bulk(count.to_string())]);
+            }
+            KvsCommand::Bigkeys(count, sample) => {
+                parts.extend([bulk("count"), bulk(count.to_string())]);
+                if let Some(sample) = sample {
+                    parts.extend([bulk("sample"), bulk(sample.to_string())]);
+                }
+            }
+            KvsCommand::RmMatching(pattern) => {
+                parts.extend([bulk("match"), bulk(pattern.as_str())])
+            }
+            KvsCommand::QuarantineDrop(key) => parts.extend([bulk("drop"), bulk(key.as_str())]),
+            KvsCommand::StatsReset => parts.push(bulk("reset")),
+            KvsCommand::DictTrain(sample) => {
+                parts.extend([bulk("train"), bulk("sample"), bulk(sample.to_string())])
+            }
+            KvsCommand::Shutdown(false) => parts.push(bulk("nosave")),
+            KvsCommand::Select(db) => parts.push(bulk(db.to_string())),
+            KvsCommand::Auth(username, password) => {
+                parts.extend(username.as_deref().map(bulk));
+                parts.push(bulk(password.as_str()));
+            }
+        }
+        encode_frame(&parts)
+    }
+
+    /// The key of a command on a single key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            KvsCommand::Set(key, ..)
+            | KvsCommand::Get(key)
+            | KvsCommand::Rm(key)
+            | KvsCommand::Append(key, _)
+            | KvsCommand::Cas(key, ..)
+            | KvsCommand::Exists(key)
+            | KvsCommand::Persist(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Operations commands, which only the admin listener takes when the
+    /// server has one
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            KvsCommand::Backup(_)
+                | KvsCommand::Export(_)
+                | KvsCommand::Bigkeys(..)
+                | KvsCommand::Quarantine
+                | KvsCommand::QuarantineDrop(_)
+                | KvsCommand::Shutdown(_)
+                | KvsCommand::StatsReset
+                | KvsCommand::DictTrain(_)
+        )
+    }
+}
+
+/// RESP array of the bulk strings `parts`, such as a command and its
+/// arguments
+pub fn command_frame<P: AsRef<[u8]>>(parts: &[P]) -> Vec<u8> {
+    let parts: Vec<_> = parts.iter().map(|part| Some(part.as_ref())).collect();
+    encode_frame(&parts)
+}
+
+/// RESP array of bulk strings, `None` parts written as null bulk strings
+fn encode_frame<P: AsRef<[u8]>>(parts: &[Option<P>]) -> Vec<u8> {
+    let mut frame = format!("*{}\r\n", parts.len()).into_bytes();
+    for part in parts {
+        match part {
+            Some(part) => {
+                let part = part.as_ref();
+                frame.extend_from_slice(format!("${}\r\n", part.len()).as_bytes());
+                frame.extend_from_slice(part);
+                frame.extend_from_slice(b"\r\n");
+            }
+            None => frame.extend_from_slice(b"$-1\r\n"),
+        }
+    }
+    frame
+}
+
+/// Keys per SCAN page when the client gives no COUNT
+const SCAN_COUNT: usize = 10;
+
+/// Offenders of each kind BIGKEYS reports when the client gives no COUNT
+const BIGKEYS_COUNT: usize = 10;
+
+/// Keys whose values DICT TRAIN samples when the client gives no SAMPLE
+pub const DICT_SAMPLE: usize = 10_000;
+
+/// Reads the request in `data`, a command name followed by its arguments as
+/// bulk strings. `None` if it names no command or its arguments don't fit.
+pub fn parse_command(data: &RespValue) -> Option<KvsCommand> {
+    let (cmd, args) = match data {
+        RespValue::Array(Some(parts)) => parts.split_first()?,
+        data => (data, &[][..]),
+    };
+
+    let cmd = match cmd {
+        RespValue::SimpleString(s) => s.as_str(),
+        cmd => cmd.as_bulk_str()?,
+    };
+
+    match cmd.to_uppercase().as_str() {
+        "PING" => match args {
+            [] => Some(KvsCommand::Ping),
+            _ => None,
+        },
+        "SET" => match args {
+            [key, value, options @ ..] if options.len().is_multiple_of(2) => {
+                let mut content_type = ContentType::Text;
+                let mut ttl = None;
+                for option in options.chunks(2) {
+                    let arg = option[1].as_bulk_str()?;
+                    match option[0].as_bulk_str()?.to_uppercase().as_str() {
+                        "TYPE" => content_type = arg.parse().ok()?,
+                        "EX" => ttl = Some(Duration::from_secs(positive(arg)?)),
+                        "PX" => ttl = Some(Duration::from_millis(positive(arg)?)),
+                        _ => return None,
+                    }
+                }
+                Some(KvsCommand::Set(
+                    string(key)?,
+                    bulk_bytes(value)?,
+                    content_type,
+                    ttl,
+                ))
+            }
+            _ => None,
+        },
+        "GET" => match args {
+            [key] => Some(KvsCommand::Get(string(key)?)),
+            _ => None,
+        },
+        "RM" => match args {
+            [key] => Some(KvsCommand::Rm(string(key)?)),
+            [option, pattern] if is(option, "MATCH") => {
+                Some(KvsCommand::RmMatching(string(pattern)?))
+            }
+            _ => None,
+        },
+        "FLUSHDB" => match args {
+            [] => Some(KvsCommand::Flushdb),
+            _ => None,
+        },
+        "MGET" if !args.is_empty() => args
+            .iter()
+            .map(string)
+            .collect::<Option<_>>()
+            .map(KvsCommand::Mget),
+        "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => args
+            .chunks(2)
+            .map(|pair| Some((string(&pair[0])?, bulk_bytes(&pair[1])?)))
+            .collect::<Option<_>>()
+            .map(KvsCommand::Mset),
+        "VERSION" => match args {
+            [] => Some(KvsCommand::Version),
+            _ => None,
+        },
+        "BACKUP" => match args {
+            [dest] => Some(KvsCommand::Backup(string(dest)?)),
+            _ => None,
+        },
+        "EXPORT" => match args {
+            [dest] => Some(KvsCommand::Export(string(dest)?)),
+            _ => None,
+        },
+        "APPEND" => match args {
+            [key, value] => Some(KvsCommand::Append(string(key)?, string(value)?)),
+            _ => None,
+        },
+        "CAS" => match args {
+            [key, expected, new] => Some(KvsCommand::Cas(
+                string(key)?,
+                optional_string(expected)?,
+                optional_string(new)?,
+            )),
+            _ => None,
+        },
+        "SETNX" => match args {
+            [key, value] => Some(KvsCommand::Cas(string(key)?, None, Some(string(value)?))),
+            _ => None,
+        },
+        "HELLO" => match args {
+            [] => Some(KvsCommand::Hello(None)),
+            [version] => Some(KvsCommand::Hello(Some(string(version)?))),
+            _ => None,
+        },
+        "SCAN" => {
+            let (cursor, count) = match args {
+                [cursor] => (cursor.as_bulk_str()?, SCAN_COUNT),
+                [cursor, option, count] if is(option, "COUNT") => (
+                    cursor.as_bulk_str()?,
+                    count
+                        .as_bulk_str()?
+                        .parse()
+                        .ok()
+                        .filter(|count| *count > 0)?,
+                ),
+                _ => return None,
+            };
+            let cursor = match cursor {
+                "0" => None,
+                cursor => Some(cursor.parse().ok()?),
+            };
+            Some(KvsCommand::Scan(cursor, count))
+        }
+        "EXISTS" => match args {
+            [key] => Some(KvsCommand::Exists(string(key)?)),
+            _ => None,
+        },
+        "PERSIST" => match args {
+            [key] => Some(KvsCommand::Persist(string(key)?)),
+            _ => None,
+        },
+        "KEYS" => match args {
+            [pattern] => Some(KvsCommand::Keys(string(pattern)?)),
+            _ => None,
+        },
+        "DBSIZE" => match args {
+            [] => Some(KvsCommand::Dbsize),
+            _ => None,
+        },
+        "BIGKEYS" if args.len().is_multiple_of(2) => {
+            let mut count = BIGKEYS_COUNT;
+            let mut sample = None;
+            for option in args.chunks(2) {
+                let arg = option[1].as_bulk_str()?;
+                match option[0].as_bulk_str()?.to_uppercase().as_str() {
+                    "COUNT" => count = positive(arg)? as usize,
+                    "SAMPLE" => sample = Some(positive(arg)? as usize),
+                    _ => return None,
+                }
+            }
+            Some(KvsCommand::Bigkeys(count, sample))
+        }
+        "QUARANTINE" => match args {
+            [] => Some(KvsCommand::Quarantine),
+            [option, key] if is(option, "DROP") => Some(KvsCommand::QuarantineDrop(string(key)?)),
+            _ => None,
+        },
+        "STATS" => match args {
+            [] => Some(KvsCommand::Stats),
+            [option] if is(option, "RESET") => Some(KvsCommand::StatsReset),
+            _ => None,
+        },
+        "DICT" => match args {
+            [option] if is(option, "TRAIN") => Some(KvsCommand::DictTrain(DICT_SAMPLE)),
+            [option, sample, n] if is(option, "TRAIN") && is(sample, "SAMPLE") => {
+                Some(KvsCommand::DictTrain(positive(n.as_bulk_str()?)? as usize))
+            }
+            _ => None,
+        },
+        "INFO" => match args {
+            [] => Some(KvsCommand::Info),
+            _ => None,
+        },
+        "SHUTDOWN" => match args {
+            [] => Some(KvsCommand::Shutdown(true)),
+            [option] if is(option, "SAVE") => Some(KvsCommand::Shutdown(true)),
+            [option] if is(option, "NOSAVE") => Some(KvsCommand::Shutdown(false)),
+            _ => None,
+        },
+        "AUTH" => match args {
+            [password] => Some(KvsCommand::Auth(None, string(password)?)),
+            [username, password] => {
+                Some(KvsCommand::Auth(Some(string(username)?), string(password)?))
+            }
+            _ => None,
+        },
+        "SELECT" => match args {
+            [db] => Some(KvsCommand::Select(db.as_bulk_str()?.parse().ok()?)),
+            _ => None,
+        },
+        "SUBSCRIBE" => match args {
+            [prefix] => Some(KvsCommand::Subscribe(string(prefix)?)),
+            _ => None,
+        },
+        "SYNC" => match args {
+            [] => Some(KvsCommand::Sync),
+            _ => None,
+        },
+        "MULTI" => match args {
+            [] => Some(KvsCommand::Multi),
+            _ => None,
+        },
+        "EXEC" => match args {
+            [] => Some(KvsCommand::Exec),
+            _ => None,
+        },
+        "DISCARD" => match args {
+            [] => Some(KvsCommand::Discard),
+            _ => None,
+        },
+        _ => {
+            error!("cmd is invalid : {}", cmd);
+            None
+        }
+    }
+}
+
+/// A bulk string argument that is UTF-8
+fn string(data: &RespValue) -> Option<String> {
+    data.as_bulk_str().map(str::to_owned)
+}
+
+/// Whether an argument is the keyword `word`, in any case
+fn is(data: &RespValue, word: &str) -> bool {
+    data.as_bulk_str()
+        .is_some_and(|arg| arg.eq_ignore_ascii_case(word))
+}
+
+/// The bytes of a bulk string argument, UTF-8 or not
+fn bulk_bytes(data: &RespValue) -> Option<Vec<u8>> {
+    match data {
+        RespValue::BulkString(Some(bytes)) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+/// A number argument greater than zero, such as the TTL of SET
+fn positive(arg: &str) -> Option<u64> {
+    arg.parse().ok().filter(|n| *n > 0)
+}
+
+/// A bulk string argument that may be null, as a RESP2 null bulk string or
+/// a RESP3 null
+fn optional_string(data: &RespValue) -> Option<Option<String>> {
+    match data {
+        RespValue::BulkString(None) | RespValue::Null => Some(None),
+        data => string(data).map(Some),
+    }
+}
+
+/// `bytes` as they went over the wire, readable on one line: printable
+/// ASCII as is, `\r`, `\n`, `\t` and `\\` escaped and every other byte as
+/// `\xNN`
+pub fn escape_wire(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'\r' => escaped.push_str("\\r"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_command_round_trip() {
+    use crate::protocol::from_bytes;
+
+    let commands = [
+        KvsCommand::Set(
+            "key".to_owned(),
+            vec![0xff, 0],
+            ContentType::Json,
+            Some(Duration::from_secs(5)),
+        ),
+        KvsCommand::Cas("key".to_owned(), None, Some("new".to_owned())),
+        KvsCommand::Scan(None, 3),
+        KvsCommand::Bigkeys(5, Some(100)),
+        KvsCommand::Auth(Some("user".to_owned()), "secret".to_owned()),
+        KvsCommand::Shutdown(false),
+    ];
+    for command in commands {
+        let frame = command.to_frame();
+        let parsed = parse_command(&from_bytes(&frame).unwrap()).unwrap();
+        assert_eq!(parsed.to_frame(), frame);
+    }
+}
+
+#[test]
+fn test_null_arguments() {
+    use crate::protocol::from_bytes;
+
+    // a RESP2 null bulk string and a RESP3 null both stand for a missing key
+    for null in ["$-1\r\n", "_\r\n"] {
+        let frame = format!("*4\r\n$3\r\nCAS\r\n$1\r\nk\r\n{}$1\r\nv\r\n", null);
+        assert!(matches!(
+            parse_command(&from_bytes(frame.as_bytes()).unwrap()),
+            Some(KvsCommand::Cas(key, None, Some(new))) if key == "k" && new == "v"
+        ));
+    }
+    for frame in ["*2\r\n$3\r\nGET\r\n$-1\r\n", "*0\r\n", "*-1\r\n", "$-1\r\n"] {
+        assert!(parse_command(&from_bytes(frame.as_bytes()).unwrap()).is_none());
+    }
+}
@@ -0,0 +1,635 @@
+use std::ops::AddAssign;
+use std::ops::MulAssign;
+use std::str;
+
+use crate::protocol::error::RespError;
+use crate::protocol::error::Result;
+use serde::de;
+
+const ARRAY_PREFIX: u8 = b'*';
+const MAP_PREFIX: u8 = b'%';
+const CRLF: &[u8] = b"\r\n";
+
+pub struct SeqAccess<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
+    len: usize,
+    current: usize,
+}
+
+impl<'a, 'de> SeqAccess<'a, 'de> {
+    pub fn new(de: &'a mut Deserializer<'de>, len: usize) -> Self {
+        SeqAccess {
+            de,
+            len,
+            current: 0,
+        }
+    }
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for SeqAccess<'a, 'de> {
+    type Error = RespError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        if self.current >= self.len {
+            return Ok(None);
+        }
+        self.current += 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+pub struct MapAccess<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
+    len: usize,
+    current: usize,
+}
+
+impl<'a, 'de> MapAccess<'a, 'de> {
+    pub fn new(de: &'a mut Deserializer<'de>, len: usize) -> Self {
+        MapAccess {
+            de,
+            len,
+            current: 0,
+        }
+    }
+}
+
+impl<'de, 'a> de::MapAccess<'de> for MapAccess<'a, 'de> {
+    type Error = RespError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        if self.current >= self.len {
+            return Ok(None);
+        }
+        self.current += 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// Deserializes RESP from raw bytes, bulk strings are borrowed from the
+/// input as they are, whether or not they are UTF-8
+pub struct Deserializer<'de> {
+    pub input: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn from_bytes(input: &'de [u8]) -> Self {
+        Deserializer { input }
+    }
+
+    pub fn from_str(input: &'de str) -> Self {
+        Deserializer::from_bytes(input.as_bytes())
+    }
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn peek_byte(&mut self) -> Result<u8> {
+        self.input.first().copied().ok_or(RespError::Eof)
+    }
+
+    pub fn next_byte(&mut self) -> Result<u8> {
+        let byte = self.peek_byte()?;
+        self.input = &self.input[1..];
+        Ok(byte)
+    }
+
+    pub fn parse_bool(&mut self) -> Result<bool> {
+        if self.input.starts_with(b"#t\r\n") {
+            self.input = &self.input[b"#t\r\n".len()..];
+            return Ok(true);
+        } else if self.input.starts_with(b"#f\r\n") {
+            self.input = &self.input[b"#f\r\n".len()..];
+            return Ok(false);
+        }
+        Err(RespError::ExpectedBoolean)
+    }
+
+    pub fn parse_unsigned<T>(&mut self) -> Result<T>
+    where
+        T: AddAssign<T> + MulAssign + From<u8>,
+    {
+        if self.next_byte()? != b':' {
+            return Err(RespError::ExpectedInteger);
+        }
+        if self.peek_byte()? == b'+' {
+            self.next_byte()?;
+        }
+
+        let mut int = match self.next_byte()? {
+            digit @ b'0'..=b'9' => T::from(digit - b'0'),
+            _ => {
+                return Err(RespError::ExpectedInteger);
+            }
+        };
+        loop {
+            match self.input.first() {
+                Some(&digit @ b'0'..=b'9') => {
+                    self.input = &self.input[1..];
+                    int *= T::from(10);
+                    int += T::from(digit - b'0');
+                }
+                Some(_) => {
+                    self.parse_crlf()?;
+                    return Ok(int);
+                }
+                None => return Err(RespError::Eof),
+            }
+        }
+    }
+
+    fn parse_crlf(&mut self) -> Result<()> {
+        if self.input.starts_with(CRLF) {
+            self.input = &self.input[CRLF.len()..];
+            Ok(())
+        } else if CRLF.starts_with(self.input) {
+            Err(RespError::Eof)
+        } else {
+            Err(RespError::ExpectedCRLF)
+        }
+    }
+
+    /// Parses an integer that may carry a `-` or `+` sign, `:-42\r\n`,
+    /// failing on one that does not fit in `T`
+    pub fn parse_signed<T>(&mut self) -> Result<T>
+    where
+        T: TryFrom<i64>,
+    {
+        if self.next_byte()? != b':' {
+            return Err(RespError::ExpectedInteger);
+        }
+        let line = self.parse_line()?;
+        let int: i64 = line.parse().map_err(|_| RespError::ExpectedInteger)?;
+        T::try_from(int).map_err(|_| RespError::Message(format!("integer {} out of range", int)))
+    }
+
+    pub fn parse_string(&mut self) -> Result<&'de str> {
+        if self.next_byte()? != b'+' {
+            return Err(RespError::ExpectedSimpleString);
+        }
+        self.parse_line()
+    }
+
+    pub fn parse_error(&mut self) -> Result<&'de str> {
+        if self.next_byte()? != b'-' {
+            return Err(RespError::Syntax);
+        }
+        self.parse_line()
+    }
+
+    /// Parses the length that follows `prefix` in an aggregate header like
+    /// `*2\r\n` or `%1\r\n`
+    pub fn parse_len(&mut self, prefix: u8) -> Result<usize> {
+        if self.next_byte()? != prefix {
+            return Err(RespError::Syntax);
+        }
+        let line = self.parse_line()?;
+        line.parse().map_err(|_| RespError::ExpectedInteger)
+    }
+
+    /// Parses a RESP3 double, `,1.5\r\n`, `,inf\r\n` or `,nan\r\n`
+    pub fn parse_double(&mut self) -> Result<f64> {
+        if self.next_byte()? != b',' {
+            return Err(RespError::ExpectedDouble);
+        }
+        self.parse_line()?
+            .parse()
+            .map_err(|_| RespError::ExpectedDouble)
+    }
+
+    /// Parses a double as RESP3 sends it, or as the bulk string RESP2 peers
+    /// get instead, or an integer
+    pub fn parse_float(&mut self) -> Result<f64> {
+        match self.peek_byte()? {
+            b',' => self.parse_double(),
+            b'$' => str::from_utf8(self.parse_bytes()?)
+                .ok()
+                .and_then(|double| double.parse().ok())
+                .ok_or(RespError::ExpectedDouble),
+            b':' => Ok(self.parse_signed::<i64>()? as f64),
+            _ => Err(RespError::ExpectedDouble),
+        }
+    }
+
+    /// Parses a RESP3 big number, the digits are left for the caller to
+    /// interpret
+    pub fn parse_big_number(&mut self) -> Result<&'de str> {
+        if self.next_byte()? != b'(' {
+            return Err(RespError::ExpectedBigNumber);
+        }
+        let digits = self.parse_line()?;
+        let unsigned = digits.strip_prefix('-').unwrap_or(digits);
+        if unsigned.is_empty() || !unsigned.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RespError::ExpectedBigNumber);
+        }
+        Ok(digits)
+    }
+
+    /// Takes everything up to the next CRLF and consumes the CRLF. Lines
+    /// hold text, unlike bulk strings they have to be UTF-8.
+    fn parse_line(&mut self) -> Result<&'de str> {
+        let len = self
+            .input
+            .windows(CRLF.len())
+            .position(|window| window == CRLF)
+            .ok_or(RespError::Eof)?;
+        let line = str::from_utf8(&self.input[..len])
+            .map_err(|_| RespError::Message("invalid utf-8 in line".into()))?;
+        self.input = &self.input[len + CRLF.len()..];
+        Ok(line)
+    }
+
+    /// Parses a bulk string, `$3\r\nfoo\r\n`, borrowing its bytes from the
+    /// input
+    pub fn parse_bytes(&mut self) -> Result<&'de [u8]> {
+        if self.peek_byte()? != b'$' {
+            return Err(RespError::ExpectedBulkString);
+        }
+        let len = self.parse_len(b'$')?;
+        if self.input.len() < len.saturating_add(CRLF.len()) {
+            return Err(RespError::Eof);
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        self.parse_crlf()?;
+        Ok(bytes)
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
+    type Error = RespError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.peek_byte()? {
+            b':' => self.deserialize_i64(visitor),
+            b'#' => self.deserialize_bool(visitor),
+            b'$' => self.deserialize_bytes(visitor),
+            b'+' => self.deserialize_str(visitor),
+            b'*' => self.deserialize_seq(visitor),
+            b'-' => Err(RespError::ErrorReply(self.parse_error()?.to_string())),
+            b',' => self.deserialize_f64(visitor),
+            b'(' => visitor.visit_borrowed_str(self.parse_big_number()?),
+            b'%' => self.deserialize_map(visitor),
+            b'_' => self.deserialize_unit(visitor),
+            _ => Err(RespError::Syntax),
+        }
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        unimplemented!()
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_i64(self.parse_signed()?)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_i32(self.parse_signed()?)
+    }
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_i16(self.parse_signed()?)
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_i8(self.parse_signed()?)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_u64(self.parse_unsigned()?)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_u32(self.parse_unsigned()?)
+    }
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_u16(self.parse_unsigned()?)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_u8(self.parse_unsigned()?)
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_bool(self.parse_bool()?)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_f32(self.parse_float()? as f32)
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_f64(self.parse_float()?)
+    }
+
+    // The `Serializer` implementation on the previous page serialized chars as
+    // single-character strings so handle that representation here.
+    fn deserialize_char<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        // Parse a string, check that it is one character, call `visit_char`.
+        unimplemented!()
+    }
+
+    // Refer to the "Understanding deserializer lifetimes" page for information
+    // about the three deserialization flavors of strings in Serde.
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.peek_byte()? {
+            b'$' => match str::from_utf8(self.parse_bytes()?) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => Err(RespError::Message("invalid utf-8 in bulk string".into())),
+            },
+            _ => visitor.visit_borrowed_str(self.parse_string()?),
+        }
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_borrowed_bytes(self.parse_bytes()?)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_bytes(visitor)
+    }
+    fn deserialize_option<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        if self.input.starts_with(b"_\r\n") {
+            self.input = &self.input[b"_\r\n".len()..];
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    // In Serde, unit means an anonymous value containing no data.
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        if self.input.starts_with(b"_\r\n") {
+            self.input = &self.input[b"_\r\n".len()..];
+            visitor.visit_unit()
+        } else {
+            Err(RespError::ExpectedNull)
+        }
+    }
+
+    // Unit struct means a named value containing no data.
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        _visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        unimplemented!()
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        if self.peek_byte()? != ARRAY_PREFIX {
+            return Err(RespError::ExpectedArray);
+        }
+        let len = self.parse_len(ARRAY_PREFIX)?;
+        let seq = SeqAccess::new(self, len);
+        visitor.visit_seq(seq)
+    }
+
+    fn deserialize_identifier<V>(self, _visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        unimplemented!()
+    }
+    fn deserialize_map<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        if self.peek_byte()? != MAP_PREFIX {
+            return Err(RespError::ExpectedMap);
+        }
+        let len = self.parse_len(MAP_PREFIX)?;
+        visitor.visit_map(MapAccess::new(self, len))
+    }
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        _visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        unimplemented!()
+    }
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        unimplemented!()
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_tuple<V>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        unimplemented!()
+    }
+}
+
+#[test]
+fn test_signed_integers() -> Result<()> {
+    use crate::protocol::{from_str, to_string, RespValue};
+    use serde::Deserialize;
+
+    for int in [0i64, 42, -42, i64::MIN, i64::MAX] {
+        let resp = to_string(&int)?;
+        assert_eq!(resp, format!(":{}\r\n", int));
+        assert_eq!(i64::deserialize(&mut Deserializer::from_str(&resp))?, int);
+        assert!(matches!(from_str(&resp)?, RespValue::Integer(i) if i == int));
+        assert_eq!(to_string(&RespValue::Integer(int))?, resp);
+    }
+    assert_eq!(i32::deserialize(&mut Deserializer::from_str(":+7\r\n"))?, 7);
+    assert_eq!(
+        i8::deserialize(&mut Deserializer::from_str(":-128\r\n"))?,
+        -128
+    );
+    assert!(i8::deserialize(&mut Deserializer::from_str(":128\r\n")).is_err());
+    assert!(i64::deserialize(&mut Deserializer::from_str(":-\r\n")).is_err());
+    assert!(matches!(
+        i64::deserialize(&mut Deserializer::from_str(":-4")),
+        Err(RespError::Eof)
+    ));
+    // a value of unknown type, such as one element of a reply
+    let mut de = Deserializer::from_str("*2\r\n:-1\r\n,-0.5\r\n");
+    serde::de::IgnoredAny::deserialize(&mut de)?;
+    assert!(de.input.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_float_round_trip() -> Result<()> {
+    use crate::protocol::{to_string_with, Protocol};
+    use serde::Deserialize;
+
+    for protocol in [Protocol::Resp2, Protocol::Resp3] {
+        for double in [
+            0.0,
+            1.5,
+            -2.25,
+            0.1,
+            1e300,
+            -1e-300,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            let resp = to_string_with(&double, protocol)?;
+            assert_eq!(
+                f64::deserialize(&mut Deserializer::from_str(&resp))?,
+                double
+            );
+        }
+        let resp = to_string_with(&f64::NAN, protocol)?;
+        assert!(f64::deserialize(&mut Deserializer::from_str(&resp))?.is_nan());
+        let resp = to_string_with(&-0.75f32, protocol)?;
+        assert_eq!(f32::deserialize(&mut Deserializer::from_str(&resp))?, -0.75);
+    }
+    assert_eq!(
+        f64::deserialize(&mut Deserializer::from_str(":-3\r\n"))?,
+        -3.0
+    );
+    assert!(f64::deserialize(&mut Deserializer::from_str("$3\r\nabc\r\n")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_binary_bulk_strings() -> Result<()> {
+    use crate::protocol::{from_bytes, from_bytes_prefix, RespValue};
+    use serde::Deserialize;
+
+    let input = b"$4\r\n\xff\x00\r\n\r\n";
+    let bytes = <&[u8]>::deserialize(&mut Deserializer::from_bytes(input))?;
+    assert_eq!(bytes, b"\xff\x00\r\n");
+    // borrowed from the input rather than copied
+    assert_eq!(bytes.as_ptr(), input[4..].as_ptr());
+    assert!(matches!(
+        from_bytes(input)?,
+        RespValue::BulkString(Some(value)) if value == b"\xff\x00\r\n"
+    ));
+
+    let s = <&str>::deserialize(&mut Deserializer::from_str("$6\r\ncaf\u{e9}!\r\n"))?;
+    assert_eq!(s, "caf\u{e9}!");
+    assert!(<&str>::deserialize(&mut Deserializer::from_bytes(input)).is_err());
+
+    // a value cut short anywhere waits for more input
+    let frame = b"*2\r\n$2\r\n\xc3\xa9\r\n:1\r\n";
+    for end in 0..frame.len() {
+        assert!(matches!(
+            from_bytes_prefix(&frame[..end]),
+            Err(RespError::Eof)
+        ));
+    }
+    let (value, rest) = from_bytes_prefix(b"$1\r\n\x80\r\n+OK\r\n")?;
+    assert!(matches!(value, RespValue::BulkString(Some(v)) if v == b"\x80"));
+    assert_eq!(rest, b"+OK\r\n");
+    Ok(())
+}
@@ -0,0 +1,240 @@
+use std::io::{self, Read};
+use std::str;
+
+use crate::protocol::error::{RespError, Result};
+
+const CRLF: &[u8] = b"\r\n";
+
+/// Bytes read at a time by `FrameDecoder::read_from`
+const READ_SIZE: usize = 4096;
+
+/// What `FrameDecoder::next_frame` found at the start of its buffer
+#[derive(Debug, PartialEq)]
+pub enum Decoded<T> {
+    Frame(T),
+    /// The buffer ends in the middle of a frame, or holds none at all
+    NeedMoreData,
+}
+
+/// Splits bytes read from a connection into whole RESP frames, however the
+/// reads happen to cut them.
+///
+/// Bytes go in with `feed`, or `read_from` a blocking reader, and complete
+/// frames come out of `next_frame` for the caller to parse. A frame that
+/// arrives over many reads is scanned once, the scan resumes where the
+/// previous one ran out of data rather than starting over.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // bytes at the start of `buffer` already handed out as frames
+    consumed: usize,
+    // bytes of the frame being decoded already scanned, and the number of
+    // values still missing from it
+    scanned: usize,
+    missing: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            consumed: 0,
+            scanned: 0,
+            missing: 1,
+        }
+    }
+
+    /// Appends `bytes` to the buffer
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.compact();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Reads once from `reader` into the buffer and returns the number of
+    /// bytes read, 0 once the reader is at its end
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        self.compact();
+        let len = self.buffer.len();
+        self.buffer.resize(len + READ_SIZE, 0);
+        let read = reader.read(&mut self.buffer[len..]);
+        self.buffer.truncate(len + *read.as_ref().unwrap_or(&0));
+        read
+    }
+
+    /// Bytes fed but not handed out as frames yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.consumed
+    }
+
+    /// Takes the next complete frame off the buffer, or tells the caller to
+    /// feed more bytes. Fails on bytes that can't start a RESP value, after
+    /// which the connection is out of step and the decoder of no more use.
+    pub fn next_frame(&mut self) -> Result<Decoded<&[u8]>> {
+        let input = &self.buffer[self.consumed..];
+        while self.missing > 0 {
+            match scan_value(&input[self.scanned..])? {
+                Some((len, values)) => {
+                    self.scanned += len;
+                    self.missing = (self.missing - 1)
+                        .checked_add(values)
+                        .ok_or_else(|| RespError::Message("frame too large".into()))?;
+                }
+                None => return Ok(Decoded::NeedMoreData),
+            }
+        }
+        let frame = &self.buffer[self.consumed..self.consumed + self.scanned];
+        self.consumed += self.scanned;
+        self.scanned = 0;
+        self.missing = 1;
+        Ok(Decoded::Frame(frame))
+    }
+
+    /// Drops the frames already handed out from the buffer
+    fn compact(&mut self) {
+        if self.consumed > 0 {
+            self.buffer.drain(..self.consumed);
+            self.consumed = 0;
+        }
+    }
+}
+
+/// Scans the value header at the start of `input`, along with the payload of
+/// a bulk string. Returns the bytes it spans and the number of values nested
+/// in it that follow, or `None` if `input` ends before the header does.
+fn scan_value(input: &[u8]) -> Result<Option<(usize, usize)>> {
+    let end = match input.windows(CRLF.len()).position(|window| window == CRLF) {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let line_len = end + CRLF.len();
+    match input[0] {
+        b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => Ok(Some((line_len, 0))),
+        // bulk strings, verbatim strings and bulk errors
+        b'$' | b'=' | b'!' => match scan_len(&input[1..end])? {
+            None => Ok(Some((line_len, 0))),
+            Some(len) => {
+                let total = line_len
+                    .checked_add(len)
+                    .and_then(|total| total.checked_add(CRLF.len()))
+                    .ok_or_else(|| RespError::Message("bulk string too large".into()))?;
+                if input.len() < total {
+                    Ok(None)
+                } else if &input[total - CRLF.len()..total] != CRLF {
+                    Err(RespError::ExpectedCRLF)
+                } else {
+                    Ok(Some((total, 0)))
+                }
+            }
+        },
+        // arrays, pushes and sets
+        b'*' | b'>' | b'~' => Ok(Some((line_len, scan_len(&input[1..end])?.unwrap_or(0)))),
+        b'%' => {
+            let pairs = scan_len(&input[1..end])?.unwrap_or(0);
+            let values = pairs
+                .checked_mul(2)
+                .ok_or_else(|| RespError::Message("map too large".into()))?;
+            Ok(Some((line_len, values)))
+        }
+        _ => Err(RespError::Syntax),
+    }
+}
+
+/// Parses the length in a header, `None` for the `-1` of a RESP2 null
+fn scan_len(digits: &[u8]) -> Result<Option<usize>> {
+    if digits == b"-1" {
+        return Ok(None);
+    }
+    str::from_utf8(digits)
+        .ok()
+        .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|digits| digits.parse().ok())
+        .map(Some)
+        .ok_or(RespError::ExpectedInteger)
+}
+
+#[test]
+fn test_frames_split_across_reads() -> Result<()> {
+    let frames: &[&[u8]] = &[
+        b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\nv\r\nx\r\n",
+        b"+OK\r\n",
+        b"$-1\r\n",
+        b"*-1\r\n",
+        b"*2\r\n*1\r\n:-1\r\n%1\r\n+a\r\n_\r\n",
+        b"$0\r\n\r\n",
+        b">2\r\n+message\r\n,1.5\r\n",
+    ];
+    let stream = frames.concat();
+    // every way of cutting the stream in two, and one byte at a time
+    for cut in 0..=stream.len() {
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        decoder.feed(&stream[..cut]);
+        while let Decoded::Frame(frame) = decoder.next_frame()? {
+            decoded.push(frame.to_vec());
+        }
+        decoder.feed(&stream[cut..]);
+        while let Decoded::Frame(frame) = decoder.next_frame()? {
+            decoded.push(frame.to_vec());
+        }
+        assert_eq!(decoded, frames);
+        assert_eq!(decoder.buffered(), 0);
+    }
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = Vec::new();
+    for byte in &stream {
+        decoder.feed(&[*byte]);
+        if let Decoded::Frame(frame) = decoder.next_frame()? {
+            decoded.push(frame.to_vec());
+        }
+    }
+    assert_eq!(decoded, frames);
+    Ok(())
+}
+
+#[test]
+fn test_large_frame_from_reader() -> Result<()> {
+    let value = vec![b'x'; 100_000];
+    let mut stream = format!("*2\r\n$3\r\nGET\r\n${}\r\n", value.len()).into_bytes();
+    stream.extend_from_slice(&value);
+    stream.extend_from_slice(b"\r\n+OK\r\n");
+
+    let mut reader = &stream[..];
+    let mut decoder = FrameDecoder::new();
+    let mut reads = 0;
+    let frame = loop {
+        match decoder.next_frame()? {
+            Decoded::Frame(frame) => break frame.to_vec(),
+            Decoded::NeedMoreData => {
+                assert!(decoder.read_from(&mut reader).unwrap() > 0);
+                reads += 1;
+            }
+        }
+    };
+    assert!(reads > 1);
+    assert_eq!(frame, stream[..stream.len() - 5]);
+    assert_eq!(decoder.next_frame()?, Decoded::Frame(&b"+OK\r\n"[..]));
+    assert_eq!(decoder.next_frame()?, Decoded::NeedMoreData);
+    assert_eq!(decoder.read_from(&mut reader).unwrap(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_invalid_frames() {
+    for input in [
+        &b"?\r\n"[..],
+        b"$x\r\n",
+        b"*-2\r\n",
+        b"$1\r\nab\r\n",
+        b"\r\n",
+    ] {
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(input);
+        assert!(decoder.next_frame().is_err(), "{:?}", input);
+    }
+}
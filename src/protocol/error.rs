@@ -8,6 +8,8 @@ pub type Result<T> = std::result::Result<T, RespError>;
 #[derive(Debug)]
 pub enum RespError {
     Message(String),
+    /// The input was an error reply, `-ERR ...`, rather than a value
+    ErrorReply(String),
 
     Eof,
     Syntax,
@@ -17,6 +19,9 @@ pub enum RespError {
     ExpectedSimpleString,
     ExpectedBulkString,
     ExpectedBoolean,
+    ExpectedDouble,
+    ExpectedBigNumber,
+    ExpectedMap,
     TrailingCharacters,
     ExpectedNull,
 }
@@ -43,6 +48,7 @@ impl Display for RespError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             RespError::Message(msg) => f.write_str(&msg),
+            RespError::ErrorReply(msg) => write!(f, "error reply: {}", msg),
             RespError::Eof => f.write_str("unexpected end of input"),
             RespError::Syntax => f.write_str("syntax does not follow RESP"),
             RespError::ExpectedCRLF => f.write_str("expected (CRLF)/\r/\n in the end"),
@@ -55,6 +61,9 @@ impl Display for RespError {
                 f.write_str("trailing characaters left in input while deserializing")
             }
             RespError::ExpectedBoolean => f.write_str("expected boolean"),
+            RespError::ExpectedDouble => f.write_str("expected double"),
+            RespError::ExpectedBigNumber => f.write_str("expected big number"),
+            RespError::ExpectedMap => f.write_str("invalid content expected a map"),
             RespError::ExpectedBulkString => f.write_str("expted bulkstring"),
             RespError::ExpectedNull => f.write_str("expected null"),
         }
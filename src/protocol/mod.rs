@@ -0,0 +1,162 @@
+mod command;
+mod de;
+mod decoder;
+mod error;
+mod ser;
+
+// pub use de::{from_string, DeSerializer};
+pub use crate::protocol::command::{
+    command_frame, escape_wire, parse_command, KvsCommand, DICT_SAMPLE,
+};
+pub use crate::protocol::de::{Deserializer, MapAccess, SeqAccess};
+pub use crate::protocol::decoder::{Decoded, FrameDecoder};
+pub use crate::protocol::error::RespError;
+pub use crate::protocol::ser::{to_string, to_string_with, Serializer};
+use serde::{
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Serialize,
+};
+
+/// Newtype struct names the serializer writes as RESP3 big numbers and push
+/// frames, other serializers see plain newtypes
+const BIG_NUMBER: &str = "$resp::BigNumber";
+const PUSH: &str = "$resp::Push";
+
+/// Protocol version spoken on a connection, negotiated with HELLO
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+#[derive(Deserialize, Debug)]
+pub enum RespValue {
+    SimpleString(String),        // tuple variant
+    Err(String),                 // tuple variant
+    Integer(i64),                // tuple variant
+    BulkString(Option<Vec<u8>>), // tuple variant
+    Array(Option<Vec<RespValue>>),
+    // RESP3 types, sent in their closest RESP2 form to RESP2 peers
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    Map(Vec<(RespValue, RespValue)>),
+    Push(Vec<RespValue>),
+}
+
+impl RespValue {
+    /// The text of a bulk string that is UTF-8, as command arguments are
+    pub fn as_bulk_str(&self) -> Option<&str> {
+        match self {
+            RespValue::BulkString(Some(bytes)) => std::str::from_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for RespValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            RespValue::SimpleString(s) => serializer.serialize_str(s),
+            RespValue::Err(e) => serializer.serialize_str(e),
+            RespValue::Integer(i) => serializer.serialize_i64(*i),
+            RespValue::BulkString(opt) => match opt {
+                None => serializer.serialize_none(),
+                Some(bytes) => serializer.serialize_bytes(&bytes),
+            },
+            RespValue::Array(opt) => match opt {
+                None => serializer.serialize_none(),
+                Some(arr) => {
+                    let mut seq = serializer.serialize_seq(Some(arr.len()))?;
+                    for value in arr {
+                        seq.serialize_element(value)?;
+                    }
+                    seq.end()
+                }
+            },
+            RespValue::Null => serializer.serialize_none(),
+            RespValue::Boolean(b) => serializer.serialize_bool(*b),
+            RespValue::Double(d) => serializer.serialize_f64(*d),
+            RespValue::BigNumber(digits) => {
+                serializer.serialize_newtype_struct(BIG_NUMBER, digits.as_str())
+            }
+            RespValue::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            RespValue::Push(values) => serializer.serialize_newtype_struct(PUSH, values),
+        }
+    }
+}
+
+pub fn from_str(s: &str) -> error::Result<RespValue> {
+    from_bytes(s.as_bytes())
+}
+
+pub fn from_bytes(input: &[u8]) -> error::Result<RespValue> {
+    let mut deserializer = Deserializer::from_bytes(input);
+    parse_value(&mut deserializer)
+}
+
+/// Parses the value at the start of `input` and returns it along with the
+/// rest of `input`, for values sent back to back such as pipelined replies
+pub fn from_bytes_prefix(input: &[u8]) -> error::Result<(RespValue, &[u8])> {
+    let mut deserializer = Deserializer::from_bytes(input);
+    let value = parse_value(&mut deserializer)?;
+    Ok((value, deserializer.input))
+}
+
+fn parse_value(deserializer: &mut Deserializer) -> error::Result<RespValue> {
+    let value = match deserializer.peek_byte()? {
+        b':' => RespValue::Integer(deserializer.parse_signed()?),
+        b'$' if deserializer.input.starts_with(b"$-1\r\n") => {
+            deserializer.input = &deserializer.input[b"$-1\r\n".len()..];
+            RespValue::BulkString(None)
+        }
+        b'$' => RespValue::BulkString(Some(deserializer.parse_bytes()?.to_vec())),
+        b'+' => RespValue::SimpleString(deserializer.parse_string()?.to_string()),
+        b'-' => RespValue::Err(deserializer.parse_error()?.to_string()),
+        b'_' => {
+            if !deserializer.input.starts_with(b"_\r\n") {
+                return Err(error::RespError::ExpectedNull);
+            }
+            deserializer.input = &deserializer.input[b"_\r\n".len()..];
+            RespValue::Null
+        }
+        b'#' => RespValue::Boolean(deserializer.parse_bool()?),
+        b',' => RespValue::Double(deserializer.parse_double()?),
+        b'(' => RespValue::BigNumber(deserializer.parse_big_number()?.to_string()),
+        b'*' => {
+            if deserializer.input.starts_with(b"*-1\r\n") {
+                deserializer.input = &deserializer.input[b"*-1\r\n".len()..];
+                return Ok(RespValue::Array(None));
+            }
+            RespValue::Array(Some(parse_values(deserializer, b'*')?))
+        }
+        b'>' => RespValue::Push(parse_values(deserializer, b'>')?),
+        b'%' => {
+            let len = deserializer.parse_len(b'%')?;
+            let mut entries = Vec::with_capacity(len);
+            for _ in 0..len {
+                let key = parse_value(deserializer)?;
+                entries.push((key, parse_value(deserializer)?));
+            }
+            RespValue::Map(entries)
+        }
+        _ => return Err(error::RespError::Syntax),
+    };
+    Ok(value)
+}
+
+fn parse_values(deserializer: &mut Deserializer, prefix: u8) -> error::Result<Vec<RespValue>> {
+    let len = deserializer.parse_len(prefix)?;
+    (0..len).map(|_| parse_value(deserializer)).collect()
+}
@@ -0,0 +1,443 @@
+use serde::{ser, Serialize};
+
+use crate::protocol::error::{RespError, Result};
+use crate::protocol::{Protocol, BIG_NUMBER, PUSH};
+
+pub struct Serializer {
+    protocol: Protocol,
+}
+
+impl Serializer {
+    pub fn new(protocol: Protocol) -> Self {
+        Serializer { protocol }
+    }
+}
+
+/// Serializes `value` as RESP2, RESP3 only types are sent in their RESP2
+/// form
+pub fn to_string<T>(value: &T) -> Result<String>
+where
+    T: Serialize,
+{
+    to_string_with(value, Protocol::Resp2)
+}
+
+pub fn to_string_with<T>(value: &T, protocol: Protocol) -> Result<String>
+where
+    T: Serialize,
+{
+    let mut serializer = Serializer::new(protocol);
+    let output = value.serialize(&mut serializer)?;
+    Ok(output)
+}
+
+fn bulk_string(s: &str) -> String {
+    format!("${}\r\n{}\r\n", s.len(), s)
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = String;
+    type Error = RespError;
+
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = ser::Impossible<String, RespError>;
+    type SerializeTupleStruct = ser::Impossible<String, RespError>;
+    type SerializeTupleVariant = ser::Impossible<String, RespError>;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = ser::Impossible<String, RespError>;
+
+    fn serialize_char(self, _v: char) -> Result<String> {
+        Err(RespError::Message("RESP does not support char".into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<String> {
+        let double = if v.is_nan() {
+            "nan".to_string()
+        } else {
+            v.to_string()
+        };
+        match self.protocol {
+            Protocol::Resp2 => Ok(bulk_string(&double)),
+            Protocol::Resp3 => Ok(format!(",{}\r\n", double)),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<String> {
+        self.serialize_f64(f64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String> {
+        let mut output = String::from(":");
+        output += &v.to_string();
+        output += "\r\n";
+        Ok(output)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String> {
+        let output = format!(":{}\r\n", v);
+        Ok(output)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<String> {
+        if self.protocol == Protocol::Resp2 {
+            return self.serialize_u8(v as u8);
+        }
+        let b = if v { "t" } else { "f" };
+        let output = format!("#{}\r\n", b);
+        Ok(output)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String> {
+        let output = format!("+{}\r\n", v);
+        Ok(output)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<String> {
+        let mut output = format!("${}\r\n", v.len());
+        for &byte in v {
+            output.push(byte as char);
+        }
+        output.push_str("\r\n");
+        Ok(output)
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        match self.protocol {
+            Protocol::Resp2 => Ok("$-1\r\n".into()),
+            Protocol::Resp3 => Ok("_\r\n".into()),
+        }
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        self.serialize_none()
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        let output = value.serialize(&mut *self)?;
+        match (name, self.protocol) {
+            // the digits come back as a simple string, `+123\r\n`
+            (BIG_NUMBER, Protocol::Resp2) => Ok(bulk_string(&output[1..output.len() - 2])),
+            (BIG_NUMBER, Protocol::Resp3) => Ok(format!("({}", &output[1..])),
+            // and the pushed values as an array, `*2\r\n...`
+            (PUSH, Protocol::Resp3) => Ok(format!(">{}", &output[1..])),
+            _ => Ok(output),
+        }
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        match len {
+            None => Ok(SeqSerializer {
+                output: "*-\r\n".into(),
+                elements: Vec::new(),
+                protocol: self.protocol,
+            }),
+            Some(_) => Ok(SeqSerializer {
+                output: String::new(),
+                elements: Vec::new(),
+                protocol: self.protocol,
+            }),
+        }
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(RespError::Message("RESP doesn't support tuples".into()))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(RespError::Message(
+            "RESP doesn't support tuple struct".into(),
+        ))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(RespError::Message(
+            "RESP doesn't support tuple variants".into(),
+        ))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(RespError::Message(
+            "RESP doesn't support tuple variants".into(),
+        ))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(MapSerializer {
+            entries: Vec::new(),
+            protocol: self.protocol,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        self.serialize_map(Some(len))
+    }
+}
+
+pub struct SeqSerializer {
+    pub output: String,
+    pub elements: Vec<String>,
+    protocol: Protocol,
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = String;
+    type Error = RespError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let mut ser = Serializer::new(self.protocol);
+        let element = value.serialize(&mut ser)?;
+        self.elements.push(element);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        let mut output = format!("*{}\r\n", self.elements.len());
+        for element in self.elements {
+            output += &element;
+        }
+
+        Ok(output)
+    }
+}
+
+/// Writes maps as RESP3 `%` maps, or as flat key value arrays for RESP2.
+/// Structs are written the same way, keyed by their field names.
+pub struct MapSerializer {
+    entries: Vec<String>,
+    protocol: Protocol,
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = String;
+    type Error = RespError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let mut ser = Serializer::new(self.protocol);
+        self.entries.push(key.serialize(&mut ser)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let mut ser = Serializer::new(self.protocol);
+        self.entries.push(value.serialize(&mut ser)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        let mut output = match self.protocol {
+            Protocol::Resp2 => format!("*{}\r\n", self.entries.len()),
+            Protocol::Resp3 => format!("%{}\r\n", self.entries.len() / 2),
+        };
+        for entry in self.entries {
+            output += &entry;
+        }
+        Ok(output)
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = String;
+    type Error = RespError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeMap::serialize_entry(self, key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+#[test]
+fn test_enum() -> Result<()> {
+    use crate::protocol::ser::to_string;
+    use crate::protocol::RespValue;
+
+    let x = RespValue::Array(Some(vec![
+        RespValue::Integer(69),
+        RespValue::SimpleString("OK".into()),
+    ]));
+    let resp_string = to_string(&x)?;
+    println!("{:?}", resp_string);
+    Ok(())
+}
+
+#[test]
+fn test_resp3_downgrade() -> Result<()> {
+    use crate::protocol::{from_str, to_string_with, Protocol, RespValue};
+
+    let x = RespValue::Map(vec![
+        (RespValue::SimpleString("pi".into()), RespValue::Double(3.5)),
+        (
+            RespValue::SimpleString("big".into()),
+            RespValue::BigNumber("12345678901234567890".into()),
+        ),
+        (RespValue::SimpleString("none".into()), RespValue::Null),
+    ]);
+    let resp3 = to_string_with(&x, Protocol::Resp3)?;
+    assert_eq!(
+        resp3,
+        "%3\r\n+pi\r\n,3.5\r\n+big\r\n(12345678901234567890\r\n+none\r\n_\r\n"
+    );
+    assert_eq!(
+        to_string_with(&x, Protocol::Resp2)?,
+        "*6\r\n+pi\r\n$3\r\n3.5\r\n+big\r\n$20\r\n12345678901234567890\r\n+none\r\n$-1\r\n"
+    );
+    assert!(matches!(from_str(&resp3)?, RespValue::Map(entries) if entries.len() == 3));
+
+    let push = RespValue::Push(vec![RespValue::Integer(1)]);
+    assert_eq!(to_string_with(&push, Protocol::Resp3)?, ">1\r\n:1\r\n");
+    assert_eq!(to_string_with(&push, Protocol::Resp2)?, "*1\r\n:1\r\n");
+    Ok(())
+}
+
+#[test]
+fn test_error_reply() {
+    use crate::protocol::{from_str, Deserializer, RespValue};
+    use serde::Deserialize;
+
+    assert!(matches!(
+        from_str("*2\r\n:1\r\n-ERR invalid command\r\n"),
+        Ok(RespValue::Array(Some(values))) if matches!(&values[1], RespValue::Err(e) if e == "ERR invalid command")
+    ));
+    let mut de = Deserializer::from_str("-Key not found\r\n");
+    assert!(matches!(
+        serde::de::IgnoredAny::deserialize(&mut de),
+        Err(RespError::ErrorReply(e)) if e == "Key not found"
+    ));
+}
+
+#[test]
+fn test_structs_and_maps() -> Result<()> {
+    use crate::protocol::{from_str, to_string_with, Protocol, RespValue};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct Keyspace {
+        keys: u64,
+        expires: Option<u64>,
+        hit_ratio: f64,
+    }
+
+    #[derive(Serialize)]
+    struct Info {
+        role: &'static str,
+        keyspace: BTreeMap<String, Keyspace>,
+    }
+
+    let mut keyspace = BTreeMap::new();
+    keyspace.insert(
+        "db0".to_string(),
+        Keyspace {
+            keys: 3,
+            expires: None,
+            hit_ratio: 0.5,
+        },
+    );
+    let info = Info {
+        role: "master",
+        keyspace,
+    };
+    let resp3 = to_string_with(&info, Protocol::Resp3)?;
+    assert_eq!(
+        resp3,
+        "%2\r\n+role\r\n+master\r\n+keyspace\r\n%1\r\n+db0\r\n\
+         %3\r\n+keys\r\n:3\r\n+expires\r\n_\r\n+hit_ratio\r\n,0.5\r\n"
+    );
+    assert!(matches!(from_str(&resp3)?, RespValue::Map(entries) if entries.len() == 2));
+    assert_eq!(
+        to_string_with(&info, Protocol::Resp2)?,
+        "*4\r\n+role\r\n+master\r\n+keyspace\r\n*2\r\n+db0\r\n\
+         *6\r\n+keys\r\n:3\r\n+expires\r\n$-1\r\n+hit_ratio\r\n$3\r\n0.5\r\n"
+    );
+    Ok(())
+}
@@ -0,0 +1,509 @@
+//! Importer of redis persistence files, so the string keys of a redis
+//! instance move into an engine without a script of one's own
+//!
+//! Reads an AOF, the RESP commands redis appends as it writes, an RDB
+//! snapshot, or an AOF that starts with an RDB preamble. Only string keys
+//! are imported: commands on other types are skipped and counted, as are
+//! commands on keys that are not UTF-8, while an RDB holding a key of
+//! another type fails the import since its encoding is not read. The RDB
+//! checksum is not checked.
+//!
+//! The file is replayed in memory first and only the keys it leaves behind
+//! are written, those that expired along the way are not. Commands apply to
+//! the keys the file wrote, not to keys the engine held before: a `DEL` of
+//! such a key removes it, a `FLUSHALL` leaves it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::{self, FromStr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::client::Command;
+use crate::engines::{ContentType, KvsEngine};
+use crate::protocol::{self, RespError, RespValue};
+use crate::{KvsError, Result};
+
+const RDB_MAGIC: &[u8; 5] = b"REDIS";
+
+/// The only value type an RDB is read with
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_OPCODE_IDLE: u8 = 248;
+const RDB_OPCODE_FREQ: u8 = 249;
+const RDB_OPCODE_AUX: u8 = 250;
+const RDB_OPCODE_RESIZEDB: u8 = 251;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 252;
+const RDB_OPCODE_EXPIRETIME: u8 = 253;
+const RDB_OPCODE_SELECTDB: u8 = 254;
+const RDB_OPCODE_EOF: u8 = 255;
+
+/// Keys written to the engine at a time
+const IMPORT_BATCH: usize = 256;
+
+/// What `import` did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RedisImport {
+    /// Keys set in the engine
+    pub keys: u64,
+    /// Commands skipped, on other types than strings, on keys that are not
+    /// UTF-8 or unknown to the importer
+    pub skipped: u64,
+}
+
+/// Imports the redis AOF or RDB file at `path` into `engine`, see the
+/// module docs
+pub fn import_file<E: KvsEngine>(engine: &E, path: &Path) -> Result<RedisImport> {
+    import(engine, &fs::read(path)?)
+}
+
+/// Imports the contents of a redis AOF or RDB file into `engine`, numbered
+/// databases into the engine's databases of the same number
+pub fn import<E: KvsEngine>(engine: &E, file: &[u8]) -> Result<RedisImport> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64);
+    let mut keyspace = Keyspace {
+        db: 0,
+        keys: BTreeMap::new(),
+        now,
+        skipped: 0,
+    };
+    let aof = match file.starts_with(RDB_MAGIC) {
+        true => read_rdb(file, &mut keyspace)?,
+        false => file,
+    };
+    read_aof(aof, &mut keyspace)?;
+    let skipped = keyspace.skipped;
+    Ok(RedisImport {
+        keys: keyspace.write(engine)?,
+        skipped,
+    })
+}
+
+/// A value and the time it expires at, in milliseconds since the epoch
+type Entry = (Vec<u8>, Option<i64>);
+
+/// The keys replayed so far, `None` for those removed
+struct Keyspace {
+    /// Database the commands apply to, as `SELECT` left it
+    db: usize,
+    keys: BTreeMap<(usize, String), Option<Entry>>,
+    now: i64,
+    skipped: u64,
+}
+
+impl Keyspace {
+    /// Applies the command `args`, `None` if it is skipped
+    fn apply(&mut self, args: &[Vec<u8>]) -> Option<()> {
+        let (name, args) = args.split_first()?;
+        match (str::from_utf8(name).ok()?.to_uppercase().as_str(), args) {
+            ("SELECT", [db]) => self.db = number(db)?,
+            ("MULTI" | "EXEC", []) => {}
+            ("SET", [key, value, options @ ..]) => self.set_command(key, value, options)?,
+            ("SETNX", [key, value]) => {
+                let key = self.key(key)?;
+                if self.live(&key).is_none() {
+                    self.keys.insert(key, Some((value.clone(), None)));
+                }
+            }
+            ("SETEX", [key, secs, value]) => {
+                let expires_at = self
+                    .now
+                    .saturating_add(number::<i64>(secs)?.saturating_mul(1000));
+                let key = self.key(key)?;
+                self.keys
+                    .insert(key, Some((value.clone(), Some(expires_at))));
+            }
+            ("PSETEX", [key, millis, value]) => {
+                let expires_at = self.now.saturating_add(number(millis)?);
+                let key = self.key(key)?;
+                self.keys
+                    .insert(key, Some((value.clone(), Some(expires_at))));
+            }
+            ("MSET", pairs) if !pairs.is_empty() && pairs.len().is_multiple_of(2) => {
+                let pairs = pairs
+                    .chunks(2)
+                    .map(|pair| Some((self.key(&pair[0])?, pair[1].clone())))
+                    .collect::<Option<Vec<_>>>()?;
+                for (key, value) in pairs {
+                    self.keys.insert(key, Some((value, None)));
+                }
+            }
+            ("APPEND", [key, value]) => {
+                let key = self.key(key)?;
+                let (mut appended, expires_at) = self.live(&key).cloned().unwrap_or_default();
+                appended.extend_from_slice(value);
+                self.keys.insert(key, Some((appended, expires_at)));
+            }
+            ("INCR", [key]) => self.increment(key, 1)?,
+            ("DECR", [key]) => self.increment(key, -1)?,
+            ("INCRBY", [key, by]) => self.increment(key, number(by)?)?,
+            ("DECRBY", [key, by]) => self.increment(key, number::<i64>(by)?.checked_neg()?)?,
+            ("DEL" | "UNLINK", keys) if !keys.is_empty() => {
+                for key in keys {
+                    // a key that is not UTF-8 was never imported
+                    if let Some(key) = self.key(key) {
+                        self.keys.insert(key, None);
+                    }
+                }
+            }
+            ("EXPIRE", [key, secs, ..]) => self.expire(
+                key,
+                self.now
+                    .saturating_add(number::<i64>(secs)?.saturating_mul(1000)),
+            )?,
+            ("PEXPIRE", [key, millis, ..]) => {
+                self.expire(key, self.now.saturating_add(number(millis)?))?
+            }
+            ("EXPIREAT", [key, secs, ..]) => {
+                self.expire(key, number::<i64>(secs)?.saturating_mul(1000))?
+            }
+            ("PEXPIREAT", [key, millis, ..]) => self.expire(key, number(millis)?)?,
+            ("PERSIST", [key]) => {
+                let key = self.key(key)?;
+                if let Some(Some((_, expires_at))) = self.keys.get_mut(&key) {
+                    *expires_at = None;
+                }
+            }
+            ("FLUSHDB", _) => {
+                let db = self.db;
+                self.keys
+                    .iter_mut()
+                    .filter(|((key_db, _), _)| *key_db == db)
+                    .for_each(|(_, entry)| *entry = None);
+            }
+            ("FLUSHALL", _) => self.keys.values_mut().for_each(|entry| *entry = None),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// `SET` with its `EX`, `PX`, `EXAT`, `PXAT`, `KEEPTTL`, `NX`, `XX` and
+    /// `GET` options
+    fn set_command(&mut self, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Option<()> {
+        let key = self.key(key)?;
+        let current = self.live(&key).map(|(_, expires_at)| *expires_at);
+        let mut expires_at = None;
+        let mut keep_ttl = false;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match str::from_utf8(option).ok()?.to_uppercase().as_str() {
+                "EX" => {
+                    let secs: i64 = number(options.next()?)?;
+                    expires_at = Some(self.now.saturating_add(secs.saturating_mul(1000)));
+                }
+                "PX" => expires_at = Some(self.now.saturating_add(number(options.next()?)?)),
+                "EXAT" => expires_at = Some(number::<i64>(options.next()?)?.saturating_mul(1000)),
+                "PXAT" => expires_at = Some(number(options.next()?)?),
+                "KEEPTTL" => keep_ttl = true,
+                "NX" if current.is_some() => return Some(()),
+                "XX" if current.is_none() => return Some(()),
+                "NX" | "XX" | "GET" => {}
+                _ => return None,
+            }
+        }
+        if keep_ttl {
+            expires_at = current.flatten();
+        }
+        self.keys.insert(key, Some((value.to_vec(), expires_at)));
+        Some(())
+    }
+
+    /// Adds `by` to the number at `key`, zero if it has no value
+    fn increment(&mut self, key: &[u8], by: i64) -> Option<()> {
+        let key = self.key(key)?;
+        let (value, expires_at) = self.live(&key).cloned().unwrap_or_default();
+        let value = match value.is_empty() {
+            true => 0,
+            false => number::<i64>(&value)?,
+        };
+        let value = value.checked_add(by)?.to_string().into_bytes();
+        self.keys.insert(key, Some((value, expires_at)));
+        Some(())
+    }
+
+    /// Sets when `key` expires if it has a value
+    fn expire(&mut self, key: &[u8], expires_at: i64) -> Option<()> {
+        let key = self.key(key)?;
+        if self.live(&key).is_some() {
+            if let Some(Some((_, at))) = self.keys.get_mut(&key) {
+                *at = Some(expires_at);
+            }
+        }
+        Some(())
+    }
+
+    /// Sets `key` as an RDB holds it, counting it skipped if it is not UTF-8
+    fn load(&mut self, key: &[u8], value: Vec<u8>, expires_at: Option<i64>) {
+        match self.key(key) {
+            Some(key) => {
+                self.keys.insert(key, Some((value, expires_at)));
+            }
+            None => self.skipped += 1,
+        }
+    }
+
+    /// `key` in the selected database, `None` if it is not UTF-8
+    fn key(&self, key: &[u8]) -> Option<(usize, String)> {
+        Some((self.db, String::from_utf8(key.to_vec()).ok()?))
+    }
+
+    /// The value of `key` unless it was removed or has expired
+    fn live(&self, key: &(usize, String)) -> Option<&Entry> {
+        self.keys
+            .get(key)?
+            .as_ref()
+            .filter(|(_, expires_at)| expires_at.is_none_or(|at| at > self.now))
+    }
+
+    /// Writes the keys to `engine`, removing those the file removed or let
+    /// expire. Returns the keys set.
+    fn write<E: KvsEngine>(self, engine: &E) -> Result<u64> {
+        let now = self.now;
+        let mut databases: BTreeMap<usize, Vec<(String, Option<Entry>)>> = BTreeMap::new();
+        for ((db, key), entry) in self.keys {
+            let entry = entry.filter(|(_, expires_at)| expires_at.is_none_or(|at| at > now));
+            databases.entry(db).or_default().push((key, entry));
+        }
+        let mut keys = 0;
+        for (db, entries) in databases {
+            let store = engine.select(db)?;
+            for batch in entries.chunks(IMPORT_BATCH) {
+                let mut cmds = Vec::with_capacity(batch.len());
+                for (key, entry) in batch {
+                    match entry {
+                        Some((value, expires_at)) => {
+                            cmds.push(Command::set_from_bytes(
+                                key.clone(),
+                                value.clone(),
+                                expires_at.map(|at| at as u64),
+                                ContentType::Text,
+                            ));
+                            keys += 1;
+                        }
+                        None if store.exists(key)? => cmds.push(Command::Rm { key: key.clone() }),
+                        None => {}
+                    }
+                }
+                if !cmds.is_empty() {
+                    store.write_batch(cmds)?;
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Applies the commands of an AOF. An AOF that ends in a partial command,
+/// as one cut short by a crash does, is read up to it like redis does.
+fn read_aof(mut aof: &[u8], keyspace: &mut Keyspace) -> Result<()> {
+    let len = aof.len();
+    while !aof.is_empty() {
+        match protocol::from_bytes_prefix(aof) {
+            Ok((RespValue::Array(Some(args)), rest)) => {
+                aof = rest;
+                let args: Option<Vec<Vec<u8>>> = args.into_iter().map(bulk_bytes).collect();
+                if args.and_then(|args| keyspace.apply(&args)).is_none() {
+                    keyspace.skipped += 1;
+                }
+            }
+            Err(RespError::Eof) => {
+                warn!(
+                    "redis AOF ends in a partial command at offset {}, ignored",
+                    len - aof.len()
+                );
+                return Ok(());
+            }
+            _ => {
+                return Err(KvsError::Message(format!(
+                    "not a redis AOF command at offset {}",
+                    len - aof.len()
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads the string keys of an RDB into `keyspace`, returns what follows
+/// it, the AOF of a file with an RDB preamble
+fn read_rdb<'a>(rdb: &'a [u8], keyspace: &mut Keyspace) -> Result<&'a [u8]> {
+    let mut reader = RdbReader {
+        input: rdb,
+        offset: RDB_MAGIC.len(),
+    };
+    let version: u32 = str::from_utf8(reader.take(4)?)
+        .ok()
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| reader.corrupt())?;
+    let mut expires_at = None;
+    loop {
+        match reader.byte()? {
+            RDB_OPCODE_EOF => {
+                // followed by a CRC64 of the file since version 5
+                if version >= 5 {
+                    reader.take(8)?;
+                }
+                keyspace.db = 0;
+                return Ok(&rdb[reader.offset..]);
+            }
+            RDB_OPCODE_SELECTDB => keyspace.db = reader.plain_len()? as usize,
+            RDB_OPCODE_RESIZEDB => {
+                reader.plain_len()?;
+                reader.plain_len()?;
+            }
+            RDB_OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            RDB_OPCODE_EXPIRETIME_MS => {
+                let millis = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+                expires_at = Some(millis as i64);
+            }
+            RDB_OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+                expires_at = Some(secs as i64 * 1000);
+            }
+            RDB_OPCODE_IDLE => {
+                reader.plain_len()?;
+            }
+            RDB_OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            RDB_TYPE_STRING => {
+                let key = reader.string()?;
+                let value = reader.string()?;
+                keyspace.load(&key, value, expires_at.take());
+            }
+            value_type => {
+                return Err(KvsError::Message(format!(
+                    "redis RDB holds a key of type {} at offset {}, only strings can be imported",
+                    value_type,
+                    reader.offset - 1
+                )))
+            }
+        }
+    }
+}
+
+/// A length in an RDB, or the kind of a string stored in a special encoding
+enum RdbLen {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct RdbReader<'a> {
+    input: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    /// A string: raw, an integer written as text, or LZF compressed
+    fn string(&mut self) -> Result<Vec<u8>> {
+        match self.len()? {
+            RdbLen::Len(len) => Ok(self.take(len as usize)?.to_vec()),
+            RdbLen::Encoded(0) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            RdbLen::Encoded(1) => {
+                let int = i16::from_le_bytes(self.take(2)?.try_into().unwrap());
+                Ok(int.to_string().into_bytes())
+            }
+            RdbLen::Encoded(2) => {
+                let int = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+                Ok(int.to_string().into_bytes())
+            }
+            RdbLen::Encoded(3) => {
+                let compressed_len = self.plain_len()? as usize;
+                let len = self.plain_len()? as usize;
+                let compressed = self.take(compressed_len)?;
+                lzf_decompress(compressed, len).ok_or_else(|| self.corrupt())
+            }
+            RdbLen::Encoded(_) => Err(self.corrupt()),
+        }
+    }
+
+    /// A length that is not a special encoding
+    fn plain_len(&mut self) -> Result<u64> {
+        match self.len()? {
+            RdbLen::Len(len) => Ok(len),
+            RdbLen::Encoded(_) => Err(self.corrupt()),
+        }
+    }
+
+    /// The two high bits of the first byte tell a 6 bit length, a 14 bit
+    /// one, a 32 or 64 bit big endian one, or a special encoding
+    fn len(&mut self) -> Result<RdbLen> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => RdbLen::Len((first & 0x3f) as u64),
+            1 => RdbLen::Len(((first & 0x3f) as u64) << 8 | self.byte()? as u64),
+            2 => match first {
+                0x80 => RdbLen::Len(u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64),
+                0x81 => RdbLen::Len(u64::from_be_bytes(self.take(8)?.try_into().unwrap())),
+                _ => return Err(self.corrupt()),
+            },
+            _ => RdbLen::Encoded(first & 0x3f),
+        })
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.input.get(self.offset..end))
+            .ok_or_else(|| self.corrupt())?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn corrupt(&self) -> KvsError {
+        KvsError::Message(format!("corrupt redis RDB at offset {}", self.offset))
+    }
+}
+
+/// Decompresses the LZF `input` into `len` bytes, `None` if it is not valid
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // a literal run of ctrl + 1 bytes
+            output.extend_from_slice(input.get(i..i + ctrl + 1)?);
+            i += ctrl + 1;
+            continue;
+        }
+        // a back reference of run + 2 bytes
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += *input.get(i)? as usize;
+            i += 1;
+        }
+        let back = ((ctrl & 0x1f) << 8) + *input.get(i)? as usize + 1;
+        i += 1;
+        let start = output.len().checked_sub(back)?;
+        for j in start..start + run + 2 {
+            output.push(output[j]);
+        }
+    }
+    (output.len() == len).then_some(output)
+}
+
+/// A command argument, UTF-8 or not
+fn bulk_bytes(data: RespValue) -> Option<Vec<u8>> {
+    match data {
+        RespValue::BulkString(Some(bytes)) => Some(bytes),
+        _ => None,
+    }
+}
+
+/// A number argument
+fn number<T: FromStr>(arg: &[u8]) -> Option<T> {
+    str::from_utf8(arg).ok()?.parse().ok()
+}
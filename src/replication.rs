@@ -0,0 +1,322 @@
+//! Primary/replica replication.
+//!
+//! A replica connects to its primary and sends `SYNC`. The primary answers
+//! with every live key as a `SET` frame, carrying the TTL the key has left,
+//! a `SYNCEND` marker, and from then on forwards each write it applies, in
+//! the order it applied them, for as long as the connection stays open. The replica applies that stream to its own
+//! engine and refuses writes from its own clients. Writes to a numbered
+//! database other than 0 are framed by a `SELECT` of it and a `SELECT 0`.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::str;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::protocol::{self, Decoded, FrameDecoder, KvsCommand, RespValue};
+use crate::server::now_millis;
+use crate::{ContentType, KvsEngine, KvsError, Result};
+
+const SYNC_END: &str = "SYNCEND";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Fans out applied writes to the replicas connected to this server
+#[derive(Clone, Default)]
+pub struct ReplicationLog {
+    replicas: Arc<Mutex<Vec<Sender<Vec<u8>>>>>,
+}
+
+impl ReplicationLog {
+    /// Runs `write` and publishes `frames` if it succeeds. Writes are
+    /// serialized here so replicas receive them in the order they were
+    /// applied to the engine.
+    pub fn replicate<T>(&self, frames: &[Vec<u8>], write: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.replicate_if(frames, || Ok((write()?, true)))
+    }
+
+    /// Like `replicate`, for writes that may turn out to change nothing, such
+    /// as a failed compare-and-swap. `write` returns its result along with
+    /// whether `frames` should be published.
+    pub fn replicate_if<T>(
+        &self,
+        frames: &[Vec<u8>],
+        write: impl FnOnce() -> Result<(T, bool)>,
+    ) -> Result<T> {
+        self.replicate_with(|| {
+            let (result, publish) = write()?;
+            Ok((result, if publish { frames } else { &[] }))
+        })
+    }
+
+    /// Like `replicate`, for writes whose frames depend on what they wrote,
+    /// such as an append replicated as a set of the whole value. `write`
+    /// returns its result along with the frames to publish.
+    pub fn replicate_with<T, F: AsRef<[Vec<u8>]>>(
+        &self,
+        write: impl FnOnce() -> Result<(T, F)>,
+    ) -> Result<T> {
+        let mut replicas = self.replicas.lock().unwrap();
+        let (result, frames) = write()?;
+        let frames = frames.as_ref();
+        if !frames.is_empty() && !replicas.is_empty() {
+            replicas.retain(|replica| {
+                frames
+                    .iter()
+                    .all(|frame| replica.send(frame.clone()).is_ok())
+            });
+        }
+        Ok(result)
+    }
+
+    fn subscribe(&self) -> Receiver<Vec<u8>> {
+        let (tx, rx) = mpsc::channel();
+        self.replicas.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/// RESP frame replicating a set of `key`
+pub fn set_frame(key: &str, value: &[u8], content_type: ContentType) -> Vec<u8> {
+    match content_type {
+        ContentType::Text => protocol::command_frame(&[b"SET", key.as_bytes(), value]),
+        _ => protocol::command_frame(&[
+            b"SET",
+            key.as_bytes(),
+            value,
+            b"TYPE",
+            content_type.name().as_bytes(),
+        ]),
+    }
+}
+
+/// RESP frame replicating a set of `key` to a text value that expires after
+/// `ttl`, which the replica counts from when it applies the set
+pub fn set_ttl_frame(key: &str, value: &[u8], ttl: Duration) -> Vec<u8> {
+    let millis = ttl.as_millis().max(1).to_string();
+    protocol::command_frame(&[b"SET", key.as_bytes(), value, b"PX", millis.as_bytes()])
+}
+
+/// RESP frame replicating a removal of `key`
+pub fn rm_frame(key: &str) -> Vec<u8> {
+    protocol::command_frame(&[b"RM", key.as_bytes()])
+}
+
+/// RESP frame replicating the removal of the keys matching `pattern`
+pub fn rm_matching_frame(pattern: &str) -> Vec<u8> {
+    protocol::command_frame(&[b"RM".as_slice(), b"MATCH", pattern.as_bytes()])
+}
+
+/// RESP frame replicating the removal of every key of a database
+pub fn flushdb_frame() -> Vec<u8> {
+    protocol::command_frame(&[b"FLUSHDB"])
+}
+
+/// RESP frame replicating the removal of the TTL of `key`
+pub fn persist_frame(key: &str) -> Vec<u8> {
+    protocol::command_frame(&[b"PERSIST", key.as_bytes()])
+}
+
+/// `frames` of writes to database `db`, framed by `SELECT` frames unless it
+/// is database 0
+pub fn in_database(db: usize, frames: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    if db == 0 {
+        return frames;
+    }
+    let mut framed = Vec::with_capacity(frames.len() + 2);
+    framed.push(protocol::command_frame(&[
+        b"SELECT",
+        db.to_string().as_bytes(),
+    ]));
+    framed.extend(frames);
+    framed.push(protocol::command_frame(&[b"SELECT".as_slice(), b"0"]));
+    framed
+}
+
+/// Serves a replica that sent `SYNC`: the current contents of every
+/// database of `engine`, then every write published to `log` until the
+/// replica goes away
+pub fn feed_replica<E: KvsEngine, W: Write>(
+    engine: &E,
+    log: &ReplicationLog,
+    writer: &mut W,
+) -> Result<()> {
+    // subscribe before reading the snapshot so no write falls in between,
+    // writes seen twice are harmless since the stream is replayed in order
+    let updates = log.subscribe();
+    for db in engine.databases()? {
+        let engine = engine.select(db)?;
+        let mut frames = Vec::new();
+        for key in engine.keys("*")? {
+            // removed since it was listed
+            let Some((value, content_type)) = engine.get_typed(&key)? else {
+                continue;
+            };
+            frames.push(match engine.expires_at(&key)? {
+                Some(expires_at) => {
+                    let ttl = Duration::from_millis(expires_at.saturating_sub(now_millis()));
+                    set_ttl_frame(&key, &value, ttl)
+                }
+                None => set_frame(&key, &value, content_type),
+            });
+        }
+        for frame in in_database(db, frames) {
+            writer.write_all(&frame)?;
+        }
+    }
+    writer.write_all(&protocol::command_frame(&[SYNC_END.as_bytes()]))?;
+    writer.flush()?;
+
+    loop {
+        match updates.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(frame) => {
+                writer.write_all(&frame)?;
+                for frame in updates.try_iter() {
+                    writer.write_all(&frame)?;
+                }
+            }
+            // pings keep the stream alive and tell us when the replica left
+            Err(RecvTimeoutError::Timeout) => {
+                writer.write_all(&protocol::command_frame(&[b"PING"]))?
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        writer.flush()?;
+    }
+}
+
+/// Keeps `engine` in sync with the server at `primary` from a background
+/// thread, reconnecting and resyncing whenever the connection drops. Sends
+/// `password` with AUTH first when there is one.
+pub fn replicate_from<E: KvsEngine>(engine: E, primary: SocketAddr, password: Option<String>) {
+    thread::spawn(move || loop {
+        match TcpStream::connect(primary) {
+            Ok(stream) => {
+                info!("replicating from {}", primary);
+                if let Err(e) = sync_from(&engine, &stream, password.as_deref()) {
+                    error!("replication from {} failed: {:?}", primary, e);
+                }
+            }
+            Err(e) => warn!("could not connect to primary {}: {}", primary, e),
+        }
+        thread::sleep(RECONNECT_INTERVAL);
+    });
+}
+
+fn sync_from<E: KvsEngine>(root: &E, mut stream: &TcpStream, password: Option<&str>) -> Result<()> {
+    if let Some(password) = password {
+        stream.write_all(&protocol::command_frame(&[b"AUTH", password.as_bytes()]))?;
+    }
+    stream.write_all(&protocol::command_frame(&[b"SYNC"]))?;
+    stream.flush()?;
+
+    let mut decoder = FrameDecoder::new();
+    // keys received in the initial snapshot by database, `None` once it is
+    // complete
+    let mut synced_keys: Option<HashMap<usize, HashSet<String>>> = Some(HashMap::new());
+    // the database the primary selected
+    let (mut db, mut engine) = (0, root.clone());
+    loop {
+        if decoder.read_from(&mut stream)? == 0 {
+            return Err(KvsError::Message("primary closed the connection".into()));
+        }
+
+        loop {
+            let frame = match decoder.next_frame() {
+                Ok(Decoded::Frame(frame)) => frame,
+                Ok(Decoded::NeedMoreData) => break,
+                Err(e) => {
+                    return Err(KvsError::Message(format!(
+                        "invalid frame from primary: {}",
+                        e
+                    )))
+                }
+            };
+            let resp = match protocol::from_bytes(frame) {
+                Ok(resp) => resp,
+                Err(e) => {
+                    return Err(KvsError::Message(format!(
+                        "invalid frame from primary: {}",
+                        e
+                    )))
+                }
+            };
+            match &resp {
+                // the answer to AUTH
+                RespValue::SimpleString(_) => continue,
+                RespValue::Err(e) => {
+                    return Err(KvsError::Message(format!("primary refused to sync: {}", e)))
+                }
+                _ => {}
+            }
+            if is_sync_end(&resp) {
+                if let Some(synced_keys) = synced_keys.take() {
+                    let none = HashSet::new();
+                    for db in root.databases()? {
+                        let keys = synced_keys.get(&db).unwrap_or(&none);
+                        drop_stale_keys(&root.select(db)?, keys)?;
+                    }
+                }
+                continue;
+            }
+            match protocol::parse_command(&resp) {
+                Some(KvsCommand::Set(key, value, content_type, ttl)) => {
+                    if let Some(synced_keys) = synced_keys.as_mut() {
+                        synced_keys.entry(db).or_default().insert(key.clone());
+                    }
+                    match (String::from_utf8(value), ttl) {
+                        (Ok(value), Some(ttl)) => engine.set_with_ttl(key, value, ttl)?,
+                        (Ok(value), None) => engine.set_typed(key, value, content_type)?,
+                        (Err(e), _) => engine.set_bytes(key, e.into_bytes())?,
+                    }
+                }
+                Some(KvsCommand::Rm(key)) => match engine.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                },
+                Some(KvsCommand::Persist(key)) => {
+                    engine.persist(key)?;
+                }
+                Some(KvsCommand::RmMatching(pattern)) => {
+                    engine.remove_matching(&pattern)?;
+                }
+                Some(KvsCommand::Flushdb) => {
+                    engine.remove_matching("*")?;
+                }
+                Some(KvsCommand::Select(selected)) => {
+                    engine = root.select(selected)?;
+                    db = selected;
+                }
+                Some(KvsCommand::Ping) => {}
+                _ => warn!("ignoring unexpected frame from primary: {:?}", resp),
+            }
+        }
+    }
+}
+
+fn is_sync_end(resp: &RespValue) -> bool {
+    match resp {
+        RespValue::Array(Some(parts)) => {
+            matches!(parts.as_slice(), [end] if end.as_bulk_str() == Some(SYNC_END))
+        }
+        _ => false,
+    }
+}
+
+/// Removes keys left over from before a resync that the primary no longer has
+fn drop_stale_keys<E: KvsEngine>(engine: &E, synced_keys: &HashSet<String>) -> Result<()> {
+    for (key, _) in engine.range(..)? {
+        if !synced_keys.contains(&key) {
+            match engine.remove(key) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
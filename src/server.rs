@@ -1,120 +1,1897 @@
 use core::str;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
 use std::io::BufReader;
-use std::io::Read;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::Subcommand;
 use log::debug;
 use log::error;
-use serde::{Deserialize, Serialize};
 
-use crate::common;
-use crate::common::tcp_send_message;
-use crate::common::KvsCommand;
+use crate::client;
+use crate::dump;
+use crate::metrics::{Metrics, WireStats};
+use crate::protocol::{self, Decoded, FrameDecoder, KvsCommand, Protocol, RespValue};
+use crate::replication::{self, ReplicationLog};
 use crate::thread_pool::ThreadPool;
-use crate::KvsEngine;
+use crate::{ChangeEvent, ContentType, Cursor, KvsEngine, ValueReader};
 use crate::{KvsError, Result};
 
-#[derive(Subcommand, Deserialize, Serialize, Debug, Clone)]
-pub enum Command {
-    #[command(name = "-V")]
-    Version,
+/// Commands a user may run, see `KvsServer::add_user`. Connection commands
+/// such as PING, AUTH, SELECT and MULTI are open to every profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Profile {
+    /// Reads, scans and server figures
+    Readonly,
+    /// Reads and writes of single keys
+    CacheClient,
+    /// Everything, including admin commands and replication
+    Admin,
 }
 
-fn handle_command<E: KvsEngine>(
-    engine: &E,
+impl Profile {
+    pub fn allows(&self, command: &KvsCommand) -> bool {
+        let connection = matches!(
+            command,
+            KvsCommand::Ping
+                | KvsCommand::Hello(_)
+                | KvsCommand::Auth(..)
+                | KvsCommand::Select(_)
+                | KvsCommand::Version
+                | KvsCommand::Multi
+                | KvsCommand::Exec
+                | KvsCommand::Discard
+        );
+        let key_read = matches!(
+            command,
+            KvsCommand::Get(_)
+                | KvsCommand::Mget(_)
+                | KvsCommand::Exists(_)
+                | KvsCommand::Subscribe(_)
+        );
+        match self {
+            Profile::Admin => true,
+            Profile::Readonly => {
+                connection
+                    || key_read
+                    || matches!(
+                        command,
+                        KvsCommand::Scan(..)
+                            | KvsCommand::Keys(_)
+                            | KvsCommand::Dbsize
+                            | KvsCommand::Info
+                            | KvsCommand::Stats
+                    )
+            }
+            Profile::CacheClient => {
+                connection
+                    || key_read
+                    || matches!(
+                        command,
+                        KvsCommand::Set(..)
+                            | KvsCommand::Mset(_)
+                            | KvsCommand::Rm(_)
+                            | KvsCommand::Append(..)
+                            | KvsCommand::Persist(_)
+                            | KvsCommand::Cas(..)
+                    )
+            }
+        }
+    }
+}
+
+/// Credentials and profile of a user AUTH can log in as
+#[derive(Clone)]
+struct User {
+    password: String,
+    profile: Profile,
+}
+
+const READONLY_REPLY: &str = "-READONLY You can't write against a read only replica\r\n";
+/// How long `run` waits for open connections to finish after a shutdown
+/// before cutting them off
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default bytes a connection may hold in partial frames and commands queued
+/// by MULTI, see `KvsServer::client_buffer_limit`
+const CLIENT_BUFFER_LIMIT: usize = 64 * 1024 * 1024;
+const BUFFER_LIMIT_REPLY: &[u8] = b"-ERR client buffer limit exceeded\r\n";
+
+/// Default size of the largest key a write may name, see
+/// `KvsServer::max_key_size`
+const MAX_KEY_SIZE: usize = 64 * 1024;
+/// Default size of the largest value a write may carry, see
+/// `KvsServer::max_value_size`
+const MAX_VALUE_SIZE: usize = 512 * 1024 * 1024;
+
+/// Reply to a read that found a corrupt value, see `KvsEngine::quarantined`
+const CORRUPT_REPLY: &[u8] = b"-CORRUPT the value is corrupt, its key is quarantined\r\n";
+/// Reply to a command the engine gave up on, see `KvStoreOptions::io_timeout`
+const TIMEOUT_REPLY: &[u8] = b"-TIMEOUT the engine timed out\r\n";
+/// Reply to a write the engine gave up on before it wrote anything
+const WRITE_TIMEOUT_REPLY: &[u8] = b"-TIMEOUT the engine timed out, nothing was written\r\n";
+/// Numbered databases SELECT takes, like Redis
+const DATABASES: usize = 16;
+/// How often a subscriber is pinged while no key it watches changes
+const SUBSCRIBER_HEARTBEAT: Duration = Duration::from_secs(1);
+const NOAUTH_REPLY: &[u8] = b"-NOAUTH Authentication required.\r\n";
+/// User AUTH logs in as when given only a password, the one
+/// `KvsServer::require_pass` sets the password of
+const DEFAULT_USER: &str = "default";
+/// Pauses of an accept loop after errors that take time to clear, such as
+/// running out of file descriptors, doubling with each error in a row
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// `raw_os_error` of an accept failing for lack of file descriptors, EMFILE
+/// and ENFILE, or WSAEMFILE
+#[cfg(unix)]
+const OUT_OF_DESCRIPTORS: &[i32] = &[24, 23];
+#[cfg(windows)]
+const OUT_OF_DESCRIPTORS: &[i32] = &[10024];
+
+/// State shared by every connection of a server
+#[derive(Clone)]
+struct ServerState<E: KvsEngine> {
+    engine: E,
+    replication: ReplicationLog,
+    /// Set on replicas, which only take writes from their primary
+    read_only: bool,
+    /// Answer GET on a missing key with `-Key not found` instead of a null
+    /// bulk string, for clients of older servers
+    missing_key_error: bool,
+    metrics: Arc<Metrics>,
+    /// Bytes a connection may buffer before it is closed
+    client_buffer_limit: usize,
+    /// Bytes of the largest key and value a write may carry
+    max_key_size: usize,
+    max_value_size: usize,
+    /// Set when admin commands are served by a listener of their own, the
+    /// data listener then refuses them
+    admin_listener: bool,
+    shutdown: ShutdownHandle,
+    /// Users clients must AUTH as before anything else, when there are any
+    users: Arc<HashMap<String, User>>,
+}
+
+impl<E: KvsEngine> ServerState<E> {
+    /// Answers GET with `value_reply`, or with an error for a missing key
+    /// when the server was started with `--missing-key-error`
+    fn get_reply(&self, value: Option<(Vec<u8>, ContentType)>, protocol: Protocol) -> Vec<u8> {
+        match value {
+            None if self.missing_key_error => b"-Key not found\r\n".to_vec(),
+            value => value_reply(value, protocol),
+        }
+    }
+}
+
+/// A value as a bulk string, or as a verbatim string of format `jsn` for a
+/// json value of a RESP3 connection, and a missing one as a null
+fn value_reply(value: Option<(Vec<u8>, ContentType)>, protocol: Protocol) -> Vec<u8> {
+    let (value, content_type) = match value {
+        Some(value) => value,
+        None if protocol == Protocol::Resp3 => return b"_\r\n".to_vec(),
+        None => return b"$-1\r\n".to_vec(),
+    };
+    let mut reply = value_header(value.len() as u64, content_type, protocol).into_bytes();
+    reply.extend_from_slice(&value);
+    reply.extend_from_slice(b"\r\n");
+    reply
+}
+
+/// What comes before the bytes of a value of `len` bytes in `value_reply`
+fn value_header(len: u64, content_type: ContentType, protocol: Protocol) -> String {
+    match content_type {
+        ContentType::Json if protocol == Protocol::Resp3 => format!("={}\r\njsn:", len + 4),
+        _ => format!("${}\r\n", len),
+    }
+}
+
+/// Writes `value` like `value_reply`, copying its bytes to `writer` as they
+/// are read instead of reading them whole first. A value that fails partway
+/// leaves the reply torn, so the error closes the connection.
+fn stream_value<W: Write>(
+    writer: &mut W,
+    mut value: ValueReader,
+    protocol: Protocol,
+) -> Result<()> {
+    let header = value_header(value.len(), value.content_type(), protocol);
+    writer.write_all(header.as_bytes())?;
+    let copied = io::copy(&mut value, writer)?;
+    if copied != value.len() {
+        return Err(KvsError::Message(format!(
+            "value ended after {} of {} bytes",
+            copied,
+            value.len()
+        )));
+    }
+    writer.write_all(b"\r\n")?;
+    Ok(())
+}
+
+/// Executes `command` and writes its reply to `writer` without flushing
+fn handle_command<E: KvsEngine, W: Write>(
+    state: &ServerState<E>,
+    session: &Session<E>,
     command: &KvsCommand,
-    stream: &TcpStream,
+    writer: &mut W,
 ) -> Result<()> {
-    let message: String = match command {
+    let engine = session.engine(state);
+    let protocol = session.protocol;
+    let message: Vec<u8> = match command {
         KvsCommand::Ping => "+PONG\r\n".into(),
-        KvsCommand::Set(key, value) => {
-            engine.set(key.into(), value.into())?;
+        _ if state.read_only && command.is_write() => READONLY_REPLY.into(),
+        KvsCommand::Set(key, value, content_type, None) => {
+            let frame = replication::set_frame(key, value, *content_type);
+            let frames = replication::in_database(session.db, vec![frame]);
+            state.replication.replicate(&frames, || {
+                set_value(engine, key.clone(), value.clone(), *content_type)
+            })?;
+            "+OK\r\n".into()
+        }
+        KvsCommand::Set(key, value, _, Some(ttl)) => {
+            let frame = replication::set_ttl_frame(key, value, *ttl);
+            let frames = replication::in_database(session.db, vec![frame]);
+            // `invalid_value` let only UTF-8 text through
+            let value = String::from_utf8(value.clone()).map_err(|_| KvsError::InvalidCommand)?;
+            state
+                .replication
+                .replicate(&frames, || engine.set_with_ttl(key.clone(), value, *ttl))?;
             "+OK\r\n".into()
         }
-        KvsCommand::Get(key) => {
-            let mut m = String::from("-Key not found\r\n");
-            if let Some(value) = engine.get(key.into())? {
-                m = format!("${}\r\n{}\r\n", value.len(), value);
+        KvsCommand::Get(key) => match engine.get_reader(key)? {
+            Some(value) => return stream_value(writer, value, protocol),
+            None => state.get_reply(None, protocol),
+        },
+        KvsCommand::Mget(keys) => {
+            let mut reply = format!("*{}\r\n", keys.len()).into_bytes();
+            for value in engine.get_many(keys)? {
+                reply.extend(value_reply(value, protocol));
             }
-            m
+            reply
+        }
+        KvsCommand::Mset(pairs) => {
+            let frames = pairs
+                .iter()
+                .map(|(key, value)| replication::set_frame(key, value, ContentType::Text))
+                .collect();
+            let frames = replication::in_database(session.db, frames);
+            let batch = pairs
+                .iter()
+                .map(|(key, value)| {
+                    client::Command::set_from_bytes(
+                        key.clone(),
+                        value.clone(),
+                        None,
+                        ContentType::Text,
+                    )
+                })
+                .collect();
+            state
+                .replication
+                .replicate(&frames, || engine.write_batch(batch))?;
+            "+OK\r\n".into()
         }
         KvsCommand::Rm(key) => {
             let mut m = String::from("+OK\r\n");
-            if let Err(e) = engine.remove(key.into()) {
+            let frame = replication::rm_frame(key);
+            let frames = replication::in_database(session.db, vec![frame]);
+            if let Err(e) = state.replication.replicate(&frames, || engine.remove(key)) {
                 match e {
                     KvsError::KeyNotFound => {
                         m = String::from("-Key not found\r\n");
                     }
-                    e => {
-                        debug!("Something went wrong on key remove: {:?}", e)
+                    e => return Err(e),
+                }
+            }
+            m.into()
+        }
+        KvsCommand::Append(key, value) => {
+            // replicated as a set of the whole value, a replica that sees an
+            // append twice while it syncs would append twice
+            let len = state.replication.replicate_with(|| {
+                let len = engine.append(key.clone(), value.as_bytes())?;
+                let frame = match (engine.get_typed(key)?, engine.expires_at(key)?) {
+                    (Some((value, _)), Some(expires_at)) => {
+                        let ttl = Duration::from_millis(expires_at.saturating_sub(now_millis()));
+                        replication::set_ttl_frame(key, &value, ttl)
+                    }
+                    (Some((value, content_type)), None) => {
+                        replication::set_frame(key, &value, content_type)
                     }
+                    // expired as soon as it was written
+                    (None, _) => replication::rm_frame(key),
+                };
+                Ok((len, replication::in_database(session.db, vec![frame])))
+            })?;
+            format!(":{}\r\n", len).into()
+        }
+        KvsCommand::Persist(key) => {
+            let frames =
+                replication::in_database(session.db, vec![replication::persist_frame(key)]);
+            let persisted = state.replication.replicate_if(&frames, || {
+                let persisted = engine.persist(key)?;
+                Ok((persisted, persisted))
+            })?;
+            integer_reply(persisted).into()
+        }
+        KvsCommand::RmMatching(pattern) => {
+            let frame = replication::rm_matching_frame(pattern);
+            let frames = replication::in_database(session.db, vec![frame]);
+            let removed = state.replication.replicate_if(&frames, || {
+                let removed = engine.remove_matching(pattern)?;
+                Ok((removed, removed > 0))
+            });
+            match removed {
+                Ok(removed) => format!(":{}\r\n", removed).into(),
+                Err(e @ KvsError::Message(_)) => {
+                    debug!("RM MATCH {} failed: {:?}", pattern, e);
+                    "-ERR invalid pattern\r\n".into()
                 }
+                Err(e) => return Err(e),
             }
-            m
+        }
+        KvsCommand::Flushdb => {
+            let frames = replication::in_database(session.db, vec![replication::flushdb_frame()]);
+            state.replication.replicate_if(&frames, || {
+                let removed = engine.remove_matching("*")?;
+                Ok(((), removed > 0))
+            })?;
+            "+OK\r\n".into()
+        }
+        KvsCommand::Cas(key, expected, new) => {
+            let frame = match new {
+                Some(value) => replication::set_frame(key, value.as_bytes(), ContentType::Text),
+                None => replication::rm_frame(key),
+            };
+            let frames = replication::in_database(session.db, vec![frame]);
+            let swapped = state.replication.replicate_if(&frames, || {
+                let swapped =
+                    engine.compare_and_swap(key.clone(), expected.clone(), new.clone())?;
+                // swapping a missing key for a missing key changes nothing
+                Ok((swapped, swapped && (expected.is_some() || new.is_some())))
+            })?;
+            integer_reply(swapped).into()
         }
         KvsCommand::Version => env!("CARGO_PKG_VERSION").into(),
+        KvsCommand::Backup(dest) => backup_reply(engine, dest).into(),
+        KvsCommand::Export(dest) => export_reply(engine, dest).into(),
+        KvsCommand::Scan(cursor, count) => scan_reply(engine, cursor.clone(), *count)?.into(),
+        KvsCommand::Exists(key) => integer_reply(engine.exists(key)?).into(),
+        KvsCommand::Keys(pattern) => keys_reply(engine, pattern).into(),
+        KvsCommand::Dbsize => format!(":{}\r\n", engine.key_count()?).into(),
+        KvsCommand::Quarantine => quarantine_reply(engine)?.into(),
+        KvsCommand::QuarantineDrop(key) => {
+            let frames = replication::in_database(session.db, vec![replication::rm_frame(key)]);
+            let dropped = state.replication.replicate_if(&frames, || {
+                let dropped = engine.drop_quarantined(key)?;
+                Ok((dropped, dropped))
+            })?;
+            integer_reply(dropped).into()
+        }
+        KvsCommand::Bigkeys(count, sample) => {
+            let report = engine.big_keys(*count, *sample)?.to_string();
+            format!("${}\r\n{}\r\n", report.len(), report).into()
+        }
+        KvsCommand::Info => {
+            let info = info_reply(state, session)?;
+            format!("${}\r\n{}\r\n", info.len(), info).into()
+        }
+        KvsCommand::Stats => {
+            let stats = state.metrics.render(&state.engine.stats()?);
+            format!("${}\r\n{}\r\n", stats.len(), stats).into()
+        }
+        KvsCommand::StatsReset => {
+            state.engine.reset_stats()?;
+            b"+OK\r\n".to_vec()
+        }
+        KvsCommand::DictTrain(sample) => train_dictionary_reply(engine, *sample).into(),
+        KvsCommand::Multi => "-ERR MULTI calls can not be nested\r\n".into(),
+        KvsCommand::Exec => "-ERR EXEC without MULTI\r\n".into(),
+        KvsCommand::Discard => "-ERR DISCARD without MULTI\r\n".into(),
+        KvsCommand::Sync => "-ERR SYNC is not allowed here\r\n".into(),
+        KvsCommand::Hello(_)
+        | KvsCommand::Shutdown(_)
+        | KvsCommand::Select(_)
+        | KvsCommand::Auth(..)
+        | KvsCommand::Subscribe(_) => {
+            unreachable!(
+                "HELLO, SHUTDOWN, SELECT, AUTH and SUBSCRIBE are answered by handle_request"
+            )
+        }
     };
-    if let Err(e) = tcp_send_message(&stream, &message) {
+    if let Err(e) = writer.write_all(&message) {
         log::error!("error sending message: {:?}", e);
+    }
+    Ok(())
+}
+
+/// Sets `key` to a value of any bytes, json values are UTF-8 once
+/// `invalid_value` let them through
+fn set_value<E: KvsEngine>(
+    engine: &E,
+    key: String,
+    value: Vec<u8>,
+    content_type: ContentType,
+) -> Result<()> {
+    match content_type {
+        ContentType::Text => engine.set_bytes(key, value),
+        _ => {
+            let value = String::from_utf8(value).map_err(|_| KvsError::InvalidCommand)?;
+            engine.set_typed(key, value, content_type)
+        }
+    }
+}
+
+/// Milliseconds since the unix epoch, what expiry times are kept in
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn integer_reply(value: bool) -> String {
+    format!(":{}\r\n", value as u8)
+}
+
+/// Answers SCAN with the next cursor, `0` once the scan is done, and the
+/// keys of the page
+fn scan_reply<E: KvsEngine>(engine: &E, cursor: Option<Cursor>, count: usize) -> Result<String> {
+    let (pairs, next) = engine.scan_page(cursor, count)?;
+    let next = next.map_or_else(|| "0".to_string(), |cursor| cursor.to_string());
+    let mut reply = format!("*2\r\n${}\r\n{}\r\n*{}\r\n", next.len(), next, pairs.len());
+    for (key, _) in pairs {
+        reply += &format!("${}\r\n{}\r\n", key.len(), key);
+    }
+    Ok(reply)
+}
+
+fn keys_reply<E: KvsEngine>(engine: &E, pattern: &str) -> String {
+    match engine.keys(pattern) {
+        Ok(keys) => {
+            let mut reply = format!("*{}\r\n", keys.len());
+            for key in keys {
+                reply += &format!("${}\r\n{}\r\n", key.len(), key);
+            }
+            reply
+        }
+        Err(e) => {
+            debug!("KEYS {} failed: {:?}", pattern, e);
+            "-ERR invalid pattern\r\n".into()
+        }
+    }
+}
+
+/// Lists the quarantined keys as `key file:offset` of their corrupt record
+fn quarantine_reply<E: KvsEngine>(engine: &E) -> Result<String> {
+    let quarantined = engine.quarantined()?;
+    let mut reply = format!("*{}\r\n", quarantined.len());
+    for quarantined in quarantined {
+        let line = format!(
+            "{} {}:{}",
+            quarantined.key,
+            quarantined.file.display(),
+            quarantined.offset
+        );
+        reply += &format!("${}\r\n{}\r\n", line.len(), line);
+    }
+    Ok(reply)
+}
+
+/// Describes the server in sections of `key:value` lines, like Redis INFO
+fn info_reply<E: KvsEngine>(state: &ServerState<E>, session: &Session<E>) -> Result<String> {
+    let stats = state.engine.stats()?;
+    let role = if state.read_only { "replica" } else { "master" };
+    let wire = state.metrics.wire();
+    Ok(format!(
+        "# Server\r\n\
+         kvs_version:{}\r\n\
+         uptime_in_seconds:{}\r\n\
+         engine:{}\r\n\
+         role:{}\r\n\
+         \r\n\
+         # Clients\r\n\
+         connected_clients:{}\r\n\
+         \r\n\
+         # Network\r\n\
+         total_net_input_bytes:{}\r\n\
+         total_net_output_bytes:{}\r\n\
+         total_frames:{}\r\n\
+         total_parse_errors:{}\r\n\
+         total_oversized_frames:{}\r\n\
+         \r\n\
+         # Connection\r\n\
+         net_input_bytes:{}\r\n\
+         net_output_bytes:{}\r\n\
+         frames:{}\r\n\
+         parse_errors:{}\r\n\
+         \r\n\
+         # Keyspace\r\n\
+         keys:{}\r\n\
+         disk_bytes:{}\r\n\
+         \r\n\
+         # Expiry\r\n\
+         expiring_1m:{}\r\n\
+         expiring_1h:{}\r\n\
+         expiring_1d:{}\r\n\
+         \r\n\
+         # Stats\r\n\
+         total_sets:{}\r\n\
+         total_removes:{}\r\n\
+         total_bytes_written:{}\r\n\
+         \r\n\
+         # Compaction\r\n\
+         compactions:{}\r\n\
+         \r\n\
+         # Writer\r\n\
+         writer_waiting:{}\r\n\
+         writer_waits:{}\r\n\
+         writer_wait_avg_us:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        state.metrics.uptime().as_secs(),
+        stats.engine,
+        role,
+        state.metrics.connected_clients(),
+        wire.bytes_read,
+        wire.bytes_written,
+        wire.frames,
+        wire.parse_errors,
+        wire.oversized_frames,
+        session.wire.bytes_read,
+        session.wire.bytes_written,
+        session.wire.frames,
+        session.wire.parse_errors,
+        stats.keys,
+        stats.disk_bytes,
+        stats.expiring.minute,
+        stats.expiring.hour,
+        stats.expiring.day,
+        stats.sets,
+        stats.removes,
+        stats.bytes_written,
+        stats.compactions,
+        stats.writer_waiting,
+        stats.writer_wait.count(),
+        stats.writer_wait.mean().as_micros()
+    ))
+}
+
+/// Switches the connection to the requested protocol version and describes
+/// the server, in a map under RESP3 and a flat array under RESP2
+fn hello_reply<E: KvsEngine>(
+    state: &ServerState<E>,
+    session: &mut Session<E>,
+    version: Option<&str>,
+) -> Result<String> {
+    session.protocol = match version {
+        None => session.protocol,
+        Some("2") => Protocol::Resp2,
+        Some("3") => Protocol::Resp3,
+        Some(_) => return Ok("-NOPROTO unsupported protocol version\r\n".into()),
+    };
+    let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+    let proto = match session.protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+    let role = if state.read_only { "replica" } else { "master" };
+    let reply = RespValue::Map(vec![
+        (bulk("server"), bulk("kvs")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), RespValue::Integer(proto)),
+        (bulk("mode"), bulk("standalone")),
+        (bulk("role"), bulk(role)),
+    ]);
+    protocol::to_string_with(&reply, session.protocol)
+        .map_err(|e| KvsError::Message(format!("unable to encode HELLO reply: {}", e)))
+}
+
+/// Logs the session in as `username`, the default user if none, when
+/// `password` is theirs
+fn auth_reply<E: KvsEngine>(
+    state: &ServerState<E>,
+    session: &mut Session<E>,
+    username: Option<&str>,
+    password: &str,
+) -> &'static [u8] {
+    if state.users.is_empty() {
+        return b"-ERR AUTH called without any password configured\r\n";
+    }
+    match state.users.get(username.unwrap_or(DEFAULT_USER)) {
+        Some(user) if constant_time_eq(password.as_bytes(), user.password.as_bytes()) => {
+            session.profile = Some(user.profile);
+            b"+OK\r\n"
+        }
+        _ => b"-WRONGPASS invalid username-password pair\r\n",
+    }
+}
+
+/// Compares without returning early, so the time taken does not tell how
+/// much of a guessed password was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Snapshots the engine into `dest` on the server's filesystem
+fn backup_reply<E: KvsEngine>(engine: &E, dest: &str) -> String {
+    match engine.snapshot(Path::new(dest)) {
+        Ok(()) => "+OK\r\n".into(),
+        Err(e) => {
+            error!("backup to {} failed: {:?}", dest, e);
+            "-ERR backup failed\r\n".into()
+        }
+    }
+}
+
+/// Trains a compression dictionary on the values of up to `sample` keys,
+/// replying with its id
+fn train_dictionary_reply<E: KvsEngine>(engine: &E, sample: usize) -> String {
+    match engine.train_dictionary(sample) {
+        Ok(id) => format!(":{}\r\n", id),
+        // the engine can not use one, or the values are too few to train on
+        Err(KvsError::Message(e)) => format!("-ERR {}\r\n", e),
+        Err(e) => {
+            error!("dictionary training failed: {:?}", e);
+            "-ERR dictionary training failed\r\n".into()
+        }
+    }
+}
+
+/// Exports the engine to the dump file `dest` on the server's filesystem,
+/// replying with the records in the dump
+fn export_reply<E: KvsEngine>(engine: &E, dest: &str) -> String {
+    match dump::export_file(engine, Path::new(dest)) {
+        Ok(records) => format!(":{}\r\n", records),
+        Err(e) => {
+            error!("export to {} failed: {:?}", dest, e);
+            "-ERR export failed\r\n".into()
+        }
+    }
+}
+
+/// State kept for the lifetime of a client connection
+struct Session<E: KvsEngine> {
+    /// Commands queued since MULTI, `None` outside of a transaction
+    queued: Option<Vec<KvsCommand>>,
+    /// Set when a command could not be queued, EXEC then discards the
+    /// transaction
+    aborted: bool,
+    /// Set once the peer sent SYNC, the connection then only feeds it writes
+    replica: bool,
+    /// Protocol version the client asked for with HELLO
+    protocol: Protocol,
+    /// Size of the frames of the commands in `queued`
+    queued_bytes: usize,
+    /// Set for connections to the admin listener
+    admin: bool,
+    /// What the user the client logged in as with AUTH may run
+    profile: Option<Profile>,
+    /// Database the client selected, along with its engine unless it is
+    /// database 0
+    db: usize,
+    selected: Option<E>,
+    /// Set once the client sent SUBSCRIBE, the connection then only streams
+    /// it changes
+    subscription: Option<Receiver<ChangeEvent>>,
+    /// Id of the connection, its requests are numbered within it
+    conn: u64,
+    /// Address of the client, as logs give it
+    client: String,
+    /// Requests answered so far
+    requests: u64,
+    /// Traffic of the connection so far, bytes written as of the last batch
+    /// of replies flushed
+    wire: WireStats,
+}
+
+impl<E: KvsEngine> Session<E> {
+    fn new(admin: bool, conn: u64, client: String) -> Self {
+        Self {
+            queued: None,
+            aborted: false,
+            replica: false,
+            protocol: Protocol::default(),
+            queued_bytes: 0,
+            admin,
+            profile: None,
+            db: 0,
+            selected: None,
+            subscription: None,
+            conn,
+            client,
+            requests: 0,
+            wire: WireStats::default(),
+        }
+    }
+
+    /// Engine of the database the client selected
+    fn engine<'a>(&'a self, state: &'a ServerState<E>) -> &'a E {
+        self.selected.as_ref().unwrap_or(&state.engine)
+    }
+}
+
+/// Error reply for a command the listener the session connected to does not
+/// take, see `KvsServer::admin_listener`
+fn listener_refusal<E: KvsEngine>(
+    state: &ServerState<E>,
+    session: &Session<E>,
+    command: &KvsCommand,
+) -> Option<&'static str> {
+    let connection_level = matches!(command, KvsCommand::Ping | KvsCommand::Hello(_));
+    if session.admin && !command.is_admin() && !connection_level {
+        return Some("-ERR only admin commands are allowed on the admin port\r\n");
+    }
+    if state.admin_listener && !session.admin && command.is_admin() {
+        return Some("-ERR admin commands are only allowed on the admin port\r\n");
+    }
+    None
+}
+
+/// Error reply for a TTL that runs past the last millisecond a deadline can
+/// count, a json value that does not parse, or a value with a TTL that is
+/// not UTF-8 text, which is all the engine expires
+fn invalid_value(command: &KvsCommand) -> Option<&'static str> {
+    match command {
+        KvsCommand::Set(.., Some(ttl))
+            if ttl.as_millis() > u128::from(i64::MAX as u64 - now_millis()) =>
+        {
+            Some("-ERR invalid expire time in 'set' command\r\n")
+        }
+        KvsCommand::Set(_, value, content_type, Some(_))
+            if !content_type.is_text() || str::from_utf8(value).is_err() =>
+        {
+            Some("-ERR only UTF-8 text values can have a TTL\r\n")
+        }
+        KvsCommand::Set(_, value, ContentType::Json, _)
+            if serde_json::from_slice::<serde::de::IgnoredAny>(value).is_err() =>
+        {
+            Some("-ERR value is not valid JSON\r\n")
+        }
+        _ => None,
+    }
+}
+
+/// Error reply for a write with a key or value larger than the server
+/// takes, see `KvsServer::max_key_size` and `max_value_size`
+fn oversized<E: KvsEngine>(state: &ServerState<E>, command: &KvsCommand) -> Option<String> {
+    let (keys, values): (Vec<&str>, Vec<usize>) = match command {
+        KvsCommand::Set(key, value, ..) => (vec![key], vec![value.len()]),
+        KvsCommand::Mset(pairs) => pairs
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.len()))
+            .unzip(),
+        KvsCommand::Append(key, value) => (vec![key], vec![value.len()]),
+        KvsCommand::Cas(key, _, new) => (vec![key], new.iter().map(String::len).collect()),
+        KvsCommand::Rm(key) | KvsCommand::Persist(key) => (vec![key], Vec::new()),
+        _ => return None,
+    };
+    let error = if let Some(key) = keys.iter().find(|key| key.len() > state.max_key_size) {
+        KvsError::KeyTooLarge {
+            len: key.len(),
+            max: state.max_key_size,
+        }
+    } else if let Some(len) = values.iter().find(|len| **len > state.max_value_size) {
+        KvsError::ValueTooLarge {
+            len: *len,
+            max: state.max_value_size,
+        }
     } else {
-        log::debug!("message sent: {}", message);
+        return None;
+    };
+    size_reply(&error)
+}
+
+/// Error reply for a key or value larger than the server or the engine
+/// takes, `None` for any other error
+fn size_reply(error: &KvsError) -> Option<String> {
+    let (what, len, max) = match error {
+        KvsError::KeyTooLarge { len, max } => ("key", len, max),
+        KvsError::ValueTooLarge { len, max } => ("value", len, max),
+        _ => return None,
+    };
+    Some(format!(
+        "-ERR {} of {} bytes is larger than the {} bytes allowed\r\n",
+        what, len, max
+    ))
+}
+
+/// Routes `command` through the connection's transaction state, queueing it
+/// while a MULTI is open
+fn handle_request<E: KvsEngine, W: Write>(
+    state: &ServerState<E>,
+    session: &mut Session<E>,
+    command: Option<KvsCommand>,
+    writer: &mut W,
+) -> Result<()> {
+    // anyone may run anything on a server without users
+    let profile = match state.users.is_empty() {
+        true => Some(Profile::Admin),
+        false => session.profile,
+    };
+    let refusal = match (profile, &command) {
+        (_, Some(KvsCommand::Auth(..))) => None,
+        (None, _) => Some(NOAUTH_REPLY.to_vec()),
+        (Some(profile), Some(command)) if !profile.allows(command) => Some(
+            format!(
+                "-NOPERM this user has no permissions to run the '{}' command\r\n",
+                command.name()
+            )
+            .into_bytes(),
+        ),
+        _ => None,
+    };
+    if let Some(reply) = refusal {
+        session.aborted |= session.queued.is_some();
+        return Ok(writer.write_all(&reply)?);
+    }
+    if let Some(reply) = command.as_ref().and_then(|command| {
+        listener_refusal(state, session, command)
+            .or_else(|| invalid_value(command))
+            .map(str::to_owned)
+            .or_else(|| oversized(state, command))
+    }) {
+        session.aborted |= session.queued.is_some();
+        return Ok(writer.write_all(reply.as_bytes())?);
+    }
+    let queued = match session.queued.as_mut() {
+        None => {
+            return match command {
+                Some(KvsCommand::Multi) => {
+                    session.queued = Some(Vec::new());
+                    session.aborted = false;
+                    writer.write_all(b"+OK\r\n")?;
+                    Ok(())
+                }
+                Some(KvsCommand::Sync) => {
+                    session.replica = true;
+                    Ok(())
+                }
+                Some(KvsCommand::Subscribe(prefix)) => {
+                    let changes = session.engine(state).subscribe(&prefix)?;
+                    let reply = protocol::command_frame(&["subscribe", &prefix, "1"]);
+                    session.subscription = Some(changes);
+                    Ok(writer.write_all(&reply)?)
+                }
+                Some(KvsCommand::Hello(version)) => {
+                    let reply = hello_reply(state, session, version.as_deref())?;
+                    Ok(writer.write_all(reply.as_bytes())?)
+                }
+                Some(KvsCommand::Shutdown(save)) => {
+                    // acknowledged before the server drains, which lets this
+                    // connection finish answering and close
+                    writer.write_all(b"+OK\r\n")?;
+                    state.shutdown.shutdown_from_client(save);
+                    Ok(())
+                }
+                Some(KvsCommand::Auth(username, password)) => {
+                    let reply = auth_reply(state, session, username.as_deref(), &password);
+                    Ok(writer.write_all(reply)?)
+                }
+                Some(KvsCommand::Select(db)) => {
+                    if db >= DATABASES {
+                        return Ok(writer.write_all(b"-ERR DB index is out of range\r\n")?);
+                    }
+                    session.selected = match db {
+                        0 => None,
+                        db => Some(state.engine.select(db)?),
+                    };
+                    session.db = db;
+                    Ok(writer.write_all(b"+OK\r\n")?)
+                }
+                Some(command) => match handle_command(state, session, &command, writer) {
+                    // the engine quarantined the key and goes on serving others
+                    Err(KvsError::Corruption { .. }) => Ok(writer.write_all(CORRUPT_REPLY)?),
+                    // the disk is stuck, the worker is free for the next command
+                    Err(KvsError::Timeout) if command.is_write() => {
+                        Ok(writer.write_all(WRITE_TIMEOUT_REPLY)?)
+                    }
+                    Err(KvsError::Timeout) => Ok(writer.write_all(TIMEOUT_REPLY)?),
+                    // limits of the engine tighter than the server's
+                    Err(e) => match size_reply(&e) {
+                        Some(reply) => Ok(writer.write_all(reply.as_bytes())?),
+                        None => Err(e),
+                    },
+                    handled => handled,
+                },
+                None => Ok(writer.write_all(b"-ERR invalid command\r\n")?),
+            };
+        }
+        Some(queued) => queued,
+    };
+    match command {
+        Some(KvsCommand::Exec) => {
+            let queued = session.queued.take().unwrap_or_default();
+            if session.aborted {
+                writer.write_all(
+                    b"-EXECABORT Transaction discarded because of previous errors\r\n",
+                )?;
+            } else {
+                exec_transaction(state, session, queued, writer)?;
+            }
+        }
+        Some(KvsCommand::Discard) => {
+            session.queued = None;
+            writer.write_all(b"+OK\r\n")?;
+        }
+        Some(KvsCommand::Multi) => {
+            writer.write_all(b"-ERR MULTI calls can not be nested\r\n")?;
+        }
+        Some(KvsCommand::Sync) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR SYNC is not allowed inside MULTI\r\n")?;
+        }
+        Some(KvsCommand::Hello(_)) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR HELLO is not allowed inside MULTI\r\n")?;
+        }
+        Some(KvsCommand::Shutdown(_)) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR SHUTDOWN is not allowed inside MULTI\r\n")?;
+        }
+        Some(KvsCommand::Select(_)) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR SELECT is not allowed inside MULTI\r\n")?;
+        }
+        Some(KvsCommand::Auth(..)) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR AUTH is not allowed inside MULTI\r\n")?;
+        }
+        Some(KvsCommand::Subscribe(_)) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR SUBSCRIBE is not allowed inside MULTI\r\n")?;
+        }
+        // a batch has no write that takes a TTL off a key
+        Some(KvsCommand::Persist(_)) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR PERSIST is not allowed inside MULTI\r\n")?;
+        }
+        // the engine's keys would not show the transaction's own writes
+        Some(
+            KvsCommand::Scan(..)
+            | KvsCommand::Keys(_)
+            | KvsCommand::RmMatching(_)
+            | KvsCommand::Flushdb
+            | KvsCommand::Dbsize
+            | KvsCommand::Bigkeys(..)
+            | KvsCommand::Quarantine
+            | KvsCommand::QuarantineDrop(_)
+            | KvsCommand::Stats
+            | KvsCommand::StatsReset
+            | KvsCommand::DictTrain(_)
+            | KvsCommand::Info,
+        ) => {
+            session.aborted = true;
+            writer.write_all(b"-ERR command is not allowed inside MULTI\r\n")?;
+        }
+        Some(
+            KvsCommand::Set(..)
+            | KvsCommand::Mset(_)
+            | KvsCommand::Rm(_)
+            | KvsCommand::Append(..)
+            | KvsCommand::Cas(..),
+        ) if state.read_only => {
+            session.aborted = true;
+            writer.write_all(READONLY_REPLY.as_bytes())?;
+        }
+        Some(command) => {
+            queued.push(command);
+            writer.write_all(b"+QUEUED\r\n")?;
+        }
+        None => {
+            session.aborted = true;
+            writer.write_all(b"-ERR invalid command\r\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Answers the queued commands of a transaction as one array reply and
+/// applies all of its writes atomically with a single `write_batch`.
+/// Reads inside the transaction observe the transaction's earlier writes.
+fn exec_transaction<E: KvsEngine, W: Write>(
+    state: &ServerState<E>,
+    session: &Session<E>,
+    queued: Vec<KvsCommand>,
+    writer: &mut W,
+) -> Result<()> {
+    let engine = session.engine(state);
+    let protocol = session.protocol;
+    // pending writes of this transaction, `None` marks a removed key
+    let mut overlay: HashMap<String, Option<(Vec<u8>, ContentType)>> = HashMap::new();
+    // and the expiry each of them is written with
+    let mut expiries: HashMap<String, Option<u64>> = HashMap::new();
+    let mut batch = Vec::new();
+    let mut replies = Vec::with_capacity(queued.len());
+    for command in queued {
+        let reply = match command {
+            KvsCommand::Set(key, value, content_type, ttl) => {
+                overlay.insert(key.clone(), Some((value.clone(), content_type)));
+                let expires_at = ttl.map(|ttl| {
+                    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+                    now_millis().saturating_add(ttl)
+                });
+                expiries.insert(key.clone(), expires_at);
+                batch.push(client::Command::set_from_bytes(
+                    key,
+                    value,
+                    expires_at,
+                    content_type,
+                ));
+                b"+OK\r\n".to_vec()
+            }
+            KvsCommand::Get(key) => {
+                let value = match overlay.get(&key) {
+                    Some(value) => value.clone(),
+                    None => engine.get_typed(key)?,
+                };
+                state.get_reply(value, protocol)
+            }
+            KvsCommand::Mset(pairs) => {
+                for (key, value) in pairs {
+                    overlay.insert(key.clone(), Some((value.clone(), ContentType::Text)));
+                    expiries.insert(key.clone(), None);
+                    batch.push(client::Command::set_from_bytes(
+                        key,
+                        value,
+                        None,
+                        ContentType::Text,
+                    ));
+                }
+                b"+OK\r\n".to_vec()
+            }
+            KvsCommand::Mget(keys) => {
+                let mut reply = format!("*{}\r\n", keys.len()).into_bytes();
+                let values = engine.get_many(&keys)?;
+                for (key, value) in keys.iter().zip(values) {
+                    let value = match overlay.get(key) {
+                        Some(value) => value.clone(),
+                        None => value,
+                    };
+                    reply.extend(value_reply(value, protocol));
+                }
+                reply
+            }
+            KvsCommand::Rm(key) => {
+                let exists = match overlay.get(&key) {
+                    Some(value) => value.is_some(),
+                    None => engine.exists(&key)?,
+                };
+                if exists {
+                    overlay.insert(key.clone(), None);
+                    expiries.insert(key.clone(), None);
+                    batch.push(client::Command::Rm { key });
+                    b"+OK\r\n".to_vec()
+                } else {
+                    b"-Key not found\r\n".to_vec()
+                }
+            }
+            KvsCommand::Append(key, value) => {
+                // the appended value keeps the expiry and content type
+                let (current, expires_at) = match overlay.get(&key) {
+                    Some(value) => (value.clone(), expiries.get(&key).copied().flatten()),
+                    None => (engine.get_typed(&key)?, engine.expires_at(&key)?),
+                };
+                let (mut appended, content_type) = current.unwrap_or_default();
+                appended.extend_from_slice(value.as_bytes());
+                let len = appended.len();
+                overlay.insert(key.clone(), Some((appended.clone(), content_type)));
+                expiries.insert(key.clone(), expires_at);
+                batch.push(client::Command::set_from_bytes(
+                    key,
+                    appended,
+                    expires_at,
+                    content_type,
+                ));
+                format!(":{}\r\n", len).into_bytes()
+            }
+            KvsCommand::Cas(key, expected, new) => {
+                let current = match overlay.get(&key) {
+                    Some(value) => value.clone().map(|(value, _)| value),
+                    None => engine.get_bytes(&key)?,
+                };
+                let swapped = current.as_deref() == expected.as_ref().map(String::as_bytes);
+                if swapped {
+                    match new {
+                        Some(value) => {
+                            let typed = (value.clone().into_bytes(), ContentType::Text);
+                            overlay.insert(key.clone(), Some(typed));
+                            expiries.insert(key.clone(), None);
+                            batch.push(client::Command::Set {
+                                key,
+                                value,
+                                expires_at: None,
+                                content_type: ContentType::Text,
+                                ttl: None,
+                            });
+                        }
+                        None if current.is_some() => {
+                            overlay.insert(key.clone(), None);
+                            expiries.insert(key.clone(), None);
+                            batch.push(client::Command::Rm { key });
+                        }
+                        None => {}
+                    }
+                }
+                integer_reply(swapped).into()
+            }
+            KvsCommand::Exists(key) => integer_reply(match overlay.get(&key) {
+                Some(value) => value.is_some(),
+                None => engine.exists(key)?,
+            })
+            .into(),
+            KvsCommand::Ping => b"+PONG\r\n".to_vec(),
+            KvsCommand::Version => env!("CARGO_PKG_VERSION").into(),
+            KvsCommand::Backup(dest) => backup_reply(engine, &dest).into(),
+            KvsCommand::Export(dest) => export_reply(engine, &dest).into(),
+            KvsCommand::Multi
+            | KvsCommand::Exec
+            | KvsCommand::Discard
+            | KvsCommand::Sync
+            | KvsCommand::Hello(_)
+            | KvsCommand::Scan(..)
+            | KvsCommand::Keys(_)
+            | KvsCommand::RmMatching(_)
+            | KvsCommand::Flushdb
+            | KvsCommand::Dbsize
+            | KvsCommand::Bigkeys(..)
+            | KvsCommand::Quarantine
+            | KvsCommand::QuarantineDrop(_)
+            | KvsCommand::Stats
+            | KvsCommand::StatsReset
+            | KvsCommand::DictTrain(_)
+            | KvsCommand::Info
+            | KvsCommand::Shutdown(_)
+            | KvsCommand::Select(_)
+            | KvsCommand::Auth(..)
+            | KvsCommand::Subscribe(_)
+            | KvsCommand::Persist(_) => {
+                unreachable!("handle_request never queues these commands")
+            }
+        };
+        replies.push(reply);
+    }
+
+    if !batch.is_empty() {
+        let frames: Vec<Vec<u8>> = batch
+            .iter()
+            .map(|cmd| match cmd {
+                client::Command::Set {
+                    key,
+                    value,
+                    expires_at: Some(expires_at),
+                    ..
+                } => {
+                    let ttl = Duration::from_millis(expires_at.saturating_sub(now_millis()));
+                    replication::set_ttl_frame(key, value.as_bytes(), ttl)
+                }
+                client::Command::Set {
+                    key,
+                    value,
+                    content_type,
+                    ..
+                } => replication::set_frame(key, value.as_bytes(), *content_type),
+                client::Command::SetBytes {
+                    key,
+                    value,
+                    expires_at: Some(expires_at),
+                } => {
+                    let ttl = Duration::from_millis(expires_at.saturating_sub(now_millis()));
+                    replication::set_ttl_frame(key, value, ttl)
+                }
+                client::Command::SetBytes { key, value, .. } => {
+                    replication::set_frame(key, value, ContentType::Text)
+                }
+                client::Command::Rm { key } => replication::rm_frame(key),
+                _ => unreachable!("transactions only batch sets and removes"),
+            })
+            .collect();
+        let frames = replication::in_database(session.db, frames);
+        if let Err(e) = state
+            .replication
+            .replicate(&frames, || engine.write_batch(batch))
+        {
+            // a concurrent client removed a key this transaction removes
+            debug!("transaction batch rejected: {:?}", e);
+            writer.write_all(b"-EXECABORT Transaction failed to apply\r\n")?;
+            return Ok(());
+        }
+    }
+    writer.write_all(format!("*{}\r\n", replies.len()).as_bytes())?;
+    for reply in replies {
+        writer.write_all(&reply)?;
     }
     Ok(())
 }
+
+/// Stops a running `KvsServer` from another thread or a signal handler
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Connections>,
+}
+
+/// Connections of a server that are being served or waiting for a worker
+#[derive(Default)]
+struct Connections {
+    stopping: AtomicBool,
+    local_addr: Mutex<Option<SocketAddr>>,
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, TcpStream>>,
+    closed: Condvar,
+    /// Set by SHUTDOWN SAVE, `run` then syncs the engine to disk
+    sync_on_close: AtomicBool,
+}
+
+impl ShutdownHandle {
+    /// Makes `run` stop accepting connections, let the open ones finish the
+    /// requests they already sent, close the engine and return
+    pub fn shutdown(&self) {
+        self.inner.stopping.store(true, Ordering::SeqCst);
+        // wake the accept loop up so it notices
+        if let Some(mut addr) = *self.inner.local_addr.lock().unwrap() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    /// Shuts down on behalf of a client's SHUTDOWN, which asks whether the
+    /// engine is synced to disk before it is closed
+    fn shutdown_from_client(&self, save: bool) {
+        self.inner.sync_on_close.store(save, Ordering::SeqCst);
+        self.shutdown();
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.inner.stopping.load(Ordering::SeqCst)
+    }
+
+    fn track(&self, tcp: &TcpStream) -> Result<u64> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.open.lock().unwrap().insert(id, tcp.try_clone()?);
+        Ok(id)
+    }
+
+    fn untrack(&self, id: u64) {
+        self.inner.open.lock().unwrap().remove(&id);
+        self.inner.closed.notify_all();
+    }
+
+    /// Stops reading from every open connection so each one ends once it
+    /// answered what it already received, then waits for them to go away
+    fn drain(&self) {
+        let open = self.inner.open.lock().unwrap();
+        for tcp in open.values() {
+            let _ = tcp.shutdown(Shutdown::Read);
+        }
+        let (mut open, _) = self
+            .inner
+            .closed
+            .wait_timeout_while(open, DRAIN_TIMEOUT, |open| !open.is_empty())
+            .unwrap();
+        if !open.is_empty() {
+            log::warn!("closing {} connections that did not finish", open.len());
+            for (_, tcp) in open.drain() {
+                let _ = tcp.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
 pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
-    engine: E,
+    state: ServerState<E>,
     pool: T,
+    metrics_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    primary: Option<SocketAddr>,
 }
 
 impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
     pub fn new(engine: E, pool: T) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            state: ServerState {
+                engine,
+                replication: ReplicationLog::default(),
+                read_only: false,
+                missing_key_error: false,
+                metrics: Arc::new(Metrics::default()),
+                client_buffer_limit: CLIENT_BUFFER_LIMIT,
+                max_key_size: MAX_KEY_SIZE,
+                max_value_size: MAX_VALUE_SIZE,
+                admin_listener: false,
+                shutdown: ShutdownHandle::default(),
+                users: Arc::new(HashMap::new()),
+            },
+            pool,
+            metrics_addr: None,
+            admin_addr: None,
+            primary: None,
+        }
     }
 
+    /// Handle that makes `run` return, see `ShutdownHandle::shutdown`
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.state.shutdown.clone()
+    }
+
+    /// Answer GET on a missing key with the legacy `-Key not found` error
+    /// rather than the null bulk string Redis clients expect
+    pub fn missing_key_error(&mut self, enabled: bool) {
+        self.state.missing_key_error = enabled;
+    }
+
+    /// Close connections holding more than `bytes` of partial frames and
+    /// commands queued by MULTI, so a few clients can't exhaust the server's
+    /// memory. Defaults to 64 MiB.
+    pub fn client_buffer_limit(&mut self, bytes: usize) {
+        self.state.client_buffer_limit = bytes;
+    }
+
+    /// Refuse writes naming a key longer than `bytes` with an error reply,
+    /// before they reach the engine. Defaults to 64 KiB.
+    pub fn max_key_size(&mut self, bytes: usize) {
+        self.state.max_key_size = bytes;
+    }
+
+    /// Refuse writes of a value larger than `bytes` with an error reply,
+    /// before they reach the engine. Defaults to 512 MiB.
+    pub fn max_value_size(&mut self, bytes: usize) {
+        self.state.max_value_size = bytes;
+    }
+
+    /// Also serve the metrics STATS returns over HTTP, at `/metrics` on
+    /// `addr`, for Prometheus to scrape
+    pub fn metrics_listener(&mut self, addr: SocketAddr) {
+        self.metrics_addr = Some(addr);
+    }
+
+    /// Serve admin commands such as BACKUP only on a listener of their own
+    /// at `addr`, which takes nothing else, rather than on the data listener
+    pub fn admin_listener(&mut self, addr: SocketAddr) {
+        self.admin_addr = Some(addr);
+        self.state.admin_listener = true;
+    }
+
+    /// Turns this server into a read-only replica of `primary`, its engine
+    /// follows the primary's writes from a background thread once the
+    /// server runs
+    pub fn replicate_from(&mut self, primary: SocketAddr) {
+        self.state.read_only = true;
+        self.primary = Some(primary);
+    }
+
+    /// Refuse every command but AUTH until the client sent `password`, as
+    /// the default user which may run everything. A replica authenticates
+    /// to its primary with the same password.
+    pub fn require_pass(&mut self, password: String) {
+        self.add_user(DEFAULT_USER.to_string(), password, Profile::Admin);
+    }
+
+    /// Let clients AUTH as `name` with `password` and then run the commands
+    /// `profile` allows. Once there are users, every client must log in.
+    pub fn add_user(&mut self, name: String, password: String, profile: Profile) {
+        Arc::make_mut(&mut self.state.users).insert(name, User { password, profile });
+    }
+
+    /// Serves clients on `addr` until the server is shut down through its
+    /// `shutdown_handle`, then closes the engine
     pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Err(e) => error!("could not bind to addres, err:{}", e),
-                Ok(stream) => {
-                    if let Err(e) = self.serve(stream) {
-                        error!("Error handling connection: {:?}", e);
-                    }
+        record_panic_backtraces();
+        *self.state.shutdown.inner.local_addr.lock().unwrap() = Some(listener.local_addr()?);
+        if let Some(metrics_addr) = self.metrics_addr {
+            let metrics_listener = TcpListener::bind(metrics_addr)?;
+            let state = self.state.clone();
+            std::thread::spawn(move || serve_metrics(metrics_listener, state));
+        }
+        if let Some(admin_addr) = self.admin_addr {
+            let admin_listener = TcpListener::bind(admin_addr)?;
+            let state = self.state.clone();
+            std::thread::spawn(move || serve_admin(admin_listener, state));
+        }
+        if let Some(primary) = self.primary {
+            let engine = self.state.engine.clone();
+            let password = self.state.users.get(DEFAULT_USER);
+            let password = password.map(|user| user.password.clone());
+            replication::replicate_from(engine, primary, password);
+        }
+        let mut acceptor = Acceptor::new(listener, "client");
+        loop {
+            let stream = acceptor.accept();
+            if self.state.shutdown.is_stopping() {
+                break;
+            }
+            if let Some(stream) = stream {
+                if let Err(e) = self.serve(stream) {
+                    error!("Error handling connection: {:?}", e);
                 }
             }
         }
-        Ok(())
+        log::info!("shutting down");
+        self.state.shutdown.drain();
+        if self
+            .state
+            .shutdown
+            .inner
+            .sync_on_close
+            .load(Ordering::SeqCst)
+        {
+            self.state.engine.sync()?;
+        }
+        self.state.engine.close()
     }
 
     fn serve(&mut self, tcp: TcpStream) -> Result<()> {
-        let engine = self.engine.clone();
-        self.pool.spawn(move || {
-            let mut reader = BufReader::new(&tcp);
-
-            loop {
-                let mut buf: Vec<u8> = vec![0; 1024];
-                match reader.read(&mut buf) {
-                    Ok(0) => {
-                        log::info!("connection closed");
+        let state = self.state.clone();
+        let id = state.shutdown.track(&tcp)?;
+        self.pool.spawn(move || serve_client(state, tcp, id, false));
+        Ok(())
+    }
+}
+
+/// Accepts the connections of a listener, riding out accept errors instead
+/// of spinning on them. A spare file descriptor is held open while there are
+/// descriptors to spare; once the process runs out, it makes room to accept
+/// the waiting connection and close it, so the client is turned away rather
+/// than left hanging in the backlog.
+struct Acceptor {
+    listener: TcpListener,
+    /// What the listener serves, as logs name it
+    name: &'static str,
+    spare_fd: Option<File>,
+    /// Pause after the last error, zero after a connection is accepted
+    backoff: Duration,
+}
+
+impl Acceptor {
+    fn new(listener: TcpListener, name: &'static str) -> Self {
+        Self {
+            listener,
+            name,
+            spare_fd: open_spare_fd(),
+            backoff: Duration::ZERO,
+        }
+    }
+
+    /// Waits for the next connection, `None` once an accept failed. Errors
+    /// a client caused are retried right away, others after a pause.
+    fn accept(&mut self) -> Option<TcpStream> {
+        let e = match self.listener.accept() {
+            Ok((tcp, _)) => {
+                self.backoff = Duration::ZERO;
+                if self.spare_fd.is_none() {
+                    self.spare_fd = open_spare_fd();
+                }
+                return Some(tcp);
+            }
+            Err(e) => e,
+        };
+        match e.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted => {
+                debug!(
+                    "{} connection went away before it was accepted: {}",
+                    self.name, e
+                );
+                return None;
+            }
+            _ => {}
+        }
+        if e.raw_os_error()
+            .is_some_and(|code| OUT_OF_DESCRIPTORS.contains(&code))
+        {
+            log::warn!(
+                "out of file descriptors accepting a {} connection: {}",
+                self.name,
+                e
+            );
+            self.turn_away();
+        } else {
+            error!("could not accept {} connection: {}", self.name, e);
+        }
+        self.backoff = (self.backoff * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+        std::thread::sleep(self.backoff);
+        None
+    }
+
+    /// Closes the spare descriptor to accept the waiting connection and close
+    /// it, then opens the spare again if there is room
+    fn turn_away(&mut self) {
+        if self.spare_fd.take().is_none() {
+            return;
+        }
+        if let Ok((tcp, addr)) = self.listener.accept() {
+            log::warn!("turned away {} connection from {}", self.name, addr);
+            drop(tcp);
+        }
+        self.spare_fd = open_spare_fd();
+    }
+}
+
+/// A descriptor held in reserve by `Acceptor`, `None` when none could be
+/// opened
+fn open_spare_fd() -> Option<File> {
+    let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+    match File::open(null) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            debug!("could not open a spare file descriptor: {}", e);
+            None
+        }
+    }
+}
+
+/// Serves each admin connection on a thread of its own, so operators get in
+/// even when every worker of the pool is busy
+fn serve_admin<E: KvsEngine>(listener: TcpListener, state: ServerState<E>) {
+    let mut acceptor = Acceptor::new(listener, "admin");
+    loop {
+        let stream = acceptor.accept();
+        if state.shutdown.is_stopping() {
+            break;
+        }
+        let Some(tcp) = stream else {
+            continue;
+        };
+        match state.shutdown.track(&tcp) {
+            Ok(id) => {
+                let state = state.clone();
+                std::thread::spawn(move || serve_client(state, tcp, id, true));
+            }
+            Err(e) => error!("Error handling admin connection: {:?}", e),
+        }
+    }
+}
+
+/// Answers the requests of one client until it disconnects. `admin` is set
+/// for connections to the admin listener.
+fn serve_client<E: KvsEngine>(state: ServerState<E>, tcp: TcpStream, id: u64, admin: bool) {
+    state.metrics.client_connected();
+    let mut writer = BufWriter::new(CountingWriter {
+        inner: &tcp,
+        metrics: &state.metrics,
+        written: 0,
+    });
+    let mut decoder = FrameDecoder::new();
+    let client = tcp
+        .peer_addr()
+        .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+    log::info!(conn = id, client = client.as_str(); "connection opened");
+    let mut session = Session::new(admin, id, client);
+
+    loop {
+        match decoder.read_from(&mut &tcp) {
+            Ok(0) => {
+                log::info!(conn = id, client = session.client.as_str(); "connection closed");
+                break;
+            }
+            Ok(size) => {
+                state.metrics.bytes_read(&mut session.wire, size as u64);
+                let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                    handle_frames(&state, &mut session, &mut decoder, &mut writer)
+                }));
+                session.wire.bytes_written = writer.get_ref().written;
+                let handled = match handled {
+                    Ok(handled) => handled,
+                    Err(panic) => {
+                        // the session may be half way through a
+                        // command, it can't be trusted to go on
+                        error!(
+                            "panic handling client request: {}\n{}",
+                            panic_message(&*panic),
+                            take_panic_backtrace()
+                        );
+                        let _ = writer.write_all(b"-ERR internal error\r\n");
+                        let _ = writer.flush();
+                        break;
+                    }
+                };
+                match handled {
+                    Ok(_) if session.replica => {
+                        log::info!("replica connected");
+                        if let Err(e) = replication::feed_replica(
+                            &state.engine,
+                            &state.replication,
+                            &mut writer,
+                        ) {
+                            log::info!("replica disconnected: {:?}", e);
+                        }
+                        break;
+                    }
+                    Ok(_) if session.subscription.is_some() => {
+                        let changes = session.subscription.take().unwrap();
+                        if let Err(e) = feed_subscriber(changes, &mut writer) {
+                            log::info!("subscriber disconnected: {:?}", e);
+                        }
                         break;
                     }
-                    Ok(size) => {
-                        let s = std::str::from_utf8(&buf[..size]).unwrap();
-                        let resp = common::parse_resp(s).unwrap().1;
-                        let command = common::parse_command(&resp).unwrap();
-                        handle_command(&engine, &command, &tcp).unwrap();
+                    Ok(()) => {
+                        if decoder.buffered() + session.queued_bytes > state.client_buffer_limit {
+                            error!("client exceeded the buffer limit, closing connection");
+                            state.metrics.oversized_frame(&mut session.wire);
+                            let _ = writer.write_all(BUFFER_LIMIT_REPLY);
+                            let _ = writer.flush();
+                            break;
+                        }
                     }
                     Err(e) => {
-                        error!("Error reading from client: {}", e);
+                        error!("Error handling client request: {:?}", e);
+                        let _ = writer.write_all(b"-ERR protocol error\r\n");
+                        let _ = writer.flush();
                         break;
                     }
                 }
             }
-        });
-        Ok(())
+            Err(e) => {
+                error!("Error reading from client: {}", e);
+                break;
+            }
+        }
+    }
+    debug!(
+        conn = id,
+        bytes_read = session.wire.bytes_read,
+        bytes_written = writer.get_ref().written,
+        frames = session.wire.frames,
+        parse_errors = session.wire.parse_errors;
+        "connection traffic"
+    );
+    state.metrics.client_disconnected();
+    state.shutdown.untrack(id);
+}
+
+/// Streams `changes` to a client that sent SUBSCRIBE as `change` arrays of
+/// the kind of change and the key, until the client goes away. Pings while
+/// nothing changes tell a client that left from one that is waiting.
+fn feed_subscriber<W: Write>(changes: Receiver<ChangeEvent>, writer: &mut W) -> Result<()> {
+    loop {
+        match changes.recv_timeout(SUBSCRIBER_HEARTBEAT) {
+            Ok(change) => {
+                for change in std::iter::once(change).chain(changes.try_iter()) {
+                    writer.write_all(&protocol::command_frame(&[
+                        "change",
+                        change.name(),
+                        change.key(),
+                    ]))?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                writer.write_all(&protocol::command_frame(&["ping"]))?
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        writer.flush()?;
+    }
+}
+
+thread_local! {
+    /// Backtrace of the last panic on this thread, see `record_panic_backtraces`
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+/// Chains a panic hook that keeps the backtrace of every panic for
+/// `take_panic_backtrace`, a caught panic has already unwound the stack
+/// that caused it
+fn record_panic_backtraces() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            PANIC_BACKTRACE.with(|backtrace| {
+                *backtrace.borrow_mut() = Some(Backtrace::force_capture());
+            });
+            previous(info);
+        }));
+    });
+}
+
+fn take_panic_backtrace() -> String {
+    PANIC_BACKTRACE
+        .with(|backtrace| backtrace.borrow_mut().take())
+        .map_or_else(
+            || "no backtrace".to_string(),
+            |backtrace| backtrace.to_string(),
+        )
+}
+
+/// The message `panic!` was called with, if it was given one
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
     }
 }
+
+/// Log target of the line every request is logged with at debug level,
+/// enable it with `RUST_LOG=kvs::request=debug`
+const REQUEST_LOG: &str = "kvs::request";
+
+/// Passes the bytes written to a client's socket through and counts them, for
+/// the connection and in the server's metrics
+struct CountingWriter<'a, W: Write> {
+    inner: W,
+    metrics: &'a Metrics,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        self.metrics.bytes_written(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes a reply through and remembers its first byte, which tells error
+/// replies apart in the request log
+struct ReplyWriter<'a, W: Write> {
+    inner: &'a mut W,
+    first: Option<u8>,
+}
+
+impl<W: Write> Write for ReplyWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.first.is_none() {
+            self.first = buf.first().copied();
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Handles every complete frame `decoder` holds in order. A trailing partial
+/// frame is left in it for the caller to complete with the next read.
+/// Replies are flushed once, after the whole pipelined batch has been
+/// answered.
+fn handle_frames<E: KvsEngine, W: Write>(
+    state: &ServerState<E>,
+    session: &mut Session<E>,
+    decoder: &mut FrameDecoder,
+    writer: &mut W,
+) -> Result<()> {
+    loop {
+        let frame = match decoder.next_frame() {
+            Ok(Decoded::Frame(frame)) => frame,
+            Ok(Decoded::NeedMoreData) => break,
+            Err(e) => {
+                state.metrics.parse_error(&mut session.wire);
+                return Err(KvsError::Message(format!("invalid RESP frame: {}", e)));
+            }
+        };
+        match protocol::from_bytes(frame) {
+            Ok(resp) => {
+                state.metrics.frame_read(&mut session.wire);
+                let command = protocol::parse_command(&resp);
+                if command.is_none() {
+                    state.metrics.parse_error(&mut session.wire);
+                }
+                let name = command.as_ref().map_or("unknown", KvsCommand::name);
+                let key = match log::log_enabled!(target: REQUEST_LOG, log::Level::Debug) {
+                    true => command
+                        .as_ref()
+                        .and_then(KvsCommand::key)
+                        .map(str::to_owned),
+                    false => None,
+                };
+                let started = Instant::now();
+                let mut reply = ReplyWriter {
+                    inner: &mut *writer,
+                    first: None,
+                };
+                let handled = handle_request(state, session, command, &mut reply);
+                let elapsed = started.elapsed();
+                state.metrics.record_command(name, elapsed);
+                session.requests += 1;
+                let outcome = match (&handled, reply.first) {
+                    (Err(_), _) => "failed",
+                    (Ok(()), Some(b'-')) => "error",
+                    (Ok(()), Some(_)) => "ok",
+                    (Ok(()), None) => "no reply",
+                };
+                debug!(
+                    target: REQUEST_LOG,
+                    id = format!("{}-{}", session.conn, session.requests).as_str(),
+                    client = session.client.as_str(),
+                    command = name,
+                    key = key.as_deref(),
+                    micros = elapsed.as_micros() as u64,
+                    outcome = outcome;
+                    "request"
+                );
+                handled?;
+                session.queued_bytes = match session.queued {
+                    Some(_) => session.queued_bytes + frame.len(),
+                    None => 0,
+                };
+                if session.replica || session.subscription.is_some() {
+                    break;
+                }
+            }
+            Err(e) => {
+                state.metrics.parse_error(&mut session.wire);
+                return Err(KvsError::Message(format!("invalid RESP frame: {}", e)));
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Answers `GET /metrics` over HTTP/1.1 with one request per connection,
+/// until the server is shut down
+fn serve_metrics<E: KvsEngine>(listener: TcpListener, state: ServerState<E>) {
+    let mut acceptor = Acceptor::new(listener, "metrics");
+    loop {
+        let stream = acceptor.accept();
+        if state.shutdown.is_stopping() {
+            break;
+        }
+        if let Some(mut stream) = stream {
+            if let Err(e) = answer_metrics_request(&state, &mut stream) {
+                debug!("metrics request failed: {:?}", e);
+            }
+        }
+    }
+}
+
+fn answer_metrics_request<E: KvsEngine>(
+    state: &ServerState<E>,
+    stream: &mut TcpStream,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&*stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // read the headers too, closing with unread input would reset the
+    // connection under the response
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = if request_line.starts_with("GET /metrics ") {
+        ("200 OK", state.metrics.render(&state.engine.stats()?))
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
@@ -1,6 +1,8 @@
 use assert_cmd::prelude::*;
+use predicates::boolean::PredicateBooleanExt;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::TcpListener;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -71,6 +73,13 @@ fn client_cli_invalid_set() {
         .assert()
         .failure();
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key", "value", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["set", "key", "value", "--addr", "invalid-addr"])
@@ -118,6 +127,24 @@ fn client_cli_invalid_rm() {
         .failure();
 }
 
+#[test]
+fn client_cli_invalid_fill() {
+    let temp_dir = TempDir::new().unwrap();
+    for args in [
+        &["fill", "--keys", "0"][..],
+        &["fill", "--keys", "1.5"],
+        &["fill", "--batch", "none"],
+        &["fill", "--pattern", "user"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
 #[test]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
@@ -212,6 +239,168 @@ fn cli_wrong_engine() {
     }
 }
 
+// The engine is named by the `engine` file, or by the log files of a
+// directory written before there was one
+#[test]
+fn cli_wrong_engine_marker() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEngine"));
+
+    let temp_dir = TempDir::new().unwrap();
+    File::create(temp_dir.path().join("wal_1.log")).unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEngine"));
+}
+
+// `kvs` refuses the sled engine it cannot open instead of panicking
+#[test]
+fn cli_dump_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    for args in [
+        &["dump", "--output", "store.dump"][..],
+        &["restore", "--input", "store.dump"],
+        &["import-redis", "appendonly.aof"],
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .args(&["--engine", "sled"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("invalid value 'sled'").and(contains("panicked").not()));
+    }
+}
+
+// Every request is logged with its id, client, command, key, latency and
+// outcome when the kvs::request target is enabled
+#[test]
+fn cli_request_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let stdout_path = temp_dir.path().join("stdout");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "memory", "--addr", "127.0.0.1:4007"])
+        .args(&["--log-format", "json"])
+        .env("RUST_LOG", "kvs::request=debug")
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let commands: [&[&str]; 2] = [&["set", "key1", "value1"], &["rm", "key2"]];
+    for args in commands {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", "127.0.0.1:4007"])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+    }
+    child.kill().expect("server exited before killed");
+
+    let content = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
+    let requests: Vec<&str> = content
+        .lines()
+        .filter(|line| line.contains(r#""target":"kvs::request""#))
+        .collect();
+    assert_eq!(requests.len(), 2, "{}", content);
+    assert!(requests[0].contains(r#""command":"set""#));
+    assert!(requests[0].contains(r#""key":"key1""#));
+    assert!(requests[0].contains(r#""outcome":"ok""#));
+    assert!(requests[0].contains(r#""client":"127.0.0.1:"#));
+    assert!(requests[0].contains(r#""micros":"#));
+    assert!(requests[1].contains(r#""outcome":"error""#));
+}
+
+// Containers configure the server through the environment and need it to
+// exit when it cannot listen
+#[test]
+fn cli_container_env() {
+    let temp_dir = TempDir::new().unwrap();
+    let _taken = TcpListener::bind("127.0.0.1:4006").unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.env("KVS_ADDR", "127.0.0.1:4006")
+        .env("KVS_ENGINE", "kvs")
+        .env("KVS_DATA_DIR", &data_dir)
+        .env("KVS_LOG_FORMAT", "json")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(r#""message":"Listening on: 127.0.0.1:4006""#));
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+}
+
+// kvs-bench reports throughput and latency percentiles of an engine, and
+// refuses a ratio it cannot mix
+#[test]
+fn cli_bench_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(&["engine", "--data-dir"])
+        .arg(temp_dir.path())
+        .args(&["-c", "2", "-n", "100", "--keys", "10", "--get-ratio", "1"])
+        .assert()
+        .success()
+        .stdout(contains("200 requests in"))
+        .stdout(contains("GET          200"))
+        .stdout(contains("SET").not());
+    assert!(temp_dir.path().join("wal_1.log").exists());
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(&["engine", "--engine", "memory", "--get-ratio", "2"])
+        .assert()
+        .failure();
+}
+
+// kvs-client --trace-wire prints the frames it sends and receives escaped
+// to one line each
+#[test]
+fn cli_trace_wire() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--trace-wire"])
+        .args(&["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            r"-> *3\r\n$3\r\nset\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n",
+        ))
+        .stdout(contains(r"<- +OK\r\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1"])
+        .args(&["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("->").not());
+    server.kill().expect("server exited before killed");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +524,56 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-server --self-test` should report on the engine and filesystem and exit
+#[test]
+fn server_cli_self_test() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--self-test"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine       ok"));
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
+
+// A server out of file descriptors keeps running and serves again once
+// clients close their connections
+#[cfg(unix)]
+#[test]
+fn cli_out_of_file_descriptors() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let server = assert_cmd::cargo::cargo_bin("kvs-server");
+    let mut child = Command::new("sh")
+        .args(&["-c", "ulimit -n 64 && exec \"$0\" \"$@\""])
+        .arg(&server)
+        .args(&["--engine", "memory", "--addr", "127.0.0.1:4009"])
+        .args(&["--threads", "100"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let clients: Vec<TcpStream> = (0..100)
+        .filter_map(|_| TcpStream::connect("127.0.0.1:4009").ok())
+        .collect();
+    thread::sleep(Duration::from_millis(500));
+    drop(clients);
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4009").unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+    let mut reply = [0; 7];
+    stream.read_exact(&mut reply).unwrap();
+    child.kill().expect("server exited before killed");
+    assert_eq!(&reply, b"+PONG\r\n");
+}
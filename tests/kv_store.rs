@@ -1,6 +1,18 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::client::Command;
+use kvs::dump::{self, DumpReader, DumpWriter};
+use kvs::engines::MemStore;
+use kvs::protocol;
+use kvs::redis;
+use kvs::{
+    ChangeEvent, Compression, ContentType, Durability, KeySize, KvStore, KvStoreOptions, KvsEngine,
+    KvsError, QuarantinedKey, Result,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -63,6 +75,87 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Keys are looked up and removed by reference as well
+#[test]
+fn borrowed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let key = "key1";
+    assert_eq!(store.get(key)?, Some("value1".to_owned()));
+    assert_eq!(store.get_bytes(key)?, Some(b"value1".to_vec()));
+    assert!(store.exists(key)?);
+    store.remove(key)?;
+    assert_eq!(store.get(key)?, None);
+    assert!(store.remove(key).is_err());
+    Ok(())
+}
+
+// Subscribers get the changes to the keys they watch in the order they were
+// applied, expirations included
+#[test]
+fn subscribe_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_interval(Duration::from_millis(20));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let users = store.subscribe("user:")?;
+    let everything = store.subscribe("")?;
+    drop(store.subscribe("user:")?);
+
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set("other".to_owned(), "b".to_owned())?;
+    store.write_batch(vec![Command::Rm {
+        key: "user:1".to_owned(),
+    }])?;
+    store.set_with_ttl(
+        "user:2".to_owned(),
+        "c".to_owned(),
+        Duration::from_millis(1),
+    )?;
+
+    let timeout = Duration::from_secs(5);
+    let mut changes = Vec::new();
+    while changes.len() < 4 {
+        changes.push(users.recv_timeout(timeout).expect("change not sent"));
+    }
+    assert_eq!(
+        changes,
+        vec![
+            ChangeEvent::Set("user:1".to_owned()),
+            ChangeEvent::Removed("user:1".to_owned()),
+            ChangeEvent::Set("user:2".to_owned()),
+            ChangeEvent::Expired("user:2".to_owned()),
+        ]
+    );
+    let keys: Vec<String> = everything
+        .try_iter()
+        .take(3)
+        .map(|change| change.key().to_owned())
+        .collect();
+    assert_eq!(keys, vec!["user:1", "other", "user:1"]);
+    store.close()
+}
+
+// Several keys are read at once, in order
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_typed("key2".to_owned(), "{}".to_owned(), ContentType::Json)?;
+    assert_eq!(
+        store.get_many(&["key2", "missing", "key1"])?,
+        vec![
+            Some((b"{}".to_vec(), ContentType::Json)),
+            None,
+            Some((b"value1".to_vec(), ContentType::Text)),
+        ]
+    );
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +302,1967 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Keys set with a TTL should disappear once it elapses, also after reopening
+#[test]
+fn set_with_ttl_expires() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // A plain set clears the expiry
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// A batch applies every command in order and persists them
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    store.write_batch(vec![
+        Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+            expires_at: None,
+            content_type: ContentType::Text,
+            ttl: None,
+        },
+        Command::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+            expires_at: None,
+            content_type: ContentType::Text,
+            ttl: None,
+        },
+        Command::Rm {
+            key: "key0".to_owned(),
+        },
+        Command::Rm {
+            key: "key2".to_owned(),
+        },
+    ])?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Removing a missing key rejects the whole batch
+    assert!(store
+        .write_batch(vec![
+            Command::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned(),
+                expires_at: None,
+                content_type: ContentType::Text,
+                ttl: None,
+            },
+            Command::Rm {
+                key: "key2".to_owned(),
+            },
+        ])
+        .is_err());
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Range and prefix scans return live pairs ordered by key
+#[test]
+fn range_and_prefix_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["user:2", "user:1", "item:1", "user:3", "users"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    store.remove("user:3".to_owned())?;
+
+    let pairs = |keys: &[&str]| -> Vec<(String, String)> {
+        keys.iter()
+            .map(|key| (key.to_string(), format!("{}-value", key)))
+            .collect()
+    };
+    assert_eq!(store.scan_prefix("user:")?, pairs(&["user:1", "user:2"]));
+    assert_eq!(store.scan_prefix("nope")?, pairs(&[]));
+    assert_eq!(
+        store.range("item:1".to_owned().."user:2".to_owned())?,
+        pairs(&["item:1", "user:1"])
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.range(..)?,
+        pairs(&["item:1", "user:1", "user:2", "users"])
+    );
+    Ok(())
+}
+
+// Iteration visits the keys there were when it started, with the values
+// they have when reached
+#[test]
+fn iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["key3", "key1", "key4", "key2"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    store.remove("key4".to_owned())?;
+
+    let mut iter = store.iter();
+    assert_eq!(
+        iter.next().transpose()?,
+        Some(("key1".to_owned(), "key1-value".to_owned()))
+    );
+    store.set("key0".to_owned(), "key0-value".to_owned())?;
+    store.set("key2".to_owned(), "key2-changed".to_owned())?;
+    store.remove("key3".to_owned())?;
+    let rest = iter.collect::<Result<Vec<_>>>()?;
+    assert_eq!(rest, vec![("key2".to_owned(), "key2-changed".to_owned())]);
+
+    let keys = store
+        .iter()
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["key0", "key1", "key2"]);
+    Ok(())
+}
+
+// A snapshot can be opened as a store and isn't affected by later writes
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    store.snapshot(backup_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(backup_dir.path().join("MANIFEST").is_file());
+    // the destination must not already hold a store
+    assert!(store.snapshot(backup_dir.path()).is_err());
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// An export cut short resumes from its last checkpoint, and the finished
+// dump imports into another store
+#[test]
+fn dump_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=4 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_typed("key5".to_owned(), "[1]".to_owned(), ContentType::Json)?;
+    store.set_bytes("key6".to_owned(), vec![0xff, 0])?;
+
+    // an export that stopped after its checkpoint and part of the next record
+    let path = temp_dir.path().join("export.dump");
+    let mut partial = DumpWriter::new(File::create(&path)?)?.checkpoint_every(2);
+    partial.write("key1", b"value1", ContentType::Text)?;
+    partial.write("key2", b"stale", ContentType::Text)?;
+    partial.write("key3", b"value3", ContentType::Text)?;
+    assert!(partial.write("key0", b"value0", ContentType::Text).is_err());
+    drop(partial);
+    let len = fs::metadata(&path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 2)?;
+
+    assert_eq!(dump::export_file(&store.handle(), &path)?, 6);
+    // the dump is finished
+    assert!(dump::export_file(&store.handle(), &path).is_err());
+
+    let mut reader = DumpReader::new(File::open(&path)?)?;
+    let records = reader.by_ref().collect::<Result<Vec<_>>>()?;
+    assert!(reader.is_finished());
+    let keys: Vec<&str> = records.iter().map(|record| record.key.as_str()).collect();
+    assert_eq!(keys, ["key1", "key2", "key3", "key4", "key5", "key6"]);
+    // records before the checkpoint are kept as they were written
+    assert_eq!(records[1].value, b"stale");
+
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let imported = KvStore::open(import_dir.path())?;
+    assert_eq!(
+        dump::import(&imported.handle(), DumpReader::new(File::open(&path)?)?)?,
+        6
+    );
+    assert_eq!(imported.get("key4")?, Some("value4".to_owned()));
+    assert_eq!(
+        imported.get_typed("key5")?,
+        Some((b"[1]".to_vec(), ContentType::Json))
+    );
+    assert_eq!(imported.get_bytes("key6")?, Some(vec![0xff, 0]));
+
+    // a flipped byte fails a checksum
+    let mut bytes = fs::read(&path)?;
+    bytes[24] ^= 1;
+    let corrupt = DumpReader::new(&bytes[..])?.collect::<Result<Vec<_>>>();
+    assert!(corrupt.is_err());
+    Ok(())
+}
+
+// A dump file of one engine restores into another
+#[test]
+fn dump_restore_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_typed("key2".to_owned(), "{}".to_owned(), ContentType::Json)?;
+    let path = temp_dir.path().join("store.dump");
+    assert_eq!(dump::export_file(&store.handle(), &path)?, 2);
+
+    let restored = MemStore::new();
+    assert_eq!(dump::import_file(&restored, &path)?, 2);
+    assert_eq!(restored.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(
+        restored.get_typed("key2")?,
+        Some((b"{}".to_vec(), ContentType::Json))
+    );
+    assert!(dump::import_file(&restored, &temp_dir.path().join("missing.dump")).is_err());
+    Ok(())
+}
+
+// The string keys a redis AOF leaves behind are imported, commands on
+// other types are skipped and a command cut short at the end is ignored
+#[test]
+fn redis_import_aof() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("old".to_owned(), "value".to_owned())?;
+
+    let mut aof = Vec::new();
+    for command in [
+        &["SELECT", "0"][..],
+        &["SET", "key1", "value1"],
+        &["APPEND", "key1", "+"],
+        &["SET", "expired", "value", "PXAT", "1000"],
+        &["INCR", "counter"],
+        &["INCRBY", "counter", "4"],
+        &["SETEX", "ttl", "100", "value"],
+        &["MULTI"],
+        &["SET", "key2", "value2"],
+        &["DEL", "key2", "old"],
+        &["EXEC"],
+        &["HSET", "hash", "field", "value"],
+        &["SELECT", "1"],
+        &["SET", "key1", "db1"],
+    ] {
+        aof.extend(protocol::command_frame(command));
+    }
+    aof.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$4\r\nkey3");
+
+    let import = redis::import(&store.handle(), &aof)?;
+    assert_eq!(import.keys, 4);
+    assert_eq!(import.skipped, 1);
+    assert_eq!(store.get("key1")?, Some("value1+".to_owned()));
+    assert_eq!(store.get("counter")?, Some("5".to_owned()));
+    assert_eq!(store.get("ttl")?, Some("value".to_owned()));
+    for key in ["expired", "key2", "old", "hash", "key3"] {
+        assert_eq!(store.get(key)?, None);
+    }
+    assert_eq!(store.select(1)?.get("key1")?, Some("db1".to_owned()));
+
+    assert!(redis::import(&store.handle(), b"+OK\r\n").is_err());
+    store.close()
+}
+
+// String keys of an RDB are imported along with the AOF after it, an RDB
+// holding another type fails
+#[test]
+fn redis_import_rdb() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut rdb = b"REDIS0009".to_vec();
+    // aux field, database 0 and its sizes
+    rdb.extend_from_slice(b"\xfa\x09redis-ver\x057.0.0\xfe\x00\xfb\x04\x01");
+    // an integer value expiring in the far future
+    rdb.push(0xfc);
+    rdb.extend_from_slice(&4_102_444_800_000u64.to_le_bytes());
+    rdb.extend_from_slice(b"\x00\x03int\xc0\x2a");
+    rdb.extend_from_slice(b"\x00\x03raw\x05hello");
+    // "a" and a back reference repeating it nine times
+    rdb.extend_from_slice(b"\x00\x03lzf\xc3\x05\x0a\x00a\xe0\x00\x00");
+    // expired before it is imported
+    rdb.push(0xfc);
+    rdb.extend_from_slice(&1000u64.to_le_bytes());
+    rdb.extend_from_slice(b"\x00\x07expired\x01x");
+    rdb.extend_from_slice(b"\xff\0\0\0\0\0\0\0\0");
+    let mut file = rdb.clone();
+    file.extend(protocol::command_frame(&["SET", "aof", "value"]));
+
+    let path = temp_dir.path().join("dump.rdb");
+    fs::write(&path, &file)?;
+    let import = redis::import_file(&store.handle(), &path)?;
+    assert_eq!(import.keys, 4);
+    assert_eq!(store.get("int")?, Some("42".to_owned()));
+    assert_eq!(store.get("raw")?, Some("hello".to_owned()));
+    assert_eq!(store.get("lzf")?, Some("a".repeat(10)));
+    assert_eq!(store.get("aof")?, Some("value".to_owned()));
+    assert_eq!(store.get("expired")?, None);
+
+    // a list
+    let mut list = rdb[..rdb.len() - 9].to_vec();
+    list.extend_from_slice(b"\x01\x04list\x01\x01x");
+    assert!(redis::import(&store.handle(), &list).is_err());
+    store.close()
+}
+
+// A record torn by a crash at the end of the newest log is dropped on open
+#[test]
+fn torn_write_is_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("wal_1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let valid_len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[42, 0, 0, 0, 1, 2])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), valid_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A damaged record in an older log is reported instead of silently dropped
+#[test]
+fn corrupt_record_is_reported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("wal_1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    // reopening seals wal_1 behind a fresh log
+    drop(KvStore::open(temp_dir.path())?);
+
+    let mut bytes = fs::read(&log)?;
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { file, offset }) => {
+            assert_eq!(file, log);
+            assert_eq!(offset, 8);
+        }
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("corrupt log opened without error"),
+    }
+    Ok(())
+}
+
+// Flips a byte of the value of the last record of `log`
+fn corrupt_last_record(log: &Path) -> Result<()> {
+    let mut bytes = fs::read(log)?;
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0xff;
+    fs::write(log, bytes)?;
+    Ok(())
+}
+
+// A value that fails its checksum quarantines its key: reads of it fail while
+// other keys are served, until the key is written again or dropped
+#[test]
+fn corrupt_value_is_quarantined() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("wal_1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let offset = fs::metadata(&log)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    corrupt_last_record(&log)?;
+
+    for _ in 0..2 {
+        match store.get("key2") {
+            Err(KvsError::Corruption { file, offset: at }) => {
+                assert_eq!((file, at), (log.clone(), offset));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(
+        store.quarantined()?,
+        [QuarantinedKey {
+            key: "key2".to_owned(),
+            file: log.clone(),
+            offset,
+        }]
+    );
+
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+    assert!(store.quarantined()?.is_empty());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    corrupt_last_record(&log)?;
+    assert!(store.get("key3").is_err());
+    assert!(store.drop_quarantined("key3")?);
+    assert_eq!(store.get("key3")?, None);
+    assert!(!store.drop_quarantined("key3")?);
+    assert!(store.quarantined()?.is_empty());
+    Ok(())
+}
+
+// Large values are streamed from the log, checked against their checksum
+// once read, small ones are read whole
+#[test]
+fn get_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("wal_1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    let large: String = (0..200_000)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set_typed(
+        "json".to_owned(),
+        format!("[\"{}\"]", large),
+        ContentType::Json,
+    )?;
+    store.set("large".to_owned(), large.clone())?;
+
+    let mut value = String::new();
+    let mut reader = store.get_reader("small")?.unwrap();
+    assert_eq!(reader.len(), 5);
+    reader.read_to_string(&mut value)?;
+    assert_eq!(value, "value");
+
+    let reader = store.get_reader("json")?.unwrap();
+    assert_eq!(reader.content_type(), ContentType::Json);
+    assert_eq!(reader.len(), large.len() as u64 + 4);
+
+    let mut reader = store.get_reader("large")?.unwrap();
+    assert_eq!(reader.content_type(), ContentType::Text);
+    value.clear();
+    reader.read_to_string(&mut value)?;
+    assert_eq!(value, large);
+    assert!(store.get_reader("missing")?.is_none());
+
+    corrupt_last_record(&log)?;
+    let mut reader = store.get_reader("large")?.unwrap();
+    let e = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(store.quarantined()?[0].key, "large");
+    Ok(())
+}
+
+// Compaction quarantines the keys whose records it cannot copy and goes on
+// with the others
+#[test]
+fn compaction_skips_corrupt_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_log_records(2)
+        .compaction_threshold(64)
+        .compaction_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    corrupt_last_record(&temp_dir.path().join("wal_1.log"))?;
+    for i in 0..20 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+
+    let start = Instant::now();
+    while store.stats()?.compactions == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "no compaction");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(store.get("key1")?, Some("value19".to_owned()));
+    assert!(matches!(
+        store.get("key2"),
+        Err(KvsError::Corruption { .. })
+    ));
+    assert!(!store.exists("key2")?);
+    assert_eq!(store.quarantined()?[0].key, "key2");
+    assert!(store.drop_quarantined("key2")?);
+    assert_eq!(store.get("key2")?, None);
+    Ok(())
+}
+
+// Closing one clone stops compaction without blocking the others
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let other = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let handle = thread::spawn(move || -> Result<()> {
+        other.remove("key1".to_owned())?;
+        other.set("key2".to_owned(), "value2".to_owned())?;
+        drop(other);
+        Ok(())
+    });
+    store.handle().close()?;
+    handle.join().unwrap()?;
+    // closing twice, through a handle and then the owner, is harmless
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.close()
+}
+
+// A directory is open once per process, for as long as the owner or a
+// handle of the store is around, closed or not
+#[test]
+fn open_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyOpen(_))
+    ));
+    assert!(matches!(
+        KvStore::open(&temp_dir.path().join(".")),
+        Err(KvsError::AlreadyOpen(_))
+    ));
+
+    let handle = store.handle();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyOpen(_))
+    ));
+    handle.set("key1".to_owned(), "value1".to_owned())?;
+    drop(handle);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.into_handle().close()?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    // a handle that outlives the closed owner keeps writing to the logs
+    let handle = store.handle();
+    store.close()?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyOpen(_))
+    ));
+    handle.set("key2".to_owned(), "value2".to_owned())?;
+    drop(handle);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.close()
+}
+
+// The owner compacts on demand, whatever the compaction threshold
+#[test]
+fn compact_now() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    let before = store.stats()?;
+    store.compact_now()?;
+    let after = store.stats()?;
+    assert_eq!(after.compactions, before.compactions + 1);
+    assert!(after.disk_bytes < before.disk_bytes);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    store.close()
+}
+
+// A warm open reuses the index saved on close, unless the logs moved on
+#[test]
+fn warm_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let saved_index = temp_dir.path().join("INDEX");
+    let store = KvStore::open_warm(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.close()?;
+    assert!(saved_index.is_file());
+
+    let store = KvStore::open_warm(temp_dir.path())?;
+    assert!(!saved_index.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.close()?;
+
+    // an index saved before later writes must not be attached to
+    let stale_index = fs::read(&saved_index)?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.close()?;
+    fs::write(&saved_index, stale_index)?;
+
+    let store = KvStore::open_warm(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.close()
+}
+
+// Options passed to open_with are honoured
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(50))
+        .read_buffer_size(64)
+        .durability(Durability::Sync);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+
+    // a threshold this low gets the logs compacted quickly
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let before = dir_size();
+    thread::sleep(Duration::from_millis(500));
+    assert!(dir_size() < before);
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    store.close()
+}
+
+// Concurrent writes share the syncs of the background thread, across log
+// rotations and compactions, and every acknowledged write survives a reopen
+#[test]
+fn group_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .durability(Durability::Group)
+        .group_sync_interval(Duration::from_millis(5))
+        .group_sync_batch(8)
+        .max_log_records(50)
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(20));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.set(format!("key{}", t), format!("value{}", i))?;
+                }
+                store.remove(format!("key{}", t))?;
+                store.set(format!("key{}", t), "done".to_owned())
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap()?;
+    }
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for t in 0..8 {
+        assert_eq!(store.get(format!("key{}", t))?, Some("done".to_owned()));
+    }
+    store.close()
+}
+
+// Logs written in older formats are still read, and rewritten by compaction
+#[test]
+fn legacy_log_formats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let set = |key: &str, value: &str| Command::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+        expires_at: None,
+        content_type: ContentType::Text,
+        ttl: None,
+    };
+
+    // bare JSON commands, no header
+    let mut legacy = serde_json::to_vec(&set("key1", "value1"))?;
+    legacy.extend(serde_json::to_vec(&set("key2", "value2"))?);
+    fs::write(temp_dir.path().join("wal_1.log"), legacy)?;
+
+    // version 1: framed records holding JSON commands
+    let mut framed = b"KVSLOG\n\x01".to_vec();
+    for cmd in [
+        set("key2", "value3"),
+        Command::Rm {
+            key: "key1".to_owned(),
+        },
+    ] {
+        let payload = serde_json::to_vec(&cmd)?;
+        framed.extend((payload.len() as u32).to_le_bytes());
+        framed.extend(crc32fast::hash(&payload).to_le_bytes());
+        framed.extend(payload);
+    }
+    fs::write(temp_dir.path().join("wal_2.log"), framed)?;
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default()
+            .compaction_threshold(0)
+            .compaction_interval(Duration::from_millis(50)),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    // wait for compaction to replace the old logs
+    for _ in 0..50 {
+        if !temp_dir.path().join("wal_1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!temp_dir.path().join("wal_1.log").exists());
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    store.close()
+}
+
+// Compaction spread over several threads keeps every live value
+#[test]
+fn parallel_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(50))
+        .compaction_threads(3);
+    // every open starts a new log, leaving live records spread over several
+    for round in 0..4 {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for key_id in round * 50..200 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+        store.close()?;
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for _ in 0..50 {
+        if !temp_dir.path().join("wal_1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!temp_dir.path().join("wal_1.log").exists());
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    store.close()
+}
+
+// A compaction that reads its copies back still removes the old logs and
+// keeps every live value
+#[test]
+fn verified_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(50))
+        .compaction_threads(2)
+        .verify_compaction(true);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for round in 0..4 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+    }
+    for _ in 0..50 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(store.stats()?.compactions > 0);
+    assert!(!temp_dir.path().join("wal_1.log").exists());
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value3".to_owned())
+        );
+    }
+    store.close()
+}
+
+// Merge operands are combined with the registered operator on read
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(64)
+        .compaction_interval(Duration::from_millis(50))
+        .merge_operator(|_key, value, operands| {
+            let base: i64 = value.map_or(0, |value| value.parse().unwrap());
+            let sum: i64 = operands.iter().map(|op| op.parse::<i64>().unwrap()).sum();
+            (base + sum).to_string()
+        });
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.merge("counter".to_owned(), "1".to_owned()).is_err());
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.merge("counter".to_owned(), "2".to_owned())?;
+    store.merge("counter".to_owned(), "3".to_owned())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("5".to_owned()));
+    store.set("total".to_owned(), "10".to_owned())?;
+    store.merge("total".to_owned(), "-4".to_owned())?;
+    assert_eq!(store.get("total".to_owned())?, Some("6".to_owned()));
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("counter".to_owned())?, Some("5".to_owned()));
+    for _ in 0..10 {
+        store.merge("counter".to_owned(), "1".to_owned())?;
+    }
+    // compaction folds the operands into plain values
+    for _ in 0..50 {
+        if !temp_dir.path().join("wal_1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!temp_dir.path().join("wal_1.log").exists());
+    assert_eq!(store.get("counter".to_owned())?, Some("15".to_owned()));
+    assert_eq!(store.get("total".to_owned())?, Some("6".to_owned()));
+    store.close()?;
+
+    // with the operands resolved the store no longer needs the operator
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("15".to_owned()));
+    store.close()
+}
+
+// compare_and_swap only writes when the current value is the expected one
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "key1".to_owned();
+
+    assert!(store.compare_and_swap(key(), None, Some("value1".to_owned()))?);
+    assert!(!store.compare_and_swap(key(), None, Some("value2".to_owned()))?);
+    assert!(!store.compare_and_swap(
+        key(),
+        Some("value2".to_owned()),
+        Some("value3".to_owned())
+    )?);
+    assert_eq!(store.get(key())?, Some("value1".to_owned()));
+    assert!(store.compare_and_swap(key(), Some("value1".to_owned()), Some("value3".to_owned()))?);
+    assert_eq!(store.get(key())?, Some("value3".to_owned()));
+    assert!(store.compare_and_swap(key(), Some("value3".to_owned()), None)?);
+    assert_eq!(store.get(key())?, None);
+    assert!(store.compare_and_swap(key(), None, None)?);
+
+    // racing increments never lose an update
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let current = store.get("counter".to_owned())?.unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        if store.compare_and_swap(
+                            "counter".to_owned(),
+                            Some(current),
+                            Some(next),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+    store.close()
+}
+
+// Pages continue by key order across removals and compaction
+#[test]
+fn scan_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..30 {
+        store.set(format!("key{:02}", key_id), format!("value{}", key_id))?;
+    }
+
+    let (page, cursor) = store.scan_page(None, 10)?;
+    assert_eq!(page.len(), 10);
+    assert_eq!(page[9], ("key09".to_owned(), "value9".to_owned()));
+    let cursor = cursor.unwrap();
+
+    // rewrite everything so compaction moves the records the cursor passed
+    store.remove("key10".to_owned())?;
+    for _ in 0..5 {
+        for key_id in 11..30 {
+            store.set(format!("key{:02}", key_id), format!("value{}", key_id))?;
+        }
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    let cursor = cursor.to_string().parse()?;
+    let (page, cursor) = store.scan_page(Some(cursor), 10)?;
+    let keys: Vec<_> = page.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys.first().map(String::as_str), Some("key11"));
+    assert_eq!(keys.len(), 10);
+    let (page, cursor) = store.scan_page(cursor, 10)?;
+    assert_eq!(page.len(), 9);
+    assert_eq!(cursor, None);
+    store.close()
+}
+
+// EXISTS, KEYS and DBSIZE only count keys with a live value
+#[test]
+fn index_queries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set("user:2".to_owned(), "b".to_owned())?;
+    store.set("order:1".to_owned(), "c".to_owned())?;
+    store.set_with_ttl(
+        "user:3".to_owned(),
+        "d".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    store.remove("user:2".to_owned())?;
+    thread::sleep(Duration::from_millis(10));
+
+    assert!(store.exists("user:1".to_owned())?);
+    assert!(!store.exists("user:2".to_owned())?);
+    assert!(!store.exists("user:3".to_owned())?);
+    assert_eq!(store.keys("user:*")?, vec!["user:1".to_owned()]);
+    assert_eq!(store.keys("*:1")?, vec!["order:1", "user:1"]);
+    assert!(store.keys("user:[").is_err());
+    assert_eq!(store.key_count()?, 2);
+    Ok(())
+}
+
+// Removing by pattern takes out the live matching keys, also after reopening
+#[test]
+fn remove_matching() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("session:{}", key_id), "value".to_owned())?;
+    }
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set_with_ttl(
+        "session:expired".to_owned(),
+        "b".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(store.remove_matching("session:*")?, 100);
+    assert_eq!(store.remove_matching("session:*")?, 0);
+    assert!(store.remove_matching("session:[").is_err());
+    assert_eq!(store.keys("*")?, vec!["user:1".to_owned()]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys("*")?, vec!["user:1".to_owned()]);
+    assert_eq!(store.remove_matching("*")?, 1);
+    assert_eq!(store.key_count()?, 0);
+    Ok(())
+}
+
+// update applies read-modify-write atomically
+#[test]
+fn update() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    store.update("counter".to_owned(), |current| {
+                        let current = current.map_or(0, |c| c.parse::<u32>().unwrap());
+                        Some((current + 1).to_string())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+
+    store.update("counter".to_owned(), |_| None)?;
+    assert_eq!(store.get("counter".to_owned())?, None);
+    // removing a missing key is not an error here
+    store.update("counter".to_owned(), |current| {
+        assert_eq!(current, None);
+        None
+    })?;
+    Ok(())
+}
+
+// Keys written with the same TTL expire spread over the jitter
+#[test]
+fn expiry_jitter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .expiry_jitter(Duration::from_secs(5))
+        .sweep_limit(10)
+        .compaction_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(10),
+        )?;
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let live = store.key_count()?;
+    assert!(live > 0 && live < 100, "{} keys left", live);
+    store.close()
+}
+
+// Keys written without a TTL take the store default or that of their namespace
+#[test]
+fn default_ttls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .default_ttl(Duration::from_millis(200))
+        .namespace_ttl("config:", None)
+        .namespace_ttl("session:", Some(Duration::from_secs(3600)));
+    check_default_ttls(KvStore::open_with(temp_dir.path(), options)?.into_handle())?;
+
+    let store = MemStore::new()
+        .default_ttl(Duration::from_millis(200))
+        .namespace_ttl("config:", None)
+        .namespace_ttl("session:", Some(Duration::from_secs(3600)));
+    check_default_ttls(store)
+}
+
+fn check_default_ttls<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("config:key".to_owned(), "value".to_owned())?;
+    store.set("session:key".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+
+    // Only a key that expires can be persisted
+    assert!(store.persist("key2")?);
+    assert!(!store.persist("key2")?);
+    assert!(!store.persist("config:key")?);
+    assert!(!store.persist("missing")?);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.get("config:key".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(
+        store.get("session:key".to_owned())?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// Every kind of write replaces the cached value of a key
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options =
+        KvStoreOptions::default()
+            .value_cache(2)
+            .merge_operator(|_key, value, operands| {
+                let mut value = value.unwrap_or_default().to_owned();
+                operands.iter().for_each(|op| value += op);
+                value
+            });
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let get = |key: &str| store.get(key.to_owned());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(get("key1")?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(get("key1")?, Some("value2".to_owned()));
+    store.merge("key1".to_owned(), "+".to_owned())?;
+    assert_eq!(get("key1")?, Some("value2+".to_owned()));
+    store.compare_and_swap("key1".to_owned(), Some("value2+".to_owned()), None)?;
+    assert_eq!(get("key1")?, None);
+
+    store.write_batch(vec![
+        Command::Set {
+            key: "key2".to_owned(),
+            value: "value1".to_owned(),
+            expires_at: None,
+            content_type: ContentType::Text,
+            ttl: None,
+        },
+        Command::Set {
+            key: "key3".to_owned(),
+            value: "value1".to_owned(),
+            expires_at: None,
+            content_type: ContentType::Text,
+            ttl: None,
+        },
+    ])?;
+    assert_eq!(get("key2")?, Some("value1".to_owned()));
+    store.update("key2".to_owned(), |value| {
+        value.map(|value| value.to_uppercase())
+    })?;
+    assert_eq!(get("key2")?, Some("VALUE1".to_owned()));
+    store.remove("key2".to_owned())?;
+    assert_eq!(get("key2")?, None);
+
+    // more keys than the cache holds are read from the log
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for _ in 0..2 {
+        for key_id in 0..10 {
+            assert_eq!(
+                get(&format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    store.set_with_ttl(
+        "key0".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    assert_eq!(get("key0")?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(get("key0")?, None);
+    store.close()
+}
+
+// Memory mapped logs read the same values, before and after compaction
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .mmap_reads(true)
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    for _ in 0..50 {
+        if !temp_dir.path().join("wal_1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!temp_dir.path().join("wal_1.log").exists());
+    for key_id in 0..100 {
+        let expected = if key_id < 50 {
+            "new".to_owned()
+        } else {
+            format!("value{}", key_id)
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(expected));
+    }
+    store.close()
+}
+
+// Clones keep reading through their own handles while compaction replaces
+// the logs under them
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(4096)
+        .compaction_interval(Duration::from_millis(10));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..2000 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        }));
+    }
+    for _ in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    for _ in 0..50 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(store.stats()?.compactions > 0);
+    store.close()
+}
+
+// Writes that land while compaction copies records are kept, merges included
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(1))
+        .merge_operator(|_key, value, operands| {
+            let base: i64 = value.map_or(0, |value| value.parse().unwrap());
+            let sum: i64 = operands.iter().map(|op| op.parse::<i64>().unwrap()).sum();
+            (base + sum).to_string()
+        });
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for round in 0..200 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", round))?;
+        }
+        store.merge("counter".to_owned(), "1".to_owned())?;
+        if round % 2 == 0 {
+            store.set("flip".to_owned(), "on".to_owned())?;
+        } else {
+            store.remove("flip".to_owned())?;
+        }
+    }
+    for _ in 0..50 {
+        if store.stats()?.compactions > 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(store.stats()?.compactions > 1);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..50 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+        }
+        assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+        assert_eq!(store.get("flip".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+    store.close()
+}
+
+// A full log is sealed behind a new one, and every value stays readable
+// from the sealed logs, mapped or not, and after reopening
+#[test]
+fn log_rotation() -> Result<()> {
+    for options in [
+        KvStoreOptions::default().max_log_size(256),
+        KvStoreOptions::default().max_log_records(10),
+        KvStoreOptions::default().max_log_size(256).mmap_reads(true),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        let logs = fs::read_dir(temp_dir.path())?.count();
+        assert!(logs >= 5, "expected rotated logs, found {}", logs);
+        for log in fs::read_dir(temp_dir.path())? {
+            // a log is sealed once it holds a record past the limit
+            assert!(log?.metadata()?.len() < 512);
+        }
+        store.close()?;
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        store.close()?;
+    }
+    Ok(())
+}
+
+// Values keep their content type through reopening and compaction
+#[test]
+fn content_types() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .value_cache(10)
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_typed("doc".to_owned(), "{\"a\":1}".to_owned(), ContentType::Json)?;
+    store.set("text".to_owned(), "{}".to_owned())?;
+    let doc = Some((b"{\"a\":1}".to_vec(), ContentType::Json));
+    assert_eq!(store.get_typed("doc".to_owned())?, doc);
+    // cached now
+    assert_eq!(store.get_typed("doc".to_owned())?, doc);
+    assert_eq!(store.get("doc".to_owned())?, Some("{\"a\":1}".to_owned()));
+    assert_eq!(
+        store.get_typed("text".to_owned())?,
+        Some((b"{}".to_vec(), ContentType::Text))
+    );
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_typed("doc".to_owned())?, doc);
+    for iter in 0..100 {
+        store.set("text".to_owned(), format!("value{}", iter))?;
+    }
+    for _ in 0..50 {
+        if !temp_dir.path().join("wal_1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!temp_dir.path().join("wal_1.log").exists());
+    assert_eq!(store.get_typed("doc".to_owned())?, doc);
+
+    // a plain set replaces the content type too
+    store.set("doc".to_owned(), "plain".to_owned())?;
+    assert_eq!(
+        store.get_typed("doc".to_owned())?,
+        Some((b"plain".to_vec(), ContentType::Text))
+    );
+    store.close()
+}
+
+// Compaction rewrites the records of a store reopened with compression
+// compressed, and a store reopened without it still reads them. Codecs this
+// build has no feature for are refused.
+#[test]
+fn compression() -> Result<()> {
+    let doc = |i: usize| {
+        let items: Vec<String> = (0..20)
+            .map(|n| format!("{{\"id\":{},\"name\":\"item\",\"tags\":[\"a\",\"b\"]}}", n))
+            .collect();
+        format!("{{\"doc\":{},\"items\":[{}]}}", i, items.join(","))
+    };
+    for codec in ["lz4", "zstd:9"] {
+        let compression: Compression = match codec.parse() {
+            Ok(compression) => compression,
+            Err(KvsError::Message(message)) => {
+                assert!(message.contains("unsupported"), "{}", message);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..100 {
+            store.set_typed(format!("key{}", i), doc(i), ContentType::Json)?;
+        }
+        store.close()?;
+        let uncompressed = fs::metadata(temp_dir.path().join("wal_1.log"))?.len();
+
+        let options = KvStoreOptions::default()
+            .compression(compression)
+            .compaction_threshold(1024)
+            .compaction_interval(Duration::from_millis(50));
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for iter in 0..100 {
+            store.set("counter".to_owned(), iter.to_string())?;
+        }
+        for _ in 0..50 {
+            if !temp_dir.path().join("wal_1.log").exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!temp_dir.path().join("wal_1.log").exists());
+        let compressed: u64 = fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(
+            compressed * 3 < uncompressed,
+            "{}: {} bytes compressed from {}",
+            codec,
+            compressed,
+            uncompressed
+        );
+        store.close()?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..100 {
+            assert_eq!(
+                store.get_typed(format!("key{}", i))?,
+                Some((doc(i).into_bytes(), ContentType::Json))
+            );
+        }
+        assert_eq!(store.get("counter")?, Some("99".to_owned()));
+        store.close()?;
+    }
+    Ok(())
+}
+
+// Small values too short to compress on their own shrink once a trained
+// dictionary is in use, and stay readable across retraining, reopening and
+// snapshots
+#[test]
+fn compression_dictionary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compression: Compression = match "zstd".parse() {
+        Ok(compression) => compression,
+        Err(KvsError::Message(_)) => {
+            let store = KvStore::open(temp_dir.path())?;
+            assert!(matches!(
+                store.train_dictionary(100),
+                Err(KvsError::Message(_))
+            ));
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let doc = |i: usize| {
+        format!(
+            "{{\"id\":{},\"type\":\"order\",\"status\":\"{}\",\"currency\":\"EUR\",\
+             \"country\":\"DE\",\"priority\":\"normal\"}}",
+            i,
+            ["pending", "shipped", "delivered"][i % 3]
+        )
+    };
+    let log_bytes = |dir: &Path| -> u64 {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().compression(compression),
+    )?;
+    // nothing to train on yet
+    assert!(matches!(
+        store.train_dictionary(100),
+        Err(KvsError::Message(_))
+    ));
+    for i in 0..2000 {
+        store.set(format!("key{}", i), doc(i))?;
+    }
+    store.sync()?;
+    let plain = log_bytes(temp_dir.path());
+
+    assert_eq!(store.train_dictionary(2000)?, 1);
+    for i in 2000..4000 {
+        store.set(format!("key{}", i), doc(i))?;
+    }
+    store.sync()?;
+    let with_dictionary = log_bytes(temp_dir.path()) - plain;
+    assert!(
+        with_dictionary * 3 < plain * 2,
+        "{} bytes with a dictionary, {} without",
+        with_dictionary,
+        plain
+    );
+
+    assert_eq!(store.train_dictionary(4000)?, 2);
+    for i in 4000..4100 {
+        store.set(format!("key{}", i), doc(i))?;
+    }
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.snapshot(snapshot_dir.path())?;
+    store.close()?;
+
+    for dir in [temp_dir.path(), snapshot_dir.path()] {
+        let store = KvStore::open(dir)?;
+        for i in (0..4100).step_by(7) {
+            assert_eq!(store.get(format!("key{}", i))?, Some(doc(i)));
+        }
+        store.close()?;
+    }
+    Ok(())
+}
+
+// Values that are not UTF-8 come back byte for byte, through reopening,
+// write batches and compaction
+#[test]
+fn byte_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1024)
+        .compaction_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let bytes = vec![0xff, 0x00, b'a', 0xc3];
+    store.set_bytes("bytes".to_owned(), bytes.clone())?;
+    store.write_batch(vec![Command::SetBytes {
+        key: "batched".to_owned(),
+        value: bytes.clone(),
+        expires_at: None,
+    }])?;
+    assert_eq!(store.get_bytes("bytes".to_owned())?, Some(bytes.clone()));
+    // the text API replaces what is not UTF-8
+    assert_eq!(
+        store.get("bytes".to_owned())?,
+        Some("\u{fffd}\0a\u{fffd}".to_owned())
+    );
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_bytes("text".to_owned())?, Some(b"value".to_vec()));
+    store.close()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_bytes("bytes".to_owned())?, Some(bytes.clone()));
+    for iter in 0..100 {
+        store.set("text".to_owned(), format!("value{}", iter))?;
+    }
+    for _ in 0..50 {
+        if !temp_dir.path().join("wal_1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!temp_dir.path().join("wal_1.log").exists());
+    assert_eq!(store.get_bytes("bytes".to_owned())?, Some(bytes.clone()));
+    assert_eq!(store.get_bytes("batched".to_owned())?, Some(bytes));
+    store.close()
+}
+
+// The lifetime counters survive reopening the store until they are reset
+#[test]
+fn lifetime_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.sets, 3);
+    assert_eq!(stats.removes, 1);
+    assert!(stats.bytes_written > 0);
+
+    store.reset_stats()?;
+    let stats = store.stats()?;
+    assert_eq!((stats.sets, stats.removes, stats.bytes_written), (0, 0, 0));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.sets, 1);
+    assert_eq!(store.stats()?.removes, 0);
+    Ok(())
+}
+
+// Numbered databases hold keyspaces of their own, in directories under the
+// store's path
+#[test]
+fn numbered_databases() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let db1 = store.select(1)?;
+    store.set("key".to_owned(), "zero".to_owned())?;
+    db1.set("key".to_owned(), "one".to_owned())?;
+    db1.set("other".to_owned(), "one".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("zero".to_owned()));
+    assert_eq!(
+        store.select(1)?.get("key".to_owned())?,
+        Some("one".to_owned())
+    );
+    assert_eq!(store.key_count()?, 1);
+    assert_eq!(store.databases()?, vec![0, 1]);
+    // only database 0 reaches the others
+    assert!(db1.select(2).is_err());
+    store.close()?;
+    drop(db1);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.databases()?, vec![0, 1]);
+    assert_eq!(
+        store.select(1)?.get("key".to_owned())?,
+        Some("one".to_owned())
+    );
+    store.close()?;
+
+    let db1 = KvStore::open_with(temp_dir.path(), KvStoreOptions::default().database(1))?;
+    assert_eq!(db1.keys("*")?, vec!["key".to_owned(), "other".to_owned()]);
+    Ok(())
+}
+
+// Every write waits for the writer, stats count how often and for how long
+#[test]
+fn writer_waits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let (store, barrier) = (store.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                for j in 0..25 {
+                    store
+                        .set(format!("key{}-{}", i, j), "value".to_owned())
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    store.remove("key0-0".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.writer_wait.count(), 101);
+    assert_eq!(stats.writer_waiting, 0);
+    Ok(())
+}
+
+// Stats count the keys due to expire within a minute, an hour and a day
+#[test]
+fn expiry_forecast() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, secs) in [
+        ("key1", 30),
+        ("key2", 30 * 60),
+        ("key3", 2 * 60 * 60),
+        ("key4", 2 * 24 * 60 * 60),
+    ] {
+        store.set_with_ttl(
+            key.to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(secs),
+        )?;
+    }
+    store.set("key5".to_owned(), "value".to_owned())?;
+    store.set_with_ttl("key6".to_owned(), "value".to_owned(), Duration::ZERO)?;
+
+    let expiring = store.stats()?.expiring;
+    assert_eq!((expiring.minute, expiring.hour, expiring.day), (1, 2, 3));
+    Ok(())
+}
+
+// BIGKEYS ranks keys by name length, bytes of log and merge operands waiting
+// to be folded
+#[test]
+fn big_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().merge_operator(|_key, value, operands| {
+        let mut value = value.unwrap_or_default().to_owned();
+        value.extend(operands.iter().map(String::as_str));
+        value
+    });
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("a".to_owned(), "x".repeat(1000))?;
+    store.set("a-much-longer-key".to_owned(), "x".to_owned())?;
+    store.set("b".to_owned(), "x".repeat(100))?;
+    for _ in 0..3 {
+        store.merge("c".to_owned(), "x".to_owned())?;
+    }
+    store.set_with_ttl("d".to_owned(), "x".repeat(5000), Duration::ZERO)?;
+
+    let big_keys = store.big_keys(2, None)?;
+    assert_eq!(big_keys.scanned, 4);
+    let keys = |sizes: &[KeySize]| {
+        sizes
+            .iter()
+            .map(|size| size.key.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(keys(&big_keys.longest_keys), ["a-much-longer-key", "a"]);
+    assert_eq!(keys(&big_keys.largest_values), ["a", "b"]);
+    assert!(big_keys.largest_values[0].value_bytes > 1000);
+    assert_eq!(keys(&big_keys.most_fragmented), ["c"]);
+    assert_eq!(big_keys.most_fragmented[0].records, 3);
+    assert!(big_keys.to_string().contains("1) c (3 records)"));
+
+    assert_eq!(store.big_keys(2, Some(1))?.scanned, 1);
+    Ok(())
+}
+
+// Keys and values over the limits are refused before they reach the log
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_key_size(8).max_value_size(16);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "x".repeat(16))?;
+    let written = store.stats()?.bytes_written;
+
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(KvsError::KeyTooLarge { len: 9, max: 8 })
+    ));
+    assert!(matches!(
+        store.set_bytes("key1".to_owned(), vec![0; 17]),
+        Err(KvsError::ValueTooLarge { len: 17, max: 16 })
+    ));
+    assert!(matches!(
+        store.remove("k".repeat(9)),
+        Err(KvsError::KeyTooLarge { .. })
+    ));
+    let batch = vec![
+        Command::Rm {
+            key: "key1".to_owned(),
+        },
+        Command::set_from_bytes("key2".to_owned(), vec![b'x'; 17], None, ContentType::Text),
+    ];
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+
+    assert_eq!(store.stats()?.bytes_written, written);
+    assert_eq!(store.get("key1")?, Some("x".repeat(16)));
+    Ok(())
+}
+
+// APPEND and update keep the expiry and content type of the value they
+// replace, and APPEND the bytes of a binary value
+#[test]
+fn append_keeps_value_attributes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes("bin".to_owned(), vec![0xff, 0])?;
+    assert_eq!(store.append("bin".to_owned(), b"ab")?, 4);
+    assert_eq!(store.get_bytes("bin")?, Some(vec![0xff, 0, b'a', b'b']));
+
+    store.set_typed("doc".to_owned(), "[1".to_owned(), ContentType::Json)?;
+    assert_eq!(store.append("doc".to_owned(), b"]")?, 3);
+    assert_eq!(
+        store.get_typed("doc")?,
+        Some((b"[1]".to_vec(), ContentType::Json))
+    );
+
+    store.set_with_ttl("ttl".to_owned(), "v".to_owned(), Duration::from_secs(60))?;
+    let expires_at = store.expires_at("ttl")?;
+    assert!(expires_at.is_some());
+    store.append("ttl".to_owned(), b"w")?;
+    assert_eq!(store.expires_at("ttl")?, expires_at);
+    store.update("ttl".to_owned(), |value| value.map(|v| v.repeat(2)))?;
+    assert_eq!(store.expires_at("ttl")?, expires_at);
+    assert_eq!(store.get("ttl")?, Some("vwvw".to_owned()));
+
+    assert_eq!(store.append("new".to_owned(), b"x")?, 1);
+    assert_eq!(store.expires_at("new")?, None);
+    Ok(())
+}
+
+// Reads and writes that run out of I/O time fail with a timeout, and a write
+// that timed out before it started leaves nothing behind
+#[test]
+fn io_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    let options = KvStoreOptions::default().io_timeout(Duration::ZERO);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert!(matches!(store.get("key1"), Err(KvsError::Timeout)));
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::Timeout)
+    ));
+    assert!(matches!(
+        store.compare_and_swap("key2".to_owned(), None, Some("value2".to_owned())),
+        Err(KvsError::Timeout)
+    ));
+    store.close()?;
+
+    let options = KvStoreOptions::default().io_timeout(Duration::from_secs(60));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// The memory engine answers like the kvs engine, and its snapshot opens as a
+// kvs store
+#[test]
+fn mem_store() -> Result<()> {
+    let store = MemStore::new().merge_operator(|_, existing, operands| {
+        existing.unwrap_or_default().to_owned() + &operands.concat()
+    });
+    let changes = store.subscribe("key")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_typed("key2".to_owned(), "[2]".to_owned(), ContentType::Json)?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::ZERO)?;
+    store.merge("key1".to_owned(), "!".to_owned())?;
+
+    assert_eq!(store.get("key1")?, Some("value1!".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.keys("key*")?, vec!["key1", "key2"]);
+    assert!(matches!(store.remove("key3"), Err(KvsError::KeyNotFound)));
+    assert!(!store.compare_and_swap("key1".to_owned(), None, None)?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1!".to_owned()),
+        Some("value4".to_owned())
+    )?);
+
+    let (page, cursor) = store.scan_page(None, 1)?;
+    assert_eq!(page, vec![("key1".to_owned(), "value4".to_owned())]);
+    let (page, cursor) = store.scan_page(cursor, 1)?;
+    assert_eq!(page, vec![("key2".to_owned(), "[2]".to_owned())]);
+    assert_eq!(cursor, None);
+
+    // a rejected batch applies nothing
+    let batch = vec![
+        Command::Rm {
+            key: "key1".to_owned(),
+        },
+        Command::Rm {
+            key: "key1".to_owned(),
+        },
+    ];
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.key_count()?, 2);
+
+    let db1 = store.select(1)?;
+    db1.set("key1".to_owned(), "other".to_owned())?;
+    assert_eq!(store.databases()?, vec![0, 1]);
+    assert_eq!(store.select(1)?.get("key1")?, Some("other".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value4".to_owned()));
+
+    let kinds: Vec<&str> = changes.try_iter().map(|change| change.name()).collect();
+    assert_eq!(kinds, vec!["set", "set", "set", "set", "set"]);
+    let stats = store.stats()?;
+    assert_eq!((stats.engine, stats.keys, stats.sets), ("memory", 2, 5));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.snapshot(temp_dir.path())?;
+    let snapshot = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        snapshot.get_typed("key2")?,
+        Some((b"[2]".to_vec(), ContentType::Json))
+    );
+    assert_eq!(snapshot.key_count()?, 2);
+    Ok(())
+}
@@ -0,0 +1,1333 @@
+use kvs::client::{KvsClient, KvsClientPool};
+use kvs::engines::MemStore;
+use kvs::server::{KvsServer, Profile};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{ChangeEvent, ContentType, KvStore, KvStoreOptions, KvsEngine, KvsError};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Starts a server on `addr` in the background and returns the data dir,
+// which must be kept alive for the duration of the test.
+fn start_server(addr: &'static str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn read_exact_reply(stream: &mut TcpStream, len: usize) -> String {
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+// Several commands in a single write should all be answered, in order
+#[test]
+fn pipelined_commands() {
+    let _dir = start_server("127.0.0.1:4101");
+    let mut stream = TcpStream::connect("127.0.0.1:4101").unwrap();
+
+    stream
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n+OK\r\n$6\r\nvalue1\r\n$6\r\nvalue2\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// A command split across writes should be answered once it is complete
+#[test]
+fn command_split_across_reads() {
+    let _dir = start_server("127.0.0.1:4102");
+    let mut stream = TcpStream::connect("127.0.0.1:4102").unwrap();
+
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nke").unwrap();
+    stream.flush().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream
+        .write_all(b"y1\r\n$6\r\nvalue1\r\n*2\r\n$3\r\nGET\r\n")
+        .unwrap();
+    stream.flush().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(b"$4\r\nkey1\r\n").unwrap();
+
+    let expected = "+OK\r\n$6\r\nvalue1\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// Commands between MULTI and EXEC are queued and answered together
+#[test]
+fn multi_exec_transaction() {
+    let _dir = start_server("127.0.0.1:4103");
+    let mut stream = TcpStream::connect("127.0.0.1:4103").unwrap();
+
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *2\r\n$2\r\nRM\r\n$4\r\nkey2\r\n\
+              *1\r\n$4\r\nEXEC\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n\
+                    *3\r\n+OK\r\n$6\r\nvalue1\r\n-Key not found\r\n\
+                    $6\r\nvalue1\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// DISCARD drops the queued commands
+#[test]
+fn multi_discard_transaction() {
+    let _dir = start_server("127.0.0.1:4104");
+    let mut stream = TcpStream::connect("127.0.0.1:4104").unwrap();
+
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n\
+              *1\r\n$7\r\nDISCARD\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *1\r\n$4\r\nEXEC\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n+QUEUED\r\n+OK\r\n$-1\r\n-ERR EXEC without MULTI\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// A replica receives the primary's existing keys and later writes, and
+// refuses writes from its own clients
+#[test]
+fn replica_follows_primary() {
+    let _primary_dir = start_server("127.0.0.1:4105");
+    let mut primary = TcpStream::connect("127.0.0.1:4105").unwrap();
+    primary
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n",
+        )
+        .unwrap();
+    assert_eq!(read_exact_reply(&mut primary, 10), "+OK\r\n+OK\r\n");
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(replica_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.replicate_from("127.0.0.1:4105".parse().unwrap());
+        server.run("127.0.0.1:4106").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    primary
+        .write_all(
+            b"*2\r\n$2\r\nRM\r\n$4\r\nkey1\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey3\r\n$6\r\nvalue3\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$4\r\nkey2\r\n$1\r\nx\r\n",
+        )
+        .unwrap();
+    assert_eq!(read_exact_reply(&mut primary, 14), "+OK\r\n+OK\r\n:7\r\n");
+    thread::sleep(Duration::from_millis(200));
+
+    let mut replica = TcpStream::connect("127.0.0.1:4106").unwrap();
+    replica
+        .write_all(
+            b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey3\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey4\r\n$6\r\nvalue4\r\n",
+        )
+        .unwrap();
+    let expected = "$-1\r\n$7\r\nvalue2x\r\n$6\r\nvalue3\r\n\
+                    -READONLY You can't write against a read only replica\r\n";
+    assert_eq!(read_exact_reply(&mut replica, expected.len()), expected);
+    // clients see the refusal as a typed server error
+    let mut client = KvsClient::connect("127.0.0.1:4106").unwrap();
+    match client.set("key4".to_owned(), "value4".to_owned()) {
+        Err(KvsError::Server(e)) => assert!(e.starts_with("READONLY")),
+        result => panic!("expected a READONLY error, got {:?}", result),
+    }
+}
+
+// DICT TRAIN replies with an error when the store does not compress with
+// zstd, and rejects a SAMPLE that is not a positive number
+#[test]
+fn dict_train_command() {
+    let _dir = start_server("127.0.0.1:4142");
+    let mut stream = TcpStream::connect("127.0.0.1:4142").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    stream
+        .write_all(b"*2\r\n$4\r\nDICT\r\n$5\r\nTRAIN\r\n")
+        .unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("-ERR dictionaries"), "{}", line);
+
+    stream
+        .write_all(b"*4\r\n$4\r\nDICT\r\n$5\r\nTRAIN\r\n$6\r\nSAMPLE\r\n$1\r\n0\r\n")
+        .unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "-ERR invalid command\r\n");
+}
+
+// CAS and SETNX only write when the current value matches
+#[test]
+fn compare_and_swap_commands() {
+    let _dir = start_server("127.0.0.1:4107");
+    let mut stream = TcpStream::connect("127.0.0.1:4107").unwrap();
+
+    stream
+        .write_all(
+            b"*3\r\n$5\r\nSETNX\r\n$4\r\nkey1\r\n$2\r\nv1\r\n\
+              *3\r\n$5\r\nSETNX\r\n$4\r\nkey1\r\n$2\r\nv2\r\n\
+              *4\r\n$3\r\nCAS\r\n$4\r\nkey1\r\n$2\r\nv2\r\n$2\r\nv3\r\n\
+              *4\r\n$3\r\nCAS\r\n$4\r\nkey1\r\n$2\r\nv1\r\n$2\r\nv3\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *4\r\n$3\r\nCAS\r\n$4\r\nkey1\r\n$2\r\nv3\r\n$-1\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        )
+        .unwrap();
+    let expected = ":1\r\n:0\r\n:0\r\n:1\r\n$2\r\nv3\r\n:1\r\n$-1\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// APPEND extends the value and replies with its new length, also inside
+// MULTI where it sees the transaction's earlier writes
+#[test]
+fn append_command() {
+    let _dir = start_server("127.0.0.1:4141");
+    let mut stream = TcpStream::connect("127.0.0.1:4141").unwrap();
+
+    stream
+        .write_all(
+            b"*3\r\n$6\r\nAPPEND\r\n$4\r\nkey1\r\n$2\r\nab\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$4\r\nkey1\r\n$3\r\ncde\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$4\r\nkey1\r\n$1\r\nf\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$4\r\nkey1\r\n$1\r\ng\r\n\
+              *1\r\n$4\r\nEXEC\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        )
+        .unwrap();
+    let expected = ":2\r\n:5\r\n$5\r\nabcde\r\n+OK\r\n+QUEUED\r\n+QUEUED\r\n*2\r\n:6\r\n:7\r\n\
+                    $7\r\nabcdefg\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    let mut client = KvsClient::connect("127.0.0.1:4141").unwrap();
+    assert_eq!(
+        client.append("key2".to_owned(), "xyz".to_owned()).unwrap(),
+        3
+    );
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("xyz".to_owned())
+    );
+}
+
+// APPEND works on the bytes of a value and keeps its TTL, inside MULTI or not
+#[test]
+fn append_keeps_bytes_and_ttl() {
+    let _dir = start_server("127.0.0.1:4146");
+    let mut stream = TcpStream::connect("127.0.0.1:4146").unwrap();
+
+    stream
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$3\r\nbin\r\n$2\r\n\xff\x00\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$3\r\nbin\r\n$1\r\na\r\n\
+              *5\r\n$3\r\nSET\r\n$3\r\nttl\r\n$1\r\nv\r\n$2\r\nPX\r\n$5\r\n60000\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$3\r\nttl\r\n$1\r\nw\r\n\
+              *5\r\n$3\r\nSET\r\n$4\r\nttl2\r\n$1\r\nv\r\n$2\r\nPX\r\n$5\r\n60000\r\n\
+              *1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$3\r\nbin\r\n$1\r\nb\r\n\
+              *3\r\n$6\r\nAPPEND\r\n$4\r\nttl2\r\n$1\r\nw\r\n\
+              *1\r\n$4\r\nEXEC\r\n\
+              *2\r\n$3\r\nGET\r\n$3\r\nbin\r\n\
+              *2\r\n$7\r\nPERSIST\r\n$3\r\nttl\r\n\
+              *2\r\n$7\r\nPERSIST\r\n$4\r\nttl2\r\n",
+        )
+        .unwrap();
+    let expected: &[u8] = b"+OK\r\n:3\r\n+OK\r\n:2\r\n+OK\r\n+OK\r\n+QUEUED\r\n+QUEUED\r\n\
+                            *2\r\n:4\r\n:2\r\n$4\r\n\xff\x00ab\r\n:1\r\n:1\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, expected);
+}
+
+// PERSIST takes the TTL off a key and replies whether it had one
+#[test]
+fn persist_command() {
+    let _dir = start_server("127.0.0.1:4143");
+    let mut stream = TcpStream::connect("127.0.0.1:4143").unwrap();
+
+    stream
+        .write_all(
+            b"*5\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$2\r\nab\r\n$2\r\nPX\r\n$3\r\n200\r\n\
+              *2\r\n$7\r\nPERSIST\r\n$4\r\nkey1\r\n\
+              *2\r\n$7\r\nPERSIST\r\n$4\r\nkey1\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n:1\r\n:0\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    let mut client = KvsClient::connect("127.0.0.1:4143").unwrap();
+    assert!(!client.persist("missing".to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("ab".to_owned())
+    );
+}
+
+// RM MATCH removes the keys matching a pattern and FLUSHDB every key of the
+// selected database
+#[test]
+fn remove_matching_commands() {
+    let _dir = start_server("127.0.0.1:4144");
+    let mut client = KvsClient::connect("127.0.0.1:4144").unwrap();
+    for key_id in 0..10 {
+        client
+            .set(format!("session:{}", key_id), "value".to_owned())
+            .unwrap();
+    }
+    client.set("user:1".to_owned(), "a".to_owned()).unwrap();
+    assert_eq!(client.remove_matching("session:*".to_owned()).unwrap(), 10);
+    assert_eq!(client.remove_matching("session:*".to_owned()).unwrap(), 0);
+    assert_eq!(
+        client.get("user:1".to_owned()).unwrap(),
+        Some("a".to_owned())
+    );
+
+    let mut stream = TcpStream::connect("127.0.0.1:4144").unwrap();
+    stream
+        .write_all(
+            b"*3\r\n$2\r\nRM\r\n$5\r\nMATCH\r\n$1\r\n[\r\n\
+              *1\r\n$7\r\nFLUSHDB\r\n\
+              *1\r\n$6\r\nDBSIZE\r\n",
+        )
+        .unwrap();
+    let expected = "-ERR invalid pattern\r\n+OK\r\n:0\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// Pipelined SETs are answered together and leave the connection usable
+#[test]
+fn pipelined_sets() {
+    let _dir = start_server("127.0.0.1:4145");
+    let mut client = KvsClient::connect("127.0.0.1:4145").unwrap();
+    let pairs: Vec<(String, String)> = (0..2000)
+        .map(|n| (format!("key{}", n), "value".repeat(n % 50)))
+        .collect();
+    client.set_pipelined(&pairs).unwrap();
+    assert_eq!(
+        client.get("key1999".to_owned()).unwrap(),
+        Some("value".repeat(1999 % 50))
+    );
+
+    let pairs = vec![
+        ("key1".to_owned(), "a".to_owned()),
+        ("k".repeat(64 * 1024 + 1), "b".to_owned()),
+        ("key2".to_owned(), "c".to_owned()),
+    ];
+    assert!(client.set_pipelined(&pairs).is_err());
+    assert_eq!(client.get("key2".to_owned()).unwrap(), Some("c".to_owned()));
+}
+
+// A shutdown answers the requests already received, then closes the engine
+#[test]
+fn graceful_shutdown() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run("127.0.0.1:4108"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4108").unwrap();
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n")
+        .unwrap();
+    let expected = "+OK\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+    // the open connection was closed rather than left hanging
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(TcpStream::connect("127.0.0.1:4108").is_err());
+
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn client_round_trip() {
+    let _dir = start_server("127.0.0.1:4109");
+    let mut client = KvsClient::connect("127.0.0.1:4109").unwrap();
+
+    client.ping().unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+}
+
+// A client should carry on against a restarted server
+#[test]
+fn client_reconnects() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run("127.0.0.1:4110"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4110").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.run("127.0.0.1:4110").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn client_interrupted_write() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run("127.0.0.1:4125"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4125").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.run("127.0.0.1:4125").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    // a write is not repeated, the caller learns it may not have applied
+    assert!(matches!(
+        client.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::Interrupted)
+    ));
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert!(client.exists("key1".to_owned()).unwrap());
+    assert!(!client.exists("key3".to_owned()).unwrap());
+
+    let (keys, cursor) = client.scan(None, 1).unwrap();
+    assert_eq!(keys, vec!["key1".to_owned()]);
+    let (keys, cursor) = client.scan(cursor.as_ref(), 1).unwrap();
+    assert_eq!(keys, vec!["key2".to_owned()]);
+    assert_eq!(client.scan(cursor.as_ref(), 1).unwrap(), (vec![], None));
+}
+
+#[test]
+fn client_pool() {
+    let _dir = start_server("127.0.0.1:4111");
+    let pool = KvsClientPool::new("127.0.0.1:4111", 2).unwrap();
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let mut client = pool.get().unwrap();
+                client
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut client = pool.get().unwrap();
+    for i in 0..4 {
+        assert_eq!(
+            client.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+// GET misses are null bulk strings unless the legacy error is asked for
+#[test]
+fn missing_key_error() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.missing_key_error(true);
+        server.run("127.0.0.1:4112").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4112").unwrap();
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n")
+        .unwrap();
+    let expected = "-Key not found\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    // the client reads both forms as a miss
+    let mut client = KvsClient::connect("127.0.0.1:4112").unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+}
+
+// HELLO switches a connection between RESP2 and RESP3
+#[test]
+fn hello_negotiates_protocol() {
+    let _dir = start_server("127.0.0.1:4113");
+    let mut stream = TcpStream::connect("127.0.0.1:4113").unwrap();
+
+    stream
+        .write_all(
+            b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n\
+              *2\r\n$5\r\nHELLO\r\n$1\r\n2\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        )
+        .unwrap();
+    let info = |header: &str, proto: u8| {
+        format!(
+            "{}$6\r\nserver\r\n$3\r\nkvs\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+             $5\r\nproto\r\n:{}\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+             $4\r\nrole\r\n$6\r\nmaster\r\n",
+            header,
+            env!("CARGO_PKG_VERSION").len(),
+            env!("CARGO_PKG_VERSION"),
+            proto
+        )
+    };
+    let expected = format!(
+        "{}_\r\n-NOPROTO unsupported protocol version\r\n{}$-1\r\n",
+        info("%5\r\n", 3),
+        info("*10\r\n", 2)
+    );
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// JSON values come back to RESP3 clients as verbatim strings, and values
+// that do not parse are refused
+#[test]
+fn json_values() {
+    let _dir = start_server("127.0.0.1:4126");
+    let mut client = KvsClient::connect("127.0.0.1:4126").unwrap();
+    client
+        .set_typed("doc".to_owned(), "{\"a\":1}".to_owned(), ContentType::Json)
+        .unwrap();
+    assert!(client
+        .set_typed("bad".to_owned(), "{\"a\":".to_owned(), ContentType::Json)
+        .is_err());
+    assert!(!client.exists("bad".to_owned()).unwrap());
+    // RESP2 has no verbatim strings
+    assert_eq!(
+        client.get("doc".to_owned()).unwrap(),
+        Some("{\"a\":1}".to_owned())
+    );
+    client.set("text".to_owned(), "{}".to_owned()).unwrap();
+    drop(client);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4126").unwrap();
+    stream
+        .write_all(
+            b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n\
+              *2\r\n$3\r\nGET\r\n$3\r\ndoc\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\ntext\r\n",
+        )
+        .unwrap();
+    let mut reader = BufReader::new(stream);
+    // skip the HELLO reply, a map of five pairs taking 20 lines
+    for _ in 0..20 {
+        reader.read_line(&mut String::new()).unwrap();
+    }
+    let expected = "=11\r\njsn:{\"a\":1}\r\n$2\r\n{}\r\n";
+    let mut reply = vec![0; expected.len()];
+    reader.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected);
+}
+
+// Values that are not UTF-8 are stored and answered byte for byte
+#[test]
+fn byte_values() {
+    let _dir = start_server("127.0.0.1:4127");
+    let mut stream = TcpStream::connect("127.0.0.1:4127").unwrap();
+    stream
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$3\r\nbin\r\n$4\r\n\xff\x00\r\xc3\r\n\
+              *2\r\n$3\r\nGET\r\n$3\r\nbin\r\n",
+        )
+        .unwrap();
+    let expected = b"+OK\r\n$4\r\n\xff\x00\r\xc3\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, expected);
+}
+
+// MSET sets every pair at once and MGET answers every key in order, also
+// inside a transaction, where MGET sees the transaction's own writes
+#[test]
+fn multi_key_commands() {
+    let _dir = start_server("127.0.0.1:4133");
+    let mut client = KvsClient::connect("127.0.0.1:4133").unwrap();
+    let pairs = [("a", "1"), ("b", "2")].map(|(k, v)| (k.to_owned(), v.to_owned()));
+    client.mset(&pairs).unwrap();
+    let keys = ["a", "missing", "b"].map(String::from);
+    assert_eq!(
+        client.mget(&keys).unwrap(),
+        vec![Some("1".to_owned()), None, Some("2".to_owned())]
+    );
+    drop(client);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4133").unwrap();
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$4\r\nMSET\r\n$1\r\nc\r\n$1\r\n3\r\n\
+              *3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nc\r\n\
+              *1\r\n$4\r\nEXEC\r\n\
+              *2\r\n$4\r\nMSET\r\n$1\r\nc\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n+QUEUED\r\n+QUEUED\r\n\
+                    *2\r\n+OK\r\n*2\r\n$1\r\n1\r\n$1\r\n3\r\n\
+                    -ERR invalid command\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// SET with EX or PX expires the key, only text values take a TTL
+#[test]
+fn set_with_ttl() {
+    let _dir = start_server("127.0.0.1:4134");
+    let mut client = KvsClient::connect("127.0.0.1:4134").unwrap();
+    client
+        .set_ex(
+            "short".to_owned(),
+            "1".to_owned(),
+            Duration::from_millis(100),
+        )
+        .unwrap();
+    client
+        .set_ex("long".to_owned(), "2".to_owned(), Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(
+        client.get("short".to_owned()).unwrap(),
+        Some("1".to_owned())
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.get("short".to_owned()).unwrap(), None);
+    assert_eq!(client.get("long".to_owned()).unwrap(), Some("2".to_owned()));
+    drop(client);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4134").unwrap();
+    stream
+        .write_all(
+            b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n0\r\n\
+              *7\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\n{}\r\n$4\r\nTYPE\r\n$4\r\njson\r\n\
+              $2\r\nPX\r\n$3\r\n100\r\n\
+              *5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n\
+              $20\r\n18446744073709551615\r\n\
+              *5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nPX\r\n\
+              $19\r\n9223372036854775807\r\n\
+              *2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+        )
+        .unwrap();
+    let expected = "-ERR invalid command\r\n-ERR only UTF-8 text values can have a TTL\r\n\
+                    -ERR invalid expire time in 'set' command\r\n\
+                    -ERR invalid expire time in 'set' command\r\n$-1\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// SUBSCRIBE streams the changes to the keys under a prefix
+#[test]
+fn subscribe_command() {
+    let _dir = start_server("127.0.0.1:4135");
+    let client = KvsClient::connect("127.0.0.1:4135").unwrap();
+    let mut changes = client.subscribe("user:").unwrap();
+
+    let mut client = KvsClient::connect("127.0.0.1:4135").unwrap();
+    client.set("user:1".to_owned(), "a".to_owned()).unwrap();
+    client.set("other".to_owned(), "b".to_owned()).unwrap();
+    client.remove("user:1".to_owned()).unwrap();
+    assert_eq!(
+        changes.next().unwrap().unwrap(),
+        ChangeEvent::Set("user:1".to_owned())
+    );
+    assert_eq!(
+        changes.next().unwrap().unwrap(),
+        ChangeEvent::Removed("user:1".to_owned())
+    );
+}
+
+// SCAN walks the keys a page at a time until the cursor comes back as 0
+#[test]
+fn scan_command() {
+    let _dir = start_server("127.0.0.1:4114");
+    let mut client = KvsClient::connect("127.0.0.1:4114").unwrap();
+    for key in ["a", "b", "c"] {
+        client.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+    drop(client);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4114").unwrap();
+    stream
+        .write_all(b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nCOUNT\r\n$1\r\n2\r\n")
+        .unwrap();
+    // the cursor is the hex of the last key, "b"
+    let expected = "*2\r\n$2\r\n62\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    stream
+        .write_all(b"*4\r\n$4\r\nSCAN\r\n$2\r\n62\r\n$5\r\nCOUNT\r\n$1\r\n2\r\n")
+        .unwrap();
+    let expected = "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nc\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+#[test]
+fn index_commands() {
+    let _dir = start_server("127.0.0.1:4115");
+    let mut stream = TcpStream::connect("127.0.0.1:4115").unwrap();
+
+    stream
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$1\r\nv\r\n\
+              *3\r\n$3\r\nSET\r\n$2\r\nk2\r\n$1\r\nv\r\n\
+              *2\r\n$6\r\nEXISTS\r\n$2\r\nk1\r\n\
+              *2\r\n$6\r\nEXISTS\r\n$2\r\nk3\r\n\
+              *2\r\n$4\r\nKEYS\r\n$2\r\nk*\r\n\
+              *1\r\n$6\r\nDBSIZE\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n+OK\r\n:1\r\n:0\r\n*2\r\n$2\r\nk1\r\n$2\r\nk2\r\n:2\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// BIGKEYS replies with a report of the largest keys, and rejects an option
+// without a number
+#[test]
+fn bigkeys_command() {
+    let _dir = start_server("127.0.0.1:4137");
+    let mut client = KvsClient::connect("127.0.0.1:4137").unwrap();
+    client.set("small".to_owned(), "v".to_owned()).unwrap();
+    client.set("big".to_owned(), "v".repeat(100)).unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4137").unwrap();
+    stream
+        .write_all(b"*3\r\n$7\r\nBIGKEYS\r\n$5\r\nCOUNT\r\n$1\r\n1\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut buf = vec![0; 4096];
+    let len = stream.read(&mut buf).unwrap();
+    let report = String::from_utf8_lossy(&buf[..len]);
+    assert!(report.starts_with('$'));
+    assert!(report.contains("scanned 2 keys"), "{:?}", report);
+    assert!(report.contains("longest keys:\n  1) small (5 bytes)\n"));
+    assert!(report.contains("largest values:\n  1) big ("));
+
+    stream
+        .write_all(b"*2\r\n$7\r\nBIGKEYS\r\n$5\r\nCOUNT\r\n")
+        .unwrap();
+    let invalid = "-ERR invalid command\r\n";
+    assert_eq!(read_exact_reply(&mut stream, invalid.len()), invalid);
+}
+
+// A corrupt value is answered with an error while the connection and the
+// other keys go on being served, and QUARANTINE lists and drops its key
+#[test]
+fn quarantine_command() {
+    let dir = start_server("127.0.0.1:4138");
+    let mut client = KvsClient::connect("127.0.0.1:4138").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let log = dir.path().join("wal_1.log");
+    let mut bytes = fs::read(&log).unwrap();
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes).unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4138").unwrap();
+    stream
+        .write_all(
+            b"*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        )
+        .unwrap();
+    let expected = "-CORRUPT the value is corrupt, its key is quarantined\r\n$6\r\nvalue1\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    stream.write_all(b"*1\r\n$10\r\nQUARANTINE\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut buf = vec![0; 4096];
+    let len = stream.read(&mut buf).unwrap();
+    let reply = String::from_utf8_lossy(&buf[..len]);
+    assert!(reply.starts_with("*1\r\n$"), "{:?}", reply);
+    assert!(reply.contains(&format!("key2 {}:", log.display())));
+
+    stream
+        .write_all(b"*3\r\n$10\r\nQUARANTINE\r\n$4\r\nDROP\r\n$4\r\nkey2\r\n*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n")
+        .unwrap();
+    assert_eq!(read_exact_reply(&mut stream, 9), ":1\r\n$-1\r\n");
+}
+
+// Values of megabytes are streamed into the reply and arrive whole, with
+// the replies pipelined after them
+#[test]
+fn large_values() {
+    let _dir = start_server("127.0.0.1:4139");
+    let mut client = KvsClient::connect("127.0.0.1:4139").unwrap();
+    let value: String = (0..4 << 20)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    client.set("key1".to_owned(), value.clone()).unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4139").unwrap();
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n*1\r\n$4\r\nPING\r\n")
+        .unwrap();
+    let expected = format!("${}\r\n{}\r\n+PONG\r\n", value.len(), value);
+    assert!(read_exact_reply(&mut stream, expected.len()) == expected);
+}
+
+// Writes over the size limits are refused with an error reply before they
+// reach the engine, and the connection goes on being served
+#[test]
+fn size_limits() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.max_key_size(8);
+        server.max_value_size(16);
+        server.run("127.0.0.1:4140").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4140").unwrap();
+    stream
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$9\r\nkkkkkkkkk\r\n$1\r\nx\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$17\r\nxxxxxxxxxxxxxxxxx\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$16\r\nxxxxxxxxxxxxxxxx\r\n",
+        )
+        .unwrap();
+    let expected = "-ERR key of 9 bytes is larger than the 8 bytes allowed\r\n\
+                    -ERR value of 17 bytes is larger than the 16 bytes allowed\r\n\
+                    +OK\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    // a transaction with an oversized write is aborted
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *5\r\n$4\r\nMSET\r\n$4\r\nkey1\r\n$1\r\nx\r\n$4\r\nkey2\r\n$17\r\nxxxxxxxxxxxxxxxxx\r\n\
+              *1\r\n$4\r\nEXEC\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n\
+                    -ERR value of 17 bytes is larger than the 16 bytes allowed\r\n\
+                    -EXECABORT Transaction discarded because of previous errors\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// STATS and the HTTP listener report commands and engine figures
+#[test]
+fn metrics() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.metrics_listener("127.0.0.1:4117".parse().unwrap());
+        server.run("127.0.0.1:4116").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4116").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4116").unwrap();
+    stream
+        .write_all(b"*1\r\n$5\r\nBOGUS\r\n*1\r\n$5\r\nSTATS\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut buf = vec![0; 64 * 1024];
+    let len = stream.read(&mut buf).unwrap();
+    let stats = String::from_utf8_lossy(&buf[..len]);
+    assert!(stats.contains("kvs_commands_total{command=\"set\"} 2\n"));
+    assert!(stats.contains("kvs_commands_total{command=\"get\"} 1\n"));
+    assert!(stats.contains("kvs_connected_clients 2\n"));
+    assert!(stats.contains("kvs_keys 2\n"));
+    assert!(stats.contains("kvs_writer_wait_seconds_count 2\n"));
+    assert!(stats.contains("kvs_writer_waiting 0\n"));
+    assert!(stats.contains("kvs_parse_errors_total 1\n"));
+    assert!(stats.contains("kvs_oversized_frames_total 0\n"));
+    assert!(!stats.contains("kvs_net_input_bytes_total 0\n"));
+
+    let mut http = TcpStream::connect("127.0.0.1:4117").unwrap();
+    http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    http.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("kvs_command_duration_seconds_count{command=\"set\"} 2\n"));
+}
+
+#[test]
+fn info_command() {
+    let _dir = start_server("127.0.0.1:4118");
+    let mut client = KvsClient::connect("127.0.0.1:4118").unwrap();
+    client
+        .set_ex(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_secs(30 * 60),
+        )
+        .unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4118").unwrap();
+    stream.write_all(b"*1\r\n$4\r\nINFO\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut buf = vec![0; 4096];
+    let len = stream.read(&mut buf).unwrap();
+    let info = String::from_utf8_lossy(&buf[..len]);
+    assert!(info.starts_with('$'));
+    let version = format!("kvs_version:{}\r\n", env!("CARGO_PKG_VERSION"));
+    for line in [
+        version.as_str(),
+        "engine:kvs\r\n",
+        "role:master\r\n",
+        "connected_clients:2\r\n",
+        "keys:1\r\n",
+        "expiring_1m:0\r\n",
+        "expiring_1h:1\r\n",
+        "total_sets:1\r\n",
+        "compactions:0\r\n",
+        "writer_waiting:0\r\n",
+        "writer_waits:1\r\n",
+        "total_parse_errors:0\r\n",
+        // the connection sent only this INFO
+        "\r\nnet_input_bytes:14\r\n",
+        "\r\nnet_output_bytes:0\r\n",
+        "\r\nframes:1\r\n",
+    ] {
+        assert!(info.contains(line), "{:?} not in {:?}", line, info);
+    }
+
+    stream
+        .write_all(b"*2\r\n$5\r\nSTATS\r\n$5\r\nRESET\r\n*1\r\n$4\r\nINFO\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let len = stream.read(&mut buf).unwrap();
+    let info = String::from_utf8_lossy(&buf[..len]);
+    assert!(info.starts_with("+OK\r\n"), "{:?}", info);
+    assert!(info.contains("total_sets:0\r\n"), "{:?}", info);
+}
+
+// A panic while handling a command is answered with an error and only
+// closes that connection
+#[test]
+fn panic_in_command() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().merge_operator(|key, _, _| {
+        assert_ne!(key, "bad", "bad key");
+        String::new()
+    });
+    let store = KvStore::open_with(temp_dir.path(), options)
+        .unwrap()
+        .into_handle();
+    store.merge("bad".to_owned(), "1".to_owned()).unwrap();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(1).unwrap());
+        server.run("127.0.0.1:4119").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4119").unwrap();
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbad\r\n")
+        .unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "-ERR internal error\r\n");
+
+    // the only worker survived the panic
+    let mut client = KvsClient::connect("127.0.0.1:4119").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A connection buffering more than the limit is closed with an error
+#[test]
+fn client_buffer_limit() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.client_buffer_limit(1024);
+        server.run("127.0.0.1:4120").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    // a partial frame
+    let mut stream = TcpStream::connect("127.0.0.1:4120").unwrap();
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$4096\r\n")
+        .unwrap();
+    stream.write_all(&[b'x'; 2048]).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "-ERR client buffer limit exceeded\r\n");
+
+    // a transaction
+    let mut stream = TcpStream::connect("127.0.0.1:4120").unwrap();
+    stream.write_all(b"*1\r\n$5\r\nMULTI\r\n").unwrap();
+    assert_eq!(read_exact_reply(&mut stream, 5), "+OK\r\n");
+    let set = format!(
+        "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$512\r\n{}\r\n",
+        "x".repeat(512)
+    );
+    stream.write_all(set.as_bytes()).unwrap();
+    assert_eq!(read_exact_reply(&mut stream, 9), "+QUEUED\r\n");
+    stream.write_all(set.as_bytes()).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "+QUEUED\r\n-ERR client buffer limit exceeded\r\n");
+
+    // requests within the limit are answered
+    let mut client = KvsClient::connect("127.0.0.1:4120").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+}
+
+// With an admin listener admin commands are only taken there, and it takes
+// nothing else
+#[test]
+fn admin_listener() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.admin_listener("127.0.0.1:4122".parse().unwrap());
+        server.run("127.0.0.1:4121").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    let backup = format!(
+        "*2\r\n$6\r\nBACKUP\r\n${}\r\n{}\r\n",
+        backup_dir.path().display().to_string().len(),
+        backup_dir.path().display()
+    );
+
+    let mut data = TcpStream::connect("127.0.0.1:4121").unwrap();
+    data.write_all(backup.as_bytes()).unwrap();
+    let refused = "-ERR admin commands are only allowed on the admin port\r\n";
+    assert_eq!(read_exact_reply(&mut data, refused.len()), refused);
+    data.write_all(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n")
+        .unwrap();
+    assert_eq!(read_exact_reply(&mut data, 5), "$-1\r\n");
+
+    let mut admin = TcpStream::connect("127.0.0.1:4122").unwrap();
+    admin
+        .write_all(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n")
+        .unwrap();
+    let refused = "-ERR only admin commands are allowed on the admin port\r\n";
+    assert_eq!(read_exact_reply(&mut admin, refused.len()), refused);
+    admin.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+    assert_eq!(read_exact_reply(&mut admin, 7), "+PONG\r\n");
+    admin.write_all(backup.as_bytes()).unwrap();
+    assert_eq!(read_exact_reply(&mut admin, 5), "+OK\r\n");
+    assert!(backup_dir.path().join("MANIFEST").exists());
+}
+
+// SHUTDOWN is acknowledged, then the server stops and closes its engine
+#[test]
+fn shutdown_command() {
+    for (port, option) in [(4123, "SAVE"), (4124, "NOSAVE")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+        let addr = format!("127.0.0.1:{}", port);
+        let server_addr = addr.clone();
+        let server = thread::spawn(move || {
+            let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+            server.run(server_addr)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect(addr.as_str()).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let mut stream = TcpStream::connect(addr.as_str()).unwrap();
+        let shutdown = format!(
+            "*2\r\n$8\r\nSHUTDOWN\r\n${}\r\n{}\r\n",
+            option.len(),
+            option
+        );
+        stream.write_all(shutdown.as_bytes()).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "+OK\r\n");
+        drop(client);
+        server.join().unwrap().unwrap();
+
+        let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+}
+
+// Keys the replica receives when it syncs keep the TTL they have left
+#[test]
+fn replica_syncs_ttl() {
+    let _primary_dir = start_server("127.0.0.1:4147");
+    let mut primary = KvsClient::connect("127.0.0.1:4147").unwrap();
+    primary
+        .set_ex("short".to_owned(), "1".to_owned(), Duration::from_secs(2))
+        .unwrap();
+    primary.set("long".to_owned(), "2".to_owned()).unwrap();
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(replica_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.replicate_from("127.0.0.1:4147".parse().unwrap());
+        server.run("127.0.0.1:4148").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut replica = KvsClient::connect("127.0.0.1:4148").unwrap();
+    assert_eq!(
+        replica.get("short".to_owned()).unwrap(),
+        Some("1".to_owned())
+    );
+    thread::sleep(Duration::from_millis(1700));
+    assert_eq!(replica.get("short".to_owned()).unwrap(), None);
+    assert_eq!(
+        replica.get("long".to_owned()).unwrap(),
+        Some("2".to_owned())
+    );
+}
+
+// SELECT switches a connection to a keyspace of its own, which replicas
+// follow both in their initial sync and afterwards
+#[test]
+fn select_databases() {
+    let _primary_dir = start_server("127.0.0.1:4128");
+    let mut client = KvsClient::connect("127.0.0.1:4128").unwrap();
+    client.select(1).unwrap();
+    client.set("key1".to_owned(), "one".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("one".to_owned())
+    );
+    let mut other = KvsClient::connect("127.0.0.1:4128").unwrap();
+    assert_eq!(other.get("key1".to_owned()).unwrap(), None);
+    assert!(matches!(other.select(16), Err(KvsError::Server(_))));
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(replica_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.replicate_from("127.0.0.1:4128".parse().unwrap());
+        server.run("127.0.0.1:4129").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    client.set("key2".to_owned(), "two".to_owned()).unwrap();
+    other.set("key3".to_owned(), "three".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let mut replica = KvsClient::connect("127.0.0.1:4129").unwrap();
+    assert_eq!(replica.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        replica.get("key3".to_owned()).unwrap(),
+        Some("three".to_owned())
+    );
+    replica.select(1).unwrap();
+    assert_eq!(
+        replica.get("key1".to_owned()).unwrap(),
+        Some("one".to_owned())
+    );
+    assert_eq!(
+        replica.get("key2".to_owned()).unwrap(),
+        Some("two".to_owned())
+    );
+    assert_eq!(replica.get("key3".to_owned()).unwrap(), None);
+}
+
+// With a password set, nothing but AUTH is answered until a client sent it,
+// and a replica syncs with the same password
+#[test]
+fn require_pass() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.require_pass("secret".to_owned());
+        server.run("127.0.0.1:4130").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4130").unwrap();
+    stream
+        .write_all(
+            b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+              *2\r\n$4\r\nAUTH\r\n$5\r\nwrong\r\n\
+              *3\r\n$4\r\nAUTH\r\n$5\r\nadmin\r\n$6\r\nsecret\r\n\
+              *2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        )
+        .unwrap();
+    let expected = "-NOAUTH Authentication required.\r\n\
+                    -WRONGPASS invalid username-password pair\r\n\
+                    -WRONGPASS invalid username-password pair\r\n\
+                    +OK\r\n$-1\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+
+    let mut client = KvsClient::connect("127.0.0.1:4130").unwrap();
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::Server(e)) if e.starts_with("NOAUTH")
+    ));
+    client.auth("secret".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(replica_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.replicate_from("127.0.0.1:4130".parse().unwrap());
+        server.require_pass("secret".to_owned());
+        server.run("127.0.0.1:4131").unwrap();
+    });
+    thread::sleep(Duration::from_millis(700));
+    let pool = KvsClientPool::new("127.0.0.1:4131", 1)
+        .unwrap()
+        .with_password("secret".to_owned());
+    assert_eq!(
+        pool.get().unwrap().get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Users only run the commands of their profile, inside MULTI too
+#[test]
+fn user_profiles() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap().into_handle();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap());
+        server.add_user("reader".to_owned(), "r".to_owned(), Profile::Readonly);
+        server.add_user("cache".to_owned(), "c".to_owned(), Profile::CacheClient);
+        server.run("127.0.0.1:4132").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut cache = KvsClient::connect("127.0.0.1:4132").unwrap();
+    // without require_pass there is no default user to log in as
+    assert!(matches!(
+        cache.auth("c".to_owned()),
+        Err(KvsError::Server(_))
+    ));
+    cache.auth_as("cache".to_owned(), "c".to_owned()).unwrap();
+    cache.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert!(matches!(
+        cache.scan(None, 10),
+        Err(KvsError::Server(e)) if e.starts_with("NOPERM")
+    ));
+    // removing by pattern can empty the store, like FLUSHDB
+    assert!(matches!(
+        cache.remove_matching("*".to_owned()),
+        Err(KvsError::Server(e)) if e.starts_with("NOPERM")
+    ));
+
+    let mut reader = KvsClient::connect("127.0.0.1:4132").unwrap();
+    reader.auth_as("reader".to_owned(), "r".to_owned()).unwrap();
+    assert_eq!(
+        reader.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(reader.scan(None, 10).unwrap().0, vec!["key1".to_owned()]);
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvsError::Server(e)) if e.starts_with("NOPERM")
+    ));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4132").unwrap();
+    stream
+        .write_all(
+            b"*3\r\n$4\r\nAUTH\r\n$5\r\ncache\r\n$1\r\nc\r\n\
+              *1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n\
+              *2\r\n$6\r\nBACKUP\r\n$4\r\n/tmp\r\n\
+              *1\r\n$4\r\nEXEC\r\n",
+        )
+        .unwrap();
+    let expected = "+OK\r\n+OK\r\n+QUEUED\r\n\
+                    -NOPERM this user has no permissions to run the 'backup' command\r\n\
+                    -EXECABORT Transaction discarded because of previous errors\r\n";
+    assert_eq!(read_exact_reply(&mut stream, expected.len()), expected);
+}
+
+// The server runs the same on the memory engine
+#[test]
+fn memory_engine() {
+    thread::spawn(|| {
+        let mut server = KvsServer::new(MemStore::new(), SharedQueueThreadPool::new(4).unwrap());
+        server.run("127.0.0.1:4136").unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4136").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+}